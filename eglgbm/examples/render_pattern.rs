@@ -0,0 +1,199 @@
+//! Render a gradient into a GBM buffer, read it back with the CPU and save it as a PNG.
+//!
+//! This exercises the whole allocate → render → export → map path, so it doubles as a smoke test.
+//!
+//! Usage: `cargo run --example render_pattern [render node] [output path]`
+
+use std::ffi::{c_void, CString};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use eglgbm::{egl_ffi, EglContextBuilder, EglDisplay, Fourcc, GraphicsApi, DRM_FORMAT_MOD_LINEAR};
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 128;
+
+const ARGB8888: Fourcc = Fourcc(u32::from_le_bytes(*b"AR24"));
+
+const GL_COLOR_BUFFER_BIT: u32 = 0x4000;
+const GL_SCISSOR_TEST: u32 = 0x0C11;
+const GL_FRAMEBUFFER: u32 = 0x8D40;
+const GL_RENDERBUFFER: u32 = 0x8D41;
+const GL_COLOR_ATTACHMENT0: u32 = 0x8CE0;
+const GL_FRAMEBUFFER_COMPLETE: u32 = 0x8CD5;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let render_node = args.next().unwrap_or_else(|| "/dev/dri/renderD128".into());
+    let out_path = args.next().unwrap_or_else(|| "pattern.png".into());
+
+    let egl = EglDisplay::new(&CString::new(render_node)?)?;
+    eprintln!("EGL v{}.{}", egl.major_version(), egl.minor_version());
+    let context = EglContextBuilder::new(GraphicsApi::OpenGl).build(&egl)?;
+    context.make_current()?;
+    let gl = Gl::load();
+
+    // Linear, so that the buffer can be mapped.
+    let (image, export) =
+        egl.alloc_buffer(WIDTH, HEIGHT, ARGB8888, &[DRM_FORMAT_MOD_LINEAR], false)?;
+
+    unsafe {
+        let mut rbo = 0;
+        (gl.gen_renderbuffers)(1, &mut rbo);
+        (gl.bind_renderbuffer)(GL_RENDERBUFFER, rbo);
+        image.set_as_gl_renderbuffer_storage();
+
+        let mut fbo = 0;
+        (gl.gen_framebuffers)(1, &mut fbo);
+        (gl.bind_framebuffer)(GL_FRAMEBUFFER, fbo);
+        (gl.framebuffer_renderbuffer)(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_RENDERBUFFER, rbo);
+        assert_eq!(
+            (gl.check_framebuffer_status)(GL_FRAMEBUFFER),
+            GL_FRAMEBUFFER_COMPLETE
+        );
+
+        // A horizontal gradient from blue to red, one column at a time. Clearing is enough for
+        // that, so no shaders are needed.
+        (gl.viewport)(0, 0, WIDTH as i32, HEIGHT as i32);
+        (gl.enable)(GL_SCISSOR_TEST);
+        for x in 0..WIDTH {
+            let t = x as f32 / (WIDTH - 1) as f32;
+            (gl.scissor)(x as i32, 0, 1, HEIGHT as i32);
+            (gl.clear_color)(t, 0.0, 1.0 - t, 1.0);
+            (gl.clear)(GL_COLOR_BUFFER_BIT);
+        }
+        (gl.finish)();
+    }
+
+    let mapped = export.map_read()?;
+    let mut rgba = Vec::with_capacity(WIDTH as usize * HEIGHT as usize * 4);
+    for row in mapped
+        .bytes()
+        .chunks(mapped.stride() as usize)
+        .take(HEIGHT as usize)
+    {
+        for bgra in row[..WIDTH as usize * 4].chunks_exact(4) {
+            rgba.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+        }
+    }
+    drop(mapped);
+
+    let mut out = BufWriter::new(File::create(&out_path)?);
+    write_png(&mut out, WIDTH, HEIGHT, &rgba)?;
+    out.flush()?;
+    eprintln!("wrote {out_path}");
+    Ok(())
+}
+
+/// The few GL functions this example needs.
+struct Gl {
+    gen_renderbuffers: unsafe extern "C" fn(i32, *mut u32),
+    bind_renderbuffer: unsafe extern "C" fn(u32, u32),
+    gen_framebuffers: unsafe extern "C" fn(i32, *mut u32),
+    bind_framebuffer: unsafe extern "C" fn(u32, u32),
+    framebuffer_renderbuffer: unsafe extern "C" fn(u32, u32, u32, u32),
+    check_framebuffer_status: unsafe extern "C" fn(u32) -> u32,
+    viewport: unsafe extern "C" fn(i32, i32, i32, i32),
+    enable: unsafe extern "C" fn(u32),
+    scissor: unsafe extern "C" fn(i32, i32, i32, i32),
+    clear_color: unsafe extern "C" fn(f32, f32, f32, f32),
+    clear: unsafe extern "C" fn(u32),
+    finish: unsafe extern "C" fn(),
+}
+
+impl Gl {
+    fn load() -> Self {
+        /// # Safety
+        ///
+        /// `F` must be the function pointer type matching the GL function.
+        unsafe fn get<F>(name: &str) -> F {
+            let cname = CString::new(name).unwrap();
+            let ptr: *mut c_void = unsafe { egl_ffi::eglGetProcAddress(cname.as_ptr()) };
+            assert!(!ptr.is_null(), "{name} is not available");
+            unsafe { std::mem::transmute_copy(&ptr) }
+        }
+
+        unsafe {
+            Self {
+                gen_renderbuffers: get("glGenRenderbuffers"),
+                bind_renderbuffer: get("glBindRenderbuffer"),
+                gen_framebuffers: get("glGenFramebuffers"),
+                bind_framebuffer: get("glBindFramebuffer"),
+                framebuffer_renderbuffer: get("glFramebufferRenderbuffer"),
+                check_framebuffer_status: get("glCheckFramebufferStatus"),
+                viewport: get("glViewport"),
+                enable: get("glEnable"),
+                scissor: get("glScissor"),
+                clear_color: get("glClearColor"),
+                clear: get("glClear"),
+                finish: get("glFinish"),
+            }
+        }
+    }
+}
+
+/// Write an 8-bit RGBA PNG. The image data is stored uncompressed, which keeps this dependency
+/// free.
+fn write_png(out: &mut impl Write, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    // Every row starts with the filter type, 0 means none.
+    let mut raw = Vec::with_capacity((width as usize * 4 + 1) * height as usize);
+    for row in rgba.chunks_exact(width as usize * 4) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // A zlib stream made of "stored" deflate blocks.
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, color type RGBA, default compression, filtering and no interlacing.
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_png_chunk(out, b"IHDR", &ihdr)?;
+    write_png_chunk(out, b"IDAT", &zlib)?;
+    write_png_chunk(out, b"IEND", &[])
+}
+
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
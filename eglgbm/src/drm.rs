@@ -1,6 +1,7 @@
 use libc::dev_t;
 use std::ffi::{c_int, CStr};
 use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
 
 // use crate::xf86drm_ffi;
 
@@ -26,11 +27,50 @@ impl DrmDevice {
         }
     }
 
+    /// Try to create DRM device from an opened node of this device
+    pub fn new_from_fd(fd: BorrowedFd) -> io::Result<Self> {
+        let mut dev_ptr = std::ptr::null_mut();
+        let result = unsafe { ffi::drmGetDevice2(fd.as_raw_fd(), 0, &mut dev_ptr) };
+        if result < 0 {
+            Err(io::Error::from_raw_os_error(-result as _))
+        } else {
+            assert!(!dev_ptr.is_null());
+            Ok(Self(dev_ptr))
+        }
+    }
+
     /// Get a render node path, if supported.
     pub fn render_node(&self) -> Option<&CStr> {
         self.get_node(ffi::DRM_NODE_RENDER)
     }
 
+    /// Get a primary node path, if supported.
+    ///
+    /// This is the node which can be used for modesetting.
+    pub fn primary_node(&self) -> Option<&CStr> {
+        self.get_node(ffi::DRM_NODE_PRIMARY)
+    }
+
+    /// Whether this device has a render node.
+    pub fn is_render_capable(&self) -> bool {
+        self.render_node().is_some()
+    }
+
+    /// Open the render node.
+    ///
+    /// Returns [`io::ErrorKind::NotFound`] if this device has no render node.
+    pub fn open_render_node(&self) -> io::Result<OwnedFd> {
+        open_node(self.render_node())
+    }
+
+    /// Open the primary node.
+    ///
+    /// Returns [`io::ErrorKind::NotFound`] if this device has no primary node. Note that opening a
+    /// primary node usually requires extra privileges, consider using a seat manager instead.
+    pub fn open_primary_node(&self) -> io::Result<OwnedFd> {
+        open_node(self.primary_node())
+    }
+
     fn get_node(&self, node: c_int) -> Option<&CStr> {
         if self.as_ref().available_nodes & (1 << node) == 0 {
             None
@@ -44,6 +84,16 @@ impl DrmDevice {
     }
 }
 
+fn open_node(path: Option<&CStr>) -> io::Result<OwnedFd> {
+    let path = path.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
 impl Drop for DrmDevice {
     fn drop(&mut self) {
         unsafe {
@@ -65,6 +115,7 @@ mod ffi {
 
     use std::ffi::{c_char, c_int};
 
+    pub const DRM_NODE_PRIMARY: c_int = 0;
     pub const DRM_NODE_RENDER: c_int = 2;
 
     #[derive(Copy, Clone)]
@@ -84,6 +135,8 @@ mod ffi {
             device: *mut drmDevicePtr,
         ) -> c_int;
 
+        pub fn drmGetDevice2(fd: c_int, flags: u32, device: *mut drmDevicePtr) -> c_int;
+
         pub fn drmFreeDevice(device: *mut drmDevicePtr);
 
         pub fn drmDevicesEqual(a: drmDevicePtr, b: drmDevicePtr) -> c_int;
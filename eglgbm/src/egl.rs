@@ -3,11 +3,15 @@ use std::ffi::{c_void, CStr};
 use std::fmt;
 use std::os::fd::{AsRawFd, RawFd};
 
-use crate::{egl_ffi, gbm, BufferExport, Error, FormatTable, Fourcc, GraphicsApi, Result};
+use crate::{
+    egl_ffi, gbm, BufferExport, Error, FormatTable, Fourcc, GraphicsApi, Result,
+    DRM_FORMAT_MOD_INVALID,
+};
 
 /// GBM-based EGL display
 ///
-/// Dropping this struct terminates the EGL display.
+/// Dropping this struct terminates the EGL display, unless it was turned into a raw pointer with
+/// [`into_raw`](Self::into_raw).
 // TODO: derive Debug when MSRV is >= 1.70
 pub struct EglDisplay {
     raw: egl_ffi::EGLDisplay,
@@ -68,7 +72,6 @@ impl EglDisplay {
 
         let extensions = EglExtensions::query(raw)?;
         extensions.require("EGL_EXT_image_dma_buf_import_modifiers")?;
-        extensions.require("EGL_KHR_no_config_context")?;
         extensions.require("EGL_KHR_surfaceless_context")?;
 
         let egl_query_dmabuf_formats_ext = unsafe {
@@ -134,6 +137,19 @@ impl EglDisplay {
         })
     }
 
+    /// The raw EGL display pointer. It is terminated when this struct is dropped.
+    pub fn as_raw(&self) -> egl_ffi::EGLDisplay {
+        self.raw
+    }
+
+    /// Consume this struct without terminating the EGL display.
+    ///
+    /// The caller becomes responsible for calling `eglTerminate`. The GBM device backing the
+    /// display is leaked, since the display keeps referencing it even after termination.
+    pub fn into_raw(self) -> egl_ffi::EGLDisplay {
+        std::mem::ManuallyDrop::new(self).raw
+    }
+
     pub(crate) fn gbm_device(&self) -> &gbm::Device {
         &self.gbm_device
     }
@@ -166,6 +182,29 @@ impl EglDisplay {
         }
     }
 
+    /// Pick the best supported modifier out of `candidates`, preferring non-linear ones.
+    ///
+    /// [`DRM_FORMAT_MOD_INVALID`] among the candidates stands for any supported modifier, which
+    /// lets the caller turn an implicit modifier into a concrete one for allocation.
+    pub fn resolve_modifier(&self, fourcc: Fourcc, candidates: &[u64]) -> Option<u64> {
+        let supported = self.supported_formats.get(&fourcc)?;
+        crate::modifier::best_modifier(supported, candidates)
+    }
+
+    /// Check whether buffers of a given format can be used for scanout on this device
+    pub fn is_format_scanout_supported(&self, fourcc: Fourcc) -> bool {
+        self.gbm_device.is_format_scanout_supported(fourcc)
+    }
+
+    /// Get a subset of [`supported_formats`](Self::supported_formats) which can be used for scanout
+    pub fn scanout_supported_formats(&self) -> FormatTable {
+        self.supported_formats
+            .iter()
+            .filter(|(fourcc, _)| self.is_format_scanout_supported(**fourcc))
+            .map(|(fourcc, mods)| (*fourcc, mods.clone()))
+            .collect()
+    }
+
     /// Allocate a new buffer
     pub fn alloc_buffer(
         &self,
@@ -199,10 +238,13 @@ impl EglDisplay {
             egl_image_attrs.push(plane.offset as _);
             egl_image_attrs.push(egl_ffi::EGL_DMA_BUF_PLANE_PITCH_EXT[i] as _);
             egl_image_attrs.push(plane.stride as _);
-            egl_image_attrs.push(egl_ffi::EGL_DMA_BUF_PLANE_MODIFIER_LO_EXT[i] as _);
-            egl_image_attrs.push((buf_parts.modifier & 0xFFFF_FFFF) as _);
-            egl_image_attrs.push(egl_ffi::EGL_DMA_BUF_PLANE_MODIFIER_HI_EXT[i] as _);
-            egl_image_attrs.push((buf_parts.modifier >> 32) as _);
+            // The implicit modifier is expressed by not passing any modifier at all.
+            if buf_parts.modifier != DRM_FORMAT_MOD_INVALID {
+                egl_image_attrs.push(egl_ffi::EGL_DMA_BUF_PLANE_MODIFIER_LO_EXT[i] as _);
+                egl_image_attrs.push((buf_parts.modifier & 0xFFFF_FFFF) as _);
+                egl_image_attrs.push(egl_ffi::EGL_DMA_BUF_PLANE_MODIFIER_HI_EXT[i] as _);
+                egl_image_attrs.push((buf_parts.modifier >> 32) as _);
+            }
         }
         egl_image_attrs.push(egl_ffi::EGL_NONE as _);
 
@@ -309,12 +351,85 @@ unsafe fn get_supported_formats(
     Ok(retval)
 }
 
+/// Choose a config for a given renderable and surface type, preferring configs matching the
+/// formats usually used for framebuffers.
+unsafe fn choose_config(
+    dpy: egl_ffi::EGLDisplay,
+    renderable_type: egl_ffi::EGLint,
+    surface_type: egl_ffi::EGLint,
+) -> Result<egl_ffi::EGLConfig> {
+    const PREFERRED_FORMATS: [Fourcc; 2] = [Fourcc(0x34325258), Fourcc(0x34325241)]; // XR24, AR24
+
+    let config_attrs = [
+        egl_ffi::EGL_SURFACE_TYPE,
+        surface_type,
+        egl_ffi::EGL_RENDERABLE_TYPE,
+        renderable_type,
+        egl_ffi::EGL_RED_SIZE,
+        8,
+        egl_ffi::EGL_GREEN_SIZE,
+        8,
+        egl_ffi::EGL_BLUE_SIZE,
+        8,
+        egl_ffi::EGL_NONE,
+    ];
+
+    let mut configs_len = 0;
+    if unsafe {
+        egl_ffi::eglChooseConfig(
+            dpy,
+            config_attrs.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            &mut configs_len,
+        )
+    } != egl_ffi::EGL_TRUE
+    {
+        return Err(Error::last_egl());
+    }
+
+    let mut configs = Vec::with_capacity(configs_len as usize);
+    if unsafe {
+        egl_ffi::eglChooseConfig(
+            dpy,
+            config_attrs.as_ptr(),
+            configs.as_mut_ptr(),
+            configs_len,
+            &mut configs_len,
+        )
+    } != egl_ffi::EGL_TRUE
+    {
+        return Err(Error::last_egl());
+    }
+    unsafe { configs.set_len(configs_len as usize) };
+
+    let native_visual = |config| {
+        let mut visual = 0;
+        unsafe {
+            egl_ffi::eglGetConfigAttrib(dpy, config, egl_ffi::EGL_NATIVE_VISUAL_ID, &mut visual)
+        };
+        Fourcc(visual as u32)
+    };
+
+    PREFERRED_FORMATS
+        .iter()
+        .find_map(|&fourcc| {
+            configs
+                .iter()
+                .copied()
+                .find(|&config| native_visual(config) == fourcc)
+        })
+        .or_else(|| configs.first().copied())
+        .ok_or(Error::NoSuitableConfig)
+}
+
 /// [`EglContext`] builder
 pub struct EglContextBuilder {
     api: GraphicsApi,
     major_v: u32,
     minor_v: u32,
     debug: bool,
+    robust: bool,
 }
 
 impl EglContextBuilder {
@@ -325,6 +440,7 @@ impl EglContextBuilder {
             major_v: 1,
             minor_v: 0,
             debug: false,
+            robust: false,
         }
     }
 
@@ -341,6 +457,15 @@ impl EglContextBuilder {
         self
     }
 
+    /// Enable/disable robust buffer access with the context being lost on GPU resets, which are
+    /// reported by `glGetGraphicsResetStatus`. Default is `false`.
+    ///
+    /// Creating the context fails if the driver does not support robustness.
+    pub fn robust(mut self, enable: bool) -> Self {
+        self.robust = enable;
+        self
+    }
+
     /// Create a new graphics API context
     ///
     /// Call [`EglContext::make_current`] to activate the context.
@@ -355,20 +480,41 @@ impl EglContextBuilder {
             return Err(Error::last_egl());
         }
 
-        let context_attrs = [
+        // Without EGL_KHR_no_config_context we have to pick a config compatible with the
+        // buffers we are going to render to.
+        let renderable_type = match self.api {
+            GraphicsApi::OpenGl => egl_ffi::EGL_OPENGL_BIT,
+            GraphicsApi::OpenGlEs => egl_ffi::EGL_OPENGL_ES2_BIT,
+            GraphicsApi::OpenVg => egl_ffi::EGL_OPENVG_BIT,
+        };
+        let config = if display.extensions.contains("EGL_KHR_no_config_context") {
+            egl_ffi::EGL_NO_CONFIG
+        } else {
+            unsafe { choose_config(display.raw, renderable_type, egl_ffi::EGL_WINDOW_BIT)? }
+        };
+
+        let mut context_attrs = vec![
             egl_ffi::EGL_CONTEXT_MAJOR_VERSION,
             self.major_v as _,
             egl_ffi::EGL_CONTEXT_MINOR_VERSION,
             self.minor_v as _,
             egl_ffi::EGL_CONTEXT_OPENGL_DEBUG,
             self.debug as _,
-            egl_ffi::EGL_NONE,
         ];
+        if self.robust {
+            context_attrs.extend_from_slice(&[
+                egl_ffi::EGL_CONTEXT_OPENGL_ROBUST_ACCESS,
+                egl_ffi::EGL_TRUE as _,
+                egl_ffi::EGL_CONTEXT_OPENGL_RESET_NOTIFICATION_STRATEGY,
+                egl_ffi::EGL_LOSE_CONTEXT_ON_RESET,
+            ]);
+        }
+        context_attrs.push(egl_ffi::EGL_NONE);
 
         let raw = unsafe {
             egl_ffi::eglCreateContext(
                 display.raw,
-                egl_ffi::EGL_NO_CONFIG,
+                config,
                 egl_ffi::EGL_NO_CONTEXT,
                 context_attrs.as_ptr(),
             )
@@ -381,6 +527,8 @@ impl EglContextBuilder {
         Ok(EglContext {
             raw,
             api,
+            config,
+            renderable_type,
             egl_display: display.raw,
         })
     }
@@ -394,6 +542,8 @@ impl EglContextBuilder {
 pub struct EglContext {
     raw: egl_ffi::EGLContext,
     api: egl_ffi::EGLenum,
+    config: egl_ffi::EGLConfig,
+    renderable_type: egl_ffi::EGLint,
     egl_display: egl_ffi::EGLDisplay,
 }
 
@@ -419,6 +569,32 @@ impl EglContext {
         }
     }
 
+    /// Make this context current on the current thread, drawing to and reading from `surface`.
+    ///
+    /// Use this instead of [`make_current`](Self::make_current) when rendering to an
+    /// [`EglSurface`] rather than to a framebuffer object.
+    pub fn make_current_surface(&self, surface: &EglSurface) -> Result<()> {
+        if unsafe { egl_ffi::eglMakeCurrent(self.egl_display, surface.raw, surface.raw, self.raw) }
+            != egl_ffi::EGL_TRUE
+        {
+            Err(Error::last_egl())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A config for surfaces used with this context.
+    ///
+    /// A context created without a config can be used with any config of the same API, so a
+    /// suitable one is picked.
+    fn surface_config(&self, surface_type: egl_ffi::EGLint) -> Result<egl_ffi::EGLConfig> {
+        if self.config == egl_ffi::EGL_NO_CONFIG {
+            unsafe { choose_config(self.egl_display, self.renderable_type, surface_type) }
+        } else {
+            Ok(self.config)
+        }
+    }
+
     /// Releases the current API context.
     ///
     /// If this context is not current on this thread, `Err(Error::NotCurrentContext)` is returned.
@@ -453,6 +629,99 @@ impl Drop for EglContext {
     }
 }
 
+/// EGL surface, for the cases when rendering to a framebuffer object is not an option
+///
+/// Call [`EglContext::make_current_surface`] to render to it. Dropping this struct will destroy
+/// the surface if it is not current on any thread. Otherwise it will be destroyed when it stops
+/// being current.
+#[derive(Debug)]
+pub struct EglSurface {
+    raw: egl_ffi::EGLSurface,
+    egl_display: egl_ffi::EGLDisplay,
+}
+
+impl EglSurface {
+    /// Create an offscreen pixel buffer surface, compatible with `context`.
+    ///
+    /// The contents can be read back with `glReadPixels` while the surface is current.
+    pub fn pbuffer(context: &EglContext, width: u32, height: u32) -> Result<Self> {
+        let config = context.surface_config(egl_ffi::EGL_PBUFFER_BIT)?;
+        let attrs = [
+            egl_ffi::EGL_WIDTH,
+            width as _,
+            egl_ffi::EGL_HEIGHT,
+            height as _,
+            egl_ffi::EGL_NONE,
+        ];
+        let raw = unsafe {
+            egl_ffi::eglCreatePbufferSurface(context.egl_display, config, attrs.as_ptr())
+        };
+        if raw == egl_ffi::EGL_NO_SURFACE {
+            return Err(Error::last_egl());
+        }
+        Ok(Self {
+            raw,
+            egl_display: context.egl_display,
+        })
+    }
+
+    /// Create a window surface, compatible with `context`.
+    ///
+    /// # Safety
+    ///
+    /// `native_window` must be a valid native window for the platform of the display, which is
+    /// a `gbm_surface` for [`EglDisplay`]. It must outlive the returned surface.
+    pub unsafe fn window(context: &EglContext, native_window: *mut c_void) -> Result<Self> {
+        let config = context.surface_config(egl_ffi::EGL_WINDOW_BIT)?;
+        let attrs = [egl_ffi::EGL_NONE as egl_ffi::EGLAttrib];
+        let raw = unsafe {
+            egl_ffi::eglCreatePlatformWindowSurface(
+                context.egl_display,
+                config,
+                native_window,
+                attrs.as_ptr(),
+            )
+        };
+        if raw == egl_ffi::EGL_NO_SURFACE {
+            return Err(Error::last_egl());
+        }
+        Ok(Self {
+            raw,
+            egl_display: context.egl_display,
+        })
+    }
+
+    /// The current size of this surface
+    pub fn size(&self) -> Result<(u32, u32)> {
+        let query = |attribute| {
+            let mut value = 0;
+            if unsafe {
+                egl_ffi::eglQuerySurface(self.egl_display, self.raw, attribute, &mut value)
+            } != egl_ffi::EGL_TRUE
+            {
+                return Err(Error::last_egl());
+            }
+            Ok(value as u32)
+        };
+        Ok((query(egl_ffi::EGL_WIDTH)?, query(egl_ffi::EGL_HEIGHT)?))
+    }
+
+    /// Post the back buffer of a window surface. Does nothing for pixel buffers.
+    pub fn swap_buffers(&self) -> Result<()> {
+        if unsafe { egl_ffi::eglSwapBuffers(self.egl_display, self.raw) } != egl_ffi::EGL_TRUE {
+            Err(Error::last_egl())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for EglSurface {
+    fn drop(&mut self) {
+        unsafe { egl_ffi::eglDestroySurface(self.egl_display, self.raw) };
+    }
+}
+
 /// A set of EGL extensions
 pub struct EglExtensions(HashSet<&'static [u8]>);
 
@@ -548,3 +817,66 @@ impl Drop for EglImage {
         unsafe { egl_ffi::eglDestroyImage(self.egl_display, self.egl_image) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    /// # Safety
+    ///
+    /// `F` must be the function pointer type matching the GL function.
+    unsafe fn gl_fn<F>(name: &str) -> F {
+        let cname = CString::new(name).unwrap();
+        let ptr = unsafe { egl_ffi::eglGetProcAddress(cname.as_ptr()) };
+        assert!(!ptr.is_null(), "{name} is not available");
+        unsafe { std::mem::transmute_copy(&ptr) }
+    }
+
+    #[test]
+    fn pbuffer_render_and_read_back() {
+        const RENDER_NODE: &CStr = c"/dev/dri/renderD128";
+        const GL_COLOR_BUFFER_BIT: u32 = 0x4000;
+        const GL_RGBA: u32 = 0x1908;
+        const GL_UNSIGNED_BYTE: u32 = 0x1401;
+
+        // Needs a GPU.
+        if !std::path::Path::new(RENDER_NODE.to_str().unwrap()).exists() {
+            eprintln!("skipping, {RENDER_NODE:?} does not exist");
+            return;
+        }
+
+        let egl = EglDisplay::new(RENDER_NODE).unwrap();
+        let context = EglContextBuilder::new(GraphicsApi::OpenGl)
+            .build(&egl)
+            .unwrap();
+        let surface = EglSurface::pbuffer(&context, 4, 2).unwrap();
+        assert_eq!(surface.size().unwrap(), (4, 2));
+        context.make_current_surface(&surface).unwrap();
+
+        let mut pixels = [0u8; 4 * 2 * 4];
+        unsafe {
+            let clear_color: unsafe extern "C" fn(f32, f32, f32, f32) = gl_fn("glClearColor");
+            let clear: unsafe extern "C" fn(u32) = gl_fn("glClear");
+            let read_pixels: unsafe extern "C" fn(i32, i32, i32, i32, u32, u32, *mut c_void) =
+                gl_fn("glReadPixels");
+            clear_color(1.0, 0.0, 0.0, 1.0);
+            clear(GL_COLOR_BUFFER_BIT);
+            read_pixels(
+                0,
+                0,
+                4,
+                2,
+                GL_RGBA,
+                GL_UNSIGNED_BYTE,
+                pixels.as_mut_ptr().cast(),
+            );
+        }
+        for rgba in pixels.chunks_exact(4) {
+            assert_eq!(rgba[..3], [255, 0, 0]);
+        }
+
+        context.release().unwrap();
+    }
+}
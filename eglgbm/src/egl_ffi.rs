@@ -62,10 +62,13 @@ pub const EGL_BAD_NATIVE_PIXMAP: EGLint = 0x300A;
 pub const EGL_BAD_NATIVE_WINDOW: EGLint = 0x300B;
 pub const EGL_BAD_PARAMETER: EGLint = 0x300C;
 pub const EGL_BAD_SURFACE: EGLint = 0x300D;
+pub const EGL_BLUE_SIZE: EGLint = 0x3022;
 pub const EGL_CONTEXT_LOST: EGLint = 0x300E;
 pub const EGL_CONTEXT_MAJOR_VERSION: EGLint = 0x3098;
 pub const EGL_CONTEXT_MINOR_VERSION: EGLint = 0x30FB;
 pub const EGL_CONTEXT_OPENGL_DEBUG: EGLint = 0x31B0;
+pub const EGL_CONTEXT_OPENGL_RESET_NOTIFICATION_STRATEGY: EGLint = 0x31BD;
+pub const EGL_CONTEXT_OPENGL_ROBUST_ACCESS: EGLint = 0x31B2;
 pub const EGL_DEFAULT_DISPLAY: *mut c_void = std::ptr::null_mut();
 pub const EGL_DMA_BUF_PLANE0_FD_EXT: EGLint = 0x3272;
 pub const EGL_DMA_BUF_PLANE0_MODIFIER_HI_EXT: EGLint = 0x3444;
@@ -91,9 +94,12 @@ pub const EGL_EXTENSIONS: EGLint = 0x3055;
 pub const EGL_FALSE: EGLBoolean = 0;
 pub const EGL_GL_RENDERBUFFER: EGLenum = 0x30B9;
 pub const EGL_GL_TEXTURE_2D: EGLenum = 0x30B1;
+pub const EGL_GREEN_SIZE: EGLint = 0x3023;
 pub const EGL_HEIGHT: EGLint = 0x3056;
 pub const EGL_LINUX_DMA_BUF_EXT: EGLenum = 0x3270;
 pub const EGL_LINUX_DRM_FOURCC_EXT: EGLint = 0x3271;
+pub const EGL_LOSE_CONTEXT_ON_RESET: EGLint = 0x31BF;
+pub const EGL_NATIVE_VISUAL_ID: EGLint = 0x302E;
 pub const EGL_NO_CONFIG: EGLConfig = EGLConfig(std::ptr::null_mut());
 pub const EGL_NO_CONTEXT: EGLContext = EGLContext(std::ptr::null_mut());
 pub const EGL_NO_DISPLAY: EGLDisplay = EGLDisplay(std::ptr::null_mut());
@@ -102,14 +108,20 @@ pub const EGL_NONE: EGLint = 0x3038;
 pub const EGL_NO_SURFACE: EGLSurface = EGLSurface(std::ptr::null_mut());
 pub const EGL_NOT_INITIALIZED: EGLint = 0x3001;
 pub const EGL_OPENGL_API: EGLenum = 0x30A2;
+pub const EGL_OPENGL_BIT: EGLint = 0x0008;
+pub const EGL_OPENGL_ES2_BIT: EGLint = 0x0004;
 pub const EGL_OPENGL_ES_API: EGLenum = 0x30A0;
 pub const EGL_OPENVG_API: EGLenum = 0x30A1;
+pub const EGL_OPENVG_BIT: EGLint = 0x0002;
 pub const EGL_PBUFFER_BIT: EGLint = 0x0001;
 pub const EGL_PLATFORM_GBM_KHR: EGLenum = 0x31D7;
+pub const EGL_RED_SIZE: EGLint = 0x3024;
+pub const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
 pub const EGL_SUCCESS: EGLint = 0x3000;
 pub const EGL_SURFACE_TYPE: EGLint = 0x3033;
 pub const EGL_TRUE: EGLBoolean = 1;
 pub const EGL_WIDTH: EGLint = 0x3057;
+pub const EGL_WINDOW_BIT: EGLint = 0x0004;
 
 pub const EGL_DMA_BUF_PLANE_FD_EXT: [EGLint; 4] = [
     EGL_DMA_BUF_PLANE0_FD_EXT,
@@ -157,6 +169,21 @@ extern "C" {
 
     pub fn eglBindAPI(api: EGLenum) -> EGLBoolean;
 
+    pub fn eglChooseConfig(
+        dpy: EGLDisplay,
+        attrib_list: *const EGLint,
+        configs: *mut EGLConfig,
+        config_size: EGLint,
+        num_config: *mut EGLint,
+    ) -> EGLBoolean;
+
+    pub fn eglGetConfigAttrib(
+        dpy: EGLDisplay,
+        config: EGLConfig,
+        attribute: EGLint,
+        value: *mut EGLint,
+    ) -> EGLBoolean;
+
     pub fn eglCreateContext(
         dpy: EGLDisplay,
         config: EGLConfig,
@@ -175,6 +202,30 @@ extern "C" {
 
     pub fn eglGetCurrentContext() -> EGLContext;
 
+    pub fn eglCreatePbufferSurface(
+        dpy: EGLDisplay,
+        config: EGLConfig,
+        attrib_list: *const EGLint,
+    ) -> EGLSurface;
+
+    pub fn eglCreatePlatformWindowSurface(
+        dpy: EGLDisplay,
+        config: EGLConfig,
+        native_window: *mut c_void,
+        attrib_list: *const EGLAttrib,
+    ) -> EGLSurface;
+
+    pub fn eglDestroySurface(dpy: EGLDisplay, surface: EGLSurface) -> EGLBoolean;
+
+    pub fn eglQuerySurface(
+        dpy: EGLDisplay,
+        surface: EGLSurface,
+        attribute: EGLint,
+        value: *mut EGLint,
+    ) -> EGLBoolean;
+
+    pub fn eglSwapBuffers(dpy: EGLDisplay, surface: EGLSurface) -> EGLBoolean;
+
     pub fn eglCreateImage(
         dpy: EGLDisplay,
         context: EGLContext,
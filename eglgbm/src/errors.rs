@@ -1,6 +1,6 @@
 use std::io;
 
-use crate::egl_ffi;
+use crate::{egl_ffi, Fourcc};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -12,10 +12,25 @@ pub enum Error {
     Egl(#[from] EglError),
     #[error("extension {0} is not supported")]
     ExtensionUnsupported(&'static str),
-    #[error("could not allocate GBM buffer")]
-    BadGbmAlloc,
+    #[error("no suitable EGLConfig found")]
+    NoSuitableConfig,
+    #[error("could not create GBM device: {0}")]
+    GbmDevice(#[source] io::Error),
+    #[error("GBM does not support {0:?} buffers")]
+    GbmUnsupportedFormat(Fourcc),
+    #[error("GBM could not allocate {width}x{height} {format:?} with modifiers {modifiers:x?}")]
+    BadGbmAlloc {
+        width: u32,
+        height: u32,
+        format: Fourcc,
+        modifiers: Vec<u64>,
+    },
     #[error("EglContext::release called for not current context")]
     NotCurrentContext,
+    #[error("only linear single-plane buffers can be mapped")]
+    NotMappable,
+    #[error("{0} planes is not valid for format {1:?}")]
+    BadPlaneCount(usize, Fourcc),
     #[error(transparent)]
     Io(#[from] io::Error),
 }
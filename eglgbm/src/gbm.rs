@@ -2,7 +2,7 @@ use std::ffi::CStr;
 use std::io;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 
-use crate::{Error, Fourcc, Result};
+use crate::{Error, Fourcc, Result, DRM_FORMAT_MOD_LINEAR};
 
 #[derive(Debug)]
 pub struct Device {
@@ -11,25 +11,27 @@ pub struct Device {
 }
 
 impl Device {
-    pub fn open(path: &CStr) -> io::Result<Self> {
+    pub fn open(path: &CStr) -> Result<Self> {
         let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            let err = io::Error::last_os_error();
+            let msg = format!("could not open {path:?}: {err}");
+            return Err(Error::GbmDevice(io::Error::new(err.kind(), msg)));
         }
         let fd = unsafe { OwnedFd::from_raw_fd(fd) };
 
         let raw = unsafe { gbm_sys::gbm_create_device(fd.as_raw_fd()) };
         if raw.is_null() {
-            return Err(io::Error::last_os_error());
+            return Err(Error::GbmDevice(io::Error::last_os_error()));
         }
 
         Ok(Self { raw, _fd: Some(fd) })
     }
 
-    pub fn with_drm_fd(fd: RawFd) -> io::Result<Self> {
+    pub fn with_drm_fd(fd: RawFd) -> Result<Self> {
         let raw = unsafe { gbm_sys::gbm_create_device(fd) };
         if raw.is_null() {
-            return Err(io::Error::last_os_error());
+            return Err(Error::GbmDevice(io::Error::last_os_error()));
         }
         Ok(Self { raw, _fd: None })
     }
@@ -50,6 +52,9 @@ impl Device {
         if scan_out {
             flags |= gbm_sys::gbm_bo_flags::GBM_BO_USE_SCANOUT;
         }
+        if !self.is_format_supported_with_usage(fourcc, flags) {
+            return Err(Error::GbmUnsupportedFormat(fourcc));
+        }
         let ptr = unsafe {
             gbm_sys::gbm_bo_create_with_modifiers2(
                 self.raw,
@@ -62,20 +67,31 @@ impl Device {
             )
         };
         if ptr.is_null() {
-            Err(Error::BadGbmAlloc)
+            Err(Error::BadGbmAlloc {
+                width,
+                height,
+                format: fourcc,
+                modifiers: modifiers.to_vec(),
+            })
         } else {
             Ok(Buffer(ptr))
         }
     }
 
     pub fn is_format_supported(&self, fourcc: Fourcc) -> bool {
-        unsafe {
-            gbm_sys::gbm_device_is_format_supported(
-                self.raw,
-                fourcc.0,
-                gbm_sys::gbm_bo_flags::GBM_BO_USE_RENDERING,
-            ) != 0
-        }
+        self.is_format_supported_with_usage(fourcc, gbm_sys::gbm_bo_flags::GBM_BO_USE_RENDERING)
+    }
+
+    pub fn is_format_scanout_supported(&self, fourcc: Fourcc) -> bool {
+        self.is_format_supported_with_usage(fourcc, gbm_sys::gbm_bo_flags::GBM_BO_USE_SCANOUT)
+    }
+
+    fn is_format_supported_with_usage(
+        &self,
+        fourcc: Fourcc,
+        usage: gbm_sys::gbm_bo_flags::Type,
+    ) -> bool {
+        unsafe { gbm_sys::gbm_device_is_format_supported(self.raw, fourcc.0, usage) != 0 }
     }
 }
 
@@ -138,6 +154,48 @@ pub struct BufferExport {
     pub planes: Vec<BufferPlane>,
 }
 
+impl BufferExport {
+    /// Assemble a buffer from planes received from elsewhere, e.g. from a client.
+    ///
+    /// Checks that the number of planes makes sense for the format. Non-linear modifiers may use
+    /// auxiliary planes, so for them only the lower bound is checked.
+    pub fn new(
+        width: u32,
+        height: u32,
+        format: Fourcc,
+        modifier: u64,
+        planes: Vec<BufferPlane>,
+    ) -> Result<Self> {
+        let format_planes = format_plane_count(format);
+        let valid = match planes.len() {
+            n if n == 0 || n > 4 => false,
+            n if modifier == DRM_FORMAT_MOD_LINEAR => n == format_planes,
+            n => n >= format_planes,
+        };
+        if !valid {
+            return Err(Error::BadPlaneCount(planes.len(), format));
+        }
+        Ok(Self {
+            width,
+            height,
+            format,
+            modifier,
+            planes,
+        })
+    }
+}
+
+/// The number of memory planes of a format. Packed formats (all RGB ones) have one plane.
+fn format_plane_count(format: Fourcc) -> usize {
+    match &format.0.to_le_bytes() {
+        b"NV12" | b"NV21" | b"NV16" | b"NV61" | b"NV24" | b"NV42" | b"P010" | b"P012" | b"P016" => {
+            2
+        }
+        b"YU12" | b"YV12" | b"YU16" | b"YV16" | b"YU24" | b"YV24" => 3,
+        _ => 1,
+    }
+}
+
 #[derive(Debug)]
 pub struct BufferPlane {
     pub dmabuf: OwnedFd,
@@ -145,3 +203,85 @@ pub struct BufferPlane {
     pub offset: u32,
     pub stride: u32,
 }
+
+// From linux/dma-buf.h
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+const DMA_BUF_IOCTL_SYNC: u32 = 0x4008_6200;
+
+impl BufferExport {
+    /// Map the buffer for reading by the CPU.
+    ///
+    /// Only single-plane buffers with the linear modifier can be mapped, since the layout of
+    /// other modifiers is opaque. The mapping is kept coherent with the GPU until it is dropped.
+    pub fn map_read(&self) -> Result<MappedBuffer<'_>> {
+        let [plane] = self.planes.as_slice() else {
+            return Err(Error::NotMappable);
+        };
+        if self.modifier != DRM_FORMAT_MOD_LINEAR {
+            return Err(Error::NotMappable);
+        }
+
+        let fd = plane.dmabuf.as_raw_fd();
+        let len = plane.offset as usize + plane.stride as usize * self.height as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mapped = MappedBuffer { plane, ptr, len };
+        dma_buf_sync(fd, DMA_BUF_SYNC_START | DMA_BUF_SYNC_READ)?;
+        Ok(mapped)
+    }
+}
+
+/// A read-only CPU mapping of a buffer, see [`BufferExport::map_read`].
+#[derive(Debug)]
+pub struct MappedBuffer<'a> {
+    plane: &'a BufferPlane,
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl MappedBuffer<'_> {
+    /// The pixels of the buffer, `stride` bytes per row.
+    pub fn bytes(&self) -> &[u8] {
+        let all = unsafe { std::slice::from_raw_parts(self.ptr.cast::<u8>(), self.len) };
+        &all[self.plane.offset as usize..]
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.plane.stride
+    }
+}
+
+impl Drop for MappedBuffer<'_> {
+    fn drop(&mut self) {
+        let _ = dma_buf_sync(
+            self.plane.dmabuf.as_raw_fd(),
+            DMA_BUF_SYNC_END | DMA_BUF_SYNC_READ,
+        );
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+fn dma_buf_sync(fd: RawFd, flags: u64) -> io::Result<()> {
+    loop {
+        if unsafe { libc::ioctl(fd, DMA_BUF_IOCTL_SYNC as _, &flags) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
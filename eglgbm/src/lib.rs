@@ -12,12 +12,14 @@ mod drm;
 mod egl;
 mod errors;
 mod gbm;
+mod modifier;
 
 pub mod egl_ffi;
 pub use drm::DrmDevice;
-pub use egl::{EglContext, EglContextBuilder, EglDisplay, EglExtensions, EglImage};
+pub use egl::{EglContext, EglContextBuilder, EglDisplay, EglExtensions, EglImage, EglSurface};
 pub use errors::*;
-pub use gbm::{Buffer as GbmBuffer, BufferExport, BufferPlane};
+pub use gbm::{Buffer as GbmBuffer, BufferExport, BufferPlane, MappedBuffer};
+pub use modifier::Modifier;
 
 #[derive(Debug, Clone, Copy)]
 pub enum GraphicsApi {
@@ -33,6 +35,32 @@ pub struct Fourcc(pub u32);
 /// A mapping from fourcc drm format to a list of modifiers
 pub type FormatTable = HashMap<Fourcc, Vec<u64>>;
 
+/// Human-readable [`FormatTable`] formatting, e.g. `AR24[LINEAR, INTEL_X_TILED], XR24[LINEAR]`
+///
+/// Formats are sorted by fourcc, modifiers are kept in the table's order.
+pub struct FormatTableDisplay<'a>(pub &'a FormatTable);
+
+/// Returns the formats and modifiers present in `a` but missing in `b`.
+pub fn format_table_diff(a: &FormatTable, b: &FormatTable) -> FormatTable {
+    a.iter()
+        .filter_map(|(fourcc, mods)| {
+            let other = b.get(fourcc).map_or(&[][..], |m| m.as_slice());
+            let missing: Vec<u64> = mods
+                .iter()
+                .copied()
+                .filter(|m| !other.contains(m))
+                .collect();
+            (!missing.is_empty()).then_some((*fourcc, missing))
+        })
+        .collect()
+}
+
+/// The modifier of buffers with a plain row-major layout
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// The "implicit modifier": the layout is chosen by the driver and not communicated
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
 impl fmt::Debug for Fourcc {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let [a, b, c, d] = self.0.to_le_bytes();
@@ -46,3 +74,20 @@ impl fmt::Debug for Fourcc {
         )
     }
 }
+
+impl fmt::Display for FormatTableDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut formats: Vec<_> = self.0.iter().collect();
+        formats.sort_by_key(|(fourcc, _)| **fourcc);
+        for (i, (fourcc, mods)) in formats.into_iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{fourcc:?}")?;
+            f.debug_list()
+                .entries(mods.iter().map(|&m| Modifier(m)))
+                .finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt;
+
+use crate::{DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR};
+
+/// A DRM format modifier wrapper with nice `Debug` formatting
+///
+/// Well-known modifiers are printed by name, e.g. `INTEL_X_TILED`, others as `VENDOR:0x...`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Modifier(pub u64);
+
+const VENDORS: [&str; 11] = [
+    "NONE",
+    "INTEL",
+    "AMD",
+    "NVIDIA",
+    "SAMSUNG",
+    "QCOM",
+    "VIVANTE",
+    "BROADCOM",
+    "ARM",
+    "ALLWINNER",
+    "AMLOGIC",
+];
+
+const VENDOR_INTEL: u64 = 0x01;
+const VENDOR_AMD: u64 = 0x02;
+const VENDOR_NVIDIA: u64 = 0x03;
+const VENDOR_SAMSUNG: u64 = 0x04;
+const VENDOR_QCOM: u64 = 0x05;
+const VENDOR_VIVANTE: u64 = 0x06;
+const VENDOR_BROADCOM: u64 = 0x07;
+const VENDOR_ALLWINNER: u64 = 0x09;
+
+/// Non-parametric modifiers from `drm_fourcc.h`, as (vendor, value, name).
+const NAMED: &[(u64, u64, &str)] = &[
+    (VENDOR_INTEL, 1, "INTEL_X_TILED"),
+    (VENDOR_INTEL, 2, "INTEL_Y_TILED"),
+    (VENDOR_INTEL, 3, "INTEL_Yf_TILED"),
+    (VENDOR_INTEL, 4, "INTEL_Y_TILED_CCS"),
+    (VENDOR_INTEL, 5, "INTEL_Yf_TILED_CCS"),
+    (VENDOR_INTEL, 6, "INTEL_Y_TILED_GEN12_RC_CCS"),
+    (VENDOR_INTEL, 7, "INTEL_Y_TILED_GEN12_MC_CCS"),
+    (VENDOR_INTEL, 8, "INTEL_Y_TILED_GEN12_RC_CCS_CC"),
+    (VENDOR_INTEL, 9, "INTEL_4_TILED"),
+    (VENDOR_INTEL, 10, "INTEL_4_TILED_DG2_RC_CCS"),
+    (VENDOR_INTEL, 11, "INTEL_4_TILED_DG2_MC_CCS"),
+    (VENDOR_INTEL, 12, "INTEL_4_TILED_DG2_RC_CCS_CC"),
+    (VENDOR_INTEL, 13, "INTEL_4_TILED_MTL_RC_CCS"),
+    (VENDOR_INTEL, 14, "INTEL_4_TILED_MTL_MC_CCS"),
+    (VENDOR_INTEL, 15, "INTEL_4_TILED_MTL_RC_CCS_CC"),
+    (VENDOR_INTEL, 16, "INTEL_4_TILED_LNL_CCS"),
+    (VENDOR_INTEL, 17, "INTEL_4_TILED_BMG_CCS"),
+    (VENDOR_NVIDIA, 1, "NVIDIA_TEGRA_TILED"),
+    (VENDOR_SAMSUNG, 1, "SAMSUNG_64_32_TILE"),
+    (VENDOR_SAMSUNG, 2, "SAMSUNG_16_16_TILE"),
+    (VENDOR_QCOM, 1, "QCOM_COMPRESSED"),
+    (VENDOR_QCOM, 2, "QCOM_TILED2"),
+    (VENDOR_QCOM, 3, "QCOM_TILED3"),
+    (VENDOR_VIVANTE, 1, "VIVANTE_TILED"),
+    (VENDOR_VIVANTE, 2, "VIVANTE_SUPER_TILED"),
+    (VENDOR_VIVANTE, 3, "VIVANTE_SPLIT_TILED"),
+    (VENDOR_VIVANTE, 4, "VIVANTE_SPLIT_SUPER_TILED"),
+    (VENDOR_BROADCOM, 1, "BROADCOM_VC4_T_TILED"),
+    (VENDOR_ALLWINNER, 1, "ALLWINNER_TILED"),
+];
+
+impl Modifier {
+    /// The vendor code, the top 8 bits of the modifier
+    pub fn vendor(self) -> u8 {
+        (self.0 >> 56) as u8
+    }
+
+    /// The vendor-specific part of the modifier
+    pub fn value(self) -> u64 {
+        self.0 & 0x00ff_ffff_ffff_ffff
+    }
+
+    /// The name of a well-known non-parametric modifier
+    pub fn name(self) -> Option<&'static str> {
+        match self.0 {
+            DRM_FORMAT_MOD_LINEAR => Some("LINEAR"),
+            DRM_FORMAT_MOD_INVALID => Some("INVALID"),
+            _ => NAMED
+                .iter()
+                .find(|&&(vendor, value, _)| {
+                    vendor == self.vendor() as u64 && value == self.value()
+                })
+                .map(|&(_, _, name)| name),
+        }
+    }
+}
+
+impl fmt::Debug for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = self.name() {
+            return f.write_str(name);
+        }
+        if self.vendor() as u64 == VENDOR_AMD {
+            if let Some(res) = fmt_amd(f, self.value()) {
+                return res;
+            }
+        }
+        match VENDORS.get(self.vendor() as usize) {
+            Some(vendor) => write!(f, "{vendor}:{:#x}", self.value()),
+            None => write!(f, "{:#018x}", self.0),
+        }
+    }
+}
+
+/// Pick the best modifier out of `candidates` which is also in `supported`, preferring non-linear
+/// ones. [`DRM_FORMAT_MOD_INVALID`] among the candidates stands for any supported modifier.
+pub(crate) fn best_modifier(supported: &[u64], candidates: &[u64]) -> Option<u64> {
+    let any = candidates.contains(&DRM_FORMAT_MOD_INVALID);
+    let usable = || {
+        supported
+            .iter()
+            .copied()
+            .filter(|m| *m != DRM_FORMAT_MOD_INVALID && (any || candidates.contains(m)))
+    };
+    usable()
+        .find(|&m| m != DRM_FORMAT_MOD_LINEAR)
+        .or_else(|| usable().next())
+}
+
+/// AMD modifiers are parametric: the tile version and the swizzle mode are printed by name, the
+/// remaining bits (DCC parameters, pipe and bank configuration) as a number.
+fn fmt_amd(f: &mut fmt::Formatter<'_>, value: u64) -> Option<fmt::Result> {
+    const DCC: u64 = 1 << 13;
+
+    let version = match value & 0xff {
+        1 => "GFX9",
+        2 => "GFX10",
+        3 => "GFX10_RBPLUS",
+        4 => "GFX11",
+        5 => "GFX12",
+        _ => return None,
+    };
+    let tile = match (value & 0xff, (value >> 8) & 0x1f) {
+        (5, 1) => "256B_2D",
+        (5, 2) => "4K_2D",
+        (5, 3) => "64K_2D",
+        (5, 4) => "256K_2D",
+        (5, _) => return None,
+        (_, 9) => "64K_S",
+        (_, 10) => "64K_D",
+        (_, 25) => "64K_S_X",
+        (_, 26) => "64K_D_X",
+        (_, 27) => "64K_R_X",
+        (_, 31) => "256K_R_X",
+        _ => return None,
+    };
+    let dcc = if value & DCC != 0 { "_DCC" } else { "" };
+    let rest = value & !(0x1fff | DCC);
+    Some(if rest == 0 {
+        write!(f, "AMD_{version}_{tile}{dcc}")
+    } else {
+        write!(f, "AMD_{version}_{tile}{dcc}:{rest:#x}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(modifier: u64) -> String {
+        format!("{:?}", Modifier(modifier))
+    }
+
+    #[test]
+    fn known_modifiers() {
+        assert_eq!(name(DRM_FORMAT_MOD_LINEAR), "LINEAR");
+        assert_eq!(name(DRM_FORMAT_MOD_INVALID), "INVALID");
+        assert_eq!(name(0x0100_0000_0000_0001), "INTEL_X_TILED");
+        assert_eq!(name(0x0100_0000_0000_0004), "INTEL_Y_TILED_CCS");
+        assert_eq!(name(0x0100_0000_0000_0005), "INTEL_Yf_TILED_CCS");
+        assert_eq!(name(0x0100_0000_0000_0009), "INTEL_4_TILED");
+        assert_eq!(name(0x0700_0000_0000_0001), "BROADCOM_VC4_T_TILED");
+        // GFX9, 64K_S_X, with and without DCC.
+        assert_eq!(name(0x0200_0000_0000_1901), "AMD_GFX9_64K_S_X");
+        assert_eq!(name(0x0200_0000_0000_3901), "AMD_GFX9_64K_S_X_DCC");
+        assert_eq!(name(0x0800_0000_0000_0001), "ARM:0x1");
+    }
+
+    #[test]
+    fn best_modifier_prefers_non_linear() {
+        const X_TILED: u64 = 0x0100_0000_0000_0001;
+        const Y_TILED: u64 = 0x0100_0000_0000_0002;
+        let supported = [DRM_FORMAT_MOD_LINEAR, X_TILED, Y_TILED];
+
+        assert_eq!(
+            best_modifier(&supported, &[DRM_FORMAT_MOD_LINEAR, Y_TILED]),
+            Some(Y_TILED)
+        );
+        assert_eq!(
+            best_modifier(&supported, &[DRM_FORMAT_MOD_LINEAR]),
+            Some(DRM_FORMAT_MOD_LINEAR)
+        );
+        assert_eq!(best_modifier(&supported, &[0x0200_0000_0000_0001]), None);
+        assert_eq!(best_modifier(&[], &[DRM_FORMAT_MOD_LINEAR]), None);
+    }
+
+    #[test]
+    fn best_modifier_resolves_implicit() {
+        const X_TILED: u64 = 0x0100_0000_0000_0001;
+
+        assert_eq!(
+            best_modifier(&[DRM_FORMAT_MOD_LINEAR, X_TILED], &[DRM_FORMAT_MOD_INVALID]),
+            Some(X_TILED)
+        );
+        assert_eq!(
+            best_modifier(&[DRM_FORMAT_MOD_LINEAR], &[DRM_FORMAT_MOD_INVALID]),
+            Some(DRM_FORMAT_MOD_LINEAR)
+        );
+        // Nothing concrete to resolve to.
+        assert_eq!(
+            best_modifier(&[DRM_FORMAT_MOD_INVALID], &[DRM_FORMAT_MOD_INVALID]),
+            None
+        );
+    }
+}
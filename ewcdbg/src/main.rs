@@ -1,3 +1,4 @@
+use std::io::BufRead;
 use std::time::Duration;
 
 use wayrs_client::{global::GlobalsExt, Connection, IoMode};
@@ -8,18 +9,47 @@ use ewc_debug_v1::Interest;
 const INTERESTS: &[(&str, &str, ewc_debug_v1::Interest)] = &[
     ("frame", "frame timings", Interest::FrameStat),
     ("message", "arbitrary debug messages", Interest::Messages),
+    (
+        "counters",
+        "frames, clients, requests and events per second",
+        Interest::Counters,
+    ),
 ];
 
 fn usage() -> ! {
     println!("Usage: ewcdbg interest1 [interest2 [...]]");
+    println!("       ewcdbg pause");
     println!("Possible interests");
     for (interest, desc, _) in INTERESTS {
         println!("  {interest} - {desc}");
     }
+    println!("Pause stops rendering, renders a frame on each line of input and resumes on EOF");
     std::process::exit(1);
 }
 
+fn pause() {
+    let (mut conn, globals) = Connection::<()>::connect_and_collect_globals().unwrap();
+    let debug: EwcDebugV1 = globals
+        .bind(&mut conn, 4..=4)
+        .expect("unsupported compositor");
+    debug.pause_rendering(&mut conn);
+    conn.flush(IoMode::Blocking).unwrap();
+    println!("rendering paused, press enter to render a frame, ctrl-d to resume");
+    for line in std::io::stdin().lock().lines() {
+        line.unwrap();
+        debug.step_frame(&mut conn);
+        conn.flush(IoMode::Blocking).unwrap();
+    }
+    debug.resume_rendering(&mut conn);
+    conn.flush(IoMode::Blocking).unwrap();
+}
+
 fn main() {
+    if std::env::args().skip(1).eq(["pause"]) {
+        pause();
+        return;
+    }
+
     let mut interest = Interest::None;
     for arg in std::env::args().skip(1) {
         if let Some((_, _, i)) = INTERESTS.iter().find(|i| i.0 == arg) {
@@ -34,7 +64,9 @@ fn main() {
 
     let (mut conn, globals) = Connection::<()>::connect_and_collect_globals().unwrap();
 
-    let debug: EwcDebugV1 = globals.bind(&mut conn, 1).expect("unsupported compositor");
+    let debug: EwcDebugV1 = globals
+        .bind(&mut conn, 1..=4)
+        .expect("unsupported compositor");
     debug.get_debugger_with_cb(&mut conn, interest, |ctx| {
         use ewc_debugger_v1::Event;
         match ctx.event {
@@ -42,9 +74,23 @@ fn main() {
                 let dur = Duration::from_nanos(nanos as u64);
                 println!("frame composed in {dur:?}");
             }
+            Event::GpuFrameStat(nanos) => {
+                let dur = Duration::from_nanos(nanos as u64);
+                println!("frame rendered by the GPU in {dur:?}");
+            }
             Event::Massage(msg) => {
                 println!("msg: {}", msg.to_str().unwrap());
             }
+            Event::Counters(c) => {
+                let secs = c.interval as f32 / 1000.0;
+                println!(
+                    "per second: {:.1} frames, {:.1} requests, {:.1} events ({} clients)",
+                    c.frames as f32 / secs,
+                    c.requests as f32 / secs,
+                    c.events as f32 / secs,
+                    c.clients,
+                );
+            }
         }
     });
 
@@ -3,14 +3,18 @@ use libseat_sys as sys;
 use std::{
     cell::RefCell,
     collections::VecDeque,
-    ffi::{c_void, CString},
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fs::File,
     io,
     os::{
         fd::{FromRawFd, OwnedFd},
-        unix::io::BorrowedFd,
+        unix::{fs::MetadataExt, io::BorrowedFd},
     },
+    panic::{self, AssertUnwindSafe},
     path::Path,
     ptr::NonNull,
+    sync::Mutex,
+    time::Duration,
 };
 
 extern "C" fn enable_seat(_seat: *mut sys::libseat, data: *mut c_void) {
@@ -30,6 +34,80 @@ static mut FFI_SEAT_LISTENER: sys::libseat_seat_listener = sys::libseat_seat_lis
     disable_seat: Some(disable_seat),
 };
 
+/// Log verbosity level of libseat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Silent = 0,
+    Error = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    fn from_raw(raw: sys::libseat_log_level) -> Self {
+        match raw {
+            0 => Self::Silent,
+            1 => Self::Error,
+            2 => Self::Info,
+            _ => Self::Debug,
+        }
+    }
+}
+
+type LogHandler = Box<dyn Fn(LogLevel, &str) + Send>;
+
+static LOG_HANDLER: Mutex<Option<LogHandler>> = Mutex::new(None);
+
+/// The `va_list` argument of a log function, as the bindings declare it for the target: a pointer
+/// on some architectures, a struct passed by value on others.
+type VaList = <sys::libseat_log_func as LogFunc>::VaList;
+
+trait LogFunc {
+    type VaList;
+}
+
+impl<A> LogFunc for Option<unsafe extern "C" fn(sys::libseat_log_level, *const c_char, A)> {
+    type VaList = A;
+}
+
+extern "C" {
+    fn vsnprintf(buf: *mut c_char, size: usize, fmt: *const c_char, args: VaList) -> c_int;
+}
+
+unsafe extern "C" fn ffi_log_handler(
+    level: sys::libseat_log_level,
+    fmt: *const c_char,
+    args: VaList,
+) {
+    let mut buf = [0u8; 1024];
+    let len = unsafe { vsnprintf(buf.as_mut_ptr().cast(), buf.len(), fmt, args) };
+    if len < 0 {
+        return;
+    }
+    let msg = CStr::from_bytes_until_nul(&buf).unwrap_or_default();
+    if let Some(handler) = &*LOG_HANDLER.lock().unwrap() {
+        // Unwinding into C is undefined behaviour.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            handler(LogLevel::from_raw(level), &msg.to_string_lossy())
+        }));
+    }
+}
+
+/// Sets the libseat log level.
+///
+/// Only log messages at or below the provided log level will be logged.
+pub fn set_log_level(level: LogLevel) {
+    unsafe { sys::libseat_set_log_level(level as _) };
+}
+
+/// Sets the handler for log messages produced by libseat.
+///
+/// The default handler prints messages to stderr. Long messages may be truncated.
+pub fn set_log_handler(handler: impl Fn(LogLevel, &str) + Send + 'static) {
+    *LOG_HANDLER.lock().unwrap() = Some(Box::new(handler));
+    unsafe { sys::libseat_set_log_handler(Some(ffi_log_handler)) };
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Event {
     Enable,
@@ -67,6 +145,24 @@ impl Seat {
             .ok_or_else(io::Error::last_os_error)
     }
 
+    /// Same as [`open`](Self::open), but retries up to `attempts` times, sleeping `delay` between
+    /// the attempts.
+    ///
+    /// Useful when the seat manager may still be starting up.
+    pub fn open_with_retry(attempts: u32, delay: Duration) -> io::Result<Self> {
+        let mut attempt = 1;
+        loop {
+            match Self::open() {
+                Ok(seat) => return Ok(seat),
+                Err(e) if attempt >= attempts => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    std::thread::sleep(delay);
+                }
+            }
+        }
+    }
+
     pub fn next_event(&self) -> Option<Event> {
         self.events.borrow_mut().pop_front()
     }
@@ -104,6 +200,24 @@ impl Seat {
         }
     }
 
+    /// Same as [`open_device`](Self::open_device), but also returns the kind of the device,
+    /// determined by the major number of the opened device.
+    pub fn open_device_with_kind<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> io::Result<(OwnedFd, DeviceId, DeviceKind)> {
+        let (fd, id) = self.open_device(path)?;
+        let file = File::from(fd);
+        let kind = match file.metadata() {
+            Ok(meta) => DeviceKind::from_rdev(meta.rdev()),
+            Err(e) => {
+                let _ = self.close_device(id);
+                return Err(e);
+            }
+        };
+        Ok((file.into(), id, kind))
+    }
+
     /// Closes a device that has been opened on the seat using the device_id from
     /// libseat_open_device.
     pub fn close_device(&self, device_id: DeviceId) -> io::Result<()> {
@@ -173,3 +287,29 @@ impl Seat {
 #[derive(Debug)]
 #[must_use]
 pub struct DeviceId(i32);
+
+/// The kind of a device opened with [`Seat::open_device_with_kind`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// A DRM card or render node
+    Drm,
+    /// An evdev input device
+    Evdev,
+    /// Some other character device
+    Other,
+}
+
+impl DeviceKind {
+    const DRM_MAJOR: u64 = 226;
+    const INPUT_MAJOR: u64 = 13;
+
+    fn from_rdev(rdev: u64) -> Self {
+        // Same as gnu_dev_major()
+        let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0x0000_0fff);
+        match major {
+            Self::DRM_MAJOR => Self::Drm,
+            Self::INPUT_MAJOR => Self::Evdev,
+            _ => Self::Other,
+        }
+    }
+}
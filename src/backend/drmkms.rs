@@ -2,40 +2,67 @@ use std::collections::VecDeque;
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
 use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 
 use drm::buffer::{Buffer as _, DrmFourcc};
 use drm::control::atomic::AtomicModeReq;
 use drm::control::dumbbuffer::DumbBuffer;
-use drm::control::{AtomicCommitFlags, Device, FbCmd2Flags};
+#[cfg(feature = "gl")]
+use drm::control::FbCmd2Flags;
+use drm::control::{AtomicCommitFlags, Device};
 use drm::Device as _;
 use input::event::keyboard::KeyboardEventTrait;
-use input::event::pointer::{PointerEventTrait, PointerScrollEvent};
+use input::event::pointer::{Axis, PointerEventTrait, PointerScrollEvent};
 use input::event::EventTrait;
 use input::Libinput;
 
 use super::*;
+use crate::protocol::wl_pointer::AxisSource;
 use crate::protocol::wl_shm;
 
-pub fn new() -> Option<Box<dyn Backend>> {
-    let seat = Rc::new(libseat::Seat::open().unwrap());
+pub fn new(renderer: Renderer) -> Option<Box<dyn Backend>> {
+    match init(renderer) {
+        Ok(backend) => Some(Box::new(backend)),
+        Err(e) => {
+            eprintln!("drmkms: {e}");
+            None
+        }
+    }
+}
+
+fn init(renderer: Renderer) -> io::Result<BackendImp> {
+    if std::env::var_os("EWC_LIBSEAT_DEBUG").is_some() {
+        libseat::set_log_level(libseat::LogLevel::Debug);
+        libseat::set_log_handler(|level, msg| eprintln!("libseat [{level:?}]: {msg}"));
+    }
+
+    let seat = Rc::new(
+        libseat::Seat::open_with_retry(5, Duration::from_millis(200))
+            .context("could not open seat")?,
+    );
     let mut libinput = input::Libinput::new_with_udev(LibinputIface {
         seat: seat.clone(),
         devices: HashMap::new(),
     });
-    libinput.udev_assign_seat(seat.name()).unwrap();
+    libinput
+        .udev_assign_seat(seat.name())
+        .map_err(|()| io::Error::other("could not assign udev seat"))?;
 
-    let card = Card::open(&seat, "/dev/dri/card1").unwrap();
+    let card = Card::open(&seat, "/dev/dri/card1").context("could not open card")?;
 
     card.set_client_capability(drm::ClientCapability::UniversalPlanes, true)
-        .expect("Unable to request UniversalPlanes capability");
+        .context("unable to request UniversalPlanes capability")?;
     card.set_client_capability(drm::ClientCapability::Atomic, true)
-        .expect("Unable to request Atomic capability");
+        .context("unable to request Atomic capability")?;
 
-    card.reset_crtcs().expect("could not reset CRTCs");
+    let saved_crtcs = card
+        .save_crtcs()
+        .context("could not save CRTC configuration")?;
+    card.reset_crtcs().context("could not reset CRTCs")?;
 
     let res = card
         .resource_handles()
-        .expect("Could not load normal resource ids.");
+        .context("could not load resource handles")?;
     let coninfo: Vec<drm::control::connector::Info> = res
         .connectors()
         .iter()
@@ -51,19 +78,21 @@ pub fn new() -> Option<Box<dyn Backend>> {
         .iter()
         .rev()
         .find(|i| i.state() == drm::control::connector::State::Connected)
-        .expect("No connected connectors");
-    let mode = *con.modes().first().expect("No modes found on connector");
+        .context("no connected connector")?;
+    let mode = *con.modes().first().context("no modes found on connector")?;
     let (disp_width, disp_height) = mode.size();
     let disp_width = disp_width as u32;
     let disp_height = disp_height as u32;
 
-    let crtc = crtcinfo.first().expect("No crtcs found");
-    let planes = card.plane_handles().expect("Could not list planes");
+    let crtc = crtcinfo.first().context("no CRTCs found")?;
+    let planes = card.plane_handles().context("could not list planes")?;
 
     #[derive(Debug)]
     struct PlaneData {
         handle: drm::control::plane::Handle,
         is_primary: bool,
+        /// Only used by the GL renderer, dumb buffers are always XRGB8888.
+        #[cfg(feature = "gl")]
         formats: HashMap<eglgbm::Fourcc, Vec<u64>>,
     }
 
@@ -78,6 +107,7 @@ pub fn new() -> Option<Box<dyn Backend>> {
         }
         let Ok(props) = card.get_properties(plane) else { continue };
         let mut is_primary = None;
+        #[cfg(feature = "gl")]
         let mut formats = None;
         for (&prop_id, &prop_value) in &props {
             let Ok(info) = card.get_property(prop_id) else { continue };
@@ -86,17 +116,24 @@ pub fn new() -> Option<Box<dyn Backend>> {
                     is_primary =
                         Some(prop_value == (drm::control::PlaneType::Primary as u32).into());
                 }
+                #[cfg(feature = "gl")]
                 Ok("IN_FORMATS") => {
-                    let blob = card.get_property_blob(prop_value).unwrap();
+                    let Ok(blob) = card.get_property_blob(prop_value) else { continue };
                     formats = Some(parse_drm_format_modifier_blob(&blob));
                 }
                 _ => (),
             }
         }
-        if let Some((is_primary, formats)) = is_primary.zip(formats) {
+        #[cfg(feature = "gl")]
+        let formats = match formats {
+            Some(formats) => formats,
+            None => continue,
+        };
+        if let Some(is_primary) = is_primary {
             plane_data.push(PlaneData {
                 handle: plane,
                 is_primary,
+                #[cfg(feature = "gl")]
                 formats,
             });
         }
@@ -104,157 +141,96 @@ pub fn new() -> Option<Box<dyn Backend>> {
 
     let (better_planes, compatible_planes): (Vec<PlaneData>, Vec<PlaneData>) =
         plane_data.into_iter().partition(|plane| plane.is_primary);
-    let plane = better_planes.first().unwrap_or(&compatible_planes[0]);
+    let plane = better_planes
+        .first()
+        .or(compatible_planes.first())
+        .context("no compatible planes found")?;
 
-    let (renderer_kind, fb_swapchain) = if std::env::var_os("EWC_NO_GL").is_none() {
+    #[cfg(feature = "gl")]
+    let gl = try_gl_renderer("drmkms", renderer, || {
         let mut state =
-            gl46_renderer::RendererStateImp::with_drm_fd(card.as_fd().as_raw_fd(), &plane.formats)
-                .unwrap();
-        let (glfb, export) = state.allocate_framebuffer(disp_width, disp_height, true);
-        let (glfb2, export2) = state.allocate_framebuffer(disp_width, disp_height, true);
-        let buf = PlanarBufer {
-            width: disp_width,
-            height: disp_height,
-            export,
-        };
-        let buf2 = PlanarBufer {
-            width: disp_width,
-            height: disp_height,
-            export: export2,
-        };
-        let fb = card
-            .add_planar_framebuffer(&buf, FbCmd2Flags::MODIFIERS)
-            .unwrap();
-        let fb2 = card
-            .add_planar_framebuffer(&buf2, FbCmd2Flags::MODIFIERS)
-            .unwrap();
-        (
+            gl46_renderer::RendererStateImp::with_drm_fd(card.as_fd().as_raw_fd(), &plane.formats)?;
+        let (swapchain, fbs) = card.create_gl_swapchain(&mut state, disp_width, disp_height)?;
+        Ok((
             RendererKind::OpenGl {
                 width: disp_width,
                 height: disp_height,
-                swapchain: [glfb, glfb2],
+                swapchain,
                 state,
             },
-            [fb, fb2],
-        )
-    } else {
-        let buf = card
-            .create_dumb_buffer((disp_width, disp_height), DrmFourcc::Xrgb8888, 32)
-            .expect("Could not create dumb buffer");
-        let buf2 = card
-            .create_dumb_buffer((disp_width, disp_height), DrmFourcc::Xrgb8888, 32)
-            .expect("Could not create dumb buffer");
-        let fb = card
-            .add_framebuffer(&buf, 24, 32)
-            .expect("Could not create FB");
-        let fb2 = card
-            .add_framebuffer(&buf2, 24, 32)
-            .expect("Could not create FB");
-        (
-            RendererKind::Pixman {
-                swapchain: [buf, buf2],
-                state: pixman_renderer::RendererStateImp::new(),
-                temp_buf: vec![0u8; disp_width as usize * disp_height as usize * 4],
-            },
-            [fb, fb2],
-        )
+            fbs,
+        ))
+    })?;
+    #[cfg(not(feature = "gl"))]
+    let gl = try_gl_renderer("drmkms", renderer)?;
+    let (renderer_kind, fb_swapchain) = match gl {
+        Some(gl) => gl,
+        None => {
+            let (swapchain, fbs) = card.create_dumb_swapchain(disp_width, disp_height)?;
+            (
+                RendererKind::Pixman {
+                    swapchain,
+                    state: pixman_renderer::RendererStateImp::new(),
+                    temp_buf: vec![0u8; disp_width as usize * disp_height as usize * 4],
+                    scene: None,
+                    cursor_bg: None,
+                },
+                fbs,
+            )
+        }
     };
 
-    let con_props = card
-        .get_properties(con.handle())
-        .expect("Could not get props of connector")
-        .as_hashmap(&card)
-        .expect("Could not get a prop from connector");
-    let crtc_props = card
-        .get_properties(crtc.handle())
-        .expect("Could not get props of crtc")
-        .as_hashmap(&card)
-        .expect("Could not get a prop from crtc");
-    let plane_props = card
-        .get_properties(plane.handle)
-        .expect("Could not get props of plane")
-        .as_hashmap(&card)
-        .expect("Could not get a prop from plane");
-
-    let mut atomic_req = AtomicModeReq::new();
-    atomic_req.add_property(
-        con.handle(),
-        con_props["CRTC_ID"].handle(),
-        drm::control::property::Value::CRTC(Some(crtc.handle())),
-    );
-    let blob = card
-        .create_property_blob(&mode)
-        .expect("Failed to create blob");
-    atomic_req.add_property(crtc.handle(), crtc_props["MODE_ID"].handle(), blob);
-    atomic_req.add_property(
-        crtc.handle(),
-        crtc_props["ACTIVE"].handle(),
-        drm::control::property::Value::Boolean(true),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["FB_ID"].handle(),
-        drm::control::property::Value::Framebuffer(Some(fb_swapchain[0])),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["CRTC_ID"].handle(),
-        drm::control::property::Value::CRTC(Some(crtc.handle())),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["SRC_X"].handle(),
-        drm::control::property::Value::UnsignedRange(0),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["SRC_Y"].handle(),
-        drm::control::property::Value::UnsignedRange(0),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["SRC_W"].handle(),
-        drm::control::property::Value::UnsignedRange((mode.size().0 as u64) << 16),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["SRC_H"].handle(),
-        drm::control::property::Value::UnsignedRange((mode.size().1 as u64) << 16),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["CRTC_X"].handle(),
-        drm::control::property::Value::SignedRange(0),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["CRTC_Y"].handle(),
-        drm::control::property::Value::SignedRange(0),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["CRTC_W"].handle(),
-        drm::control::property::Value::UnsignedRange(mode.size().0 as u64),
-    );
-    atomic_req.add_property(
-        plane.handle,
-        plane_props["CRTC_H"].handle(),
-        drm::control::property::Value::UnsignedRange(mode.size().1 as u64),
-    );
-    card.atomic_commit(
-        AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::PAGE_FLIP_EVENT,
-        atomic_req.clone(),
-    )
-    .expect("Failed to set mode");
+    let kms = KmsObjects {
+        con: con.handle(),
+        con_props: card
+            .get_properties(con.handle())
+            .and_then(|props| props.as_hashmap(&card))
+            .context("could not get connector properties")?,
+        crtc: crtc.handle(),
+        crtc_props: card
+            .get_properties(crtc.handle())
+            .and_then(|props| props.as_hashmap(&card))
+            .context("could not get CRTC properties")?,
+        plane: plane.handle,
+        plane_props: card
+            .get_properties(plane.handle)
+            .and_then(|props| props.as_hashmap(&card))
+            .context("could not get plane properties")?,
+    };
+    let atomic_req = kms.modeset(&card, &mode, fb_swapchain[0])?;
+    let gamma_size = match kms.crtc_props.get("GAMMA_LUT_SIZE") {
+        Some(size_prop) if kms.crtc_props.contains_key("GAMMA_LUT") => {
+            let props = card
+                .get_properties(kms.crtc)
+                .context("could not get CRTC properties")?;
+            let (handles, values) = props.as_props_and_values();
+            handles
+                .iter()
+                .zip(values)
+                .find(|(handle, _)| **handle == size_prop.handle())
+                .map_or(0, |(_, &size)| size as u32)
+        }
+        _ => 0,
+    };
 
-    Some(Box::new(BackendImp {
+    Ok(BackendImp {
         suspended: false,
+        dpms_on: true,
+        gamma_size,
+        gamma_blob: None,
+        output: OutputId(NonZeroU64::MIN),
+        output_name: format!("{}-{}", con.interface().as_str(), con.interface_id()),
+        output_size: (disp_width, disp_height),
+        modes: con.modes().to_vec(),
+        mode,
         card,
         seat,
         libinput,
         atomic_req,
-        plane: plane.handle,
-        plane_props,
+        kms,
+        saved_crtcs,
+        // The initial modeset requested a page flip event.
+        flip_pending: true,
         backend_events_queue: VecDeque::new(),
         fb_swapchain,
         renderer_kind,
@@ -262,7 +238,23 @@ pub fn new() -> Option<Box<dyn Backend>> {
         next_input_id: NonZeroU64::MIN,
         pointer_mapping: HashMap::new(),
         pointers: HashMap::new(),
-    }))
+    })
+}
+
+trait Context<T> {
+    fn context(self, msg: &str) -> io::Result<T>;
+}
+
+impl<T, E: std::fmt::Display> Context<T> for Result<T, E> {
+    fn context(self, msg: &str) -> io::Result<T> {
+        self.map_err(|e| io::Error::other(format!("{msg}: {e}")))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context(self, msg: &str) -> io::Result<T> {
+        self.ok_or_else(|| io::Error::other(msg))
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -271,7 +263,11 @@ enum RendererKind {
         swapchain: [DumbBuffer; 2],
         state: pixman_renderer::RendererStateImp,
         temp_buf: Vec<u8>,
+        /// The scene currently in `temp_buf`.
+        scene: Option<pixman_renderer::Scene>,
+        cursor_bg: Option<pixman_renderer::CursorBackground>,
     },
+    #[cfg(feature = "gl")]
     OpenGl {
         width: u32,
         height: u32,
@@ -282,12 +278,25 @@ enum RendererKind {
 
 struct BackendImp {
     suspended: bool,
+    /// Whether the CRTC is active, see `Backend::set_dpms`.
+    dpms_on: bool,
+    /// Zero if the CRTC has no `GAMMA_LUT`.
+    gamma_size: u32,
+    /// The `GAMMA_LUT` blob currently set, if any.
+    gamma_blob: Option<u64>,
+    output: OutputId,
+    output_name: String,
+    output_size: (u32, u32),
+    modes: Vec<drm::control::Mode>,
+    mode: drm::control::Mode,
     card: Card,
     seat: Rc<libseat::Seat>,
     libinput: Libinput,
     atomic_req: AtomicModeReq,
-    plane: drm::control::plane::Handle,
-    plane_props: HashMap<String, drm::control::property::Info>,
+    kms: KmsObjects,
+    saved_crtcs: Vec<SavedCrtc>,
+    /// Whether a commit with `PAGE_FLIP_EVENT` has not completed yet.
+    flip_pending: bool,
     backend_events_queue: VecDeque<BackendEvent>,
     fb_swapchain: [drm::control::framebuffer::Handle; 2],
     renderer_kind: RendererKind,
@@ -297,6 +306,75 @@ struct BackendImp {
     pointers: HashMap<input::Device, Pointer>,
 }
 
+/// A CRTC configuration left by the previous DRM master (usually fbcon).
+struct SavedCrtc {
+    info: drm::control::crtc::Info,
+    connectors: Vec<drm::control::connector::Handle>,
+}
+
+struct KmsObjects {
+    con: drm::control::connector::Handle,
+    con_props: HashMap<String, drm::control::property::Info>,
+    crtc: drm::control::crtc::Handle,
+    crtc_props: HashMap<String, drm::control::property::Info>,
+    plane: drm::control::plane::Handle,
+    plane_props: HashMap<String, drm::control::property::Info>,
+}
+
+impl KmsObjects {
+    /// Set the mode and show `fb` on the primary plane, stretched to the whole CRTC.
+    ///
+    /// Returns the committed request, so that it can be re-applied when the session is resumed.
+    fn modeset(
+        &self,
+        card: &Card,
+        mode: &drm::control::Mode,
+        fb: drm::control::framebuffer::Handle,
+    ) -> io::Result<AtomicModeReq> {
+        use drm::control::property::Value;
+
+        let (width, height) = mode.size();
+        let blob = card
+            .create_property_blob(mode)
+            .context("could not create mode blob")?;
+
+        let mut atomic_req = AtomicModeReq::new();
+        atomic_req.add_property(
+            self.con,
+            self.con_props["CRTC_ID"].handle(),
+            Value::CRTC(Some(self.crtc)),
+        );
+        atomic_req.add_property(self.crtc, self.crtc_props["MODE_ID"].handle(), blob);
+        atomic_req.add_property(
+            self.crtc,
+            self.crtc_props["ACTIVE"].handle(),
+            Value::Boolean(true),
+        );
+        let plane_props = [
+            ("FB_ID", Value::Framebuffer(Some(fb))),
+            ("CRTC_ID", Value::CRTC(Some(self.crtc))),
+            ("SRC_X", Value::UnsignedRange(0)),
+            ("SRC_Y", Value::UnsignedRange(0)),
+            ("SRC_W", Value::UnsignedRange((width as u64) << 16)),
+            ("SRC_H", Value::UnsignedRange((height as u64) << 16)),
+            ("CRTC_X", Value::SignedRange(0)),
+            ("CRTC_Y", Value::SignedRange(0)),
+            ("CRTC_W", Value::UnsignedRange(width as u64)),
+            ("CRTC_H", Value::UnsignedRange(height as u64)),
+        ];
+        for (name, value) in plane_props {
+            atomic_req.add_property(self.plane, self.plane_props[name].handle(), value);
+        }
+
+        card.atomic_commit(
+            AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::PAGE_FLIP_EVENT,
+            atomic_req.clone(),
+        )
+        .context("could not set mode")?;
+        Ok(atomic_req)
+    }
+}
+
 struct Pointer {
     id: PointerId,
     #[allow(dead_code)]
@@ -311,10 +389,15 @@ struct LibinputIface {
 
 impl input::LibinputInterface for LibinputIface {
     fn open_restricted(&mut self, path: &Path, _flags: i32) -> Result<OwnedFd, i32> {
-        let (fd, id) = self
+        let (fd, id, kind) = self
             .seat
-            .open_device(path)
+            .open_device_with_kind(path)
             .map_err(|e| e.raw_os_error().unwrap())?;
+        if kind != libseat::DeviceKind::Evdev {
+            eprintln!("drmkms: libinput tried to open a non-input device {path:?}");
+            let _ = self.seat.close_device(id);
+            return Err(libc::ENODEV);
+        }
         self.devices.insert(fd.as_raw_fd(), id);
         Ok(fd)
     }
@@ -342,6 +425,47 @@ impl Card {
         Ok(Self { fd, id: Some(id) })
     }
 
+    fn save_crtcs(&self) -> io::Result<Vec<SavedCrtc>> {
+        let resources = self.resource_handles()?;
+        let mut connectors = Vec::new();
+        for &con in resources.connectors() {
+            let Some(encoder) = self.get_connector(con, false)?.current_encoder() else {
+                continue;
+            };
+            if let Some(crtc) = self.get_encoder(encoder)?.crtc() {
+                connectors.push((con, crtc));
+            }
+        }
+        let mut saved = Vec::new();
+        for &crtc in resources.crtcs() {
+            saved.push(SavedCrtc {
+                info: self.get_crtc(crtc)?,
+                connectors: connectors
+                    .iter()
+                    .filter(|(_, c)| *c == crtc)
+                    .map(|(con, _)| *con)
+                    .collect(),
+            });
+        }
+        Ok(saved)
+    }
+
+    fn restore_crtcs(&self, saved: &[SavedCrtc]) -> io::Result<()> {
+        self.reset_crtcs()?;
+        for crtc in saved {
+            if crtc.info.mode().is_some() && !crtc.connectors.is_empty() {
+                self.set_crtc(
+                    crtc.info.handle(),
+                    crtc.info.framebuffer(),
+                    crtc.info.position(),
+                    &crtc.connectors,
+                    crtc.info.mode(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     fn reset_crtcs(&self) -> io::Result<()> {
         let resources = self.resource_handles()?;
         let mut atomic_req = AtomicModeReq::new();
@@ -382,6 +506,57 @@ impl Card {
         self.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req.clone())?;
         Ok(())
     }
+
+    #[cfg(feature = "gl")]
+    fn create_gl_swapchain(
+        &self,
+        state: &mut gl46_renderer::RendererStateImp,
+        width: u32,
+        height: u32,
+    ) -> io::Result<(
+        [gl46_renderer::Framebuffer; 2],
+        [drm::control::framebuffer::Handle; 2],
+    )> {
+        let (glfb, export) = state.allocate_framebuffer(width, height, true);
+        let (glfb2, export2) = state.allocate_framebuffer(width, height, true);
+        let buf = PlanarBufer {
+            width,
+            height,
+            export,
+        };
+        let buf2 = PlanarBufer {
+            width,
+            height,
+            export: export2,
+        };
+        let fb = self
+            .add_planar_framebuffer(&buf, FbCmd2Flags::MODIFIERS)
+            .context("could not create FB")?;
+        let fb2 = self
+            .add_planar_framebuffer(&buf2, FbCmd2Flags::MODIFIERS)
+            .context("could not create FB")?;
+        Ok(([glfb, glfb2], [fb, fb2]))
+    }
+
+    fn create_dumb_swapchain(
+        &self,
+        width: u32,
+        height: u32,
+    ) -> io::Result<([DumbBuffer; 2], [drm::control::framebuffer::Handle; 2])> {
+        let buf = self
+            .create_dumb_buffer((width, height), DrmFourcc::Xrgb8888, 32)
+            .context("could not create dumb buffer")?;
+        let buf2 = self
+            .create_dumb_buffer((width, height), DrmFourcc::Xrgb8888, 32)
+            .context("could not create dumb buffer")?;
+        let fb = self
+            .add_framebuffer(&buf, 24, 32)
+            .context("could not create FB")?;
+        let fb2 = self
+            .add_framebuffer(&buf2, 24, 32)
+            .context("could not create FB")?;
+        Ok(([buf, buf2], [fb, fb2]))
+    }
 }
 
 impl drm::Device for Card {}
@@ -396,11 +571,12 @@ impl Drop for BackendImp {
                 self.card.destroy_dumb_buffer(swapchain[0]).unwrap();
                 self.card.destroy_dumb_buffer(swapchain[1]).unwrap();
             }
+            #[cfg(feature = "gl")]
             RendererKind::OpenGl {
                 swapchain, state, ..
             } => {
-                swapchain[0].destroy(state.gl());
-                swapchain[1].destroy(state.gl());
+                swapchain[0].destroy(state);
+                swapchain[1].destroy(state);
             }
         }
 
@@ -413,7 +589,53 @@ const DRM: u32 = 0;
 const LIBSEAT: u32 = 1;
 const LIBINPUT: u32 = 2;
 
+impl BackendImp {
+    /// Block until the pending page flip, if any, completes.
+    fn wait_for_flip(&mut self) -> io::Result<()> {
+        while self.flip_pending {
+            let mut pollfd = libc::pollfd {
+                fd: self.card.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut pollfd, 1, 1000) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                0 => return Err(io::Error::other("timed out")),
+                _ => self.poll(DRM)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Backend for BackendImp {
+    fn shutdown(&mut self) {
+        // While suspended, someone else owns the card and the input devices.
+        if self.suspended {
+            return;
+        }
+        // Tearing down the framebuffers while the kernel is flipping to them leaves a frozen
+        // display on some drivers.
+        if let Err(e) = self.wait_for_flip() {
+            eprintln!("drmkms: could not wait for page flip: {e}");
+        }
+        // The gamma LUT is not part of the saved CRTC configuration and would outlive us.
+        if self.gamma_blob.is_some() {
+            if let Err(e) = self.set_gamma(self.output, None) {
+                eprintln!("drmkms: could not reset gamma: {e}");
+            }
+        }
+        if let Err(e) = self.card.restore_crtcs(&self.saved_crtcs) {
+            eprintln!("drmkms: could not restore CRTC configuration: {e}");
+        }
+        self.libinput.suspend();
+    }
+
     fn register_fds_with(
         &self,
         reg: &'_ mut dyn FnMut(RawFd, u32) -> io::Result<()>,
@@ -427,45 +649,51 @@ impl Backend for BackendImp {
     fn poll(&mut self, data: u32) -> io::Result<()> {
         match data {
             DRM => {
-                for event in self.card.receive_events().unwrap() {
+                for event in self.card.receive_events()? {
                     match event {
                         drm::control::Event::Vblank(_) => todo!("vblank"),
-                        drm::control::Event::PageFlip(_event) => {
-                            self.backend_events_queue.push_back(BackendEvent::Frame);
+                        drm::control::Event::PageFlip(event) => {
+                            if event.crtc == self.kms.crtc {
+                                self.flip_pending = false;
+                                self.backend_events_queue
+                                    .push_back(BackendEvent::Frame(self.output));
+                            }
                         }
                         drm::control::Event::Unknown(_) => todo!("unknown"),
                     }
                 }
             }
             LIBSEAT => {
-                self.seat.dispatch(0).unwrap();
+                self.seat.dispatch(0)?;
                 while let Some(seat_event) = self.seat.next_event() {
                     match seat_event {
                         libseat::Event::Enable => {
                             eprintln!("seat enabled");
                             if self.suspended {
-                                self.card.reset_crtcs().expect("could not reset CRTCs");
+                                self.card.reset_crtcs()?;
                                 self.atomic_req.add_property(
-                                    self.plane,
-                                    self.plane_props["FB_ID"].handle(),
+                                    self.kms.plane,
+                                    self.kms.plane_props["FB_ID"].handle(),
                                     drm::control::property::Value::Framebuffer(Some(
                                         self.fb_swapchain[0],
                                     )),
                                 );
-                                self.card
-                                    .atomic_commit(
-                                        AtomicCommitFlags::ALLOW_MODESET
-                                            | AtomicCommitFlags::PAGE_FLIP_EVENT,
-                                        self.atomic_req.clone(),
-                                    )
-                                    .expect("Failed to set mode");
-                                self.libinput.resume().unwrap();
+                                // An inactive CRTC does not flip.
+                                let mut flags = AtomicCommitFlags::ALLOW_MODESET;
+                                if self.dpms_on {
+                                    flags |= AtomicCommitFlags::PAGE_FLIP_EVENT;
+                                }
+                                self.card.atomic_commit(flags, self.atomic_req.clone())?;
+                                self.flip_pending = self.dpms_on;
+                                self.libinput
+                                    .resume()
+                                    .map_err(|()| io::Error::other("could not resume libinput"))?;
                                 self.suspended = false;
                             }
                         }
                         libseat::Event::Disable => {
                             eprintln!("seat disabled");
-                            self.seat.disable().unwrap();
+                            self.seat.disable()?;
                             self.libinput.suspend();
                             self.suspended = true;
                         }
@@ -473,7 +701,7 @@ impl Backend for BackendImp {
                 }
             }
             LIBINPUT => {
-                self.libinput.dispatch().unwrap();
+                self.libinput.dispatch()?;
                 for event in &mut self.libinput {
                     match event {
                         input::Event::Device(e) => match e {
@@ -533,6 +761,7 @@ impl Backend for BackendImp {
                         input::Event::Pointer(e) => {
                             let timestamp = InputTimestamp(e.time());
                             let ptr = self.pointers.get(&e.device()).unwrap();
+                            let inverted = e.device().config_scroll_natural_scroll_enabled();
                             match e {
                                 input::event::PointerEvent::Motion(e) => {
                                     self.backend_events_queue.push_back(
@@ -558,40 +787,67 @@ impl Backend for BackendImp {
                                     );
                                 }
                                 // input::event::PointerEvent::Axis(_) => todo!(),
-                                input::event::PointerEvent::ScrollWheel(scroll_wheel) => {
-                                    assert!(!scroll_wheel
-                                        .has_axis(input::event::pointer::Axis::Horizontal));
-                                    let value = scroll_wheel
-                                        .has_axis(input::event::pointer::Axis::Vertical)
-                                        .then(|| {
-                                            scroll_wheel
-                                                .scroll_value(input::event::pointer::Axis::Vertical)
-                                        })
-                                        .unwrap_or(0.0);
-                                    self.backend_events_queue.push_back(
-                                        BackendEvent::PointerAxisVertial(
+                                input::event::PointerEvent::ScrollWheel(e) => {
+                                    let value = |axis| AxisValue {
+                                        value120: e
+                                            .has_axis(axis)
+                                            .then(|| e.scroll_value_v120(axis))
+                                            .unwrap_or(0.0)
+                                            as i32,
+                                        stop: false,
+                                        ..scroll_axis_value(&e, axis, inverted)
+                                    };
+                                    self.backend_events_queue
+                                        .push_back(BackendEvent::PointerAxis(
                                             ptr.id,
                                             timestamp,
-                                            value as f32,
-                                        ),
-                                    );
+                                            PointerAxis {
+                                                source: Some(AxisSource::Wheel),
+                                                vertical: value(Axis::Vertical),
+                                                horizontal: value(Axis::Horizontal),
+                                            },
+                                        ));
                                 }
                                 input::event::PointerEvent::ScrollFinger(e) => {
-                                    let vertical = e
-                                        .has_axis(input::event::pointer::Axis::Vertical)
-                                        .then(|| {
-                                            e.scroll_value(input::event::pointer::Axis::Vertical)
-                                        })
-                                        .unwrap_or(0.0);
-                                    self.backend_events_queue.push_back(
-                                        BackendEvent::PointerAxisVertial(
+                                    self.backend_events_queue
+                                        .push_back(BackendEvent::PointerAxis(
                                             ptr.id,
                                             timestamp,
-                                            vertical as f32,
-                                        ),
-                                    );
+                                            PointerAxis {
+                                                source: Some(AxisSource::Finger),
+                                                vertical: scroll_axis_value(
+                                                    &e,
+                                                    Axis::Vertical,
+                                                    inverted,
+                                                ),
+                                                horizontal: scroll_axis_value(
+                                                    &e,
+                                                    Axis::Horizontal,
+                                                    inverted,
+                                                ),
+                                            },
+                                        ));
+                                }
+                                input::event::PointerEvent::ScrollContinuous(e) => {
+                                    self.backend_events_queue
+                                        .push_back(BackendEvent::PointerAxis(
+                                            ptr.id,
+                                            timestamp,
+                                            PointerAxis {
+                                                source: Some(AxisSource::Continuous),
+                                                vertical: scroll_axis_value(
+                                                    &e,
+                                                    Axis::Vertical,
+                                                    inverted,
+                                                ),
+                                                horizontal: scroll_axis_value(
+                                                    &e,
+                                                    Axis::Horizontal,
+                                                    inverted,
+                                                ),
+                                            },
+                                        ));
                                 }
-                                // input::event::PointerEvent::ScrollContinuous(_) => todo!(),
                                 _ => (),
                             }
                         }
@@ -641,12 +897,220 @@ impl Backend for BackendImp {
     fn renderer_state(&mut self) -> &mut dyn RendererState {
         match &mut self.renderer_kind {
             RendererKind::Pixman { state, .. } => state,
+            #[cfg(feature = "gl")]
             RendererKind::OpenGl { state, .. } => state,
         }
     }
 
-    fn render_frame(&mut self, clear: Color, render_list: &[RenderNode], time: u32) {
+    fn outputs(&self) -> &[OutputId] {
+        std::slice::from_ref(&self.output)
+    }
+
+    fn output_name(&self, output: OutputId) -> &str {
+        assert_eq!(output, self.output);
+        &self.output_name
+    }
+
+    fn output_geometry(&self, output: OutputId) -> pixman::Rectangle32 {
+        assert_eq!(output, self.output);
+        pixman::Rectangle32 {
+            x: 0,
+            y: 0,
+            width: self.output_size.0,
+            height: self.output_size.1,
+        }
+    }
+
+    fn output_modes(&self, output: OutputId) -> Vec<OutputMode> {
+        assert_eq!(output, self.output);
+        self.modes.iter().map(output_mode).collect()
+    }
+
+    fn output_current_mode(&self, output: OutputId) -> OutputMode {
+        assert_eq!(output, self.output);
+        output_mode(&self.mode)
+    }
+
+    fn set_output_mode(&mut self, output: OutputId, mode: OutputMode) -> io::Result<()> {
+        assert_eq!(output, self.output);
+        if mode == output_mode(&self.mode) {
+            return Ok(());
+        }
         if self.suspended {
+            return Err(io::Error::other("the session is not active"));
+        }
+        if !self.dpms_on {
+            return Err(io::Error::other("the output is powered off"));
+        }
+        let drm_mode = *self
+            .modes
+            .iter()
+            .find(|m| output_mode(m) == mode)
+            .context("unsupported mode")?;
+        let (width, height) = (drm_mode.size().0 as u32, drm_mode.size().1 as u32);
+
+        match &mut self.renderer_kind {
+            RendererKind::Pixman {
+                swapchain,
+                temp_buf,
+                scene,
+                cursor_bg,
+                ..
+            } => {
+                let (new_swapchain, new_fbs) = self.card.create_dumb_swapchain(width, height)?;
+                match self.kms.modeset(&self.card, &drm_mode, new_fbs[0]) {
+                    Ok(atomic_req) => self.atomic_req = atomic_req,
+                    Err(e) => {
+                        for (buf, fb) in new_swapchain.into_iter().zip(new_fbs) {
+                            self.card.destroy_framebuffer(fb).unwrap();
+                            self.card.destroy_dumb_buffer(buf).unwrap();
+                        }
+                        return Err(e);
+                    }
+                }
+                for (buf, fb) in std::mem::replace(swapchain, new_swapchain)
+                    .into_iter()
+                    .zip(std::mem::replace(&mut self.fb_swapchain, new_fbs))
+                {
+                    self.card.destroy_framebuffer(fb).unwrap();
+                    self.card.destroy_dumb_buffer(buf).unwrap();
+                }
+                *temp_buf = vec![0u8; width as usize * height as usize * 4];
+                // The new buffer is empty, the next frame must be rendered from scratch.
+                *scene = None;
+                *cursor_bg = None;
+            }
+            #[cfg(feature = "gl")]
+            RendererKind::OpenGl {
+                width: gl_width,
+                height: gl_height,
+                swapchain,
+                state,
+            } => {
+                let (new_swapchain, new_fbs) =
+                    self.card.create_gl_swapchain(state, width, height)?;
+                match self.kms.modeset(&self.card, &drm_mode, new_fbs[0]) {
+                    Ok(atomic_req) => self.atomic_req = atomic_req,
+                    Err(e) => {
+                        for (buf, fb) in new_swapchain.into_iter().zip(new_fbs) {
+                            self.card.destroy_framebuffer(fb).unwrap();
+                            buf.destroy(state);
+                        }
+                        return Err(e);
+                    }
+                }
+                for (buf, fb) in std::mem::replace(swapchain, new_swapchain)
+                    .into_iter()
+                    .zip(std::mem::replace(&mut self.fb_swapchain, new_fbs))
+                {
+                    self.card.destroy_framebuffer(fb).unwrap();
+                    buf.destroy(state);
+                }
+                *gl_width = width;
+                *gl_height = height;
+            }
+        }
+
+        self.mode = drm_mode;
+        self.output_size = (width, height);
+        self.flip_pending = true;
+        Ok(())
+    }
+
+    fn set_dpms(&mut self, output: OutputId, on: bool) -> io::Result<()> {
+        use drm::control::property::Value;
+
+        assert_eq!(output, self.output);
+        if on == self.dpms_on {
+            return Ok(());
+        }
+        let active = self.kms.crtc_props["ACTIVE"].handle();
+        // While suspended, only remember the state for when the session is resumed.
+        if !self.suspended {
+            self.wait_for_flip()?;
+            let mut atomic_req = AtomicModeReq::new();
+            atomic_req.add_property(self.kms.crtc, active, Value::Boolean(on));
+            let mut flags = AtomicCommitFlags::ALLOW_MODESET;
+            if on {
+                flags |= AtomicCommitFlags::PAGE_FLIP_EVENT;
+            }
+            self.card
+                .atomic_commit(flags, atomic_req)
+                .context("could not set DPMS state")?;
+            self.flip_pending = on;
+        }
+        self.atomic_req
+            .add_property(self.kms.crtc, active, Value::Boolean(on));
+        self.dpms_on = on;
+        Ok(())
+    }
+
+    fn gamma_size(&self, output: OutputId) -> u32 {
+        assert_eq!(output, self.output);
+        self.gamma_size
+    }
+
+    fn set_gamma(&mut self, output: OutputId, ramps: Option<&[u16]>) -> io::Result<()> {
+        use drm::control::property::Value;
+
+        assert_eq!(output, self.output);
+        if self.gamma_size == 0 {
+            return Err(io::Error::other("the CRTC has no gamma LUT"));
+        }
+        let blob = match ramps {
+            None => None,
+            Some(ramps) => {
+                let size = self.gamma_size as usize;
+                assert_eq!(ramps.len(), size * 3);
+                // An array of `struct drm_color_lut`.
+                let mut lut = Vec::with_capacity(size * 8);
+                for i in 0..size {
+                    for channel in [&ramps[..size], &ramps[size..size * 2], &ramps[size * 2..]] {
+                        lut.extend_from_slice(&channel[i].to_ne_bytes());
+                    }
+                    lut.extend_from_slice(&[0, 0]);
+                }
+                let blob = drm_ffi::mode::create_property_blob(self.card.as_fd(), &mut lut)
+                    .context("could not create gamma blob")?;
+                Some(blob.blob_id as u64)
+            }
+        };
+
+        let gamma_lut = self.kms.crtc_props["GAMMA_LUT"].handle();
+        let value = Value::Blob(blob.unwrap_or(0));
+        // While suspended, only remember the LUT for when the session is resumed.
+        if !self.suspended {
+            self.wait_for_flip()?;
+            let mut atomic_req = AtomicModeReq::new();
+            atomic_req.add_property(self.kms.crtc, gamma_lut, value);
+            if let Err(e) = self
+                .card
+                .atomic_commit(AtomicCommitFlags::empty(), atomic_req)
+            {
+                if let Some(blob) = blob {
+                    let _ = self.card.destroy_property_blob(blob);
+                }
+                return Err(io::Error::other(format!("could not set gamma LUT: {e}")));
+            }
+        }
+        self.atomic_req
+            .add_property(self.kms.crtc, gamma_lut, value);
+        if let Some(old) = std::mem::replace(&mut self.gamma_blob, blob) {
+            let _ = self.card.destroy_property_blob(old);
+        }
+        Ok(())
+    }
+
+    fn render_frame(
+        &mut self,
+        output: OutputId,
+        clear: Color,
+        render_list: &[RenderNode],
+        cursor: Option<&CursorNode>,
+        time: u32,
+    ) {
+        assert_eq!(output, self.output);
+        if self.suspended || !self.dpms_on {
             return;
         }
 
@@ -655,6 +1119,8 @@ impl Backend for BackendImp {
                 swapchain,
                 state,
                 temp_buf,
+                scene,
+                cursor_bg,
             } => {
                 self.fb_swapchain.swap(0, 1);
                 swapchain.swap(0, 1);
@@ -662,10 +1128,29 @@ impl Backend for BackendImp {
                 let (width, height) = swapchain[0].size();
                 const FORMAT: wl_shm::Format = wl_shm::Format::Xrgb8888;
 
-                let mut frame = state.frame(temp_buf, width, height, FORMAT);
-                frame.clear(clear.r, clear.g, clear.a);
-                frame.render(render_list, time);
-                drop(frame);
+                // If only the cursor has moved, erase it from the previous frame instead of
+                // compositing everything again.
+                let new_scene =
+                    pixman_renderer::Scene::new(state, width, height, clear, render_list);
+                if scene.as_ref() == Some(&new_scene) {
+                    if let Some(bg) = cursor_bg.take() {
+                        bg.restore(temp_buf, width);
+                    }
+                    send_frame_callbacks(render_list, time);
+                } else {
+                    let mut frame = state.frame(temp_buf, width, height, FORMAT);
+                    frame.clear(clear.r, clear.g, clear.a);
+                    frame.render(render_list, time);
+                    *scene = Some(new_scene);
+                }
+                *cursor_bg = cursor.map(|cursor| {
+                    pixman_renderer::CursorBackground::save(temp_buf, width, height, cursor)
+                });
+                if let Some(cursor) = cursor {
+                    state
+                        .frame(temp_buf, width, height, FORMAT)
+                        .render_cursor(cursor);
+                }
 
                 // Reading from mapped buffer is terribly slow, but required for blending.
                 // When blending is involved, rendering to a CPU buffer and then copying is much faster.
@@ -677,6 +1162,7 @@ impl Backend for BackendImp {
                     map.copy_from_slice(temp_buf);
                 }
             }
+            #[cfg(feature = "gl")]
             RendererKind::OpenGl {
                 width,
                 height,
@@ -685,9 +1171,24 @@ impl Backend for BackendImp {
             } => {
                 self.fb_swapchain.swap(0, 1);
                 swapchain.swap(0, 1);
-                let mut frame = state.frame(*width, *height, &swapchain[1]);
+                let mut frame = match state.frame(*width, *height, &swapchain[1]) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        eprintln!("drmkms: skipping frame: {e}");
+                        self.fb_swapchain.swap(0, 1);
+                        swapchain.swap(0, 1);
+                        // Nothing flips, so ask for the next frame right away. It is rendered,
+                        // retrying the recovery, once the output needs a repaint.
+                        self.backend_events_queue
+                            .push_back(BackendEvent::Frame(self.output));
+                        return;
+                    }
+                };
                 frame.clear(clear.r, clear.g, clear.b);
                 frame.render(render_list, time);
+                if let Some(cursor) = cursor {
+                    frame.render_cursor(cursor);
+                }
                 drop(frame);
                 state.finish_frame();
             }
@@ -695,25 +1196,60 @@ impl Backend for BackendImp {
 
         let mut atomic_req = AtomicModeReq::new();
         atomic_req.add_property(
-            self.plane,
-            self.plane_props["FB_ID"].handle(),
+            self.kms.plane,
+            self.kms.plane_props["FB_ID"].handle(),
             drm::control::property::Value::Framebuffer(Some(self.fb_swapchain[1])),
         );
-        if let Err(e) = self.card.atomic_commit(
+        match self.card.atomic_commit(
             AtomicCommitFlags::PAGE_FLIP_EVENT | AtomicCommitFlags::NONBLOCK,
             atomic_req,
         ) {
-            eprintln!("drmkms: atomic nonblock page flip failed: {e:?}");
-        };
+            Ok(()) => self.flip_pending = true,
+            Err(e) => eprintln!("drmkms: atomic nonblock page flip failed: {e:?}"),
+        }
     }
 }
 
+/// The scroll distance of a libinput event on one axis. For finger and continuous scrolling, a
+/// zero value marks the end of scrolling.
+fn scroll_axis_value(e: &impl PointerScrollEvent, axis: Axis, inverted: bool) -> AxisValue {
+    if !e.has_axis(axis) {
+        return AxisValue::default();
+    }
+    let value = e.scroll_value(axis) as f32;
+    AxisValue {
+        value,
+        value120: 0,
+        stop: value == 0.0,
+        inverted,
+    }
+}
+
+fn output_mode(mode: &drm::control::Mode) -> OutputMode {
+    let (width, height) = mode.size();
+    let (_, _, htotal) = mode.hsync();
+    let (_, _, vtotal) = mode.vsync();
+    let refresh = (mode.clock() as u64 * 1_000_000)
+        .checked_div(htotal as u64 * vtotal as u64)
+        .unwrap_or(0);
+    OutputMode {
+        width: width as u32,
+        height: height as u32,
+        refresh: refresh as u32,
+        preferred: mode
+            .mode_type()
+            .contains(drm::control::ModeTypeFlags::PREFERRED),
+    }
+}
+
+#[cfg(feature = "gl")]
 struct PlanarBufer {
     width: u32,
     height: u32,
     export: eglgbm::BufferExport,
 }
 
+#[cfg(feature = "gl")]
 impl drm::buffer::PlanarBuffer for PlanarBufer {
     fn size(&self) -> (u32, u32) {
         (self.width, self.height)
@@ -764,6 +1300,7 @@ impl drm::buffer::PlanarBuffer for PlanarBufer {
     }
 }
 
+#[cfg(feature = "gl")]
 fn parse_drm_format_modifier_blob(blob: &[u8]) -> HashMap<eglgbm::Fourcc, Vec<u64>> {
     /*
     struct drm_format_modifier_blob {
@@ -832,9 +1369,9 @@ fn parse_drm_format_modifier_blob(blob: &[u8]) -> HashMap<eglgbm::Fourcc, Vec<u6
         let modifier = &blob[modifiers_offset + 16 + i * 24..][..8];
         let modifier = u64::from_ne_bytes(modifier.try_into().unwrap());
 
-        for i in 0..64 {
-            if formats_mask & (1u64 << i) != 0 {
-                let format = formats[(i + offset) as usize];
+        for bit in 0..64 {
+            if formats_mask & (1u64 << bit) != 0 {
+                let Some(&format) = formats.get((bit + offset) as usize) else { continue };
                 map.entry(eglgbm::Fourcc(format))
                     .or_default()
                     .push(modifier);
@@ -844,3 +1381,81 @@ fn parse_drm_format_modifier_blob(blob: &[u8]) -> HashMap<eglgbm::Fourcc, Vec<u6
 
     map
 }
+
+#[cfg(all(test, feature = "gl"))]
+mod tests {
+    use super::*;
+
+    const LINEAR: u64 = 0;
+    const X_TILED: u64 = 0x0100000000000001;
+
+    fn fourcc(s: &[u8; 4]) -> u32 {
+        u32::from_le_bytes(*s)
+    }
+
+    /// Build a `drm_format_modifier_blob` with some padding between the sections, to make sure
+    /// the offsets from the header are actually respected.
+    fn make_blob(formats: &[u32], modifiers: &[(u64, u32, u64)]) -> Vec<u8> {
+        let formats_offset = 24 + 8;
+        let modifiers_offset = formats_offset + formats.len() * 4 + 4;
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&1u32.to_ne_bytes());
+        blob.extend_from_slice(&0u32.to_ne_bytes());
+        blob.extend_from_slice(&(formats.len() as u32).to_ne_bytes());
+        blob.extend_from_slice(&(formats_offset as u32).to_ne_bytes());
+        blob.extend_from_slice(&(modifiers.len() as u32).to_ne_bytes());
+        blob.extend_from_slice(&(modifiers_offset as u32).to_ne_bytes());
+        blob.resize(formats_offset, 0xff);
+        for format in formats {
+            blob.extend_from_slice(&format.to_ne_bytes());
+        }
+        blob.resize(modifiers_offset, 0xff);
+        for &(mask, offset, modifier) in modifiers {
+            blob.extend_from_slice(&mask.to_ne_bytes());
+            blob.extend_from_slice(&offset.to_ne_bytes());
+            blob.extend_from_slice(&0u32.to_ne_bytes());
+            blob.extend_from_slice(&modifier.to_ne_bytes());
+        }
+        blob
+    }
+
+    #[test]
+    fn test_parse_blob_multiple_modifiers() {
+        let xr24 = fourcc(b"XR24");
+        let ar24 = fourcc(b"AR24");
+        let nv12 = fourcc(b"NV12");
+        let blob = make_blob(
+            &[xr24, ar24, nv12],
+            &[(0b111, 0, LINEAR), (0b011, 0, X_TILED)],
+        );
+        let map = parse_drm_format_modifier_blob(&blob);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&eglgbm::Fourcc(xr24)], [LINEAR, X_TILED]);
+        assert_eq!(map[&eglgbm::Fourcc(ar24)], [LINEAR, X_TILED]);
+        assert_eq!(map[&eglgbm::Fourcc(nv12)], [LINEAR]);
+    }
+
+    #[test]
+    fn test_parse_blob_offset_window() {
+        let formats: Vec<u32> = (0..110).map(|i| 0x1000 + i).collect();
+        // Formats 66 and 98..=102 with an offset of 64.
+        let mask = (1 << 2) | (0x1f << 34);
+        let blob = make_blob(&formats, &[(1, 0, LINEAR), (mask, 64, X_TILED)]);
+        let map = parse_drm_format_modifier_blob(&blob);
+        assert_eq!(map.len(), 7);
+        assert_eq!(map[&eglgbm::Fourcc(formats[0])], [LINEAR]);
+        assert_eq!(map[&eglgbm::Fourcc(formats[66])], [X_TILED]);
+        for i in 98..=102 {
+            assert_eq!(map[&eglgbm::Fourcc(formats[i])], [X_TILED]);
+        }
+    }
+
+    #[test]
+    fn test_parse_blob_mask_out_of_range() {
+        let xr24 = fourcc(b"XR24");
+        let blob = make_blob(&[xr24], &[(u64::MAX, 0, LINEAR)]);
+        let map = parse_drm_format_modifier_blob(&blob);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map[&eglgbm::Fourcc(xr24)], [LINEAR]);
+    }
+}
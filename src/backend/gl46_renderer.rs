@@ -1,4 +1,6 @@
+use std::cell::Cell;
 use std::ffi::CStr;
+use std::time::Duration;
 
 use eglgbm::{egl_ffi::eglGetProcAddress, BufferExport, FormatTable, Fourcc};
 use wayrs_protocols::linux_dmabuf_unstable_v1::zwp_linux_dmabuf_feedback_v1::TrancheFlags;
@@ -8,27 +10,49 @@ use super::*;
 use crate::protocol::*;
 use crate::Proxy;
 
-const DRM_FORMAT_XRGB8888: Fourcc = Fourcc(u32::from_le_bytes(*b"XR24"));
+/// Formats suitable for framebuffers, in the order of preference.
+const FRAMEBUFFER_FORMATS: [Fourcc; 4] = [
+    Fourcc(u32::from_le_bytes(*b"XR24")),
+    Fourcc(u32::from_le_bytes(*b"AR24")),
+    Fourcc(u32::from_le_bytes(*b"XB24")),
+    Fourcc(u32::from_le_bytes(*b"AB24")),
+];
 
 pub struct RendererStateImp {
     shm_pools: HashMap<WlShmPool, ShmPool>,
     shm_buffers: HashMap<WlBuffer, ShmBufferSpec>,
     tex_buffers: HashMap<WlBuffer, BufferId>,
+    /// Textures holding the contents of SHM buffers, reused when a buffer is committed again.
+    shm_textures: HashMap<WlBuffer, BufferId>,
     textures: HashMap<BufferId, Texture>,
     next_id: NonZeroU64,
 
     format_table: FormatTable,
     fourcc: Fourcc,
     mods: Vec<u64>,
+    /// The modifier out of `mods` which framebuffers are allocated with, non-linear if possible.
+    modifier: u64,
 
     verts_buffer: u32,
     verts: Vec<Vert>,
 
+    upload_pbos: UploadPbos,
+
     bound_textures: u32,
     texture_units: u32,
 
+    /// `GL_TIMESTAMP` queries taken when a frame starts and when it is finished.
+    timer_queries: [u32; 2],
+    last_frame_gpu_time: Option<Duration>,
+
     gl: Box<gl46::GlFns>,
     _context: eglgbm::EglContext,
+    /// Incremented every time the context is recreated after a GPU reset. GL objects created
+    /// before belong to the lost context.
+    generation: u32,
+    /// Set when a GPU reset is detected and cleared once the context is recreated, so that a
+    /// failed recovery is retried on the next frame.
+    context_lost: bool,
     egl: eglgbm::EglDisplay,
 }
 
@@ -47,16 +71,27 @@ struct GlTexture {
     width: u32,
     height: u32,
     resource: Option<WlBuffer>,
+    source: TextureSource,
+}
+
+/// Where the contents of a texture come from, to restore them after a GPU reset.
+enum TextureSource {
+    /// Uploaded from an SHM buffer, which is `GlTexture::resource`.
+    Shm,
+    Dmabuf(BufferExport),
+    Pixels(PixelSource),
 }
 
 impl RendererStateImp {
-    pub fn new(render_node: &CStr, feedback: DmabufFeedback) -> Option<Self> {
-        let egl = eglgbm::EglDisplay::new(render_node).unwrap();
+    pub fn new(render_node: &CStr, feedback: DmabufFeedback) -> io::Result<Self> {
+        let egl = eglgbm::EglDisplay::new(render_node)
+            .map_err(|e| io::Error::other(format!("could not initialize EGL: {e}")))?;
         Self::with_egl(egl, Some(feedback), None)
     }
 
-    pub fn with_drm_fd(fd: RawFd, supported_plane_formats: &FormatTable) -> Option<Self> {
-        let egl = eglgbm::EglDisplay::with_drm_fd(fd).unwrap();
+    pub fn with_drm_fd(fd: RawFd, supported_plane_formats: &FormatTable) -> io::Result<Self> {
+        let egl = eglgbm::EglDisplay::with_drm_fd(fd)
+            .map_err(|e| io::Error::other(format!("could not initialize EGL: {e}")))?;
         Self::with_egl(egl, None, Some(supported_plane_formats))
     }
 
@@ -64,15 +99,10 @@ impl RendererStateImp {
         egl: eglgbm::EglDisplay,
         feedback: Option<DmabufFeedback>,
         format_table: Option<&FormatTable>,
-    ) -> Option<Self> {
+    ) -> io::Result<Self> {
         eprintln!("EGL v{}.{}", egl.major_version(), egl.minor_version());
 
-        let egl_context = eglgbm::EglContextBuilder::new(eglgbm::GraphicsApi::OpenGl)
-            .version(4, 6)
-            .debug(true)
-            .build(&egl)
-            .unwrap();
-        egl_context.make_current().unwrap();
+        let egl_context = create_context(&egl)?;
 
         let gl = unsafe {
             let gl = gl46::GlFns::load_from(&|name| eglGetProcAddress(name.cast())).unwrap();
@@ -85,112 +115,211 @@ impl RendererStateImp {
             gl
         };
 
-        let mut verts_buffer = 0;
-        let mut vertex_array = 0;
-        let shader;
-
-        let texture_units = {
-            let mut n = 0;
-            unsafe { gl.GetIntegerv(gl46::GL_MAX_TEXTURE_IMAGE_UNITS, &mut n) };
-            assert!(n >= 16);
-            n as u32
-        };
-
+        let (verts_buffer, texture_units) = unsafe { setup_pipeline(&gl) };
         eprintln!("gl46_renderer: {texture_units} texture units available");
+        let timer_queries = unsafe { create_timer_queries(&gl) };
 
-        unsafe {
-            gl.Enable(gl46::GL_BLEND);
-            gl.BlendFunc(gl46::GL_ONE, gl46::GL_ONE_MINUS_SRC_ALPHA);
-
-            gl.GenVertexArrays(1, &mut vertex_array);
-            gl.CreateBuffers(1, &mut verts_buffer);
-
-            gl.BindVertexArray(vertex_array);
-            gl.BindVertexBuffer(0, verts_buffer, 0, std::mem::size_of::<Vert>() as i32);
-            gl.EnableVertexAttribArray(0);
-            gl.EnableVertexAttribArray(1);
-            gl.VertexAttribBinding(0, 0);
-            gl.VertexAttribBinding(1, 0);
-            gl.VertexAttribFormat(0, 2, gl46::GL_FLOAT, 0, 0);
-            gl.VertexAttribFormat(1, 4, gl46::GL_FLOAT, 0, 8);
-
-            shader = create_shader(&gl, texture_units);
-            gl.UseProgram(shader);
-
-            let units: Vec<_> = (0..texture_units as i32).collect();
-            gl.Uniform1iv(1, units.len() as i32, units.as_ptr());
-        }
+        // Framebuffers are scanned out directly when there is no parent compositor.
+        let scan_out = feedback.is_none();
 
         let format_table = match feedback {
             Some(feedback) => format_table_from_feedback(&egl, feedback),
-            None => filter_format_table(&egl, format_table.unwrap()),
+            None => {
+                let plane_formats = format_table.unwrap();
+                log_unsupported_plane_formats(&egl, plane_formats);
+                filter_format_table(&egl, plane_formats)
+            }
         };
 
-        let fourcc = DRM_FORMAT_XRGB8888;
-        let mods = format_table
-            .get(&fourcc)
-            .expect("xrgb8888 not supported")
-            .clone();
+        let Some((fourcc, mods, modifier)) = FRAMEBUFFER_FORMATS
+            .iter()
+            .filter(|fourcc| !scan_out || egl.is_format_scanout_supported(**fourcc))
+            .find_map(|fourcc| {
+                let mods = format_table.get(fourcc)?;
+                let modifier = egl.resolve_modifier(*fourcc, mods)?;
+                Some((*fourcc, mods.clone(), modifier))
+            })
+        else {
+            return Err(io::Error::other("no supported framebuffer format"));
+        };
+        eprintln!(
+            "gl46_renderer: using {fourcc:?} framebuffers with {:?}",
+            eglgbm::Modifier(modifier)
+        );
 
-        Some(Self {
+        Ok(Self {
             shm_pools: HashMap::new(),
             shm_buffers: HashMap::new(),
             tex_buffers: HashMap::new(),
+            shm_textures: HashMap::new(),
             textures: HashMap::new(),
             next_id: NonZeroU64::MIN,
 
             format_table,
             fourcc,
             mods,
+            modifier,
 
             verts_buffer,
             verts: Vec::new(),
 
+            upload_pbos: unsafe { UploadPbos::new(&gl) },
+
             texture_units,
             bound_textures: 0,
 
+            timer_queries,
+            last_frame_gpu_time: None,
+
             gl: Box::new(gl),
             _context: egl_context,
+            generation: 0,
+            context_lost: false,
             egl,
         })
     }
 
+    /// Recreate the context and restore the contents of all textures if the GPU was reset.
+    /// Framebuffers are restored when they are rendered to.
+    fn recover_from_reset(&mut self) -> io::Result<()> {
+        if !self.context_lost {
+            let status = unsafe { self.gl.GetGraphicsResetStatus() };
+            let cause = match status {
+                gl46::GL_NO_ERROR => return Ok(()),
+                gl46::GL_GUILTY_CONTEXT_RESET => "caused by the compositor",
+                gl46::GL_INNOCENT_CONTEXT_RESET => "not caused by the compositor",
+                _ => "of unknown cause",
+            };
+            eprintln!("gl46_renderer: GPU reset {cause}, recreating the context");
+            self.context_lost = true;
+        }
+
+        self._context = create_context(&self.egl)?;
+        self.context_lost = false;
+        self.generation += 1;
+        unsafe {
+            setup_gl_debug_cb(&self.gl);
+            (self.verts_buffer, self.texture_units) = setup_pipeline(&self.gl);
+            self.upload_pbos = UploadPbos::new(&self.gl);
+            self.timer_queries = create_timer_queries(&self.gl);
+        }
+        self.verts.clear();
+        self.bound_textures = 0;
+
+        for tex in self.textures.values_mut() {
+            let TextureKind::Gl(tex) = &mut tex.kind else {
+                continue;
+            };
+            let rect = full_rect(tex.width, tex.height);
+            tex.gl_name = match &tex.source {
+                TextureSource::Shm => unsafe {
+                    let gl_name =
+                        create_texture(&self.gl, tex.width, tex.height, wl_shm::Format::Argb8888);
+                    // The contents are lost if the buffer has been destroyed since the upload.
+                    let spec = tex.resource.as_ref().and_then(|r| self.shm_buffers.get(r));
+                    if let Some(spec) = spec {
+                        let pool = &self.shm_pools[&spec.pool];
+                        let _access = pool.access();
+                        let bytes = &pool.memmap[spec.offset as usize..]
+                            [..spec.stride as usize * spec.height as usize];
+                        self.upload_pbos
+                            .upload(&self.gl, gl_name, spec.stride, bytes, &rect);
+                    }
+                    gl_name
+                },
+                TextureSource::Dmabuf(export) => match self.egl.import_as_egl_image(export) {
+                    Ok(egl_image) => unsafe { create_dmabuf_texture(&self.gl, &egl_image) },
+                    Err(e) => {
+                        eprintln!("gl46_renderer: could not import dmabuf again: {e}");
+                        unsafe {
+                            create_texture(
+                                &self.gl,
+                                tex.width,
+                                tex.height,
+                                wl_shm::Format::Argb8888,
+                            )
+                        }
+                    }
+                },
+                TextureSource::Pixels(regenerate) => unsafe {
+                    let gl_name =
+                        create_texture(&self.gl, tex.width, tex.height, wl_shm::Format::Argb8888);
+                    match regenerate() {
+                        Some(bytes) if bytes.len() == (tex.width * tex.height * 4) as usize => {
+                            upload_rect(&self.gl, gl_name, tex.width * 4, &bytes, &rect);
+                        }
+                        _ => eprintln!("gl46_renderer: could not regenerate a texture"),
+                    }
+                    gl_name
+                },
+            };
+        }
+
+        Ok(())
+    }
+
     pub fn allocate_framebuffer(
         &mut self,
         width: u32,
         height: u32,
         scan_out: bool,
     ) -> (Framebuffer, BufferExport) {
+        // GBM may not manage to allocate the chosen modifier with the requested usage, it can still
+        // pick one of the others.
         let (egl_image, export) = self
             .egl
-            .alloc_buffer(width, height, self.fourcc, &self.mods, scan_out)
+            .alloc_buffer(width, height, self.fourcc, &[self.modifier], scan_out)
+            .or_else(|_| {
+                self.egl
+                    .alloc_buffer(width, height, self.fourcc, &self.mods, scan_out)
+            })
             .unwrap();
-        let fb = unsafe { Framebuffer::new(egl_image, &self.gl) };
+        let fb = unsafe { Framebuffer::new(egl_image, &self.gl, self.generation) };
         (fb, export)
     }
 
-    pub fn gl(&self) -> &gl46::GlFns {
-        &self.gl
-    }
-
+    /// Start rendering a frame into `fb`. Fails if the context was lost and could not be
+    /// recreated, in which case the frame should be skipped; recovery is attempted again on the
+    /// next call.
     pub fn frame<'a>(
         &'a mut self,
         width: u32,
         height: u32,
         fb: &Framebuffer,
-    ) -> Box<dyn Frame + 'a> {
+    ) -> io::Result<Box<dyn Frame + 'a>> {
+        self.recover_from_reset()
+            .map_err(|e| io::Error::other(format!("could not recover from a GPU reset: {e}")))?;
+        if fb.generation.get() != self.generation {
+            unsafe { fb.attach(&self.gl, self.generation) };
+        }
+
         unsafe {
-            self.gl.BindFramebuffer(gl46::GL_FRAMEBUFFER, fb.fbo);
+            self.gl.BindFramebuffer(gl46::GL_FRAMEBUFFER, fb.fbo.get());
             self.gl.Viewport(0, 0, width as i32, height as i32);
             self.gl.Uniform2f(0, width as f32, height as f32);
+            self.gl
+                .QueryCounter(self.timer_queries[0], gl46::GL_TIMESTAMP);
         }
+        self.last_frame_gpu_time = None;
 
-        Box::new(FrameImp { state: self })
+        Ok(Box::new(FrameImp { state: self }))
     }
 
     pub fn finish_frame(&mut self) {
         self.flush_quads();
-        unsafe { self.gl.Finish() };
+        unsafe {
+            self.gl
+                .QueryCounter(self.timer_queries[1], gl46::GL_TIMESTAMP);
+            self.gl.Finish();
+            // The results are available right away, the GPU has finished the frame.
+            let mut start = 0;
+            let mut end = 0;
+            self.gl
+                .GetQueryObjectui64v(self.timer_queries[0], gl46::GL_QUERY_RESULT, &mut start);
+            self.gl
+                .GetQueryObjectui64v(self.timer_queries[1], gl46::GL_QUERY_RESULT, &mut end);
+            self.last_frame_gpu_time = Some(Duration::from_nanos(end.saturating_sub(start)));
+        }
     }
 
     fn flush_quads(&mut self) {
@@ -219,7 +348,10 @@ impl RendererStateImp {
             TextureKind::Gl(buffer) => {
                 if let Some(resource) = &buffer.resource {
                     if resource.is_alive() {
-                        resource.release();
+                        // SHM buffers are released right after the upload.
+                        if !self.shm_buffers.contains_key(resource) {
+                            resource.release();
+                        }
                         return;
                     }
                 }
@@ -244,17 +376,19 @@ impl RendererState for RendererStateImp {
         &mut self.shm_pools
     }
 
-    fn create_argb8_texture(&mut self, width: u32, height: u32, bytes: &[u8]) -> BufferId {
+    fn create_argb8_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        bytes: &[u8],
+        regenerate: PixelSource,
+    ) -> BufferId {
         let gl_name = unsafe {
-            create_texture(
-                &self.gl,
-                width,
-                height,
-                width * 4,
-                wl_shm::Format::Argb8888,
-                bytes,
-            )
+            let tex = create_texture(&self.gl, width, height, wl_shm::Format::Argb8888);
+            upload_rect(&self.gl, tex, width * 4, bytes, &full_rect(width, height));
+            tex
         };
+        let source = TextureSource::Pixels(regenerate);
         let new_id = BufferId(next_id(&mut self.next_id));
         self.textures.insert(
             new_id,
@@ -265,6 +399,7 @@ impl RendererState for RendererStateImp {
                     width,
                     height,
                     resource: None,
+                    source,
                 }),
             },
         );
@@ -276,55 +411,17 @@ impl RendererState for RendererStateImp {
         self.shm_buffers.insert(resource, spec);
     }
 
-    fn create_dma_buffer(&mut self, spec: DmaBufSpec, resource: protocol::WlBuffer) {
-        let buf_parts = BufferExport {
-            width: spec.width,
-            height: spec.height,
-            format: spec.format,
-            modifier: spec.planes[0].modifier,
-            planes: spec
-                .planes
-                .into_iter()
-                .map(|p| eglgbm::BufferPlane {
-                    dmabuf: p.fd,
-                    handle: 0,
-                    offset: p.offset,
-                    stride: p.stride,
-                })
-                .collect(),
-        };
+    fn create_dma_buffer(
+        &mut self,
+        buffer: BufferExport,
+        resource: protocol::WlBuffer,
+    ) -> io::Result<()> {
         let egl_image = self
             .egl
-            .import_as_egl_image(&buf_parts)
-            .expect("could not import dmabuf");
+            .import_as_egl_image(&buffer)
+            .map_err(io::Error::other)?;
 
-        let mut gl_name = 0;
-        unsafe {
-            self.gl.GenTextures(1, &mut gl_name);
-            self.gl.BindTexture(gl46::GL_TEXTURE_2D, gl_name);
-            self.gl.TexParameteri(
-                gl46::GL_TEXTURE_2D,
-                gl46::GL_TEXTURE_MIN_FILTER,
-                gl46::GL_NEAREST.0 as i32,
-            );
-            self.gl.TexParameteri(
-                gl46::GL_TEXTURE_2D,
-                gl46::GL_TEXTURE_MAG_FILTER,
-                gl46::GL_NEAREST.0 as i32,
-            );
-            self.gl.TexParameteri(
-                gl46::GL_TEXTURE_2D,
-                gl46::GL_TEXTURE_WRAP_S,
-                gl46::GL_CLAMP_TO_EDGE.0 as i32,
-            );
-            self.gl.TexParameteri(
-                gl46::GL_TEXTURE_2D,
-                gl46::GL_TEXTURE_WRAP_T,
-                gl46::GL_CLAMP_TO_EDGE.0 as i32,
-            );
-            egl_image.set_as_gl_texture_2d();
-            self.gl.BindTexture(gl46::GL_TEXTURE_2D, 0);
-        }
+        let gl_name = unsafe { create_dmabuf_texture(&self.gl, &egl_image) };
 
         let new_id = BufferId(next_id(&mut self.next_id));
         self.textures.insert(
@@ -333,13 +430,15 @@ impl RendererState for RendererStateImp {
                 locks: 0,
                 kind: TextureKind::Gl(GlTexture {
                     gl_name,
-                    width: spec.width,
-                    height: spec.height,
+                    width: buffer.width,
+                    height: buffer.height,
                     resource: Some(resource.clone()),
+                    source: TextureSource::Dmabuf(buffer),
                 }),
             },
         );
         self.tex_buffers.insert(resource, new_id);
+        Ok(())
     }
 
     fn create_single_pix_buffer(&mut self, color: Color, resource: protocol::WlBuffer) {
@@ -354,7 +453,11 @@ impl RendererState for RendererStateImp {
         self.tex_buffers.insert(resource, new_id);
     }
 
-    fn buffer_commited(&mut self, buffer_resource: WlBuffer) -> BufferId {
+    fn buffer_commited(
+        &mut self,
+        buffer_resource: WlBuffer,
+        damage: Option<&pixman::Region32>,
+    ) -> BufferId {
         if let Some(&tex_id) = self.tex_buffers.get(&buffer_resource) {
             let tex = self.textures.get_mut(&tex_id).unwrap();
             tex.locks += 1;
@@ -369,18 +472,48 @@ impl RendererState for RendererStateImp {
 
         buffer_resource.release();
         let pool = &self.shm_pools[&spec.pool];
+        let _access = pool.access();
         let bytes =
             &pool.memmap[spec.offset as usize..][..spec.stride as usize * spec.height as usize];
 
+        // The contents of the texture are still valid if the buffer was committed before, so
+        // only the damaged part has to be uploaded again.
+        if let Some(&tex_id) = self.shm_textures.get(&buffer_resource) {
+            let tex = self.textures.get_mut(&tex_id).unwrap();
+            let TextureKind::Gl(gl_tex) = &mut tex.kind else { unreachable!() };
+            let upload = match damage {
+                Some(damage) => {
+                    damage.intersect(&pixman::Region32::init_rect(0, 0, spec.width, spec.height))
+                }
+                None if tex.locks == 0 => {
+                    pixman::Region32::init_rect(0, 0, spec.width, spec.height)
+                }
+                None => {
+                    // The texture is still shown, e.g. by a synchronized subsurface, so it must
+                    // not change. It is dropped once unlocked.
+                    gl_tex.resource = None;
+                    pixman::Region32::default()
+                }
+            };
+            if gl_tex.resource.is_some() {
+                tex.locks += 1;
+                for rect in upload.rectangles() {
+                    unsafe {
+                        self.upload_pbos
+                            .upload(&self.gl, gl_tex.gl_name, spec.stride, bytes, rect)
+                    };
+                }
+                return tex_id;
+            }
+            self.shm_textures.remove(&buffer_resource);
+        }
+
         let gl_name = unsafe {
-            create_texture(
-                &self.gl,
-                spec.width,
-                spec.height,
-                spec.stride,
-                wl_shm::Format::Argb8888,
-                bytes,
-            )
+            let tex = create_texture(&self.gl, spec.width, spec.height, wl_shm::Format::Argb8888);
+            let rect = full_rect(spec.width, spec.height);
+            self.upload_pbos
+                .upload(&self.gl, tex, spec.stride, bytes, &rect);
+            tex
         };
         let new_id = BufferId(next_id(&mut self.next_id));
         self.textures.insert(
@@ -391,10 +524,12 @@ impl RendererState for RendererStateImp {
                     gl_name,
                     width: spec.width,
                     height: spec.height,
-                    resource: None,
+                    resource: Some(buffer_resource.clone()),
+                    source: TextureSource::Shm,
                 }),
             },
         );
+        self.shm_textures.insert(buffer_resource, new_id);
         new_id
     }
 
@@ -422,6 +557,13 @@ impl RendererState for RendererStateImp {
             return;
         }
 
+        if let Some(tex) = self.shm_textures.remove(&resource) {
+            if let TextureKind::Gl(gl) = &mut self.textures.get_mut(&tex).unwrap().kind {
+                gl.resource = None;
+            }
+            self.consider_dropping_buffer(tex);
+        }
+
         let shm_spec = self.shm_buffers.remove(&resource).unwrap();
         let shm_pool = self.shm_pools.get_mut(&shm_spec.pool).unwrap();
         shm_pool.refcnt -= 1;
@@ -429,6 +571,10 @@ impl RendererState for RendererStateImp {
             self.shm_pools.remove(&shm_spec.pool);
         }
     }
+
+    fn last_frame_gpu_time(&self) -> Option<Duration> {
+        self.last_frame_gpu_time
+    }
 }
 
 pub struct FrameImp<'a> {
@@ -565,17 +711,36 @@ struct Vert {
 }
 
 pub struct Framebuffer {
-    fbo: u32,
-    rbo: u32,
+    fbo: Cell<u32>,
+    rbo: Cell<u32>,
+    /// The generation of the context the renderbuffer belongs to.
+    generation: Cell<u32>,
+    /// Kept to attach the buffer to a new renderbuffer after a GPU reset.
+    egl_image: eglgbm::EglImage,
 }
 
 impl Framebuffer {
-    pub fn destroy(&self, gl: &gl46::GlFns) {
-        unsafe { gl.DeleteFramebuffers(1, &self.fbo) };
-        unsafe { gl.DeleteRenderbuffers(1, &self.rbo) };
+    pub fn destroy(&self, state: &RendererStateImp) {
+        // The objects of a lost context are gone already, and their names may be reused.
+        if self.generation.get() == state.generation {
+            unsafe { state.gl.DeleteFramebuffers(1, &self.fbo.get()) };
+            unsafe { state.gl.DeleteRenderbuffers(1, &self.rbo.get()) };
+        }
+    }
+
+    unsafe fn new(egl_image: eglgbm::EglImage, gl: &gl46::GlFns, generation: u32) -> Self {
+        let fb = Self {
+            fbo: Cell::new(0),
+            rbo: Cell::new(0),
+            generation: Cell::new(generation),
+            egl_image,
+        };
+        fb.attach(gl, generation);
+        fb
     }
 
-    unsafe fn new(egl_image: eglgbm::EglImage, gl: &gl46::GlFns) -> Self {
+    /// Create the framebuffer and renderbuffer objects in the current context.
+    unsafe fn attach(&self, gl: &gl46::GlFns, generation: u32) {
         let mut fbo = 0;
         let mut rbo = 0;
 
@@ -586,7 +751,7 @@ impl Framebuffer {
         gl.BindRenderbuffer(gl46::GL_RENDERBUFFER, rbo);
         gl.DrawBuffers(1, &gl46::GL_COLOR_ATTACHMENT0);
 
-        egl_image.set_as_gl_renderbuffer_storage();
+        self.egl_image.set_as_gl_renderbuffer_storage();
 
         gl.FramebufferRenderbuffer(
             gl46::GL_FRAMEBUFFER,
@@ -600,7 +765,9 @@ impl Framebuffer {
             gl46::GL_FRAMEBUFFER_COMPLETE
         );
 
-        Self { fbo, rbo }
+        self.fbo.set(fbo);
+        self.rbo.set(rbo);
+        self.generation.set(generation);
     }
 }
 
@@ -640,6 +807,84 @@ fn filter_format_table(egl: &eglgbm::EglDisplay, format_table: &FormatTable) ->
     formats
 }
 
+fn log_unsupported_plane_formats(egl: &eglgbm::EglDisplay, plane_formats: &FormatTable) {
+    let missing = eglgbm::format_table_diff(plane_formats, egl.supported_formats());
+    let mut missing: Vec<_> = missing.into_iter().collect();
+    missing.sort_by_key(|(fourcc, _)| *fourcc);
+    for (fourcc, mods) in missing {
+        let plane = FormatTable::from([(fourcc, plane_formats[&fourcc].clone())]);
+        let lacks: Vec<_> = mods.into_iter().map(eglgbm::Modifier).collect();
+        eprintln!(
+            "gl46_renderer: plane supports {} but EGL lacks {lacks:?}",
+            eglgbm::FormatTableDisplay(&plane)
+        );
+    }
+}
+
+fn create_context(egl: &eglgbm::EglDisplay) -> io::Result<eglgbm::EglContext> {
+    let builder = || {
+        eglgbm::EglContextBuilder::new(eglgbm::GraphicsApi::OpenGl)
+            .version(4, 6)
+            .debug(true)
+    };
+    // A robust context is lost on a GPU reset, which can then be detected and recovered from.
+    let egl_context = builder()
+        .robust(true)
+        .build(egl)
+        .or_else(|e| {
+            eprintln!("gl46_renderer: no robust context, GPU resets are not recoverable: {e}");
+            builder().build(egl)
+        })
+        .map_err(|e| io::Error::other(format!("could not create OpenGL context: {e}")))?;
+    egl_context
+        .make_current()
+        .map_err(|e| io::Error::other(format!("could not make the context current: {e}")))?;
+    Ok(egl_context)
+}
+
+/// Set up the vertex buffer, the shader and the blending. Returns the vertex buffer and the
+/// number of texture units.
+unsafe fn setup_pipeline(gl: &gl46::GlFns) -> (u32, u32) {
+    let mut verts_buffer = 0;
+    let mut vertex_array = 0;
+
+    let texture_units = {
+        let mut n = 0;
+        gl.GetIntegerv(gl46::GL_MAX_TEXTURE_IMAGE_UNITS, &mut n);
+        assert!(n >= 16);
+        n as u32
+    };
+
+    gl.Enable(gl46::GL_BLEND);
+    gl.BlendFunc(gl46::GL_ONE, gl46::GL_ONE_MINUS_SRC_ALPHA);
+
+    gl.GenVertexArrays(1, &mut vertex_array);
+    gl.CreateBuffers(1, &mut verts_buffer);
+
+    gl.BindVertexArray(vertex_array);
+    gl.BindVertexBuffer(0, verts_buffer, 0, std::mem::size_of::<Vert>() as i32);
+    gl.EnableVertexAttribArray(0);
+    gl.EnableVertexAttribArray(1);
+    gl.VertexAttribBinding(0, 0);
+    gl.VertexAttribBinding(1, 0);
+    gl.VertexAttribFormat(0, 2, gl46::GL_FLOAT, 0, 0);
+    gl.VertexAttribFormat(1, 4, gl46::GL_FLOAT, 0, 8);
+
+    let shader = create_shader(gl, texture_units);
+    gl.UseProgram(shader);
+
+    let units: Vec<_> = (0..texture_units as i32).collect();
+    gl.Uniform1iv(1, units.len() as i32, units.as_ptr());
+
+    (verts_buffer, texture_units)
+}
+
+unsafe fn create_timer_queries(gl: &gl46::GlFns) -> [u32; 2] {
+    let mut names = [0; 2];
+    gl.GenQueries(2, names.as_mut_ptr());
+    names
+}
+
 unsafe fn create_shader(gl: &gl46::GlFns, texture_units: u32) -> u32 {
     let vertex_shader = b"
         #version 460 core
@@ -749,14 +994,7 @@ pub unsafe fn setup_gl_debug_cb(gl: &gl46::GlFns) {
     unsafe { gl.DebugMessageCallback(Some(gl_debug_cb), std::ptr::null()) };
 }
 
-unsafe fn create_texture(
-    gl: &gl46::GlFns,
-    width: u32,
-    height: u32,
-    stride: u32,
-    format: wl_shm::Format,
-    bytes: &[u8],
-) -> u32 {
+unsafe fn create_texture(gl: &gl46::GlFns, width: u32, height: u32, format: wl_shm::Format) -> u32 {
     let mut tex = 0;
     gl.CreateTextures(gl46::GL_TEXTURE_2D, 1, &mut tex);
     gl.TextureParameteri(tex, gl46::GL_TEXTURE_MIN_FILTER, gl46::GL_NEAREST.0 as i32);
@@ -782,18 +1020,121 @@ unsafe fn create_texture(
         width as i32,
         height as i32,
     );
+    tex
+}
+
+unsafe fn create_dmabuf_texture(gl: &gl46::GlFns, egl_image: &eglgbm::EglImage) -> u32 {
+    let mut gl_name = 0;
+    gl.GenTextures(1, &mut gl_name);
+    gl.BindTexture(gl46::GL_TEXTURE_2D, gl_name);
+    gl.TexParameteri(
+        gl46::GL_TEXTURE_2D,
+        gl46::GL_TEXTURE_MIN_FILTER,
+        gl46::GL_NEAREST.0 as i32,
+    );
+    gl.TexParameteri(
+        gl46::GL_TEXTURE_2D,
+        gl46::GL_TEXTURE_MAG_FILTER,
+        gl46::GL_NEAREST.0 as i32,
+    );
+    gl.TexParameteri(
+        gl46::GL_TEXTURE_2D,
+        gl46::GL_TEXTURE_WRAP_S,
+        gl46::GL_CLAMP_TO_EDGE.0 as i32,
+    );
+    gl.TexParameteri(
+        gl46::GL_TEXTURE_2D,
+        gl46::GL_TEXTURE_WRAP_T,
+        gl46::GL_CLAMP_TO_EDGE.0 as i32,
+    );
+    egl_image.set_as_gl_texture_2d();
+    gl.BindTexture(gl46::GL_TEXTURE_2D, 0);
+    gl_name
+}
+
+fn full_rect(width: u32, height: u32) -> pixman::Box32 {
+    pixman::Box32 {
+        x1: 0,
+        y1: 0,
+        x2: width as i32,
+        y2: height as i32,
+    }
+}
+
+/// Copy a rectangle of ARGB8888/XRGB8888 pixels from `bytes` to the same place in the texture.
+unsafe fn upload_rect(gl: &gl46::GlFns, tex: u32, stride: u32, bytes: &[u8], rect: &pixman::Box32) {
+    let offset = rect.y1 as usize * stride as usize + rect.x1 as usize * 4;
     gl.PixelStorei(gl46::GL_UNPACK_ROW_LENGTH, stride as i32 / 4);
     gl.TextureSubImage2D(
         tex,
         0,
-        0,
-        0,
-        width as i32,
-        height as i32,
+        rect.x1,
+        rect.y1,
+        rect.x2 - rect.x1,
+        rect.y2 - rect.y1,
         gl46::GL_BGRA,
         gl46::GL_UNSIGNED_BYTE,
-        bytes.as_ptr().cast(),
+        bytes[offset..].as_ptr().cast(),
     );
     gl.PixelStorei(gl46::GL_UNPACK_ROW_LENGTH, 0);
-    tex
+}
+
+/// A pair of pixel buffer objects used for SHM uploads.
+///
+/// The pixels are copied from the client's memory into a PBO before [`UploadPbos::upload`]
+/// returns, but the texture is then updated from the PBO, which the driver can queue on the GPU
+/// instead of stalling until the texture is no longer in use. The two PBOs are used in turns, so
+/// that refilling one does not have to wait for the transfer from the other to finish.
+struct UploadPbos {
+    names: [u32; 2],
+    next: usize,
+}
+
+impl UploadPbos {
+    unsafe fn new(gl: &gl46::GlFns) -> Self {
+        let mut names = [0; 2];
+        gl.CreateBuffers(2, names.as_mut_ptr());
+        Self { names, next: 0 }
+    }
+
+    /// Like [`upload_rect`], but goes through a PBO.
+    unsafe fn upload(
+        &mut self,
+        gl: &gl46::GlFns,
+        tex: u32,
+        stride: u32,
+        bytes: &[u8],
+        rect: &pixman::Box32,
+    ) {
+        let pbo = self.names[self.next];
+        self.next = (self.next + 1) % self.names.len();
+
+        let start = rect.y1 as usize * stride as usize + rect.x1 as usize * 4;
+        let end = (rect.y2 as usize - 1) * stride as usize + rect.x2 as usize * 4;
+        let data = &bytes[start..end];
+
+        // This copies `data` synchronously. Respecifying the whole store lets the driver hand out
+        // fresh memory if the GPU is still reading the previous contents.
+        gl.NamedBufferData(
+            pbo,
+            data.len() as isize,
+            data.as_ptr().cast(),
+            gl46::GL_STREAM_DRAW,
+        );
+        gl.BindBuffer(gl46::GL_PIXEL_UNPACK_BUFFER, pbo);
+        gl.PixelStorei(gl46::GL_UNPACK_ROW_LENGTH, stride as i32 / 4);
+        gl.TextureSubImage2D(
+            tex,
+            0,
+            rect.x1,
+            rect.y1,
+            rect.x2 - rect.x1,
+            rect.y2 - rect.y1,
+            gl46::GL_BGRA,
+            gl46::GL_UNSIGNED_BYTE,
+            std::ptr::null(),
+        );
+        gl.PixelStorei(gl46::GL_UNPACK_ROW_LENGTH, 0);
+        gl.BindBuffer(gl46::GL_PIXEL_UNPACK_BUFFER, 0);
+    }
 }
@@ -3,19 +3,25 @@ use std::collections::HashMap;
 use std::io;
 use std::num::NonZeroU64;
 use std::os::fd::{OwnedFd, RawFd};
+use std::time::Duration;
 
 pub mod drmkms;
+#[cfg(feature = "gl")]
 mod gl46_renderer;
 mod pixman_renderer;
+#[cfg(test)]
+pub mod recording;
 pub mod wayland;
 
 use crate::buffer_transform::BufferTransform;
-use crate::config::PointerConfig;
-use crate::globals::linux_dmabuf::DmaBufSpec;
+use crate::config::{PointerConfig, Renderer};
 use crate::globals::shm::{ShmBufferSpec, ShmPool};
 use crate::protocol;
 
 pub trait Backend {
+    /// Restore the state of the devices used by the backend, so that the session is usable after
+    /// the compositor exits. Called once before exiting, the backend is dropped right after.
+    fn shutdown(&mut self);
     fn register_fds_with(
         &self,
         reg: &'_ mut dyn FnMut(RawFd, u32) -> io::Result<()>,
@@ -26,21 +32,88 @@ pub trait Backend {
     fn pointer_get_name(&self, id: PointerId) -> Option<&str>;
     fn pointer_configure(&mut self, id: PointerId, config: &PointerConfig);
     fn renderer_state(&mut self) -> &mut dyn RendererState;
-    fn render_frame(&mut self, clear: Color, render_list: &[RenderNode], time: u32);
+    fn outputs(&self) -> &[OutputId];
+    fn output_name(&self, output: OutputId) -> &str;
+    /// Position and size of an output in the global compositor space.
+    fn output_geometry(&self, output: OutputId) -> pixman::Rectangle32;
+    /// Every output together with its position and size in the global compositor space.
+    fn output_geometries(&self) -> Vec<(OutputId, pixman::Rectangle32)> {
+        self.outputs()
+            .iter()
+            .map(|&output| (output, self.output_geometry(output)))
+            .collect()
+    }
+    fn output_modes(&self, output: OutputId) -> Vec<OutputMode>;
+    fn output_current_mode(&self, output: OutputId) -> OutputMode;
+    /// Switch the output to one of the modes returned by `output_modes`.
+    ///
+    /// Does not emit `BackendEvent::OutputChanged`, the caller is responsible for announcing the
+    /// new state.
+    fn set_output_mode(&mut self, output: OutputId, mode: OutputMode) -> io::Result<()>;
+    /// Turn the display on or off for power saving. While off, the output does not produce
+    /// frames. Turning it back on resumes them.
+    fn set_dpms(&mut self, output: OutputId, on: bool) -> io::Result<()>;
+    /// Number of entries in each gamma ramp of the output, zero if gamma can't be set.
+    fn gamma_size(&self, output: OutputId) -> u32;
+    /// Set the gamma ramps: `gamma_size` red entries, followed by green and blue ones. `None`
+    /// restores the identity ramps.
+    fn set_gamma(&mut self, output: OutputId, ramps: Option<&[u16]>) -> io::Result<()>;
+    /// Render a frame on a given output. Coordinates in `render_list` and `cursor` are
+    /// output-local. The cursor is drawn on top of everything else.
+    fn render_frame(
+        &mut self,
+        output: OutputId,
+        clear: Color,
+        render_list: &[RenderNode],
+        cursor: Option<&CursorNode>,
+        time: u32,
+    );
 }
 
+/// Produces the pixels of a texture again, or `None` if they are not available anymore.
+pub type PixelSource = Box<dyn Fn() -> Option<Vec<u8>>>;
+
 pub trait RendererState: Any {
+    /// The formats advertised by `wl_shm`, in this order. Must include `Argb8888` and `Xrgb8888`,
+    /// which every client may assume to be supported.
     fn supported_shm_formats(&self) -> &[protocol::wl_shm::Format];
+    #[cfg(feature = "gl")]
     fn supported_dma_buf_formats(&self) -> Option<&eglgbm::FormatTable>;
     fn get_shm_state(&mut self) -> &mut HashMap<protocol::WlShmPool, ShmPool>;
-    fn create_argb8_texture(&mut self, width: u32, height: u32, bytes: &[u8]) -> BufferId;
+    /// Create a texture from premultiplied ARGB8888 pixels. Renderers which lose their textures
+    /// on a GPU reset call `regenerate` to get the pixels again instead of keeping a copy.
+    fn create_argb8_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        bytes: &[u8],
+        regenerate: PixelSource,
+    ) -> BufferId;
     fn create_shm_buffer(&mut self, spec: ShmBufferSpec, resource: protocol::WlBuffer);
-    fn create_dma_buffer(&mut self, spec: DmaBufSpec, resource: protocol::WlBuffer);
+    #[cfg(feature = "gl")]
+    fn create_dma_buffer(
+        &mut self,
+        buffer: eglgbm::BufferExport,
+        resource: protocol::WlBuffer,
+    ) -> io::Result<()>;
     fn create_single_pix_buffer(&mut self, color: Color, resource: protocol::WlBuffer);
-    fn buffer_commited(&mut self, buffer_resource: protocol::WlBuffer) -> BufferId;
+    /// Lock the buffer attached to a committed surface.
+    ///
+    /// `damage` is the region of the buffer which changed since it was committed the last time, in
+    /// buffer coordinates. It may extend beyond the buffer. With `None`, the whole buffer has
+    /// changed and what was committed before must stay as it is.
+    fn buffer_commited(
+        &mut self,
+        buffer_resource: protocol::WlBuffer,
+        damage: Option<&pixman::Region32>,
+    ) -> BufferId;
     fn get_buffer_size(&self, buffer_id: BufferId) -> (u32, u32);
     fn buffer_unlock(&mut self, buffer_id: BufferId);
     fn buffer_resource_destroyed(&mut self, resource: protocol::WlBuffer);
+    /// The time the GPU spent rendering the last frame, if the renderer measures it.
+    fn last_frame_gpu_time(&self) -> Option<Duration> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -50,9 +123,25 @@ pub struct KeyboardId(NonZeroU64);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PointerId(NonZeroU64);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OutputId(NonZeroU64);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct InputTimestamp(u32);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputMode {
+    pub width: u32,
+    pub height: u32,
+    /// Refresh rate in mHz, zero if unknown.
+    pub refresh: u32,
+    pub preferred: bool,
+}
+
 impl InputTimestamp {
+    #[cfg(test)]
+    pub fn new(millis: u32) -> Self {
+        Self(millis)
+    }
+
     pub fn get(self) -> u32 {
         self.0
     }
@@ -70,6 +159,10 @@ trait Frame {
     );
     fn render_rect(&mut self, color: Color, rect: pixman::Rectangle32);
 
+    fn render_cursor(&mut self, cursor: &CursorNode) {
+        self.render_buffer(None, 1.0, cursor.buf_transform, cursor.x, cursor.y);
+    }
+
     fn render(&mut self, render_list: &[RenderNode], time: u32) {
         for node in render_list {
             match node {
@@ -95,7 +188,7 @@ trait Frame {
 /// Pre-multiplied RGBA color _or_ a texture coordinate. Negative `a` denotes that this is a texture
 /// coordinate, in which case `r` and `g` are the coordinates into a texture at index `b`, and alpha
 /// is `-a`. This is done to use a single shader for both colored and textured quads.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct Color {
     r: f32,
@@ -118,6 +211,7 @@ impl Color {
         }
     }
 
+    #[cfg(feature = "gl")]
     pub fn from_tex_uv(u: f32, v: f32, tex_i: u32, a: f32) -> Self {
         Self {
             r: u,
@@ -141,7 +235,8 @@ impl std::ops::Mul<f32> for Color {
 
 pub enum BackendEvent {
     ShutDown,
-    Frame,
+    Frame(OutputId),
+    OutputChanged(OutputId),
 
     NewKeyboard(KeyboardId),
     KeyPressed(KeyboardId, InputTimestamp, u32),
@@ -153,10 +248,42 @@ pub enum BackendEvent {
     PointerMotionRelative(PointerId, InputTimestamp, f32, f32),
     PointerBtnPress(PointerId, InputTimestamp, u32),
     PointerBtnRelease(PointerId, InputTimestamp, u32),
-    PointerAxisVertial(PointerId, InputTimestamp, f32),
+    PointerAxis(PointerId, InputTimestamp, PointerAxis),
     PointerRemoved(PointerId),
 }
 
+/// Scrolling on both axes which happened at the same time, like a single `wl_pointer.frame`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PointerAxis {
+    pub source: Option<protocol::wl_pointer::AxisSource>,
+    pub vertical: AxisValue,
+    pub horizontal: AxisValue,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AxisValue {
+    /// Scroll distance in the same units as pointer motion.
+    pub value: f32,
+    /// Scroll distance in 1/120 fractions of a wheel detent, zero if not a wheel.
+    pub value120: i32,
+    /// Scrolling on this axis stopped, e.g. the fingers were lifted from the touchpad.
+    pub stop: bool,
+    /// The scroll direction is the opposite of the physical motion ("natural scrolling").
+    pub inverted: bool,
+}
+
+impl PointerAxis {
+    pub fn is_empty(&self) -> bool {
+        self.vertical.is_empty() && self.horizontal.is_empty()
+    }
+}
+
+impl AxisValue {
+    pub fn is_empty(&self) -> bool {
+        self.value == 0.0 && self.value120 == 0 && !self.stop
+    }
+}
+
 pub enum RenderNode {
     Rect(pixman::Rectangle32, Color),
     Buffer {
@@ -169,6 +296,71 @@ pub enum RenderNode {
     },
 }
 
+pub struct CursorNode {
+    pub x: i32,
+    pub y: i32,
+    pub buf_transform: BufferTransform,
+}
+
+/// Send `done` to all frame callbacks in the list without rendering anything.
+fn send_frame_callbacks(render_list: &[RenderNode], time: u32) {
+    for node in render_list {
+        if let RenderNode::Buffer {
+            frame_callbacks, ..
+        } = node
+        {
+            for cb in frame_callbacks {
+                cb.done(time);
+            }
+        }
+    }
+}
+
+/// Initialize the GL renderer with `init_gl`, unless the pixman renderer is requested. Returns
+/// `None` if pixman should be used. GL errors are only fatal if GL is explicitly requested,
+/// otherwise they are logged and pixman is used instead.
+#[cfg(feature = "gl")]
+fn try_gl_renderer<T>(
+    backend: &str,
+    renderer: Renderer,
+    init_gl: impl FnOnce() -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    if renderer == Renderer::Pixman {
+        eprintln!("{backend}: using pixman renderer, as requested");
+        return Ok(None);
+    }
+    match init_gl() {
+        Ok(gl) => {
+            eprintln!("{backend}: using GL renderer");
+            Ok(Some(gl))
+        }
+        Err(e) if renderer == Renderer::Gl => Err(io::Error::other(format!(
+            "GL renderer requested, but could not be initialized: {e}"
+        ))),
+        Err(e) => {
+            eprintln!("{backend}: using pixman renderer, GL initialization failed: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// `try_gl_renderer` for builds without the `gl` feature.
+#[cfg(not(feature = "gl"))]
+fn try_gl_renderer<T>(backend: &str, renderer: Renderer) -> io::Result<Option<T>> {
+    match renderer {
+        Renderer::Gl => {
+            return Err(io::Error::other(
+                "GL renderer requested, but ewc was built without the gl feature",
+            ));
+        }
+        Renderer::Pixman => eprintln!("{backend}: using pixman renderer, as requested"),
+        Renderer::Auto => {
+            eprintln!("{backend}: using pixman renderer, ewc was built without the gl feature");
+        }
+    }
+    Ok(None)
+}
+
 #[must_use]
 fn next_id(id: &mut NonZeroU64) -> NonZeroU64 {
     let val = *id;
@@ -7,6 +7,7 @@ pub struct RendererStateImp {
     resource_mapping: HashMap<WlBuffer, BufferId>,
     buffers: HashMap<BufferId, Buffer>,
     next_id: NonZeroU64,
+    commit_seq: u64,
 }
 
 struct Buffer {
@@ -32,6 +33,7 @@ impl RendererStateImp {
             resource_mapping: HashMap::new(),
             buffers: HashMap::new(),
             next_id: NonZeroU64::MIN,
+            commit_seq: 0,
         }
     }
 
@@ -81,6 +83,7 @@ impl RendererState for RendererStateImp {
         &[wl_shm::Format::Argb8888, wl_shm::Format::Xrgb8888]
     }
 
+    #[cfg(feature = "gl")]
     fn supported_dma_buf_formats(&self) -> Option<&eglgbm::FormatTable> {
         None
     }
@@ -89,7 +92,13 @@ impl RendererState for RendererStateImp {
         &mut self.shm_pools
     }
 
-    fn create_argb8_texture(&mut self, width: u32, height: u32, bytes: &[u8]) -> BufferId {
+    fn create_argb8_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        bytes: &[u8],
+        _regenerate: PixelSource,
+    ) -> BufferId {
         let id = BufferId(next_id(&mut self.next_id));
         self.buffers.insert(
             id,
@@ -118,8 +127,13 @@ impl RendererState for RendererStateImp {
         );
     }
 
-    fn create_dma_buffer(&mut self, _spec: DmaBufSpec, _resource: protocol::WlBuffer) {
-        panic!("not supproted");
+    #[cfg(feature = "gl")]
+    fn create_dma_buffer(
+        &mut self,
+        _buffer: eglgbm::BufferExport,
+        _resource: protocol::WlBuffer,
+    ) -> io::Result<()> {
+        Err(io::Error::other("dmabufs are not supported"))
     }
 
     fn create_single_pix_buffer(&mut self, color: Color, resource: protocol::WlBuffer) {
@@ -134,10 +148,15 @@ impl RendererState for RendererStateImp {
         );
     }
 
-    fn buffer_commited(&mut self, resource: WlBuffer) -> BufferId {
+    fn buffer_commited(
+        &mut self,
+        resource: WlBuffer,
+        _damage: Option<&pixman::Region32>,
+    ) -> BufferId {
         let buffer_id = *self.resource_mapping.get(&resource).unwrap();
         let buf = self.buffers.get_mut(&buffer_id).unwrap();
         buf.locks += 1;
+        self.commit_seq += 1;
         match &buf.kind {
             BufferKind::Shm(_) => (),
             BufferKind::Argb8Texture(_, _, _) => (),
@@ -203,6 +222,94 @@ impl RendererState for RendererStateImp {
     }
 }
 
+/// Everything that affects a frame except for the cursor. If two consecutive frames have the same
+/// scene, only the cursor needs to be redrawn.
+#[derive(PartialEq)]
+pub struct Scene {
+    size: (u32, u32),
+    clear: Color,
+    /// Buffer contents may change without the render list changing, so any commit invalidates
+    /// the scene.
+    commit_seq: u64,
+    nodes: Vec<SceneNode>,
+}
+
+#[derive(PartialEq)]
+enum SceneNode {
+    Rect((i32, i32, u32, u32), Color),
+    Buffer(i32, i32, f32, BufferTransform),
+}
+
+impl Scene {
+    pub fn new(
+        state: &RendererStateImp,
+        width: u32,
+        height: u32,
+        clear: Color,
+        render_list: &[RenderNode],
+    ) -> Self {
+        Self {
+            size: (width, height),
+            clear,
+            commit_seq: state.commit_seq,
+            nodes: render_list
+                .iter()
+                .map(|node| match node {
+                    RenderNode::Rect(rect, color) => {
+                        SceneNode::Rect((rect.x, rect.y, rect.width, rect.height), *color)
+                    }
+                    RenderNode::Buffer {
+                        x,
+                        y,
+                        alpha,
+                        buf_transform,
+                        ..
+                    } => SceneNode::Buffer(*x, *y, *alpha, *buf_transform),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The pixels of a 32bpp frame covered by the cursor, saved before the cursor was drawn.
+pub struct CursorBackground {
+    x: usize,
+    y: usize,
+    width: usize,
+    pixels: Vec<u8>,
+}
+
+impl CursorBackground {
+    pub fn save(bytes: &[u8], width: u32, height: u32, cursor: &CursorNode) -> Self {
+        let x1 = cursor.x.clamp(0, width as i32) as usize;
+        let y1 = cursor.y.clamp(0, height as i32) as usize;
+        let x2 = (cursor.x + cursor.buf_transform.dst_width() as i32).clamp(0, width as i32);
+        let y2 = (cursor.y + cursor.buf_transform.dst_height() as i32).clamp(0, height as i32);
+        let (x2, y2) = (x2 as usize, y2 as usize);
+        let mut pixels = Vec::with_capacity((x2 - x1) * (y2 - y1) * 4);
+        for row in y1..y2 {
+            let start = (row * width as usize + x1) * 4;
+            pixels.extend_from_slice(&bytes[start..][..(x2 - x1) * 4]);
+        }
+        Self {
+            x: x1,
+            y: y1,
+            width: x2 - x1,
+            pixels,
+        }
+    }
+
+    pub fn restore(&self, bytes: &mut [u8], width: u32) {
+        if self.width == 0 {
+            return;
+        }
+        for (i, row) in self.pixels.chunks_exact(self.width * 4).enumerate() {
+            let start = ((self.y + i) * width as usize + self.x) * 4;
+            bytes[start..][..row.len()].copy_from_slice(row);
+        }
+    }
+}
+
 struct FrameImp<'a> {
     image: pixman::Image<'a, 'static>,
     state: &'a RendererStateImp,
@@ -234,12 +341,14 @@ impl Frame for FrameImp<'_> {
     ) {
         let t;
         let t2;
+        let _access;
 
         let buf = &self.state.buffers[&buf_transform.buf_id()];
         let (src, tex_width, tex_height) = match &buf.kind {
             BufferKind::Shm(shm) => {
                 let spec = &shm.spec;
                 let pool = &self.state.shm_pools[&spec.pool];
+                _access = pool.access();
                 let bytes = &pool.memmap[spec.offset as usize..]
                     [..spec.stride as usize * spec.height as usize];
                 t = unsafe {
@@ -0,0 +1,223 @@
+//! A backend for tests: it has a single fake output, renders nothing and records the render list
+//! of every frame instead. Input events are queued by the test.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::pixman_renderer::RendererStateImp;
+use super::*;
+
+/// Frames rendered by a [`RecordingBackend`], shared with the test.
+pub type Recording = Rc<RefCell<Vec<RecordedFrame>>>;
+
+/// Input events for a [`RecordingBackend`], queued by the test and returned by `next_event`.
+#[derive(Clone, Default)]
+pub struct Input(Rc<RefCell<VecDeque<BackendEvent>>>);
+
+impl Input {
+    const POINTER: PointerId = PointerId(NonZeroU64::MIN);
+    const KEYBOARD: KeyboardId = KeyboardId(NonZeroU64::MIN);
+
+    pub fn pointer_motion(&self, time: u32, x: f32, y: f32) {
+        let timestamp = InputTimestamp(time);
+        self.0
+            .borrow_mut()
+            .push_back(BackendEvent::PointerMotionAbsolute(
+                Self::POINTER,
+                timestamp,
+                x,
+                y,
+            ));
+    }
+
+    pub fn pointer_button(&self, time: u32, btn: u32, pressed: bool) {
+        let timestamp = InputTimestamp(time);
+        self.0.borrow_mut().push_back(if pressed {
+            BackendEvent::PointerBtnPress(Self::POINTER, timestamp, btn)
+        } else {
+            BackendEvent::PointerBtnRelease(Self::POINTER, timestamp, btn)
+        });
+    }
+
+    pub fn key(&self, time: u32, key: u32, pressed: bool) {
+        let timestamp = InputTimestamp(time);
+        self.0.borrow_mut().push_back(if pressed {
+            BackendEvent::KeyPressed(Self::KEYBOARD, timestamp, key)
+        } else {
+            BackendEvent::KeyReleased(Self::KEYBOARD, timestamp, key)
+        });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    pub output: OutputId,
+    pub clear: Color,
+    pub nodes: Vec<RecordedNode>,
+    /// Output-local position of the cursor, if it was drawn.
+    pub cursor: Option<(i32, i32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedNode {
+    Rect {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+        color: Color,
+    },
+    Buffer {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+        alpha: f32,
+    },
+}
+
+pub struct RecordingBackend {
+    renderer_state: RendererStateImp,
+    output: OutputId,
+    mode: OutputMode,
+    recording: Recording,
+    input: Input,
+}
+
+impl RecordingBackend {
+    pub fn new(width: u32, height: u32) -> (Box<Self>, Recording, Input) {
+        let recording = Recording::default();
+        let input = Input::default();
+        let backend = Box::new(Self {
+            renderer_state: RendererStateImp::new(),
+            output: OutputId(NonZeroU64::MIN),
+            mode: OutputMode {
+                width,
+                height,
+                refresh: 0,
+                preferred: true,
+            },
+            recording: recording.clone(),
+            input: input.clone(),
+        });
+        (backend, recording, input)
+    }
+}
+
+impl Backend for RecordingBackend {
+    fn shutdown(&mut self) {}
+
+    fn register_fds_with(
+        &self,
+        _reg: &'_ mut dyn FnMut(RawFd, u32) -> io::Result<()>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll(&mut self, _data: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<BackendEvent> {
+        self.input.0.borrow_mut().pop_front()
+    }
+
+    fn switch_vt(&mut self, _vt: u32) {}
+
+    fn pointer_get_name(&self, _id: PointerId) -> Option<&str> {
+        None
+    }
+
+    fn pointer_configure(&mut self, _id: PointerId, _config: &PointerConfig) {}
+
+    fn renderer_state(&mut self) -> &mut dyn RendererState {
+        &mut self.renderer_state
+    }
+
+    fn outputs(&self) -> &[OutputId] {
+        std::slice::from_ref(&self.output)
+    }
+
+    fn output_name(&self, _output: OutputId) -> &str {
+        "TEST-1"
+    }
+
+    fn output_geometry(&self, _output: OutputId) -> pixman::Rectangle32 {
+        pixman::Rectangle32 {
+            x: 0,
+            y: 0,
+            width: self.mode.width,
+            height: self.mode.height,
+        }
+    }
+
+    fn output_modes(&self, _output: OutputId) -> Vec<OutputMode> {
+        vec![self.mode]
+    }
+
+    fn output_current_mode(&self, _output: OutputId) -> OutputMode {
+        self.mode
+    }
+
+    fn set_output_mode(&mut self, _output: OutputId, mode: OutputMode) -> io::Result<()> {
+        if mode != self.mode {
+            return Err(io::Error::other("mode not supported"));
+        }
+        Ok(())
+    }
+
+    fn set_dpms(&mut self, _output: OutputId, _on: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn gamma_size(&self, _output: OutputId) -> u32 {
+        0
+    }
+
+    fn set_gamma(&mut self, _output: OutputId, _ramps: Option<&[u16]>) -> io::Result<()> {
+        Err(io::Error::other("gamma is not supported"))
+    }
+
+    fn render_frame(
+        &mut self,
+        output: OutputId,
+        clear: Color,
+        render_list: &[RenderNode],
+        cursor: Option<&CursorNode>,
+        time: u32,
+    ) {
+        let nodes = render_list
+            .iter()
+            .map(|node| match node {
+                RenderNode::Rect(rect, color) => RecordedNode::Rect {
+                    x: rect.x,
+                    y: rect.y,
+                    width: rect.width,
+                    height: rect.height,
+                    color: *color,
+                },
+                RenderNode::Buffer {
+                    x,
+                    y,
+                    alpha,
+                    buf_transform,
+                    ..
+                } => RecordedNode::Buffer {
+                    x: *x,
+                    y: *y,
+                    width: buf_transform.dst_width(),
+                    height: buf_transform.dst_height(),
+                    alpha: *alpha,
+                },
+            })
+            .collect();
+        self.recording.borrow_mut().push(RecordedFrame {
+            output,
+            clear,
+            nodes,
+            cursor: cursor.map(|c| (c.x, c.y)),
+        });
+        send_frame_callbacks(render_list, time);
+    }
+}
@@ -19,7 +19,7 @@ struct BackendImp {
     state: State,
 }
 
-pub fn new() -> Option<Box<dyn Backend>> {
+pub fn new(renderer: Renderer) -> Option<Box<dyn Backend>> {
     let InitState {
         mut conn,
         globals,
@@ -35,24 +35,38 @@ pub fn new() -> Option<Box<dyn Backend>> {
     let xdg_toplevel = xdg_surface.get_toplevel_with_cb(&mut conn, xdg_toplevel_cb);
     wl_surface.commit(&mut conn);
 
-    let renderer_kind = match dmabuf {
-        Some((linux_dmabuf, feedback)) if std::env::var_os("EWC_NO_GL").is_none() => {
-            let drm_device =
-                eglgbm::DrmDevice::new_from_id(feedback.main_device().unwrap()).unwrap();
-            let render_node_path = drm_device.render_node().unwrap();
-            RendererKind::OpenGl {
-                linux_dmabuf,
-                swapchain: None,
-                state: Box::new(gl46_renderer::RendererStateImp::new(
-                    render_node_path,
-                    feedback,
-                )?),
-            }
-        }
-        _ => RendererKind::Pixman {
+    #[cfg(feature = "gl")]
+    let gl = try_gl_renderer("backend/wayland", renderer, || {
+        let (linux_dmabuf, feedback) =
+            dmabuf.ok_or_else(|| io::Error::other("linux-dmabuf v4 is not supported"))?;
+        let drm_device = eglgbm::DrmDevice::new_from_id(feedback.main_device().unwrap())?;
+        let render_node_path = drm_device
+            .render_node()
+            .ok_or_else(|| io::Error::other("the main device has no render node"))?;
+        Ok(RendererKind::OpenGl {
+            linux_dmabuf,
+            swapchain: None,
+            state: Box::new(gl46_renderer::RendererStateImp::new(
+                render_node_path,
+                feedback,
+            )?),
+        })
+    });
+    #[cfg(not(feature = "gl"))]
+    let gl = {
+        drop(dmabuf);
+        try_gl_renderer("backend/wayland", renderer)
+    };
+    let renderer_kind = match gl {
+        Ok(Some(gl)) => gl,
+        Ok(None) => RendererKind::Pixman {
             shm: ShmAlloc::bind(&mut conn, &globals).unwrap(),
             state: pixman_renderer::RendererStateImp::new(),
         },
+        Err(e) => {
+            eprintln!("backend/wayland: {e}");
+            return None;
+        }
     };
 
     let state = State {
@@ -68,6 +82,7 @@ pub fn new() -> Option<Box<dyn Backend>> {
         wl_surface,
         xdg_surface,
         xdg_toplevel,
+        output: OutputId(NonZeroU64::MIN),
         throttle_cb: None,
         mapped: false,
         width: 80,
@@ -78,6 +93,10 @@ pub fn new() -> Option<Box<dyn Backend>> {
 }
 
 impl Backend for BackendImp {
+    fn shutdown(&mut self) {
+        // The parent compositor cleans up after us when the connection is closed.
+    }
+
     fn register_fds_with(
         &self,
         reg: &'_ mut dyn FnMut(RawFd, u32) -> io::Result<()>,
@@ -110,11 +129,75 @@ impl Backend for BackendImp {
     fn renderer_state(&mut self) -> &mut dyn RendererState {
         match &mut self.state.renderer_kind {
             RendererKind::Pixman { state, .. } => state,
+            #[cfg(feature = "gl")]
             RendererKind::OpenGl { state, .. } => state.as_mut(),
         }
     }
 
-    fn render_frame(&mut self, clear: Color, render_list: &[RenderNode], time: u32) {
+    fn outputs(&self) -> &[OutputId] {
+        std::slice::from_ref(&self.state.output)
+    }
+
+    fn output_name(&self, output: OutputId) -> &str {
+        assert_eq!(output, self.state.output);
+        "WL-1"
+    }
+
+    fn output_geometry(&self, output: OutputId) -> pixman::Rectangle32 {
+        assert_eq!(output, self.state.output);
+        pixman::Rectangle32 {
+            x: 0,
+            y: 0,
+            width: self.state.width,
+            height: self.state.height,
+        }
+    }
+
+    fn output_modes(&self, output: OutputId) -> Vec<OutputMode> {
+        vec![self.output_current_mode(output)]
+    }
+
+    fn output_current_mode(&self, output: OutputId) -> OutputMode {
+        assert_eq!(output, self.state.output);
+        OutputMode {
+            width: self.state.width,
+            height: self.state.height,
+            refresh: 0,
+            preferred: true,
+        }
+    }
+
+    fn set_output_mode(&mut self, output: OutputId, mode: OutputMode) -> io::Result<()> {
+        if mode == self.output_current_mode(output) {
+            Ok(())
+        } else {
+            Err(io::Error::other("nested backend cannot change modes"))
+        }
+    }
+
+    fn set_dpms(&mut self, output: OutputId, _on: bool) -> io::Result<()> {
+        // The parent compositor owns the display.
+        assert_eq!(output, self.state.output);
+        Ok(())
+    }
+
+    fn gamma_size(&self, _output: OutputId) -> u32 {
+        0
+    }
+
+    fn set_gamma(&mut self, _output: OutputId, _ramps: Option<&[u16]>) -> io::Result<()> {
+        Err(io::Error::other("nested backend cannot set gamma"))
+    }
+
+    fn render_frame(
+        &mut self,
+        output: OutputId,
+        clear: Color,
+        render_list: &[RenderNode],
+        cursor: Option<&CursorNode>,
+        time: u32,
+    ) {
+        assert_eq!(output, self.state.output);
         assert!(self.state.mapped);
         assert!(self.state.throttle_cb.is_none());
 
@@ -123,7 +206,7 @@ impl Backend for BackendImp {
             ctx.state.throttle_cb = None;
             ctx.state
                 .backend_events_queue
-                .push_back(BackendEvent::Frame);
+                .push_back(BackendEvent::Frame(ctx.state.output));
         }));
 
         match &mut self.state.renderer_kind {
@@ -147,10 +230,14 @@ impl Backend for BackendImp {
                 );
                 frame.clear(clear.r, clear.g, clear.b);
                 frame.render(render_list, time);
+                if let Some(cursor) = cursor {
+                    frame.render_cursor(cursor);
+                }
                 self.state
                     .wl_surface
                     .attach(&mut self.conn, Some(buffer.into_wl_buffer()), 0, 0);
             }
+            #[cfg(feature = "gl")]
             RendererKind::OpenGl {
                 linux_dmabuf,
                 swapchain,
@@ -160,7 +247,7 @@ impl Backend for BackendImp {
                     if sw.width != self.state.width || sw.height != self.state.height {
                         let sw = swapchain.take().unwrap();
                         for buf in sw.bufs {
-                            buf.destroy(&mut self.conn, state.gl());
+                            buf.destroy(&mut self.conn, state);
                         }
                     }
                 }
@@ -208,9 +295,18 @@ impl Backend for BackendImp {
                 };
                 assert!(!buf.in_use);
 
-                let mut frame = state.frame(sw.width, sw.height, &buf.fb);
+                let mut frame = match state.frame(sw.width, sw.height, &buf.fb) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        eprintln!("backend/wayland/gl46: skipping frame: {e}");
+                        break 'blk;
+                    }
+                };
                 frame.clear(clear.r, clear.g, clear.b);
                 frame.render(render_list, time);
+                if let Some(cursor) = cursor {
+                    frame.render_cursor(cursor);
+                }
                 drop(frame);
                 state.finish_frame();
 
@@ -244,6 +340,7 @@ struct State {
     xdg_surface: XdgSurface,
     #[allow(dead_code)]
     xdg_toplevel: XdgToplevel,
+    output: OutputId,
     throttle_cb: Option<WlCallback>,
     mapped: bool,
     width: u32,
@@ -255,6 +352,7 @@ enum RendererKind {
         shm: ShmAlloc,
         state: pixman_renderer::RendererStateImp,
     },
+    #[cfg(feature = "gl")]
     OpenGl {
         linux_dmabuf: ZwpLinuxDmabufV1,
         swapchain: Option<GlSwapchain>,
@@ -262,19 +360,22 @@ enum RendererKind {
     },
 }
 
+#[cfg(feature = "gl")]
 struct GlBuf {
     wl: WlBuffer,
     fb: gl46_renderer::Framebuffer,
     in_use: bool,
 }
 
+#[cfg(feature = "gl")]
 impl GlBuf {
-    fn destroy(self, conn: &mut Connection<State>, gl: &gl46::GlFns) {
+    fn destroy(self, conn: &mut Connection<State>, state: &gl46_renderer::RendererStateImp) {
         self.wl.destroy(conn);
-        self.fb.destroy(gl);
+        self.fb.destroy(state);
     }
 }
 
+#[cfg(feature = "gl")]
 struct GlSwapchain {
     width: u32,
     height: u32,
@@ -287,12 +388,18 @@ struct Keyboard {
     seat: WlSeat,
     pressed_keys: Vec<u32>,
     entered_with_keys: Vec<u32>,
+    /// Timestamp of the last key event. `leave` has none, so the synthesized releases use this.
+    last_time: u32,
 }
 
 struct Pointer {
     id: PointerId,
     wl: WlPointer,
     seat: WlSeat,
+    /// Scroll events received since the last `frame`.
+    axis: PointerAxis,
+    /// Timestamp of the last timed event, for the events which don't carry one.
+    last_time: u32,
 }
 
 impl SeatHandler for State {
@@ -309,6 +416,7 @@ impl SeatHandler for State {
             seat,
             pressed_keys: Vec::new(),
             entered_with_keys: Vec::new(),
+            last_time: 0,
         });
         self.backend_events_queue
             .push_back(BackendEvent::NewKeyboard(id));
@@ -327,7 +435,13 @@ impl SeatHandler for State {
     fn pointer_added(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
         let id = PointerId(next_id(&mut self.next_input_id));
         let wl = seat.get_pointer_with_cb(conn, wl_pointer_cb);
-        self.pointers.push(Pointer { id, wl, seat });
+        self.pointers.push(Pointer {
+            id,
+            wl,
+            seat,
+            axis: PointerAxis::default(),
+            last_time: 0,
+        });
         self.backend_events_queue
             .push_back(BackendEvent::NewPointer(id));
     }
@@ -362,13 +476,20 @@ fn wl_keyboard_cb(ctx: EventCtx<State, WlKeyboard>) {
                 .collect();
         }
         Event::Leave(_) => {
-            // for key in kbd.pressed_keys.drain(..) {
-            //     ctx.state
-            //         .backend_events_queue
-            //         .push_back(BackendEvent::KeyReleased(kbd.id, key));
-            // }
+            // We won't see the releases of the keys held while the focus is elsewhere.
+            kbd.entered_with_keys.clear();
+            for key in kbd.pressed_keys.drain(..) {
+                ctx.state
+                    .backend_events_queue
+                    .push_back(BackendEvent::KeyReleased(
+                        kbd.id,
+                        InputTimestamp(kbd.last_time),
+                        key,
+                    ));
+            }
         }
         Event::Key(args) => {
+            kbd.last_time = args.time;
             let timestamp = InputTimestamp(args.time);
             use wl_keyboard::KeyState;
             match args.state {
@@ -409,16 +530,18 @@ fn wl_pointer_cb(ctx: EventCtx<State, WlPointer>) {
     match ctx.event {
         Event::Enter(args) => {
             ptr.wl.set_cursor(ctx.conn, args.serial, None, 0, 0);
-            // ctx.state
-            //     .backend_events_queue
-            //     .push_back(BackendEvent::PointerMotionAbsolute(
-            //         ptr.id,
-            //         args.surface_x.as_f32(),
-            //         args.surface_y.as_f32(),
-            //     ))
+            ctx.state
+                .backend_events_queue
+                .push_back(BackendEvent::PointerMotionAbsolute(
+                    ptr.id,
+                    InputTimestamp(ptr.last_time),
+                    args.surface_x.as_f32(),
+                    args.surface_y.as_f32(),
+                ))
         }
         // Event::Leave(_) => todo!(),
         Event::Motion(args) => {
+            ptr.last_time = args.time;
             ctx.state
                 .backend_events_queue
                 .push_back(BackendEvent::PointerMotionAbsolute(
@@ -429,6 +552,7 @@ fn wl_pointer_cb(ctx: EventCtx<State, WlPointer>) {
                 ))
         }
         Event::Button(args) => {
+            ptr.last_time = args.time;
             let timestamp = InputTimestamp(args.time);
             ctx.state.backend_events_queue.push_back(match args.state {
                 wl_pointer::ButtonState::Released => {
@@ -441,26 +565,66 @@ fn wl_pointer_cb(ctx: EventCtx<State, WlPointer>) {
             });
         }
         Event::Axis(args) => {
-            if args.axis == wl_pointer::Axis::VerticalScroll {
+            ptr.last_time = args.time;
+            axis_value(&mut ptr.axis, args.axis).value = args.value.as_f32();
+            // Without frames every event stands on its own.
+            if ptr.wl.version() < 5 {
                 ctx.state
                     .backend_events_queue
-                    .push_back(BackendEvent::PointerAxisVertial(
+                    .push_back(BackendEvent::PointerAxis(
                         ptr.id,
-                        InputTimestamp(args.time),
-                        args.value.as_f32(),
+                        InputTimestamp(ptr.last_time),
+                        std::mem::take(&mut ptr.axis),
                     ));
             }
         }
-        // Event::Frame => todo!(),
-        // Event::AxisSource(_) => todo!(),
-        // Event::AxisStop(_) => todo!(),
-        // Event::AxisDiscrete(_) => todo!(),
-        // Event::AxisValue120(_) => todo!(),
-        // Event::AxisRelativeDirection(_) => todo!(),
+        Event::Frame => {
+            let axis = std::mem::take(&mut ptr.axis);
+            if !axis.is_empty() {
+                ctx.state
+                    .backend_events_queue
+                    .push_back(BackendEvent::PointerAxis(
+                        ptr.id,
+                        InputTimestamp(ptr.last_time),
+                        axis,
+                    ));
+            }
+        }
+        Event::AxisSource(source) => {
+            ptr.axis.source = Some(match source {
+                wl_pointer::AxisSource::Wheel => protocol::wl_pointer::AxisSource::Wheel,
+                wl_pointer::AxisSource::Finger => protocol::wl_pointer::AxisSource::Finger,
+                wl_pointer::AxisSource::Continuous => protocol::wl_pointer::AxisSource::Continuous,
+                wl_pointer::AxisSource::WheelTilt => protocol::wl_pointer::AxisSource::WheelTilt,
+                _ => return,
+            });
+        }
+        Event::AxisStop(args) => {
+            ptr.last_time = args.time;
+            axis_value(&mut ptr.axis, args.axis).stop = true;
+        }
+        Event::AxisDiscrete(args) => {
+            axis_value(&mut ptr.axis, args.axis).value120 = args.discrete * 120;
+        }
+        Event::AxisValue120(args) => {
+            axis_value(&mut ptr.axis, args.axis).value120 = args.value120;
+        }
+        Event::AxisRelativeDirection(args) => {
+            axis_value(&mut ptr.axis, args.axis).inverted =
+                args.direction == wl_pointer::AxisRelativeDirection::Inverted;
+        }
         _ => (),
     }
 }
 
+fn axis_value(axis: &mut PointerAxis, wl_axis: wl_pointer::Axis) -> &mut AxisValue {
+    if wl_axis == wl_pointer::Axis::HorizontalScroll {
+        &mut axis.horizontal
+    } else {
+        &mut axis.vertical
+    }
+}
+
 fn xdg_surface_cb(ctx: EventCtx<State, XdgSurface>) {
     if let xdg_surface::Event::Configure(serial) = ctx.event {
         ctx.proxy.ack_configure(ctx.conn, serial);
@@ -468,7 +632,7 @@ fn xdg_surface_cb(ctx: EventCtx<State, XdgSurface>) {
             ctx.state.mapped = true;
             ctx.state
                 .backend_events_queue
-                .push_back(BackendEvent::Frame);
+                .push_back(BackendEvent::Frame(ctx.state.output));
         }
     }
 }
@@ -476,12 +640,18 @@ fn xdg_surface_cb(ctx: EventCtx<State, XdgSurface>) {
 fn xdg_toplevel_cb(ctx: EventCtx<State, XdgToplevel>) {
     match ctx.event {
         xdg_toplevel::Event::Configure(args) => {
+            let old_size = (ctx.state.width, ctx.state.height);
             if args.width != 0 {
                 ctx.state.width = args.width.try_into().unwrap();
             }
             if args.height != 0 {
                 ctx.state.height = args.height.try_into().unwrap();
             }
+            if (ctx.state.width, ctx.state.height) != old_size {
+                ctx.state
+                    .backend_events_queue
+                    .push_back(BackendEvent::OutputChanged(ctx.state.output));
+            }
         }
         xdg_toplevel::Event::Close => {
             ctx.state
@@ -557,6 +727,7 @@ impl InitState {
     }
 }
 
+#[cfg(feature = "gl")]
 fn dmabuf_wl_buffer_cb(ctx: EventCtx<State, WlBuffer>) {
     let wl_buffer::Event::Release = ctx.event;
     let RendererKind::OpenGl { swapchain, .. } = &mut ctx.state.renderer_kind else {
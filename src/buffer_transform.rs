@@ -1,11 +1,11 @@
-use std::io;
+use std::fmt;
 use std::num::NonZeroU32;
 
 use crate::backend::{Backend, BufferId};
 use crate::protocol::wl_output;
 use crate::Fixed;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct BufferTransform {
     buf_id: BufferId,
     buf_width: u32,
@@ -20,50 +20,48 @@ pub struct BufferTransform {
     dst_height: u32,
 }
 
+/// Why a buffer does not fit the scale and viewport it is committed with. Each is a protocol error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeError {
+    /// The buffer size is not a multiple of the scale, `wl_surface.invalid_size`.
+    InvalidSize,
+    /// The viewport source rectangle extends outside of the buffer, `wp_viewport.out_of_buffer`.
+    OutOfBuffer,
+    /// The viewport source size is not integer and there is no destination size,
+    /// `wp_viewport.bad_size`.
+    BadSize,
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidSize => "buffer size is not a multiple of the buffer scale",
+            Self::OutOfBuffer => "source rectangle extends outside of the buffer",
+            Self::BadSize => "destination size is not set and source size is not integer",
+        })
+    }
+}
+
+impl std::error::Error for SizeError {}
+
 impl BufferTransform {
     pub fn new(
         buf_id: BufferId,
         backend: &mut dyn Backend,
         transform: wl_output::Transform,
-        scale: i32,
+        scale: NonZeroU32,
         viewport_src: Option<(f64, f64, Fixed, Fixed)>,
         viewport_dst: Option<(u32, u32)>,
-    ) -> io::Result<Self> {
-        let scale = u32::try_from(scale)
-            .ok()
-            .and_then(NonZeroU32::new)
-            .ok_or_else(|| io::Error::other("invalid buffer scale"))?;
-
+    ) -> Result<Self, SizeError> {
         let (buf_width, buf_height) = backend.renderer_state().get_buffer_size(buf_id);
-        if buf_width % scale.get() != 0 || buf_height % scale.get() != 0 {
-            return Err(io::Error::other("buffer size not a multiple of scale"));
-        }
-
-        let (transformed_w, transformed_h) = {
-            let mut w = buf_width / scale.get();
-            let mut h = buf_height / scale.get();
-            if transform as u32 & 1 != 0 {
-                std::mem::swap(&mut w, &mut h);
-            }
-            (w, h)
-        };
-
-        if let Some((x, y, w, h)) = viewport_src {
-            if x + w.as_f64() > transformed_w as f64 || y + h.as_f64() > transformed_h as f64 {
-                return Err(io::Error::other("viewport src out of buffer"));
-            }
-        }
-
-        let (dst_width, dst_height) = if let Some((w, h)) = viewport_dst {
-            (w, h)
-        } else if let Some((_x, _y, w, h)) = viewport_src {
-            if !h.is_int() || !w.is_int() {
-                return Err(io::Error::other("viewport dst not set, so src must by int"));
-            }
-            (w.as_int() as u32, h.as_int() as u32)
-        } else {
-            (transformed_w, transformed_h)
-        };
+        let (dst_width, dst_height) = surface_size(
+            buf_width,
+            buf_height,
+            transform,
+            scale,
+            viewport_src,
+            viewport_dst,
+        )?;
 
         let (src_x, src_y, src_width, src_height) = match viewport_src {
             None => (0.0, 0.0, dst_width as f64, dst_height as f64),
@@ -114,6 +112,7 @@ impl BufferTransform {
         Some(mat)
     }
 
+    #[cfg(feature = "gl")]
     pub fn surface_to_uv(&self) -> Option<pixman::FTransform> {
         self.surface_to_buffer().and_then(|m| {
             m.scale(
@@ -136,3 +135,117 @@ impl BufferTransform {
         self.dst_height
     }
 }
+
+/// Check that the buffer size is consistent with the scale and the viewport, and compute the
+/// size of the surface.
+///
+/// These are the checks the protocol requires at commit time: the buffer size must be a multiple
+/// of the scale (`wl_surface.invalid_size`), the viewport source must lie within the buffer
+/// (`wp_viewport.out_of_buffer`) and, without a destination size, the source size must be integer
+/// (`wp_viewport.bad_size`).
+fn surface_size(
+    buf_width: u32,
+    buf_height: u32,
+    transform: wl_output::Transform,
+    scale: NonZeroU32,
+    viewport_src: Option<(f64, f64, Fixed, Fixed)>,
+    viewport_dst: Option<(u32, u32)>,
+) -> Result<(u32, u32), SizeError> {
+    if buf_width % scale.get() != 0 || buf_height % scale.get() != 0 {
+        return Err(SizeError::InvalidSize);
+    }
+
+    let (transformed_w, transformed_h) = {
+        let mut w = buf_width / scale.get();
+        let mut h = buf_height / scale.get();
+        if transform as u32 & 1 != 0 {
+            std::mem::swap(&mut w, &mut h);
+        }
+        (w, h)
+    };
+
+    if let Some(src) = viewport_src {
+        if !viewport_src_fits(buf_width, buf_height, transform, scale, src) {
+            return Err(SizeError::OutOfBuffer);
+        }
+    }
+
+    if let Some((w, h)) = viewport_dst {
+        Ok((w, h))
+    } else if let Some((_x, _y, w, h)) = viewport_src {
+        if !h.is_int() || !w.is_int() {
+            return Err(SizeError::BadSize);
+        }
+        Ok((w.as_int() as u32, h.as_int() as u32))
+    } else {
+        Ok((transformed_w, transformed_h))
+    }
+}
+
+/// Whether the viewport source rectangle, which is in surface coordinates, lies within the
+/// buffer after the buffer transform and scale are applied.
+fn viewport_src_fits(
+    buf_width: u32,
+    buf_height: u32,
+    transform: wl_output::Transform,
+    scale: NonZeroU32,
+    (x, y, w, h): (f64, f64, Fixed, Fixed),
+) -> bool {
+    let mut max_x = buf_width as f64 / scale.get() as f64;
+    let mut max_y = buf_height as f64 / scale.get() as f64;
+    if transform as u32 & 1 != 0 {
+        std::mem::swap(&mut max_x, &mut max_y);
+    }
+    x + w.as_f64() <= max_x && y + h.as_f64() <= max_y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surface_size_checks() {
+        let normal = wl_output::Transform::Normal;
+        let rotated = wl_output::Transform::_90;
+        let scale = |s| NonZeroU32::new(s).unwrap();
+
+        assert_eq!(
+            surface_size(200, 100, normal, scale(2), None, None).unwrap(),
+            (100, 50)
+        );
+        assert_eq!(
+            surface_size(200, 100, rotated, scale(1), None, None).unwrap(),
+            (100, 200)
+        );
+        assert_eq!(
+            surface_size(201, 100, normal, scale(2), None, None),
+            Err(SizeError::InvalidSize)
+        );
+
+        let src = Some((10.0, 10.0, Fixed::from(50), Fixed::from(20)));
+        assert_eq!(
+            surface_size(100, 100, normal, scale(1), src, None).unwrap(),
+            (50, 20)
+        );
+        assert_eq!(
+            surface_size(100, 100, normal, scale(1), src, Some((30, 30))).unwrap(),
+            (30, 30)
+        );
+        assert_eq!(
+            surface_size(100, 100, normal, scale(2), src, None),
+            Err(SizeError::OutOfBuffer)
+        );
+
+        let src = Some((0.0, 0.0, Fixed::from(10.5), Fixed::from(20)));
+        assert_eq!(
+            surface_size(100, 100, normal, scale(1), src, None),
+            Err(SizeError::BadSize)
+        );
+        assert!(surface_size(100, 100, normal, scale(1), src, Some((10, 10))).is_ok());
+
+        let src = (50.0, 0.0, Fixed::from(60), Fixed::from(10));
+        assert!(!viewport_src_fits(100, 200, normal, scale(1), src));
+        assert!(viewport_src_fits(100, 200, rotated, scale(1), src));
+        assert!(!viewport_src_fits(200, 200, normal, scale(2), src));
+    }
+}
@@ -1,13 +1,19 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, VecDeque};
-use std::io;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut};
 use std::num::NonZeroU64;
-use std::os::fd::{AsRawFd, RawFd};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
 
 use crate::globals::compositor::Compositor;
+use crate::globals::ewc_debug;
+#[cfg(feature = "gl")]
 use crate::globals::linux_dmabuf::LinuxDmabuf;
+use crate::globals::security_context::SecurityContext;
 use crate::globals::shm::Shm;
 use crate::globals::single_pixel_buffer::SinglePixelBufferManager;
 use crate::protocol::*;
@@ -15,13 +21,17 @@ use crate::seat::{ClientSeat, DataSource};
 use crate::wayland_core::*;
 use crate::{State, ToFlushSet};
 
-use wayrs_core::transport::{PeekHeaderError, RecvMessageError};
+use wayrs_core::transport::{PeekHeaderError, RecvMessageError, Transport};
 use wayrs_core::MessageBuffersPool;
 
 pub struct Connection {
     client_id: ClientId,
     to_flush_set: Rc<ToFlushSet>,
-    socket: RefCell<BufferedSocket<UnixStream>>,
+    socket: RefCell<BufferedSocket<ClientStream>>,
+    /// Shared with `ClientStream`.
+    fds_in_flight: Rc<Cell<usize>>,
+    /// Log all messages, enabled with `EWC_WAYLAND_DEBUG=1`.
+    trace: bool,
     msg_buf_pool: RefCell<MessageBuffersPool>,
     events_queue: RefCell<VecDeque<Message>>,
     resources: RefCell<ObjectStorage>,
@@ -29,6 +39,43 @@ pub struct Connection {
     pub seat: ClientSeat,
 }
 
+/// Received fds which are not taken by a request yet are kept in the socket's buffer. A client
+/// with more of them than this is disconnected, so that it can't exhaust the fd limit.
+const MAX_FDS_IN_FLIGHT: usize = 256;
+
+/// A client socket which counts the received fds.
+struct ClientStream {
+    stream: UnixStream,
+    /// Fds received minus fds taken by parsed requests.
+    fds_in_flight: Rc<Cell<usize>>,
+}
+
+impl Transport for ClientStream {
+    fn pollable_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    fn send(&mut self, bytes: &[IoSlice], fds: &[OwnedFd], mode: IoMode) -> io::Result<usize> {
+        Transport::send(&mut self.stream, bytes, fds, mode)
+    }
+
+    fn recv(
+        &mut self,
+        bytes: &mut [IoSliceMut],
+        fds: &mut VecDeque<OwnedFd>,
+        mode: IoMode,
+    ) -> io::Result<usize> {
+        let fds_before = fds.len();
+        let n = Transport::recv(&mut self.stream, bytes, fds, mode)?;
+        let in_flight = self.fds_in_flight.get() + (fds.len() - fds_before);
+        self.fds_in_flight.set(in_flight);
+        if in_flight > MAX_FDS_IN_FLIGHT {
+            return Err(io::Error::other("client sent too many fds"));
+        }
+        Ok(n)
+    }
+}
+
 impl AsRawFd for Connection {
     fn as_raw_fd(&self) -> RawFd {
         self.socket.borrow().as_raw_fd()
@@ -48,14 +95,60 @@ impl ClientId {
     }
 }
 
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An error which the client is told about with `wl_display.error` before it is disconnected.
+/// Request handlers return it wrapped in an `io::Error`, see `ProtocolError::new`.
+#[derive(Debug)]
+pub struct ProtocolError {
+    object: ObjectId,
+    /// One of the error codes of the object's interface.
+    code: u32,
+    message: String,
+}
+
+impl ProtocolError {
+    pub fn new(object: &impl Proxy, code: u32, message: impl Into<String>) -> io::Error {
+        io::Error::other(Self {
+            object: object.id(),
+            code,
+            message: message.into(),
+        })
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (error {} on object {})",
+            self.message,
+            self.code,
+            self.object.as_u32()
+        )
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 impl Connection {
     fn new(stream: UnixStream, client_id: ClientId, to_flush_set: Rc<ToFlushSet>) -> Rc<Self> {
         Rc::new_cyclic(|conn| {
             let (wl_display, resources) = ObjectStorage::new(conn.clone());
+            let fds_in_flight = Rc::new(Cell::new(0));
             Self {
                 client_id,
                 to_flush_set,
-                socket: RefCell::new(BufferedSocket::from(stream)),
+                socket: RefCell::new(BufferedSocket::from(ClientStream {
+                    stream,
+                    fds_in_flight: fds_in_flight.clone(),
+                })),
+                fds_in_flight,
+                trace: std::env::var_os("EWC_WAYLAND_DEBUG").is_some_and(|x| x == "1"),
                 msg_buf_pool: RefCell::new(MessageBuffersPool::default()),
                 events_queue: RefCell::new(VecDeque::new()),
                 resources: RefCell::new(resources),
@@ -69,6 +162,20 @@ impl Connection {
         self.client_id
     }
 
+    /// Send `wl_display.error` if the client is being disconnected because of a `ProtocolError`.
+    pub fn post_error(&self, error: &io::Error) {
+        let Some(error) = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ProtocolError>())
+        else {
+            return;
+        };
+        let message = CString::new(error.message.as_str()).unwrap_or_default();
+        self.wl_display.error(error.object, error.code, message);
+        // The client is going away anyway.
+        let _ = self.flush();
+    }
+
     pub fn flush(&self) -> io::Result<()> {
         let mut eq = self.events_queue.borrow_mut();
         let mut socket = self.socket.borrow_mut();
@@ -83,6 +190,10 @@ impl Connection {
     }
 
     pub fn send_event(&self, msg: Message) {
+        ewc_debug::EVENTS.fetch_add(1, Ordering::Relaxed);
+        if self.trace {
+            self.trace_message(&msg, true);
+        }
         self.events_queue.borrow_mut().push_back(msg);
         self.to_flush_set.add(self.client_id);
     }
@@ -110,6 +221,8 @@ impl Connection {
         self.resources.borrow().get(id)
     }
 
+    /// Receive the next request. Fails with `WouldBlock` until the whole message, including its
+    /// fds, has arrived; the parts received so far stay buffered in the socket.
     fn recv_request(self: &Rc<Self>) -> io::Result<(Message, Object)> {
         let mut socket = self.socket.borrow_mut();
         let mut msg_buf_pool = self.msg_buf_pool.borrow_mut();
@@ -134,6 +247,18 @@ impl Connection {
                 RecvMessageError::Io(io) => io,
                 other => io::Error::new(io::ErrorKind::InvalidData, other),
             })?;
+        ewc_debug::REQUESTS.fetch_add(1, Ordering::Relaxed);
+        if self.trace {
+            self.trace_message(&msg, false);
+        }
+        // The fds are owned by the message now, and are closed when it is dropped, even if
+        // handling it fails.
+        let fds = msg
+            .args
+            .iter()
+            .filter(|arg| matches!(arg, ArgValue::Fd(_)))
+            .count();
+        self.fds_in_flight.set(self.fds_in_flight.get() - fds);
         for (arg_i, arg) in msg.args.iter().enumerate() {
             if let &ArgValue::NewId(id) = arg {
                 let ArgType::NewId(iface) = signature[arg_i] else { unreachable!() };
@@ -142,6 +267,72 @@ impl Connection {
         }
         Ok((msg, object))
     }
+
+    /// Print a message like `WAYLAND_DEBUG` does.
+    fn trace_message(&self, msg: &Message, is_event: bool) {
+        let object = self.get_object(msg.header.object_id);
+        let desc = object.as_ref().and_then(|object| {
+            let interface = object.interface();
+            let messages = if is_event {
+                interface.events
+            } else {
+                interface.requests
+            };
+            messages.get(msg.header.opcode as usize)
+        });
+        let args = msg
+            .args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| match arg {
+                ArgValue::Uint(x) => x.to_string(),
+                ArgValue::Int(x) => x.to_string(),
+                ArgValue::Fixed(x) => x.as_f64().to_string(),
+                ArgValue::Object(id) | ArgValue::OptObject(Some(id)) => self.object_name(*id),
+                ArgValue::OptObject(None) | ArgValue::OptString(None) => "nil".into(),
+                ArgValue::NewId(id) => match desc.map(|desc| desc.signature[i]) {
+                    Some(ArgType::NewId(interface)) => format!(
+                        "new id {}@{}",
+                        interface.name.to_string_lossy(),
+                        id.as_u32()
+                    ),
+                    _ => format!("new id ?@{}", id.as_u32()),
+                },
+                ArgValue::AnyNewId(interface, _version, id) => {
+                    format!("new id {}@{}", interface.to_string_lossy(), id.as_u32())
+                }
+                ArgValue::String(s) | ArgValue::OptString(Some(s)) => format!("{s:?}"),
+                ArgValue::Array(a) => format!("array[{}]", a.len()),
+                ArgValue::Fd(fd) => format!("fd {}", fd.as_raw_fd()),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let micros = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u32;
+        eprintln!(
+            "[{:7}.{:03}] client {} {}{}.{}({args})",
+            micros / 1000,
+            micros % 1000,
+            self.client_id,
+            if is_event { " -> " } else { "" },
+            self.object_name(msg.header.object_id),
+            desc.map_or("?", |desc| desc.name),
+        );
+    }
+
+    /// `interface@id`, for logs.
+    fn object_name(&self, id: ObjectId) -> String {
+        match self.get_object(id) {
+            Some(object) => format!(
+                "{}@{}",
+                object.interface().name.to_string_lossy(),
+                id.as_u32()
+            ),
+            None => format!("?@{}", id.as_u32()),
+        }
+    }
 }
 
 pub type ResourceCallback = Box<dyn Fn(&mut Client, &mut State, Object, Message) -> io::Result<()>>;
@@ -153,29 +344,69 @@ pub struct RequestCtx<'a, P: Proxy> {
     pub request: P::Request,
 }
 
+/// Credentials of the process on the other end of a client connection, as reported by
+/// `SO_PEERCRED` when the connection was accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
 pub struct Client {
     pub conn: Rc<Connection>,
+    peer_cred: Option<PeerCred>,
+    /// Set for clients which connected through a `wp_security_context_v1` socket.
+    pub security_context: Option<Rc<SecurityContext>>,
     pub compositor: Compositor,
     pub shm: Shm,
     pub data_sources: HashMap<WlDataSource, DataSource>,
+    #[cfg(feature = "gl")]
     pub linux_dambuf: LinuxDmabuf,
     pub single_pixel_buffer_manager: SinglePixelBufferManager,
 }
 
 impl Client {
     pub fn new(stream: UnixStream, id: ClientId, to_flush_set: Rc<ToFlushSet>) -> Self {
+        let peer_cred = match read_peer_cred(&stream) {
+            Ok(cred) => Some(cred),
+            Err(e) => {
+                eprintln!("could not get credentials of client {id}: {e}");
+                None
+            }
+        };
         let conn = Connection::new(stream, id, to_flush_set);
         conn.wl_display.set_callback(wl_display_cb);
         Self {
             conn,
+            peer_cred,
+            security_context: None,
             compositor: Compositor::default(),
             shm: Shm::default(),
             data_sources: HashMap::new(),
+            #[cfg(feature = "gl")]
             linux_dambuf: LinuxDmabuf::default(),
             single_pixel_buffer_manager: SinglePixelBufferManager::default(),
         }
     }
 
+    /// The credentials of the client process.
+    ///
+    /// These are captured at connection time, so they describe the process which connected, even
+    /// if the socket was passed to another process afterwards.
+    pub fn peer_credentials(&self) -> io::Result<PeerCred> {
+        self.peer_cred
+            .ok_or_else(|| io::Error::other("client credentials are not available"))
+    }
+
+    /// The app id of one of the client's toplevels, if it has set any.
+    pub fn app_id(&self) -> Option<CString> {
+        self.compositor
+            .xdg_toplevels
+            .values()
+            .find_map(|toplevel| toplevel.app_id())
+    }
+
     pub fn poll(&mut self, state: &mut State) -> io::Result<()> {
         loop {
             let (msg, object) = match self.conn.recv_request() {
@@ -200,11 +431,60 @@ impl Client {
     }
 }
 
+/// Identifies the client in logs, e.g. `client 3 pid=1234 (org.mozilla.firefox)`.
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.conn.client_id())?;
+        if let Ok(cred) = self.peer_credentials() {
+            write!(f, " pid={} uid={}", cred.pid, cred.uid)?;
+        }
+        if let Some(app_id) = self.app_id() {
+            write!(f, " ({})", app_id.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+fn read_peer_cred(stream: &UnixStream) -> io::Result<PeerCred> {
+    let mut ucred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut ucred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: ucred.pid,
+        uid: ucred.uid,
+        gid: ucred.gid,
+    })
+}
+
 fn wl_display_cb(ctx: RequestCtx<WlDisplay>) -> io::Result<()> {
     use wl_display::Request;
     match ctx.request {
-        Request::Sync(cb) => cb.done(0), // WTF is this "event serial"?
-        Request::GetRegistry(registry) => ctx.state.globals.add_registry(registry),
+        Request::Sync(cb) => {
+            // WTF is this "event serial"?
+            cb.done(0);
+            // Don't wait for the rest of the event loop iteration, which may include rendering.
+            // Everything queued before `done` is sent along with it, so ordering is kept.
+            ctx.client.conn.flush()?;
+        }
+        Request::GetRegistry(registry) => ctx
+            .state
+            .globals
+            .add_registry(registry, ctx.client.security_context.is_some()),
     }
     Ok(())
 }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::env;
+use std::io;
 use std::path::PathBuf;
 
 use serde::Deserialize;
@@ -8,11 +9,69 @@ use serde::Deserialize;
 #[serde(deny_unknown_fields, default)]
 pub struct Config {
     pub bg_color: (f32, f32, f32),
+    pub max_fps: Option<u32>,
+    /// Seconds without input after which the outputs are turned off.
+    pub blank_timeout: Option<u32>,
+    pub screensaver: Option<ScreensaverConfig>,
+    /// Turn dragging with the left button while holding the logo key into touch events.
+    pub touch_emulation: bool,
+    /// Fade newly mapped windows in.
+    pub window_fade_in: bool,
+    /// How close, in pixels, a moved window has to get to an edge to snap to it. Zero disables
+    /// snapping.
+    pub snap_distance: u32,
+    /// Tile windows dragged to the top, left or right edge of an output.
+    pub edge_tiling: bool,
+    /// How many pixels the keyboard bindings move or resize windows by.
+    pub keyboard_step: u32,
+    /// Give keyboard focus to the window under the pointer, without raising it.
+    pub focus_follows_mouse: bool,
+    /// The longest time, in milliseconds, between the two clicks of a double click. Double
+    /// clicking the top border of a window maximizes or restores it. Zero disables double clicks.
+    pub double_click_interval: u32,
+    /// Which renderer to use, overridden by `EWC_RENDERER`.
+    pub renderer: Renderer,
 
     pub xkb_layout: String,
     pub xkb_options: Option<String>,
 
     pub pointer: HashMap<String, PointerConfig>,
+    pub output: HashMap<String, OutputConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Renderer {
+    /// OpenGL, or pixman if OpenGL could not be initialized.
+    Auto,
+    Gl,
+    Pixman,
+}
+
+impl Renderer {
+    /// The renderer selected by `EWC_RENDERER`, or by the older `EWC_NO_GL`.
+    fn from_env() -> io::Result<Option<Self>> {
+        match env::var("EWC_RENDERER") {
+            Ok(value) => match value.as_str() {
+                "auto" => Ok(Some(Self::Auto)),
+                "gl" => Ok(Some(Self::Gl)),
+                "pixman" => Ok(Some(Self::Pixman)),
+                _ => Err(io::Error::other(format!(
+                    "EWC_RENDERER must be auto, gl or pixman, got {value:?}"
+                ))),
+            },
+            Err(_) if env::var_os("EWC_NO_GL").is_some() => Ok(Some(Self::Pixman)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// A command started after `timeout` seconds without input, and killed by the next input.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScreensaverConfig {
+    pub timeout: u32,
+    pub command: String,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -22,14 +81,45 @@ pub struct PointerConfig {
     pub natural_scroll: Option<bool>,
 }
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct OutputConfig {
+    pub bg_color: Option<(f32, f32, f32)>,
+    pub wallpaper: Option<PathBuf>,
+}
+
 impl Config {
     pub fn new() -> Self {
+        Self::load().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn load() -> io::Result<Self> {
+        let mut config = Self::load_file()?;
+        if let Some(renderer) = Renderer::from_env()? {
+            config.renderer = renderer;
+        }
+        Ok(config)
+    }
+
+    fn load_file() -> io::Result<Self> {
         match config_path() {
-            None => Self::default(),
+            None => Ok(Self::default()),
             Some(path) => {
-                let contents =
-                    std::fs::read_to_string(path).expect("could not read the config file");
-                toml_edit::de::from_str(&contents).expect("config error")
+                let contents = std::fs::read_to_string(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("could not read the config file: {e}"))
+                })?;
+                let config: Self = toml_edit::de::from_str(&contents)
+                    .map_err(|e| io::Error::other(format!("config error: {e}")))?;
+                if config.max_fps == Some(0) {
+                    return Err(io::Error::other("max_fps must be positive"));
+                }
+                if config.blank_timeout == Some(0) {
+                    return Err(io::Error::other("blank_timeout must be positive"));
+                }
+                if config.screensaver.as_ref().is_some_and(|s| s.timeout == 0) {
+                    return Err(io::Error::other("screensaver.timeout must be positive"));
+                }
+                Ok(config)
             }
         }
     }
@@ -39,9 +129,21 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             bg_color: (0.2, 0.1, 0.2),
+            max_fps: None,
+            blank_timeout: None,
+            screensaver: None,
+            touch_emulation: false,
+            window_fade_in: false,
+            snap_distance: 0,
+            edge_tiling: false,
+            keyboard_step: 20,
+            focus_follows_mouse: false,
+            double_click_interval: 400,
+            renderer: Renderer::Auto,
             xkb_layout: String::new(),
             xkb_options: None,
             pointer: HashMap::new(),
+            output: HashMap::new(),
         }
     }
 }
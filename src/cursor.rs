@@ -1,12 +1,15 @@
 use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::Path;
 use std::rc::Rc;
 
 use crate::backend::Backend;
 use crate::buffer_transform::BufferTransform;
 use crate::client::ClientId;
 use crate::globals::compositor::Surface;
-use crate::protocol::wl_output;
 use crate::protocol::wp_cursor_shape_device_v1::Shape;
+use crate::protocol::xdg_toplevel::ResizeEdge;
+use crate::protocol::{wl_output, WlCallback, WlSurface};
 use crate::Proxy;
 
 pub struct Cursor {
@@ -41,11 +44,14 @@ impl Cursor {
 
         let mut shapes = HashMap::new();
 
-        for &(shape, str) in TO_STR_MAPPING {
-            if let Some(tex) = get_texture(&theme, backend, str) {
+        for &(shape, names) in TO_STR_MAPPING {
+            if let Some(tex) = names
+                .iter()
+                .find_map(|name| get_texture(&theme, backend, name))
+            {
                 shapes.insert(shape, tex);
             } else {
-                eprintln!("cursor theme does not contain '{str}");
+                eprintln!("cursor theme does not contain '{}'", names[0]);
             }
         }
 
@@ -63,6 +69,27 @@ impl Cursor {
         self.kind = Kind::Surface { surface, hx, hy }
     }
 
+    /// A cursor surface committed a buffer moved by `dx, dy`. The hotspot moves the other way, so
+    /// that the image stays in place relative to the pointer.
+    pub fn surface_offset(&mut self, wl_surface: &WlSurface, dx: i32, dy: i32) {
+        if let Kind::Surface { surface, hx, hy } = &mut self.kind {
+            if surface.wl == *wl_surface {
+                *hx -= dx;
+                *hy -= dy;
+            }
+        }
+    }
+
+    /// Take the frame callbacks of the cursor surface, to be sent when the cursor is drawn.
+    pub fn take_frame_callbacks(&self) -> Vec<WlCallback> {
+        match &self.kind {
+            Kind::Surface { surface, .. } => {
+                std::mem::take(&mut surface.cur.borrow_mut().frame_cbs)
+            }
+            _ => Vec::new(),
+        }
+    }
+
     pub fn set_shape(&mut self, shape: Shape) {
         if let Some(tex) = self.shapes.get(&shape) {
             self.kind = Kind::Texture(*tex);
@@ -71,6 +98,21 @@ impl Cursor {
         }
     }
 
+    /// Show the cursor matching an interactive resize from the given edge.
+    pub fn set_resize_shape(&mut self, edge: ResizeEdge) {
+        self.set_shape(match edge {
+            ResizeEdge::None => Shape::Default,
+            ResizeEdge::Top => Shape::NResize,
+            ResizeEdge::Bottom => Shape::SResize,
+            ResizeEdge::Left => Shape::WResize,
+            ResizeEdge::Right => Shape::EResize,
+            ResizeEdge::TopLeft => Shape::NwResize,
+            ResizeEdge::TopRight => Shape::NeResize,
+            ResizeEdge::BottomLeft => Shape::SwResize,
+            ResizeEdge::BottomRight => Shape::SeResize,
+        });
+    }
+
     pub fn get_buffer(&self) -> Option<(BufferTransform, i32, i32)> {
         match &self.kind {
             Kind::Hidden => None,
@@ -85,7 +127,7 @@ impl Cursor {
     pub fn remove_client(&mut self, client_id: ClientId) {
         match &self.kind {
             Kind::Surface { surface, .. } if surface.wl.client_id() == client_id => {
-                self.hide();
+                self.set_shape(Shape::Default);
             }
             _ => (),
         }
@@ -98,18 +140,22 @@ fn get_texture(
     name: &str,
 ) -> Option<Texture> {
     let path = theme.load_icon(name)?;
-    let content = std::fs::read(path).ok()?;
-    let mut images = xcursor::parser::parse_xcursor(&content)?;
-    images.sort_by(|a, b| a.size.cmp(&b.size));
-    let (Ok(i) | Err(i)) = images.binary_search_by_key(&24, |x| x.size);
-    let image = images.get(i).or_else(|| images.last())?;
+    let image = load_image(&path)?;
     let buf_id = backend.renderer_state().create_argb8_texture(
         image.width,
         image.height,
         &image.pixels_rgba,
+        Box::new(move || load_image(&path).map(|image| image.pixels_rgba)),
     );
-    let buf_transform =
-        BufferTransform::new(buf_id, backend, wl_output::Transform::Normal, 1, None, None).unwrap();
+    let buf_transform = BufferTransform::new(
+        buf_id,
+        backend,
+        wl_output::Transform::Normal,
+        NonZeroU32::MIN,
+        None,
+        None,
+    )
+    .unwrap();
     Some(Texture {
         buf_transform,
         hx: image.xhot as i32,
@@ -117,39 +163,84 @@ fn get_texture(
     })
 }
 
-const TO_STR_MAPPING: &[(Shape, &str)] = &[
-    (Shape::Default, "default"),
-    (Shape::ContextMenu, "context-menu"),
-    (Shape::Help, "help"),
-    (Shape::Pointer, "pointer"),
-    (Shape::Progress, "progress"),
-    (Shape::Wait, "wait"),
-    (Shape::Cell, "cell"),
-    (Shape::Crosshair, "crosshair"),
-    (Shape::Text, "text"),
-    (Shape::VerticalText, "vertical-text"),
-    (Shape::Alias, "alias"),
-    (Shape::Copy, "copy"),
-    (Shape::Move, "move"),
-    (Shape::NoDrop, "no-drop"),
-    (Shape::NotAllowed, "not-allowed"),
-    (Shape::Grab, "grab"),
-    (Shape::Grabbing, "grabbing"),
-    (Shape::EResize, "e-resize"),
-    (Shape::NResize, "n-resize"),
-    (Shape::NeResize, "ne-resize"),
-    (Shape::NwResize, "nw-resize"),
-    (Shape::SResize, "s-resize"),
-    (Shape::SeResize, "se-resize"),
-    (Shape::SwResize, "sw-resize"),
-    (Shape::WResize, "w-resize"),
-    (Shape::EwResize, "ew-resize"),
-    (Shape::NsResize, "ns-resize"),
-    (Shape::NeswResize, "nesw-resize"),
-    (Shape::NwseResize, "nwse-resize"),
-    (Shape::ColResize, "col-resize"),
-    (Shape::RowResize, "row-resize"),
-    (Shape::AllScroll, "all-scroll"),
-    (Shape::ZoomIn, "zoom-in"),
-    (Shape::ZoomOut, "zoom-out"),
+/// Load the image of a cursor file which is closest to the size we use.
+fn load_image(path: &Path) -> Option<xcursor::parser::Image> {
+    let content = std::fs::read(path).ok()?;
+    let mut images = xcursor::parser::parse_xcursor(&content)?;
+    images.sort_by(|a, b| a.size.cmp(&b.size));
+    let (Ok(i) | Err(i)) = images.binary_search_by_key(&24, |x| x.size);
+    let i = i.min(images.len().checked_sub(1)?);
+    Some(images.swap_remove(i))
+}
+
+/// CSS cursor names, followed by legacy X cursor names used by older themes.
+const TO_STR_MAPPING: &[(Shape, &[&str])] = &[
+    (Shape::Default, &["default", "left_ptr"]),
+    (Shape::ContextMenu, &["context-menu"]),
+    (Shape::Help, &["help", "question_arrow", "whats_this"]),
+    (
+        Shape::Pointer,
+        &["pointer", "hand2", "hand1", "pointing_hand"],
+    ),
+    (Shape::Progress, &["progress", "left_ptr_watch"]),
+    (Shape::Wait, &["wait", "watch"]),
+    (Shape::Cell, &["cell", "plus"]),
+    (Shape::Crosshair, &["crosshair", "cross", "tcross"]),
+    (Shape::Text, &["text", "xterm", "ibeam"]),
+    (Shape::VerticalText, &["vertical-text"]),
+    (Shape::Alias, &["alias", "dnd-link"]),
+    (Shape::Copy, &["copy", "dnd-copy"]),
+    (Shape::Move, &["move", "fleur", "dnd-move"]),
+    (Shape::NoDrop, &["no-drop", "dnd-none", "circle"]),
+    (
+        Shape::NotAllowed,
+        &["not-allowed", "crossed_circle", "circle"],
+    ),
+    (Shape::Grab, &["grab", "openhand", "hand1"]),
+    (Shape::Grabbing, &["grabbing", "closedhand", "fleur"]),
+    (Shape::EResize, &["e-resize", "right_side"]),
+    (Shape::NResize, &["n-resize", "top_side"]),
+    (Shape::NeResize, &["ne-resize", "top_right_corner"]),
+    (Shape::NwResize, &["nw-resize", "top_left_corner"]),
+    (Shape::SResize, &["s-resize", "bottom_side"]),
+    (Shape::SeResize, &["se-resize", "bottom_right_corner"]),
+    (Shape::SwResize, &["sw-resize", "bottom_left_corner"]),
+    (Shape::WResize, &["w-resize", "left_side"]),
+    (
+        Shape::EwResize,
+        &[
+            "ew-resize",
+            "sb_h_double_arrow",
+            "h_double_arrow",
+            "size_hor",
+        ],
+    ),
+    (
+        Shape::NsResize,
+        &[
+            "ns-resize",
+            "sb_v_double_arrow",
+            "v_double_arrow",
+            "size_ver",
+        ],
+    ),
+    (
+        Shape::NeswResize,
+        &["nesw-resize", "fd_double_arrow", "size_bdiag"],
+    ),
+    (
+        Shape::NwseResize,
+        &["nwse-resize", "bd_double_arrow", "size_fdiag"],
+    ),
+    (
+        Shape::ColResize,
+        &["col-resize", "sb_h_double_arrow", "split_h"],
+    ),
+    (
+        Shape::RowResize,
+        &["row-resize", "sb_v_double_arrow", "split_v"],
+    ),
+    (Shape::AllScroll, &["all-scroll", "fleur"]),
+    (Shape::ZoomIn, &["zoom-in"]),
+    (Shape::ZoomOut, &["zoom-out"]),
 ];
@@ -2,13 +2,17 @@ use std::collections::HashMap;
 use std::ffi::c_int;
 use std::io;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
 
 use crate::client::ClientId;
+use crate::globals::security_context::ListenerId;
+use crate::ipc::IpcClientId;
 
 pub struct EventLoop {
     epoll: OwnedFd,
     next_id: u64,
     data_map: HashMap<u64, Event>,
+    fd_ids: HashMap<RawFd, u64>,
     event_buf: [libc::epoll_event; 32],
     event_cnt: usize,
     event_head: usize,
@@ -20,6 +24,12 @@ pub enum Event {
     Backend(u32),
     Quit,
     Client(ClientId),
+    IpcSocket,
+    IpcClient(IpcClientId),
+    SecurityContextSocket(ListenerId),
+    SecurityContextClosed(ListenerId),
+    FrameTimer,
+    IdleTimer,
     MayGoIdle,
 }
 
@@ -29,6 +39,7 @@ impl EventLoop {
             epoll: epoll_create1(libc::EPOLL_CLOEXEC)?,
             next_id: 0,
             data_map: HashMap::new(),
+            fd_ids: HashMap::new(),
             event_buf: unsafe { std::mem::zeroed() },
             event_cnt: 0,
             event_head: 0,
@@ -54,12 +65,41 @@ impl EventLoop {
         }
 
         self.data_map.insert(self.next_id, event);
+        self.fd_ids.insert(fd, self.next_id);
         self.next_id = self.next_id.checked_add(1).unwrap();
 
         Ok(())
     }
 
+    /// Also report the fd when it becomes writable, or stop doing so.
+    pub fn set_writable(&mut self, fd: RawFd, writable: bool) -> io::Result<()> {
+        let mut epoll_event = libc::epoll_event {
+            events: if writable {
+                (libc::EPOLLIN | libc::EPOLLOUT) as u32
+            } else {
+                libc::EPOLLIN as u32
+            },
+            u64: self.fd_ids[&fd],
+        };
+
+        if unsafe {
+            libc::epoll_ctl(
+                self.epoll.as_raw_fd(),
+                libc::EPOLL_CTL_MOD,
+                fd,
+                &mut epoll_event,
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
     pub fn remove(&mut self, fd: RawFd) -> io::Result<()> {
+        // The data stays, events of the fd may still be pending in the buffer.
+        self.fd_ids.remove(&fd);
         if unsafe {
             libc::epoll_ctl(
                 self.epoll.as_raw_fd(),
@@ -111,6 +151,64 @@ impl EventLoop {
     }
 }
 
+/// A one-shot monotonic timer, readable when it expires.
+pub struct Timer(OwnedFd);
+
+impl Timer {
+    pub fn new() -> io::Result<Self> {
+        match unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) })),
+        }
+    }
+
+    /// Arm the timer to expire after `timeout`, overriding any previous deadline.
+    pub fn set(&self, timeout: Duration) -> io::Result<()> {
+        // A zero value would disarm the timer.
+        let timeout = timeout.max(Duration::from_nanos(1));
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: timeout.as_secs() as _,
+                tv_nsec: timeout.subsec_nanos() as _,
+            },
+        };
+        if unsafe { libc::timerfd_settime(self.0.as_raw_fd(), 0, &spec, std::ptr::null_mut()) }
+            == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Acknowledge the expiration, so that the fd is no longer readable.
+    pub fn clear(&self) -> io::Result<()> {
+        let mut expirations = 0u64;
+        if unsafe { libc::read(self.0.as_raw_fd(), (&mut expirations as *mut u64).cast(), 8) } == -1
+        {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
 fn epoll_create1(flags: c_int) -> io::Result<OwnedFd> {
     match unsafe { libc::epoll_create1(flags) } {
         -1 => Err(io::Error::last_os_error()),
@@ -1,14 +1,35 @@
 use std::rc::{Rc, Weak};
 
 use crate::client::ClientId;
-use crate::globals::compositor::Surface;
+use crate::globals::compositor::{SubsurfaceNode, Surface};
 use crate::globals::xdg_shell::toplevel::XdgToplevelRole;
 use crate::seat::Seat;
 use crate::wayland_core::Proxy;
 
+pub const WORKSPACES: u32 = 9;
+
+/// The width of the borders drawn around the window geometry of toplevels.
+pub const BORDER_WIDTH: u32 = 2;
+
+/// Toplevels are stacked by layer first: a toplevel is never below a toplevel of a lower layer,
+/// even when that one is focused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    Below,
+    Normal,
+    Above,
+}
+
 #[derive(Default)]
 pub struct FocusStack {
+    /// From bottom to top, sorted by layer.
     inner: Vec<Weak<XdgToplevelRole>>,
+    /// The toplevel given keyboard focus by `set_keyboard_focus_i`, which may be below others.
+    focused: Option<Weak<XdgToplevelRole>>,
+    workspace: u32,
+    /// The toplevels `cycle` goes through, from bottom to top as they were stacked when the cycle
+    /// started, and the index of the one it focused last.
+    cycle: Option<(Vec<Weak<XdgToplevelRole>>, usize)>,
 }
 
 pub struct SurfaceUnderCursor {
@@ -18,49 +39,84 @@ pub struct SurfaceUnderCursor {
     pub toplevel_idx: usize,
 }
 
+/// What is at a point: a surface, or the border of a toplevel which is drawn around its window
+/// geometry.
+enum Under {
+    Surface(SurfaceUnderCursor),
+    Border { toplevel_idx: usize, top: bool },
+}
+
 impl FocusStack {
     pub fn surface_at(&self, x: f32, y: f32) -> Option<SurfaceUnderCursor> {
+        match self.under(x, y)? {
+            Under::Surface(under) => Some(under),
+            Under::Border { .. } => None,
+        }
+    }
+
+    /// The index of the toplevel whose top border is at `x, y`, if it is not covered there.
+    pub fn top_border_at(&self, x: f32, y: f32) -> Option<usize> {
+        match self.under(x, y)? {
+            Under::Border {
+                toplevel_idx,
+                top: true,
+            } => Some(toplevel_idx),
+            _ => None,
+        }
+    }
+
+    fn under(&self, x: f32, y: f32) -> Option<Under> {
         fn surface_at(surf: Rc<Surface>, x: f32, y: f32) -> Option<(Rc<Surface>, f32, f32)> {
             if let Some(xdg) = surf.get_xdg_surface() {
                 if let Some(popup) = &*xdg.popup.borrow() {
-                    let par_geom = xdg.get_window_geometry().unwrap();
-                    let pop_geom = popup
-                        .xdg_surface
-                        .upgrade()
-                        .unwrap()
-                        .get_window_geometry()
-                        .unwrap();
+                    let (dx, dy) = popup.offset().unwrap();
                     if let Some(res) = surface_at(
                         popup.wl_surface.upgrade().unwrap(),
-                        x - (popup.x.get() + par_geom.x - pop_geom.x) as f32,
-                        y - (popup.y.get() + par_geom.y - pop_geom.y) as f32,
+                        x - dx as f32,
+                        y - dy as f32,
                     ) {
                         return Some(res);
                     }
                 }
             }
-            for subs in surf.cur.borrow().subsurfaces.iter().rev() {
-                if let Some(res) = surface_at(
-                    subs.surface.clone(),
-                    x - subs.position.0 as f32,
-                    y - subs.position.1 as f32,
-                ) {
-                    return Some(res);
-                }
-            }
+            let sub_at = |subs: &[SubsurfaceNode]| {
+                subs.iter().rev().find_map(|sub| {
+                    surface_at(
+                        sub.surface.clone(),
+                        x - sub.position.0 as f32,
+                        y - sub.position.1 as f32,
+                    )
+                })
+            };
+            // Subsurfaces of a surface without a buffer are not rendered, see `render_surface`.
             let buf_transform = surf.buf_transform()?;
+            let (below, above) = {
+                let cur = surf.cur.borrow();
+                let (below, above) = cur.subsurfaces_split();
+                (below.to_vec(), above.to_vec())
+            };
+            if let Some(res) = sub_at(&above) {
+                return Some(res);
+            }
             let ok = x >= 0.0
                 && y >= 0.0
                 && x < buf_transform.dst_width() as f32
                 && y < buf_transform.dst_height() as f32
                 && surf.cur.borrow().input_region.as_ref().map_or(true, |reg| {
-                    reg.contains_point(x.round() as i32, y.round() as i32)
+                    // Pixel `i` covers `[i, i + 1)`, so round down.
+                    reg.contains_point(x.floor() as i32, y.floor() as i32)
                         .is_some()
                 });
-            ok.then_some((surf, x, y))
+            if ok {
+                return Some((surf, x, y));
+            }
+            sub_at(&below)
         }
         for (toplevel_idx, toplevel) in self.inner.iter().enumerate().rev() {
             let tl = toplevel.upgrade().unwrap();
+            if !self.is_visible(&tl) {
+                continue;
+            }
             let xdg = tl.xdg_surface.upgrade().unwrap();
             let Some(geom) = xdg.get_window_geometry() else { continue };
             if let Some((surf, sx, sy)) = surface_at(
@@ -68,26 +124,177 @@ impl FocusStack {
                 x - (tl.x.get() - geom.x) as f32,
                 y - (tl.y.get() - geom.y) as f32,
             ) {
-                return Some(SurfaceUnderCursor {
+                return Some(Under::Surface(SurfaceUnderCursor {
                     sx,
                     sy,
                     surf,
                     toplevel_idx,
+                }));
+            }
+            // The borders (see `Server::render_output`) hide the windows below.
+            let (bx, by) = (x - tl.x.get() as f32, y - tl.y.get() as f32);
+            let (w, h) = (geom.width.get() as f32, geom.height.get() as f32);
+            let b = BORDER_WIDTH as f32;
+            let in_window = bx >= 0.0 && by >= 0.0 && bx < w && by < h;
+            if !in_window && bx >= -b && by >= -b && bx < w + b && by < h + b {
+                return Some(Under::Border {
+                    toplevel_idx,
+                    top: by < 0.0,
                 });
             }
         }
         None
     }
 
+    /// The focused toplevel: the one last given keyboard focus if it is visible, or the topmost
+    /// toplevel on the active workspace.
     pub fn top(&self) -> Option<Rc<XdgToplevelRole>> {
-        self.inner.last().map(|x| x.upgrade().unwrap())
+        self.focused
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .filter(|tl| self.is_visible(tl))
+            .or_else(|| self.visible().next_back())
+    }
+
+    /// Toplevels on the active workspace, from bottom to top.
+    pub fn visible(&self) -> impl DoubleEndedIterator<Item = Rc<XdgToplevelRole>> + '_ {
+        self.inner
+            .iter()
+            .map(|x| x.upgrade().unwrap())
+            .filter(|x| self.is_visible(x))
+    }
+
+    pub fn is_visible(&self, toplevel: &XdgToplevelRole) -> bool {
+        toplevel.tags.get() & (1 << self.workspace) != 0 && !toplevel.minimized.get()
+    }
+
+    /// Hide the toplevel until it is focused again.
+    pub fn minimize(&mut self, toplevel: &XdgToplevelRole, seat: &mut Seat) {
+        toplevel.minimized.set(true);
+        seat.surface_unmapped(&toplevel.wl_surface.upgrade().unwrap().wl);
+        self.focus_top(seat);
+    }
+
+    /// Focus the next toplevel on the active workspace, restoring it if it is minimized. Starting
+    /// with the bottommost one, repeating this goes through all of them in the order they were
+    /// stacked in when the cycle started, whatever their layers. Focusing a toplevel in another
+    /// way starts a new cycle.
+    pub fn cycle(&mut self, seat: &mut Seat) {
+        let continues = self.cycle.as_ref().is_some_and(|(order, pos)| {
+            self.focused
+                .as_ref()
+                .is_some_and(|focused| order.get(*pos).is_some_and(|tl| tl.ptr_eq(focused)))
+        });
+        if !continues {
+            let order = self
+                .inner
+                .iter()
+                .filter(|tl| tl.upgrade().unwrap().tags.get() & (1 << self.workspace) != 0)
+                .cloned()
+                .collect();
+            self.cycle = Some((order, usize::MAX));
+        }
+        let (order, pos) = self.cycle.as_mut().unwrap();
+        for _ in 0..order.len() {
+            *pos = pos.wrapping_add(1) % order.len();
+            let tl = &order[*pos];
+            let i = self.inner.iter().position(|x| x.ptr_eq(tl));
+            if let Some(i) = i.filter(|&i| {
+                self.inner[i].upgrade().unwrap().tags.get() & (1 << self.workspace) != 0
+            }) {
+                self.focus_i(i, seat);
+                return;
+            }
+        }
+        self.cycle = None;
+    }
+
+    pub fn workspace(&self) -> u32 {
+        self.workspace
+    }
+
+    pub fn switch_workspace(&mut self, workspace: u32, seat: &mut Seat) {
+        assert!(workspace < WORKSPACES);
+        self.workspace = workspace;
+        self.focus_top(seat);
+    }
+
+    /// Move the focused toplevel to the given workspace.
+    pub fn move_top_to_workspace(&mut self, workspace: u32, seat: &mut Seat) {
+        assert!(workspace < WORKSPACES);
+        if let Some(top) = self.top() {
+            top.tags.set(1 << workspace);
+            self.focus_top(seat);
+        }
+    }
+
+    /// Add the focused toplevel to or remove it from the given workspace. A toplevel always
+    /// belongs to at least one workspace.
+    pub fn toggle_top_workspace(&mut self, workspace: u32, seat: &mut Seat) {
+        assert!(workspace < WORKSPACES);
+        if let Some(top) = self.top() {
+            let tags = top.tags.get() ^ (1 << workspace);
+            if tags != 0 {
+                top.tags.set(tags);
+                self.focus_top(seat);
+            }
+        }
+    }
+
+    /// Put the focused toplevel into the layer, or back into the normal layer if it already is in
+    /// it.
+    pub fn toggle_top_layer(&mut self, layer: Layer) {
+        if let Some(top) = self.top() {
+            if top.layer.get() == layer {
+                top.layer.set(Layer::Normal);
+            } else {
+                top.layer.set(layer);
+            }
+            let i = self
+                .inner
+                .iter()
+                .position(|tl| tl.as_ptr() == Rc::as_ptr(&top));
+            self.raise_i(i.unwrap());
+        }
     }
 
+    fn sort_layers(&mut self) {
+        self.inner
+            .sort_by_key(|tl| tl.upgrade().unwrap().layer.get());
+    }
+
+    fn focus_top(&self, seat: &mut Seat) {
+        seat.keyboard.focus_surface(
+            self.top()
+                .map(|tl| tl.wl_surface.upgrade().unwrap().wl.clone()),
+        );
+    }
+
+    /// Raise the toplevel and give it keyboard focus.
     pub fn focus_i(&mut self, i: usize, seat: &mut Seat) {
-        let tl = self.inner.remove(i).upgrade().unwrap();
+        let tl = self.inner[i].clone();
+        self.raise_i(i);
+        let i = self.inner.iter().position(|x| x.ptr_eq(&tl)).unwrap();
+        self.set_keyboard_focus_i(i, seat);
+    }
+
+    /// Put the toplevel on top of the others in its layer, restoring it if it is minimized.
+    /// Keyboard focus stays where it is.
+    pub fn raise_i(&mut self, i: usize) {
+        let tl = self.inner.remove(i);
+        tl.upgrade().unwrap().minimized.set(false);
+        self.inner.push(tl);
+        self.sort_layers();
+    }
+
+    /// Give the toplevel keyboard focus, restoring it if it is minimized, without changing its
+    /// place in the stack.
+    pub fn set_keyboard_focus_i(&mut self, i: usize, seat: &mut Seat) {
+        let tl = self.inner[i].upgrade().unwrap();
+        tl.minimized.set(false);
         seat.keyboard
             .focus_surface(Some(tl.wl_surface.upgrade().unwrap().wl.clone()));
-        self.inner.push(Rc::downgrade(&tl));
+        self.focused = Some(Rc::downgrade(&tl));
     }
 
     pub fn get_i(&mut self, i: usize) -> Option<Rc<XdgToplevelRole>> {
@@ -97,18 +304,114 @@ impl FocusStack {
     pub fn remove(&mut self, toplevel: &XdgToplevelRole) {
         self.inner
             .retain(|s| s.upgrade().unwrap().wl != toplevel.wl);
+        self.forget_removed_focus();
     }
 
     pub fn remove_client(&mut self, client_id: ClientId) {
         self.inner
             .retain(|s| s.upgrade().unwrap().wl.client_id() != client_id);
+        self.forget_removed_focus();
+    }
+
+    fn forget_removed_focus(&mut self) {
+        if let Some(focused) = &self.focused {
+            if !self.inner.iter().any(|tl| tl.ptr_eq(focused)) {
+                self.focused = None;
+            }
+        }
     }
 
+    /// Add a newly mapped toplevel on top of the others in its layer. It becomes the focused
+    /// toplevel.
     pub fn push(&mut self, toplevel: &Rc<XdgToplevelRole>) {
+        toplevel.tags.set(1 << self.workspace);
         self.inner.push(Rc::downgrade(toplevel));
+        self.sort_layers();
+        self.focused = Some(Rc::downgrade(toplevel));
     }
 
     pub fn inner(&self) -> &[Weak<XdgToplevelRole>] {
         &self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_harness::Harness;
+
+    #[test]
+    fn keyboard_focus_without_raising() {
+        let mut h = Harness::new();
+        for _ in 0..2 {
+            h.map_toplevel(100, 50);
+        }
+
+        let state = &mut h.server.state;
+        let bottom = state.focus_stack.get_i(0).unwrap();
+        let top = state.focus_stack.get_i(1).unwrap();
+        assert!(Rc::ptr_eq(&state.focus_stack.top().unwrap(), &top));
+
+        state.focus_stack.set_keyboard_focus_i(0, &mut state.seat);
+        assert!(Rc::ptr_eq(&state.focus_stack.top().unwrap(), &bottom));
+        assert!(Rc::ptr_eq(&state.focus_stack.get_i(1).unwrap(), &top));
+
+        state.focus_stack.focus_i(1, &mut state.seat);
+        assert!(Rc::ptr_eq(&state.focus_stack.top().unwrap(), &top));
+
+        state.focus_stack.raise_i(0);
+        assert!(Rc::ptr_eq(&state.focus_stack.top().unwrap(), &top));
+        assert!(Rc::ptr_eq(&state.focus_stack.get_i(1).unwrap(), &bottom));
+    }
+
+    #[test]
+    fn always_on_top_layer() {
+        let mut h = Harness::new();
+        for i in 0..2 {
+            h.map_toplevel(100, 50);
+            if i == 0 {
+                h.server.state.focus_stack.toggle_top_layer(Layer::Above);
+            }
+        }
+
+        // The new window is focused, but stays below the pinned one.
+        let state = &mut h.server.state;
+        let pinned = state.focus_stack.get_i(1).unwrap();
+        let normal = state.focus_stack.get_i(0).unwrap();
+        assert_eq!(pinned.layer.get(), Layer::Above);
+        assert!(Rc::ptr_eq(&state.focus_stack.top().unwrap(), &normal));
+
+        state.focus_stack.focus_i(0, &mut state.seat);
+        assert!(Rc::ptr_eq(&state.focus_stack.top().unwrap(), &normal));
+        assert!(Rc::ptr_eq(&state.focus_stack.get_i(1).unwrap(), &pinned));
+
+        state.focus_stack.toggle_top_layer(Layer::Below);
+        assert_eq!(normal.layer.get(), Layer::Below);
+        assert!(Rc::ptr_eq(&state.focus_stack.get_i(0).unwrap(), &normal));
+    }
+
+    #[test]
+    fn cycle_reaches_all_layers() {
+        let mut h = Harness::new();
+        for layer in [Layer::Below, Layer::Normal, Layer::Above, Layer::Normal] {
+            h.map_toplevel(100, 50);
+            if layer != Layer::Normal {
+                h.server.state.focus_stack.toggle_top_layer(layer);
+            }
+        }
+
+        let state = &mut h.server.state;
+        let all: Vec<_> = (0..4)
+            .map(|i| state.focus_stack.get_i(i).unwrap())
+            .collect();
+        let mut focused = Vec::new();
+        for _ in 0..5 {
+            state.focus_stack.cycle(&mut state.seat);
+            focused.push(state.focus_stack.top().unwrap());
+        }
+        // From the bottom to the top of the stack before the cycle, then from the start again.
+        for (tl, expected) in focused.iter().zip(all.iter().chain(&all[..1])) {
+            assert!(Rc::ptr_eq(tl, expected));
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::io;
+use std::num::NonZeroU32;
 use std::rc::{Rc, Weak};
 
 use super::xdg_shell;
 use crate::backend::{Backend, BufferId};
-use crate::buffer_transform::BufferTransform;
-use crate::client::RequestCtx;
+use crate::buffer_transform::{BufferTransform, SizeError};
+use crate::client::{ProtocolError, RequestCtx};
+use crate::focus_stack::BORDER_WIDTH;
 use crate::globals::{GlobalsManager, IsGlobal};
 use crate::protocol::*;
 use crate::wayland_core::{Fixed, Proxy};
@@ -52,8 +54,16 @@ pub struct Surface {
     pending: RefCell<SurfaceState>,
     cached_state: RefCell<SurfaceState>,
     pub pending_buffer: Cell<Option<WlBuffer>>,
+    /// Damage accumulated since the last commit, in buffer coordinates.
+    pending_damage: RefCell<pixman::Region32>,
+    /// The buffer attached by the last commit with an attach request. Damage is relative to it.
+    last_buffer: RefCell<Option<WlBuffer>>,
     viewport: Cell<Option<WpViewport>>,
     buf_transform: Cell<Option<BufferTransform>>,
+    /// Cached result of `get_bounding_box`, `None` if it has to be recomputed.
+    bounding_box: Cell<Option<Option<pixman::Box32>>>,
+    /// The last `preferred_buffer_scale` sent, `None` if none was sent yet.
+    preferred_scale: Cell<Option<i32>>,
 
     pub mapped: Cell<bool>,
     pub configured: Cell<bool>,
@@ -65,14 +75,20 @@ pub struct SurfaceState {
 
     pub buffer: Option<BufferId>,
     pub transform: Option<wl_output::Transform>,
-    pub scale: Option<i32>,
+    pub scale: Option<NonZeroU32>,
     pub opaque_region: Option<pixman::Region32>,
     pub input_region: Option<pixman::Region32>,
+    /// Subsurfaces from bottom to top. The first `subsurfaces_below` of them are placed below
+    /// the surface itself.
     pub subsurfaces: Vec<SubsurfaceNode>,
+    pub subsurfaces_below: usize,
     pub frame_cbs: Vec<WlCallback>,
 
     pub viewport_src: Option<(f64, f64, Fixed, Fixed)>,
     pub viewport_dst: Option<(u32, u32)>,
+    /// Movement of the new buffer relative to the old one, from `attach` or `offset`. Offsets
+    /// add up until the role consumes them.
+    pub offset: (i32, i32),
 }
 
 impl SurfaceState {
@@ -95,6 +111,7 @@ impl SurfaceState {
         }
         if self.mask.contains(CommittedMaskBit::Subsurfaces) {
             dst.subsurfaces.clone_from(&self.subsurfaces);
+            dst.subsurfaces_below = self.subsurfaces_below;
         }
         if self.mask.contains(CommittedMaskBit::FrameCb) {
             dst.frame_cbs.extend_from_slice(&self.frame_cbs);
@@ -112,8 +129,62 @@ impl SurfaceState {
         if self.mask.contains(CommittedMaskBit::Scale) {
             dst.scale = self.scale.take();
         }
+        if self.mask.contains(CommittedMaskBit::Offset) {
+            let (dx, dy) = std::mem::take(&mut self.offset);
+            dst.offset.0 += dx;
+            dst.offset.1 += dy;
+        }
         self.mask.clear();
     }
+
+    /// The subsurfaces placed below and above the surface itself.
+    pub fn subsurfaces_split(&self) -> (&[SubsurfaceNode], &[SubsurfaceNode]) {
+        self.subsurfaces.split_at(self.subsurfaces_below)
+    }
+
+    fn remove_subsurface(&mut self, surface: &WlSurface) -> Option<SubsurfaceNode> {
+        let i = self
+            .subsurfaces
+            .iter()
+            .position(|node| node.surface.wl == *surface)?;
+        if i < self.subsurfaces_below {
+            self.subsurfaces_below -= 1;
+        }
+        Some(self.subsurfaces.remove(i))
+    }
+
+    /// Whether `surface` is a subsurface of this surface.
+    fn has_subsurface(&self, surface: &WlSurface) -> bool {
+        self.subsurfaces.iter().any(|x| x.surface.wl == *surface)
+    }
+
+    /// Insert a subsurface directly above or below `sibling`, which must be either another
+    /// subsurface of this surface or `parent`, the surface itself.
+    fn place_subsurface(
+        &mut self,
+        node: SubsurfaceNode,
+        sibling: &WlSurface,
+        parent: &WlSurface,
+        above: bool,
+    ) {
+        let (i, is_below) = if sibling == parent {
+            (self.subsurfaces_below, !above)
+        } else {
+            let sibling_i = self
+                .subsurfaces
+                .iter()
+                .position(|x| x.surface.wl == *sibling)
+                .expect("not a sibling");
+            (
+                sibling_i + above as usize,
+                sibling_i < self.subsurfaces_below,
+            )
+        };
+        self.subsurfaces.insert(i, node);
+        if is_below {
+            self.subsurfaces_below += 1;
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -128,6 +199,7 @@ pub enum CommittedMaskBit {
     ViewportSrc = 1 << 6,
     ViewportDst = 1 << 7,
     Scale = 1 << 8,
+    Offset = 1 << 9,
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -166,40 +238,98 @@ impl Surface {
             pending: RefCell::new(SurfaceState::default()),
             cached_state: RefCell::new(SurfaceState::default()),
             pending_buffer: Cell::new(None),
+            pending_damage: RefCell::new(pixman::Region32::default()),
+            last_buffer: RefCell::new(None),
             viewport: Cell::new(None),
             buf_transform: Cell::new(None),
+            bounding_box: Cell::new(None),
+            preferred_scale: Cell::new(None),
 
             mapped: Cell::new(false),
             configured: Cell::new(false),
         }
     }
 
+    /// Unmap the surface and its subsurfaces, and release the buffer of the surface so that the
+    /// client can reuse it. The buffers of the subsurfaces are kept, they are shown again when the
+    /// surface is mapped again.
     pub fn unmap(&self, state: &mut State) {
+        self.hide(state);
+        if let Some(buf_id) = self.cur.borrow_mut().buffer.take() {
+            state.backend.renderer_state().buffer_unlock(buf_id);
+            self.buf_transform.set(None);
+            self.invalidate_bounding_box();
+        }
+    }
+
+    fn hide(&self, state: &mut State) {
         if self.mapped.get() {
+            self.repaint(state);
             if let Some(toplevel) = self.get_xdg_toplevel() {
                 state.focus_stack.remove(&toplevel);
             }
             state.seat.surface_unmapped(&self.wl);
             for sub in &self.cur.borrow().subsurfaces {
-                sub.surface.unmap(state);
+                sub.surface.hide(state);
             }
         }
         self.mapped.set(false);
         self.configured.set(false);
     }
 
+    /// Tell the client which buffer scale to use for the surface and its subsurfaces, if it
+    /// changed. The preferred transform is always normal, since outputs cannot be transformed.
+    pub fn send_preferred_buffer_scale(&self, scale: i32) {
+        if self.wl.version() >= 6 && self.preferred_scale.get() != Some(scale) {
+            if self.preferred_scale.get().is_none() {
+                self.wl
+                    .preferred_buffer_transform(wl_output::Transform::Normal);
+            }
+            self.wl.preferred_buffer_scale(scale);
+            self.preferred_scale.set(Some(scale));
+        }
+        for sub in &self.cur.borrow().subsurfaces {
+            sub.surface.send_preferred_buffer_scale(scale);
+        }
+    }
+
     fn validate_and_update_buf_transform(&self, backend: &mut dyn Backend) -> io::Result<()> {
         let cur = self.cur.borrow();
         match cur.buffer {
             Some(buf_id) => {
-                self.buf_transform.set(Some(BufferTransform::new(
+                let transform = cur.transform.unwrap_or(wl_output::Transform::Normal);
+                let scale = cur.scale.unwrap_or(NonZeroU32::MIN);
+                // Only the buffer size known at commit tells whether the buffer fits.
+                let buf_transform = BufferTransform::new(
                     buf_id,
                     backend,
-                    cur.transform.unwrap_or(wl_output::Transform::Normal),
-                    cur.scale.unwrap_or(1),
+                    transform,
+                    scale,
                     cur.viewport_src,
                     cur.viewport_dst,
-                )?));
+                )
+                .map_err(|e| {
+                    let viewport = self.viewport.take();
+                    self.viewport.set(viewport.clone());
+                    match (e, viewport) {
+                        (SizeError::OutOfBuffer, Some(viewport)) => ProtocolError::new(
+                            &viewport,
+                            wp_viewport::Error::OutOfBuffer as u32,
+                            e.to_string(),
+                        ),
+                        (SizeError::BadSize, Some(viewport)) => ProtocolError::new(
+                            &viewport,
+                            wp_viewport::Error::BadSize as u32,
+                            e.to_string(),
+                        ),
+                        _ => ProtocolError::new(
+                            &self.wl,
+                            wl_surface::Error::InvalidSize as u32,
+                            e.to_string(),
+                        ),
+                    }
+                })?;
+                self.buf_transform.set(Some(buf_transform));
             }
             None => self.buf_transform.set(None),
         }
@@ -210,7 +340,19 @@ impl Surface {
         self.buf_transform.get()
     }
 
+    /// The box containing the surface and all of its subsurfaces, relative to the surface. Cached
+    /// until the buffer transform or the subsurfaces of the surface or one of its descendants
+    /// change.
     pub fn get_bounding_box(&self) -> Option<pixman::Box32> {
+        if let Some(bbox) = self.bounding_box.get() {
+            return bbox;
+        }
+        let bbox = self.compute_bounding_box();
+        self.bounding_box.set(Some(bbox));
+        bbox
+    }
+
+    fn compute_bounding_box(&self) -> Option<pixman::Box32> {
         let buf_transfom = self.buf_transform()?;
         let mut bbox = pixman::Box32 {
             x1: 0,
@@ -229,6 +371,34 @@ impl Surface {
         Some(bbox)
     }
 
+    /// Drop the cached bounding box of the surface and of all of its ancestors.
+    fn invalidate_bounding_box(&self) {
+        self.bounding_box.set(None);
+        if let SurfaceRole::Subsurface(sub) = &*self.role.borrow() {
+            if let Some(parent) = sub.parent.upgrade() {
+                parent.invalidate_bounding_box();
+            }
+        }
+    }
+
+    /// Repaint the outputs the surface, together with its subsurfaces and window border, is on.
+    /// Surfaces which are not placed anywhere repaint all outputs.
+    pub fn repaint(&self, state: &mut State) {
+        if matches!(*self.role.borrow(), SurfaceRole::Cursor) {
+            state.repaint_cursor();
+            return;
+        }
+        match (self.get_pos(), self.get_bounding_box()) {
+            (Some((x, y)), Some(bbox)) => state.repaint_rect(&pixman::Rectangle32 {
+                x: x + bbox.x1 - BORDER_WIDTH as i32,
+                y: y + bbox.y1 - BORDER_WIDTH as i32,
+                width: (bbox.x2 - bbox.x1) as u32 + 2 * BORDER_WIDTH,
+                height: (bbox.y2 - bbox.y1) as u32 + 2 * BORDER_WIDTH,
+            }),
+            _ => state.repaint_all(),
+        }
+    }
+
     pub fn get_pos(&self) -> Option<(i32, i32)> {
         match &*self.role.borrow() {
             SurfaceRole::None => None,
@@ -252,12 +422,8 @@ impl Surface {
                 xdg_shell::SpecificRole::Popup(popup) => {
                     let parent = popup.parent.upgrade().unwrap();
                     let (parent_x, parent_y) = parent.wl_surface.upgrade().unwrap().get_pos()?;
-                    let parent_geom = parent.get_window_geometry()?;
-                    let popup_geom = popup.xdg_surface.upgrade().unwrap().get_window_geometry()?;
-                    Some((
-                        parent_x + parent_geom.x + popup.x.get() - popup_geom.x,
-                        parent_y + parent_geom.y + popup.y.get() - popup_geom.y,
-                    ))
+                    let (dx, dy) = popup.offset()?;
+                    Some((parent_x + dx, parent_y + dy))
                 }
             },
         }
@@ -273,6 +439,20 @@ impl Surface {
         !matches!(&*self.role.borrow(), SurfaceRole::None)
     }
 
+    /// The error for a request on `object` which would give this surface a role, while it already
+    /// has a different one.
+    pub fn role_conflict(&self, object: &impl Proxy, code: u32) -> io::Error {
+        ProtocolError::new(
+            object,
+            code,
+            format!(
+                "{:?} already has the {} role",
+                self.wl,
+                self.role.borrow().name()
+            ),
+        )
+    }
+
     pub fn get_subsurface(&self) -> Option<Rc<SubsurfaceRole>> {
         match &*self.role.borrow() {
             SurfaceRole::Subsurface(sub) => Some(sub.clone()),
@@ -295,19 +475,37 @@ impl Surface {
     }
 
     fn apply_state(&self, state: &mut State) -> io::Result<()> {
+        let mask = self.cached_state.borrow().mask;
         self.cached_state
             .borrow_mut()
             .apply_to_and_clear(&mut self.cur.borrow_mut(), state);
 
-        self.validate_and_update_buf_transform(state.backend.as_mut())?; // todo: run only if relevant data was updated
+        if [
+            CommittedMaskBit::Buffer,
+            CommittedMaskBit::Transform,
+            CommittedMaskBit::Scale,
+            CommittedMaskBit::ViewportSrc,
+            CommittedMaskBit::ViewportDst,
+        ]
+        .into_iter()
+        .any(|bit| mask.contains(bit))
+        {
+            self.validate_and_update_buf_transform(state.backend.as_mut())?;
+            self.invalidate_bounding_box();
+        }
+        if mask.contains(CommittedMaskBit::Subsurfaces) {
+            self.invalidate_bounding_box();
+        }
         for subs in &self.cur.borrow().subsurfaces {
             subs.surface.apply_state(state)?;
         }
 
+        // Only the cursor hotspot follows the offset, windows are placed by the compositor.
+        let (dx, dy) = std::mem::take(&mut self.cur.borrow_mut().offset);
         match &*self.role.borrow() {
             SurfaceRole::None => (),
             SurfaceRole::Xdg(xdg) => xdg.committed(state)?,
-            SurfaceRole::Cursor => (),
+            SurfaceRole::Cursor => state.cursor.surface_offset(&self.wl, dx, dy),
             SurfaceRole::Subsurface(_) => {
                 let has_buffer = self.cur.borrow().buffer.is_some();
                 if !has_buffer && self.mapped.get() {
@@ -329,6 +527,22 @@ pub enum SurfaceRole {
     Xdg(Rc<xdg_shell::XdgSurfaceRole>),
 }
 
+impl SurfaceRole {
+    /// The name of the role, for error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Cursor => "cursor",
+            Self::Subsurface(_) => "wl_subsurface",
+            Self::Xdg(xdg) => match &*xdg.specific.borrow() {
+                xdg_shell::SpecificRole::None => "xdg_surface",
+                xdg_shell::SpecificRole::Toplevel(_) => "xdg_toplevel",
+                xdg_shell::SpecificRole::Popup(_) => "xdg_popup",
+            },
+        }
+    }
+}
+
 pub struct SubsurfaceRole {
     pub wl: WlSubsurface,
     pub surface: Weak<Surface>,
@@ -372,7 +586,10 @@ impl IsGlobal for WlSubcompositor {
                     let surface = ctx.client.compositor.surfaces.get(&args.surface).unwrap();
                     let parent = ctx.client.compositor.surfaces.get(&args.parent).unwrap();
                     if surface.has_role() {
-                        return Err(io::Error::other("surface already has a role"));
+                        return Err(surface.role_conflict(
+                            &ctx.proxy,
+                            wl_subcompositor::Error::BadSurface as u32,
+                        ));
                     }
                     let subsurface = Rc::new(SubsurfaceRole {
                         wl: args.id.clone(),
@@ -457,16 +674,21 @@ fn wl_surface_cb(ctx: RequestCtx<WlSurface>) -> io::Result<()> {
                     "attach on wl_surface version >=5 must have x,y=0",
                 ));
             }
-            assert_eq!(args.x, 0, "unimplemented");
-            assert_eq!(args.y, 0, "unimplemented");
             surface.pending_buffer.set(args.buffer);
-            surface
-                .pending
-                .borrow_mut()
-                .mask
-                .set(CommittedMaskBit::Buffer);
+            let mut pending = surface.pending.borrow_mut();
+            pending.mask.set(CommittedMaskBit::Buffer);
+            if args.x != 0 || args.y != 0 {
+                pending.offset = (args.x, args.y);
+                pending.mask.set(CommittedMaskBit::Offset);
+            }
+        }
+        Request::Damage(_) => {
+            // Mapping surface coordinates to the buffer depends on the state which is not known
+            // until commit, so just damage the whole buffer. Clients should use damage_buffer.
+            let mut damage = surface.pending_damage.borrow_mut();
+            let limit = REGION_LIMIT as i32;
+            *damage = damage.union_rect(-limit, -limit, 2 * limit as u32, 2 * limit as u32);
         }
-        Request::Damage(_) => (),
         Request::Frame(cb) => {
             let mut pending = surface.pending.borrow_mut();
             pending.frame_cbs.push(cb);
@@ -485,24 +707,36 @@ fn wl_surface_cb(ctx: RequestCtx<WlSurface>) -> io::Result<()> {
             pending.mask.set(CommittedMaskBit::InputRegion);
         }
         Request::Commit => {
+            // Both where the surface was and where it is after the commit.
+            surface.repaint(ctx.state);
             let mut pending = surface.pending.borrow_mut();
+            let damage = surface.pending_damage.take();
             if pending.mask.contains(CommittedMaskBit::Buffer) {
-                pending.buffer = surface.pending_buffer.take().and_then(|pending_buffer| {
+                // A buffer destroyed between attach and commit is committed as a null buffer, which
+                // unmaps the surface. The renderer has already forgotten about it.
+                let pending_buffer = surface.pending_buffer.take();
+                let last_buffer = surface.last_buffer.replace(pending_buffer.clone());
+                pending.buffer = pending_buffer.and_then(|pending_buffer| {
+                    // The damage is only valid for the buffer it was reported for. A synchronized
+                    // subsurface still shows the contents from before until its parent commits.
+                    let damage = (last_buffer.as_ref() == Some(&pending_buffer)
+                        && !surface.effective_is_sync())
+                    .then_some(&damage);
                     pending_buffer.is_alive().then(|| {
                         ctx.state
                             .backend
                             .renderer_state()
-                            .buffer_commited(pending_buffer)
+                            .buffer_commited(pending_buffer, damage)
                     })
                 });
             }
 
-            if surface.effective_is_sync() {
-                pending.apply_to_and_clear(&mut surface.cached_state.borrow_mut(), ctx.state);
-            } else {
-                pending.apply_to_and_clear(&mut surface.cur.borrow_mut(), ctx.state);
+            // Always go through the cached state, so that `apply_state` knows what has changed.
+            pending.apply_to_and_clear(&mut surface.cached_state.borrow_mut(), ctx.state);
+            if !surface.effective_is_sync() {
                 surface.apply_state(ctx.state)?;
             }
+            surface.repaint(ctx.state);
         }
         Request::SetBufferTransform(transform) => {
             let mut pending = surface.pending.borrow_mut();
@@ -510,14 +744,33 @@ fn wl_surface_cb(ctx: RequestCtx<WlSurface>) -> io::Result<()> {
             pending.mask.set(CommittedMaskBit::Transform);
         }
         Request::SetBufferScale(scale) => {
+            let scale = u32::try_from(scale)
+                .ok()
+                .and_then(NonZeroU32::new)
+                .ok_or_else(|| {
+                    ProtocolError::new(
+                        &ctx.proxy,
+                        wl_surface::Error::InvalidScale as u32,
+                        "buffer scale must be positive",
+                    )
+                })?;
             let mut pending = surface.pending.borrow_mut();
             pending.scale = Some(scale);
             pending.mask.set(CommittedMaskBit::Scale);
         }
-        Request::DamageBuffer(_) => (),
+        Request::DamageBuffer(args) => {
+            // Damage is only a hint, so rectangles with negative size are ignored.
+            if let Some((x, y, width, height)) =
+                region_rect(args.x, args.y, args.width, args.height)
+            {
+                let mut damage = surface.pending_damage.borrow_mut();
+                *damage = damage.union_rect(x, y, width, height);
+            }
+        }
         Request::Offset(args) => {
-            assert_eq!(args.x, 0, "unimplemnted");
-            assert_eq!(args.y, 0, "unimplemnted");
+            let mut pending = surface.pending.borrow_mut();
+            pending.offset = (args.x, args.y);
+            pending.mask.set(CommittedMaskBit::Offset);
         }
     }
     Ok(())
@@ -530,6 +783,10 @@ fn wl_subsurface_cb(ctx: RequestCtx<WlSubsurface>) -> io::Result<()> {
     use wl_subsurface::Request;
     match ctx.request {
         Request::Destroy => {
+            // The subsurface disappears immediately, not on the next commit of the parent.
+            if surface.mapped.get() {
+                surface.repaint(ctx.state);
+            }
             *surface.role.borrow_mut() = SurfaceRole::None;
             let subsurface = ctx
                 .client
@@ -538,21 +795,13 @@ fn wl_subsurface_cb(ctx: RequestCtx<WlSubsurface>) -> io::Result<()> {
                 .remove(&ctx.proxy)
                 .unwrap();
             if let Some(parent) = subsurface.parent.upgrade() {
-                parent
-                    .cur
-                    .borrow_mut()
-                    .subsurfaces
-                    .retain(|node| node.surface.wl != surface.wl);
-                parent
-                    .pending
-                    .borrow_mut()
-                    .subsurfaces
-                    .retain(|node| node.surface.wl != surface.wl);
+                parent.cur.borrow_mut().remove_subsurface(&surface.wl);
+                parent.pending.borrow_mut().remove_subsurface(&surface.wl);
                 parent
                     .cached_state
                     .borrow_mut()
-                    .subsurfaces
-                    .retain(|node| node.surface.wl != surface.wl);
+                    .remove_subsurface(&surface.wl);
+                parent.invalidate_bounding_box();
             }
         }
         Request::SetPosition(args) => {
@@ -566,38 +815,8 @@ fn wl_subsurface_cb(ctx: RequestCtx<WlSubsurface>) -> io::Result<()> {
                 .position = (args.x, args.y);
             parent_pending.mask.set(CommittedMaskBit::Subsurfaces)
         }
-        Request::PlaceAbove(sibling) => {
-            let parent = subsurface.parent.upgrade().unwrap();
-            let mut parent_pending = parent.pending.borrow_mut();
-            let old_i = parent_pending
-                .subsurfaces
-                .iter()
-                .position(|x| x.surface.wl == surface.wl)
-                .unwrap();
-            let node = parent_pending.subsurfaces.remove(old_i);
-            let sibling_i = parent_pending
-                .subsurfaces
-                .iter()
-                .position(|x| x.surface.wl == sibling)
-                .ok_or_else(|| io::Error::other("place_above: surface not a sibling"))?;
-            parent_pending.subsurfaces.insert(sibling_i + 1, node);
-        }
-        Request::PlaceBelow(sibling) => {
-            let parent = subsurface.parent.upgrade().unwrap();
-            let mut parent_pending = parent.pending.borrow_mut();
-            let old_i = parent_pending
-                .subsurfaces
-                .iter()
-                .position(|x| x.surface.wl == surface.wl)
-                .unwrap();
-            let node = parent_pending.subsurfaces.remove(old_i);
-            let sibling_i = parent_pending
-                .subsurfaces
-                .iter()
-                .position(|x| x.surface.wl == sibling)
-                .ok_or_else(|| io::Error::other("place_above: surface not a sibling"))?;
-            parent_pending.subsurfaces.insert(sibling_i, node);
-        }
+        Request::PlaceAbove(sibling) => place_subsurface(subsurface, &surface, &sibling, true)?,
+        Request::PlaceBelow(sibling) => place_subsurface(subsurface, &surface, &sibling, false)?,
         Request::SetSync => subsurface.is_sync.set(true),
         Request::SetDesync => {
             subsurface.is_sync.set(false);
@@ -609,26 +828,83 @@ fn wl_subsurface_cb(ctx: RequestCtx<WlSubsurface>) -> io::Result<()> {
     Ok(())
 }
 
+fn place_subsurface(
+    subsurface: &SubsurfaceRole,
+    surface: &Surface,
+    sibling: &WlSurface,
+    above: bool,
+) -> io::Result<()> {
+    let parent = subsurface.parent.upgrade().unwrap();
+    let mut parent_pending = parent.pending.borrow_mut();
+    if *sibling == surface.wl {
+        return Err(ProtocolError::new(
+            &subsurface.wl,
+            wl_subsurface::Error::BadSurface as u32,
+            "subsurface placed relative to itself",
+        ));
+    }
+    if *sibling != parent.wl && !parent_pending.has_subsurface(sibling) {
+        return Err(ProtocolError::new(
+            &subsurface.wl,
+            wl_subsurface::Error::BadSurface as u32,
+            "subsurface placed relative to a non-sibling",
+        ));
+    }
+    let node = parent_pending.remove_subsurface(&surface.wl).unwrap();
+    parent_pending.place_subsurface(node, sibling, &parent.wl, above);
+    parent_pending.mask.set(CommittedMaskBit::Subsurfaces);
+    Ok(())
+}
+
 fn wl_region_cb(ctx: RequestCtx<WlRegion>) -> io::Result<()> {
+    let negative_size = || {
+        ProtocolError::new(
+            &ctx.proxy,
+            wl_display::Error::InvalidMethod as u32,
+            "wl_region rectangle has negative size",
+        )
+    };
+
     use wl_region::Request;
     match ctx.request {
         Request::Destroy => {
             ctx.client.compositor.regions.remove(&ctx.proxy);
         }
         Request::Add(args) => {
+            let (x, y, width, height) =
+                region_rect(args.x, args.y, args.width, args.height).ok_or_else(negative_size)?;
             let region = ctx.client.compositor.regions.get_mut(&ctx.proxy).unwrap();
-            *region = region.union_rect(args.x, args.y, args.width as u32, args.height as u32);
+            *region = region.union_rect(x, y, width, height);
         }
         Request::Subtract(args) => {
+            let (x, y, width, height) =
+                region_rect(args.x, args.y, args.width, args.height).ok_or_else(negative_size)?;
             let region = ctx.client.compositor.regions.get_mut(&ctx.proxy).unwrap();
-            let other =
-                pixman::Region32::init_rect(args.x, args.y, args.width as u32, args.height as u32);
+            let other = pixman::Region32::init_rect(x, y, width, height);
             *region = region.subtract(&other);
         }
     }
     Ok(())
 }
 
+/// Regions are clamped to this many pixels in each direction from the origin, so that the
+/// rectangle corners always fit into pixman's 32-bit coordinates.
+const REGION_LIMIT: i64 = 1 << 30;
+
+/// Clamp a rectangle passed to `wl_region.add`/`subtract` or `wl_surface.damage_buffer` to
+/// `REGION_LIMIT`. `None` if it has a negative size.
+fn region_rect(x: i32, y: i32, width: i32, height: i32) -> Option<(i32, i32, u32, u32)> {
+    if width < 0 || height < 0 {
+        return None;
+    }
+    let clamp = |v: i64| v.clamp(-REGION_LIMIT, REGION_LIMIT);
+    let x1 = clamp(x as i64);
+    let y1 = clamp(y as i64);
+    let x2 = clamp(x as i64 + width as i64);
+    let y2 = clamp(y as i64 + height as i64);
+    Some((x1 as i32, y1 as i32, (x2 - x1) as u32, (y2 - y1) as u32))
+}
+
 fn wp_viewport_cb(ctx: RequestCtx<WpViewport>) -> io::Result<()> {
     let surf = ctx
         .client
@@ -680,3 +956,22 @@ fn wp_viewport_cb(ctx: RequestCtx<WpViewport>) -> io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_rect_clamping() {
+        assert_eq!(region_rect(10, 20, 30, 40).unwrap(), (10, 20, 30, 40));
+        assert_eq!(
+            region_rect(0, 0, i32::MAX, i32::MAX).unwrap(),
+            (0, 0, 1 << 30, 1 << 30)
+        );
+        assert_eq!(
+            region_rect(i32::MIN, 5, 10, 0).unwrap(),
+            (-(1 << 30), 5, 0, 0)
+        );
+        assert!(region_rect(0, 0, -1, 10).is_none());
+    }
+}
@@ -16,7 +16,16 @@ impl IsGlobal for WpCursorShapeManagerV1 {
             match ctx.request {
                 Request::Destroy => (),
                 Request::GetPointer(args) => args.cursor_shape_device.set_callback(shape_device_cb),
-                Request::GetTabletToolV2(_) => todo!(),
+                Request::GetTabletToolV2(args) => {
+                    // Tablet cursors are not rendered, so the requested shapes are ignored.
+                    args.cursor_shape_device.set_callback(|ctx| {
+                        use wp_cursor_shape_device_v1::Request;
+                        match ctx.request {
+                            Request::Destroy | Request::SetShape(_) => (),
+                        }
+                        Ok(())
+                    });
+                }
             }
             Ok(())
         });
@@ -28,7 +37,11 @@ fn shape_device_cb(ctx: RequestCtx<WpCursorShapeDeviceV1>) -> io::Result<()> {
     match ctx.request {
         Request::Destroy => (),
         Request::SetShape(args) => {
-            ctx.state.cursor.set_shape(args.shape);
+            if !ctx.state.seat.pointer.is_grabbed() {
+                ctx.state.repaint_cursor();
+                ctx.state.cursor.set_shape(args.shape);
+                ctx.state.repaint_cursor();
+            }
         }
     }
     Ok(())
@@ -1,6 +1,12 @@
-use std::{ffi::CString, time::Duration};
+use std::{
+    cell::Cell,
+    ffi::CString,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
 
 use crate::{
+    backend::OutputId,
     client::{Client, ClientId},
     protocol::*,
     Proxy, State,
@@ -8,10 +14,22 @@ use crate::{
 
 use super::IsGlobal;
 
+/// Requests received from all clients since the last counters report.
+pub static REQUESTS: AtomicU32 = AtomicU32::new(0);
+/// Events sent to all clients since the last counters report.
+pub static EVENTS: AtomicU32 = AtomicU32::new(0);
+
 #[derive(Default)]
 pub struct Debugger {
     subscribers: Vec<Subscriber>,
     accum_interest: ewc_debug_v1::Interest,
+    frames: Cell<u32>,
+    /// When the counters were last reported or reset, `None` while nobody is interested in them.
+    counters_since: Option<Instant>,
+    /// The client which paused rendering, if it is paused.
+    paused_by: Option<ClientId>,
+    /// Outputs which may render one more frame while rendering is paused.
+    step_outputs: Vec<OutputId>,
 }
 
 struct Subscriber {
@@ -21,6 +39,9 @@ struct Subscriber {
 
 impl Debugger {
     pub fn remove_client(&mut self, client_id: ClientId) {
+        if self.paused_by == Some(client_id) {
+            self.paused_by = None;
+        }
         self.subscribers.retain(|s| s.wl.client_id() != client_id);
         self.accum_interest = self
             .subscribers
@@ -32,15 +53,71 @@ impl Debugger {
         self.accum_interest
     }
 
-    pub fn frame(&self, duration: Duration) {
+    /// Whether a frame may be rendered on the output. While rendering is paused, this allows a
+    /// single frame after each `step_frame`.
+    pub fn may_render(&mut self, output: OutputId) -> bool {
+        if self.paused_by.is_none() {
+            return true;
+        }
+        let Some(i) = self.step_outputs.iter().position(|&o| o == output) else {
+            return false;
+        };
+        self.step_outputs.swap_remove(i);
+        true
+    }
+
+    /// Report a rendered frame, which took `duration` on the CPU and `gpu_duration` on the GPU if
+    /// the renderer measures it.
+    pub fn frame(&self, duration: Duration, gpu_duration: Option<Duration>) {
+        self.frames.set(self.frames.get().wrapping_add(1));
         let nanos = duration.as_nanos() as u32;
         for sub in &self.subscribers {
             if sub.interest.contains(ewc_debug_v1::Interest::FrameStat) {
                 sub.wl.frame_stat(nanos);
+                if let Some(gpu_duration) = gpu_duration.filter(|_| sub.wl.version() >= 3) {
+                    sub.wl.gpu_frame_stat(gpu_duration.as_nanos() as u32);
+                }
             }
         }
     }
 
+    /// Send the counters to interested subscribers, at most once per second.
+    pub fn report_counters(&mut self, clients: usize) {
+        use ewc_debug_v1::Interest;
+        if !self.accum_interest.contains(Interest::Counters) {
+            self.counters_since = None;
+            return;
+        }
+        let now = Instant::now();
+        let Some(since) = self.counters_since else {
+            // Start counting from now.
+            self.frames.set(0);
+            REQUESTS.store(0, Ordering::Relaxed);
+            EVENTS.store(0, Ordering::Relaxed);
+            self.counters_since = Some(now);
+            return;
+        };
+        let interval = now - since;
+        if interval < Duration::from_secs(1) {
+            return;
+        }
+        let frames = self.frames.take();
+        let requests = REQUESTS.swap(0, Ordering::Relaxed);
+        let events = EVENTS.swap(0, Ordering::Relaxed);
+        for sub in &self.subscribers {
+            if sub.interest.contains(Interest::Counters) && sub.wl.version() >= 2 {
+                sub.wl.counters(
+                    interval.as_millis() as u32,
+                    frames,
+                    clients as u32,
+                    requests,
+                    events,
+                );
+            }
+        }
+        self.counters_since = Some(now);
+    }
+
     pub fn message(&self, msg: &str) {
         let cstr = CString::new(msg).expect("debug message has null bytes");
         for sub in &self.subscribers {
@@ -57,6 +134,21 @@ impl IsGlobal for EwcDebugV1 {
             use ewc_debug_v1::Request;
             match ctx.request {
                 Request::Destroy => (),
+                Request::PauseRendering => {
+                    ctx.state.debugger.paused_by = Some(ctx.proxy.client_id());
+                    ctx.state.debugger.step_outputs.clear();
+                }
+                Request::ResumeRendering => {
+                    ctx.state.debugger.paused_by = None;
+                    ctx.state.repaint_all();
+                }
+                Request::StepFrame => {
+                    if ctx.state.debugger.paused_by.is_some() {
+                        let outputs = ctx.state.backend.outputs().to_vec();
+                        ctx.state.debugger.step_outputs = outputs;
+                        ctx.state.repaint_all();
+                    }
+                }
                 Request::GetDebugger(args) => {
                     args.id.set_callback(|ctx| match ctx.request {});
                     ctx.state.debugger.accum_interest |= args.interest;
@@ -0,0 +1,113 @@
+//! `zwlr_gamma_control_manager_v1`: lets a privileged client (like `gammastep` or `wlsunset`) set
+//! the gamma ramps of an output.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::{GlobalsManager, IsGlobal};
+use crate::backend::{Backend, OutputId};
+use crate::client::{Client, ClientId, ProtocolError, RequestCtx};
+use crate::protocol::*;
+use crate::{Proxy, State};
+
+pub fn register_global(globals: &mut GlobalsManager) {
+    globals.add_privileged_global::<ZwlrGammaControlManagerV1>(1);
+}
+
+/// The gamma control which currently owns each output. Controls which failed are not tracked,
+/// their requests are ignored.
+#[derive(Default)]
+pub struct GammaControls {
+    active: HashMap<OutputId, ZwlrGammaControlV1>,
+}
+
+impl GammaControls {
+    /// Restore the gamma of outputs controlled by the client.
+    pub fn remove_client(&mut self, client_id: ClientId, backend: &mut dyn Backend) {
+        self.active.retain(|&output, control| {
+            let keep = control.client_id() != client_id;
+            if !keep {
+                reset_gamma(backend, output);
+            }
+            keep
+        });
+    }
+}
+
+fn reset_gamma(backend: &mut dyn Backend, output: OutputId) {
+    if let Err(e) = backend.set_gamma(output, None) {
+        eprintln!(
+            "could not reset gamma of {}: {e}",
+            backend.output_name(output)
+        );
+    }
+}
+
+impl IsGlobal for ZwlrGammaControlManagerV1 {
+    fn on_bind(&self, _client: &mut Client, _state: &mut State) {
+        self.set_callback(|ctx| {
+            use zwlr_gamma_control_manager_v1::Request;
+            match ctx.request {
+                Request::GetGammaControl(args) => {
+                    args.id.set_callback(gamma_control_cb);
+                    let Some(output) = ctx.state.globals.output_of(&args.output) else {
+                        args.id.failed();
+                        return Ok(());
+                    };
+                    let size = ctx.state.backend.gamma_size(output);
+                    let controls = &mut ctx.state.gamma_controls;
+                    if size == 0 || controls.active.contains_key(&output) {
+                        args.id.failed();
+                    } else {
+                        args.id.gamma_size(size);
+                        controls.active.insert(output, args.id);
+                    }
+                }
+                Request::Destroy => (),
+            }
+            Ok(())
+        });
+    }
+}
+
+fn gamma_control_cb(ctx: RequestCtx<ZwlrGammaControlV1>) -> io::Result<()> {
+    use zwlr_gamma_control_v1::Request;
+    let controls = &mut ctx.state.gamma_controls;
+    let Some(output) = controls
+        .active
+        .iter()
+        .find(|(_, control)| **control == ctx.proxy)
+        .map(|(&output, _)| output)
+    else {
+        return Ok(());
+    };
+    match ctx.request {
+        Request::SetGamma(fd) => {
+            let size = ctx.state.backend.gamma_size(output) as usize;
+            let mut bytes = vec![0; size * 3 * 2];
+            File::from(fd).read_exact_at(&mut bytes, 0).map_err(|e| {
+                ProtocolError::new(
+                    &ctx.proxy,
+                    zwlr_gamma_control_v1::Error::InvalidGamma as u32,
+                    format!("could not read the gamma ramps: {e}"),
+                )
+            })?;
+            let ramps: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|x| u16::from_ne_bytes([x[0], x[1]]))
+                .collect();
+            if let Err(e) = ctx.state.backend.set_gamma(output, Some(&ramps)) {
+                eprintln!("could not set gamma: {e}");
+                controls.active.remove(&output);
+                ctx.proxy.failed();
+            }
+        }
+        Request::Destroy => {
+            controls.active.remove(&output);
+            reset_gamma(ctx.state.backend.as_mut(), output);
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 use std::io;
-use std::os::fd::OwnedFd;
+use std::os::fd::{AsRawFd, OwnedFd};
 
-use eglgbm::Fourcc;
+use eglgbm::{BufferExport, BufferPlane, Fourcc, DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR};
 
 use super::{GlobalsManager, IsGlobal};
-use crate::client::{Client, RequestCtx};
+use crate::client::{Client, ProtocolError, RequestCtx};
+use crate::protocol::zwp_linux_buffer_params_v1::Error;
 use crate::protocol::*;
 use crate::{Proxy, State};
 
@@ -21,22 +22,15 @@ struct Params {
 }
 
 #[derive(Debug)]
-pub struct Plane {
-    pub fd: OwnedFd,
-    pub offset: u32,
-    pub stride: u32,
-    pub modifier: u64,
-}
-
-#[derive(Debug)]
-pub struct DmaBufSpec {
-    pub width: u32,
-    pub height: u32,
-    pub format: Fourcc,
-    pub planes: Vec<Plane>,
+struct Plane {
+    fd: OwnedFd,
+    offset: u32,
+    stride: u32,
+    modifier: u64,
 }
 
 impl LinuxDmabuf {
+    /// Version 4 replaces the format events with feedback objects, which are not implemented.
     pub fn register_global(globals: &mut GlobalsManager) {
         globals.add_global::<ZwpLinuxDmabufV1>(3);
     }
@@ -54,18 +48,17 @@ impl LinuxDmabuf {
 impl IsGlobal for ZwpLinuxDmabufV1 {
     fn on_bind(&self, _client: &mut Client, state: &mut State) {
         self.set_callback(linux_dmabuf_cb);
-        if self.version() < 4 {
-            for (format, mods) in state
-                .backend
-                .renderer_state()
-                .supported_dma_buf_formats()
-                .unwrap()
-            {
-                self.format(format.0);
-                if self.version() == 3 {
-                    for &modifier in mods {
-                        self.modifier(format.0, (modifier >> 32) as u32, modifier as u32);
-                    }
+        // Advertise exactly the formats and modifiers `create` and `create_immed` accept.
+        for (format, mods) in state
+            .backend
+            .renderer_state()
+            .supported_dma_buf_formats()
+            .unwrap()
+        {
+            self.format(format.0);
+            if self.version() >= 3 {
+                for &modifier in mods {
+                    self.modifier(format.0, (modifier >> 32) as u32, modifier as u32);
                 }
             }
         }
@@ -86,14 +79,18 @@ fn linux_dmabuf_cb(ctx: RequestCtx<ZwpLinuxDmabufV1>) -> io::Result<()> {
                 },
             );
         }
-        Request::GetDefaultFeedback(_) => todo!(),
-        Request::GetSurfaceFeedback(_) => todo!(),
+        Request::GetDefaultFeedback(_) | Request::GetSurfaceFeedback(_) => {
+            return Err(io::Error::other(
+                "feedback requires zwp_linux_dmabuf_v1 version 4",
+            ));
+        }
     }
     Ok(())
 }
 
 fn params_cb(ctx: RequestCtx<ZwpLinuxBufferParamsV1>) -> io::Result<()> {
     use zwp_linux_buffer_params_v1::Request;
+    let error = |code: Error, message: &str| ProtocolError::new(&ctx.proxy, code as u32, message);
     match ctx.request {
         Request::Destroy => {
             ctx.client.linux_dambuf.params.remove(&ctx.proxy);
@@ -101,13 +98,13 @@ fn params_cb(ctx: RequestCtx<ZwpLinuxBufferParamsV1>) -> io::Result<()> {
         Request::Add(args) => {
             let params = ctx.client.linux_dambuf.params.get_mut(&ctx.proxy).unwrap();
             if params.used {
-                return Err(io::Error::other("params already used"));
+                return Err(error(Error::AlreadyUsed, "params already used"));
             }
             if args.plane_idx > 3 {
-                return Err(io::Error::other("plane index out of bounds"));
+                return Err(error(Error::PlaneIdx, "plane index out of bounds"));
             }
             if params.planes[args.plane_idx as usize].is_some() {
-                return Err(io::Error::other("plane with this index already set"));
+                return Err(error(Error::PlaneSet, "plane with this index already set"));
             }
             params.planes[args.plane_idx as usize] = Some(Plane {
                 fd: args.fd,
@@ -116,41 +113,247 @@ fn params_cb(ctx: RequestCtx<ZwpLinuxBufferParamsV1>) -> io::Result<()> {
                 modifier: ((args.modifier_hi as u64) << 32) | args.modifier_lo as u64,
             });
         }
-        Request::Create(_) => todo!(),
+        Request::Create(args) => {
+            let (modifier, planes) = take_planes(
+                ctx.client,
+                ctx.state,
+                &ctx.proxy,
+                args.width,
+                args.height,
+                args.format,
+            )?;
+            let buffer = if args.flags == zwp_linux_buffer_params_v1::Flags::empty() {
+                BufferExport::new(
+                    args.width as u32,
+                    args.height as u32,
+                    Fourcc(args.format),
+                    modifier,
+                    planes,
+                )
+                .map_err(io::Error::other)
+            } else {
+                Err(io::Error::other("dmabuf flags are not supported"))
+            };
+            // The import needs the resource, it is destroyed again if the import fails.
+            let wl_buffer: WlBuffer = ctx.proxy.conn().create_servers_object(1)?;
+            let imported = buffer.and_then(|buffer| {
+                ctx.state
+                    .backend
+                    .renderer_state()
+                    .create_dma_buffer(buffer, wl_buffer.clone())
+            });
+            match imported {
+                Ok(()) => {
+                    wl_buffer.set_callback(wl_buffer_cb);
+                    ctx.client.linux_dambuf.buffers.push(wl_buffer.clone());
+                    ctx.proxy.created(&wl_buffer);
+                }
+                Err(e) => {
+                    eprintln!("could not import dmabuf: {e}");
+                    wl_buffer.as_object().destroy();
+                    ctx.proxy.failed();
+                }
+            }
+        }
         Request::CreateImmed(args) => {
             args.buffer_id.set_callback(wl_buffer_cb);
-            let params = ctx.client.linux_dambuf.params.get_mut(&ctx.proxy).unwrap();
-            assert_eq!(
-                args.flags,
-                zwp_linux_buffer_params_v1::Flags::empty(),
-                "not implemented"
-            );
-            if params.used {
-                return Err(io::Error::other("params already used"));
+            let (modifier, planes) = take_planes(
+                ctx.client,
+                ctx.state,
+                &ctx.proxy,
+                args.width,
+                args.height,
+                args.format,
+            )?;
+            // Like a failed import, which is a protocol error here.
+            if args.flags != zwp_linux_buffer_params_v1::Flags::empty() {
+                return Err(ProtocolError::new(
+                    &ctx.proxy,
+                    Error::InvalidWlBuffer as u32,
+                    "dmabuf flags are not supported",
+                ));
             }
-            if params.planes.iter().all(|x| x.is_none()) {
-                return Err(io::Error::other("params with zero planes"));
-            }
-            if args.width < 1 || args.height < 1 {
-                return Err(io::Error::other("invalid buffer size"));
-            }
-            params.used = true;
-            let spec = DmaBufSpec {
-                width: args.width as u32,
-                height: args.height as u32,
-                format: Fourcc(args.format),
-                planes: params.planes.iter_mut().flat_map(|x| x.take()).collect(),
-            };
-            ctx.client.linux_dambuf.buffers.push(args.buffer_id.clone());
-            ctx.state
+            let buffer = BufferExport::new(
+                args.width as u32,
+                args.height as u32,
+                Fourcc(args.format),
+                modifier,
+                planes,
+            )
+            .map_err(io::Error::other)?;
+            if let Err(e) = ctx
+                .state
                 .backend
                 .renderer_state()
-                .create_dma_buffer(spec, args.buffer_id);
+                .create_dma_buffer(buffer, args.buffer_id.clone())
+            {
+                return Err(ProtocolError::new(
+                    &ctx.proxy,
+                    Error::InvalidWlBuffer as u32,
+                    &format!("could not import dmabuf: {e}"),
+                ));
+            }
+            ctx.client.linux_dambuf.buffers.push(args.buffer_id);
         }
     }
     Ok(())
 }
 
+/// Validate the params for a buffer of the given size and format and take their planes. Invalid
+/// params are a protocol error for both `create` and `create_immed`.
+fn take_planes(
+    client: &mut Client,
+    state: &mut State,
+    proxy: &ZwpLinuxBufferParamsV1,
+    width: i32,
+    height: i32,
+    format: u32,
+) -> io::Result<(u64, Vec<BufferPlane>)> {
+    let error = |code: Error, message: &str| ProtocolError::new(proxy, code as u32, message);
+    let params = client.linux_dambuf.params.get_mut(proxy).unwrap();
+    if params.used {
+        return Err(error(Error::AlreadyUsed, "params already used"));
+    }
+    if params.planes.iter().all(|x| x.is_none()) {
+        return Err(error(Error::Incomplete, "params with zero planes"));
+    }
+    let plane_cnt = params.planes.iter().take_while(|x| x.is_some()).count();
+    if params.planes[plane_cnt..].iter().any(|x| x.is_some()) {
+        return Err(error(Error::Incomplete, "params with missing planes"));
+    }
+    if width < 1 || height < 1 {
+        return Err(error(Error::InvalidDimensions, "invalid buffer size"));
+    }
+    let modifier = params.planes.iter().flatten().next().unwrap().modifier;
+    if params
+        .planes
+        .iter()
+        .flatten()
+        .any(|x| x.modifier != modifier)
+    {
+        return Err(error(
+            Error::InvalidFormat,
+            "planes with different modifiers",
+        ));
+    }
+    if let Some(expected) = format_plane_count(format) {
+        // Vendor modifiers may add auxiliary planes.
+        let implicit = modifier == DRM_FORMAT_MOD_INVALID || modifier == DRM_FORMAT_MOD_LINEAR;
+        if plane_cnt < expected || (implicit && plane_cnt > expected) {
+            return Err(error(
+                Error::Incomplete,
+                &format!("format has {expected} planes, got {plane_cnt}"),
+            ));
+        }
+    }
+    for (i, plane) in params.planes.iter().flatten().enumerate() {
+        // Planes other than the first one may be subsampled, only the first one is known to have
+        // `height` rows.
+        let rows = if i == 0 { height as u64 } else { 1 };
+        let end = plane.offset as u64 + plane.stride as u64 * rows;
+        if dmabuf_size(&plane.fd).is_some_and(|size| end > size) {
+            return Err(error(
+                Error::OutOfBounds,
+                &format!("plane {i} is out of bounds of its dmabuf"),
+            ));
+        }
+    }
+    let supported = state
+        .backend
+        .renderer_state()
+        .supported_dma_buf_formats()
+        .unwrap()
+        .get(&Fourcc(format))
+        .is_some_and(|mods| modifier == DRM_FORMAT_MOD_INVALID || mods.contains(&modifier));
+    if !supported {
+        return Err(error(
+            Error::InvalidFormat,
+            "unsupported format/modifier combination",
+        ));
+    }
+    params.used = true;
+    let planes = params
+        .planes
+        .iter_mut()
+        .flat_map(|x| x.take())
+        .map(|p| BufferPlane {
+            dmabuf: p.fd,
+            handle: 0,
+            offset: p.offset,
+            stride: p.stride,
+        })
+        .collect();
+    Ok((modifier, planes))
+}
+
+/// The number of planes of a format without auxiliary planes, or `None` for formats not known here.
+fn format_plane_count(format: u32) -> Option<usize> {
+    const fn fourcc(code: &[u8; 4]) -> u32 {
+        u32::from_le_bytes(*code)
+    }
+    const SINGLE: &[u32] = &[
+        fourcc(b"AR24"),
+        fourcc(b"XR24"),
+        fourcc(b"AB24"),
+        fourcc(b"XB24"),
+        fourcc(b"RA24"),
+        fourcc(b"RX24"),
+        fourcc(b"BA24"),
+        fourcc(b"BX24"),
+        fourcc(b"AR30"),
+        fourcc(b"XR30"),
+        fourcc(b"AB30"),
+        fourcc(b"XB30"),
+        fourcc(b"AB4H"),
+        fourcc(b"XB4H"),
+        fourcc(b"RG16"),
+        fourcc(b"BG16"),
+        fourcc(b"RG24"),
+        fourcc(b"BG24"),
+        fourcc(b"YUYV"),
+        fourcc(b"YVYU"),
+        fourcc(b"UYVY"),
+        fourcc(b"VYUY"),
+        fourcc(b"AYUV"),
+        fourcc(b"XYUV"),
+    ];
+    const DOUBLE: &[u32] = &[
+        fourcc(b"NV12"),
+        fourcc(b"NV21"),
+        fourcc(b"NV16"),
+        fourcc(b"NV61"),
+        fourcc(b"NV24"),
+        fourcc(b"NV42"),
+        fourcc(b"P010"),
+        fourcc(b"P012"),
+        fourcc(b"P016"),
+    ];
+    const TRIPLE: &[u32] = &[
+        fourcc(b"YU12"),
+        fourcc(b"YV12"),
+        fourcc(b"YU16"),
+        fourcc(b"YV16"),
+        fourcc(b"YU24"),
+        fourcc(b"YV24"),
+    ];
+    if SINGLE.contains(&format) {
+        Some(1)
+    } else if DOUBLE.contains(&format) {
+        Some(2)
+    } else if TRIPLE.contains(&format) {
+        Some(3)
+    } else {
+        None
+    }
+}
+
+/// The size of a dmabuf, if the kernel reports it.
+fn dmabuf_size(fd: &OwnedFd) -> Option<u64> {
+    // Seeking a dmabuf does not affect importing it.
+    let size = unsafe { libc::lseek(fd.as_raw_fd(), 0, libc::SEEK_END) };
+    u64::try_from(size).ok()
+}
+
 fn wl_buffer_cb(ctx: RequestCtx<WlBuffer>) -> io::Result<()> {
     let wl_buffer::Request::Destroy = ctx.request;
     ctx.client.linux_dambuf.buffers.retain(|x| *x != ctx.proxy);
@@ -1,7 +1,9 @@
+use std::ffi::CString;
 use std::io;
 use std::marker::PhantomData;
 use std::rc::Rc;
 
+use crate::backend::OutputId;
 use crate::client::{ClientId, RequestCtx};
 use crate::protocol::wl_registry::BindArgs;
 use crate::protocol::*;
@@ -11,7 +13,11 @@ use crate::{Client, State};
 pub mod compositor;
 pub mod cursor_shape;
 pub mod ewc_debug;
+pub mod gamma_control;
+#[cfg(feature = "gl")]
 pub mod linux_dmabuf;
+pub mod output_management;
+pub mod security_context;
 pub mod shm;
 pub mod single_pixel_buffer;
 pub mod xdg_shell;
@@ -24,39 +30,89 @@ pub trait IsGlobal: Proxy + 'static {
 pub struct GlobalsManager {
     globals: Vec<Global>,
     registries: Vec<WlRegistry>,
+    /// Bound `wl_output`s and the outputs they stand for.
+    wl_outputs: Vec<(WlOutput, OutputId)>,
     last_name: u32,
 }
 
 impl GlobalsManager {
     pub fn add_global<P: IsGlobal>(&mut self, version: u32) {
+        self.add_global_imp::<P>(version, false);
+    }
+
+    /// Add a global which is hidden from clients connected through a security context.
+    pub fn add_privileged_global<P: IsGlobal>(&mut self, version: u32) {
+        self.add_global_imp::<P>(version, true);
+    }
+
+    /// Add a `wl_output` global for the output.
+    pub fn add_output_global(&mut self, output: OutputId, version: u32) {
+        assert!(version <= WlOutput::INTERFACE.version);
+        assert_ne!(version, 0);
+        let name = self.next_name();
+        self.globals.push(Global {
+            name,
+            version,
+            privileged: false,
+            imp: Rc::new(OutputGlobal(output)),
+        });
+    }
+
+    fn add_global_imp<P: IsGlobal>(&mut self, version: u32, privileged: bool) {
         assert!(version <= P::INTERFACE.version);
         assert_ne!(version, 0);
-        let name = self.last_name.checked_add(1).unwrap();
-        self.globals.push(Global::new::<P>(name, version));
-        self.last_name = name;
+        let name = self.next_name();
+        self.globals
+            .push(Global::new::<P>(name, version, privileged));
+    }
+
+    fn next_name(&mut self) -> u32 {
+        self.last_name = self.last_name.checked_add(1).unwrap();
+        self.last_name
     }
 
-    pub fn add_registry(&mut self, registry: WlRegistry) {
+    pub fn add_registry(&mut self, registry: WlRegistry, sandboxed: bool) {
         registry.set_callback(wl_registry_cb);
         for g in &self.globals {
-            registry.global(g.name(), g.interface().name.to_owned(), g.version());
+            if !(sandboxed && g.privileged) {
+                registry.global(g.name(), g.interface().name.to_owned(), g.version());
+            }
         }
         self.registries.push(registry);
     }
 
     pub fn remove_client(&mut self, client_id: ClientId) {
         self.registries.retain(|r| r.client_id() != client_id);
+        self.wl_outputs.retain(|(o, _)| o.client_id() != client_id);
+    }
+
+    /// The output a bound `wl_output` stands for, or `None` if it was released.
+    pub fn output_of(&self, wl_output: &WlOutput) -> Option<OutputId> {
+        self.wl_outputs
+            .iter()
+            .find(|(o, _)| o == wl_output)
+            .map(|&(_, output)| output)
     }
 }
 
+/// Re-send the output state to all bound `wl_output`s and output managers. Should be called
+/// whenever any of the advertised output properties change.
+pub fn output_changed(state: &mut State) {
+    for (wl_output, output) in &state.globals.wl_outputs {
+        send_output_state(wl_output, *output, state);
+    }
+    output_management::output_changed(state);
+}
+
 fn wl_registry_cb(ctx: RequestCtx<WlRegistry>) -> io::Result<()> {
     let wl_registry::Request::Bind(args) = ctx.request;
+    let sandboxed = ctx.client.security_context.is_some();
     let global = ctx
         .state
         .globals
         .globals
         .iter()
-        .find(|g| g.name() == args.name)
+        .find(|g| g.name() == args.name && !(sandboxed && g.privileged))
         .ok_or_else(|| io::Error::other("wl_registry::bind with invalid name"))?
         .clone();
     global.bind(ctx.client, ctx.state, args)
@@ -66,6 +122,7 @@ fn wl_registry_cb(ctx: RequestCtx<WlRegistry>) -> io::Result<()> {
 struct Global {
     name: u32,
     version: u32,
+    privileged: bool,
     imp: Rc<dyn GlobalImp>,
 }
 
@@ -80,7 +137,7 @@ trait GlobalImp {
 }
 
 impl Global {
-    pub fn new<G: IsGlobal>(name: u32, version: u32) -> Self {
+    pub fn new<G: IsGlobal>(name: u32, version: u32, privileged: bool) -> Self {
         struct Imp<G: IsGlobal>(PhantomData<G>);
         impl<G: IsGlobal> GlobalImp for Imp<G> {
             fn interface(&self) -> &'static Interface {
@@ -102,6 +159,7 @@ impl Global {
         Self {
             name,
             version,
+            privileged,
             imp: Rc::new(Imp::<G>(PhantomData)),
         }
     }
@@ -123,35 +181,80 @@ impl Global {
         if self.interface().name != iface.as_ref() {
             return Err(io::Error::other("wl_registry::bind with invalid interface"));
         }
-        if self.version() < *version {
+        // Binding above the advertised version is a protocol error rather than something to
+        // clamp, so the objects are always created at the requested version, which every event
+        // emitter checks against.
+        if *version == 0 || self.version() < *version {
             return Err(io::Error::other("wl_registry::bind with invalid version"));
         }
         self.imp.bind(client, state, args)
     }
 }
 
-impl IsGlobal for WlOutput {
-    fn on_bind(&self, _client: &mut Client, _state: &mut State) {
-        // For some unholy reason, firefox would disable popups without output info, so for now,
-        // send this dummy info.
-        self.geometry(
-            0,
-            0,
-            0,
-            0,
-            wl_output::Subpixel::Unknown,
-            c"N/A".into(),
-            c"N/A".into(),
-            wl_output::Transform::Normal,
-        );
-        self.mode(
-            wl_output::Mode::Current | wl_output::Mode::Preferred,
-            1920,
-            1080,
-            0,
-        );
-        if self.version() >= 2 {
-            self.done();
+/// The `wl_output` global of an output. Unlike other globals, there is one per output, so it
+/// cannot be an [`IsGlobal`].
+struct OutputGlobal(OutputId);
+
+impl GlobalImp for OutputGlobal {
+    fn interface(&self) -> &'static Interface {
+        WlOutput::INTERFACE
+    }
+
+    fn bind(
+        &self,
+        client: &mut Client,
+        state: &mut State,
+        args: wl_registry::BindArgs,
+    ) -> io::Result<()> {
+        let (_iface, version, object_id) = args.id;
+        let object = Object::new(&client.conn, object_id, WlOutput::INTERFACE, version);
+        client.conn.register_clients_object(object.clone())?;
+        let wl_output = WlOutput::try_from(object).unwrap();
+        wl_output.set_callback(|ctx| {
+            let wl_output::Request::Release = ctx.request;
+            ctx.state
+                .globals
+                .wl_outputs
+                .retain(|(o, _)| *o != ctx.proxy);
+            Ok(())
+        });
+        if wl_output.version() >= 4 {
+            // The name never changes, so it is only sent once.
+            wl_output.name(CString::new(state.backend.output_name(self.0)).unwrap());
         }
+        send_output_state(&wl_output, self.0, state);
+        state.globals.wl_outputs.push((wl_output, self.0));
+        Ok(())
+    }
+}
+
+/// Send the full output state, terminated with `done` for v2+.
+fn send_output_state(wl_output: &WlOutput, output: OutputId, state: &State) {
+    let rect = state.backend.output_geometry(output);
+    // For some unholy reason, firefox would disable popups without output info. Physical size,
+    // make and model are unknown for now, so send dummy values.
+    wl_output.geometry(
+        rect.x,
+        rect.y,
+        0,
+        0,
+        wl_output::Subpixel::Unknown,
+        c"N/A".into(),
+        c"N/A".into(),
+        wl_output::Transform::Normal,
+    );
+    wl_output.mode(
+        wl_output::Mode::Current | wl_output::Mode::Preferred,
+        rect.width as i32,
+        rect.height as i32,
+        0,
+    );
+    if wl_output.version() >= 4 {
+        let name = state.backend.output_name(output);
+        wl_output.description(CString::new(format!("ewc output {name}")).unwrap());
+    }
+    if wl_output.version() >= 2 {
+        wl_output.scale(state.output_layout.scale(output));
+        wl_output.done();
     }
 }
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+
+use super::{GlobalsManager, IsGlobal};
+use crate::backend::{Backend, OutputId, OutputMode};
+use crate::client::{Client, ClientId, RequestCtx};
+use crate::output_layout::OutputLayout;
+use crate::protocol::*;
+use crate::{Fixed, Proxy, State};
+
+pub fn register_global(globals: &mut GlobalsManager) {
+    globals.add_privileged_global::<ZwlrOutputManagerV1>(3);
+}
+
+#[derive(Default)]
+pub struct OutputManagement {
+    serial: u32,
+    managers: Vec<Manager>,
+    configurations: HashMap<ZwlrOutputConfigurationV1, Configuration>,
+    config_heads: HashMap<ZwlrOutputConfigurationHeadV1, ZwlrOutputConfigurationV1>,
+}
+
+struct Manager {
+    wl: ZwlrOutputManagerV1,
+    heads: Vec<Head>,
+}
+
+struct Head {
+    wl: ZwlrOutputHeadV1,
+    output: OutputId,
+    modes: Vec<(ZwlrOutputModeV1, OutputMode)>,
+}
+
+struct Configuration {
+    manager: ZwlrOutputManagerV1,
+    serial: u32,
+    used: bool,
+    /// `None` if the head is disabled.
+    heads: Vec<(ZwlrOutputHeadV1, Option<HeadConfig>)>,
+}
+
+struct HeadConfig {
+    wl: ZwlrOutputConfigurationHeadV1,
+    mode: Option<OutputMode>,
+    position: Option<(i32, i32)>,
+    transform: Option<wl_output::Transform>,
+    scale: Option<Fixed>,
+}
+
+impl OutputManagement {
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.managers.retain(|m| m.wl.client_id() != client_id);
+        self.configurations
+            .retain(|c, _| c.client_id() != client_id);
+        self.config_heads.retain(|c, _| c.client_id() != client_id);
+    }
+}
+
+/// Re-send the state of all heads to all managers.
+pub(super) fn output_changed(state: &mut State) {
+    let this = &mut state.output_management;
+    this.serial = this.serial.wrapping_add(1);
+    for manager in &mut this.managers {
+        for head in &mut manager.heads {
+            if let Err(e) = head.send_state(state.backend.as_ref(), &state.output_layout) {
+                eprintln!("output management: {e}");
+            }
+        }
+        manager.wl.done(this.serial);
+    }
+}
+
+impl Head {
+    fn new(manager: &ZwlrOutputManagerV1, output: OutputId, state: &State) -> io::Result<Self> {
+        let wl: ZwlrOutputHeadV1 = manager.conn().create_servers_object(manager.version())?;
+        wl.set_callback(head_cb);
+        manager.head(&wl);
+        let name = state.backend.output_name(output);
+        wl.name(CString::new(name).unwrap());
+        wl.description(CString::new(format!("ewc output {name}")).unwrap());
+        wl.enabled(1);
+        let mut head = Self {
+            wl,
+            output,
+            modes: Vec::new(),
+        };
+        head.send_state(state.backend.as_ref(), &state.output_layout)?;
+        Ok(head)
+    }
+
+    /// Advertise new modes, retire stale ones and send the current configuration.
+    fn send_state(&mut self, backend: &dyn Backend, layout: &OutputLayout) -> io::Result<()> {
+        let modes = backend.output_modes(self.output);
+        self.modes.retain(|(wl, mode)| {
+            let keep = modes.contains(mode);
+            if !keep {
+                wl.finished();
+            }
+            keep
+        });
+        for mode in modes {
+            if self.modes.iter().any(|(_, m)| *m == mode) {
+                continue;
+            }
+            let wl: ZwlrOutputModeV1 = self.wl.conn().create_servers_object(self.wl.version())?;
+            wl.set_callback(mode_cb);
+            self.wl.mode(&wl);
+            wl.size(mode.width as i32, mode.height as i32);
+            if mode.refresh != 0 {
+                wl.refresh(mode.refresh as i32);
+            }
+            if mode.preferred {
+                wl.preferred();
+            }
+            self.modes.push((wl, mode));
+        }
+
+        let current = backend.output_current_mode(self.output);
+        if let Some((wl, _)) = self.modes.iter().find(|(_, m)| *m == current) {
+            self.wl.current_mode(wl);
+        }
+        let rect = backend.output_geometry(self.output);
+        self.wl.position(rect.x, rect.y);
+        self.wl.transform(wl_output::Transform::Normal);
+        self.wl.scale(Fixed::from(layout.scale(self.output)));
+        Ok(())
+    }
+}
+
+impl HeadConfig {
+    fn new(wl: ZwlrOutputConfigurationHeadV1) -> Self {
+        Self {
+            wl,
+            mode: None,
+            position: None,
+            transform: None,
+            scale: None,
+        }
+    }
+}
+
+impl IsGlobal for ZwlrOutputManagerV1 {
+    fn on_bind(&self, _client: &mut Client, state: &mut State) {
+        self.set_callback(manager_cb);
+        let heads = state
+            .backend
+            .outputs()
+            .iter()
+            .filter_map(|&output| match Head::new(self, output, state) {
+                Ok(head) => Some(head),
+                Err(e) => {
+                    eprintln!("output management: could not create head: {e}");
+                    None
+                }
+            })
+            .collect();
+        self.done(state.output_management.serial);
+        state.output_management.managers.push(Manager {
+            wl: self.clone(),
+            heads,
+        });
+    }
+}
+
+fn manager_cb(ctx: RequestCtx<ZwlrOutputManagerV1>) -> io::Result<()> {
+    use zwlr_output_manager_v1::Request;
+    let this = &mut ctx.state.output_management;
+    match ctx.request {
+        Request::CreateConfiguration(args) => {
+            args.id.set_callback(configuration_cb);
+            this.configurations.insert(
+                args.id,
+                Configuration {
+                    manager: ctx.proxy,
+                    serial: args.serial,
+                    used: false,
+                    heads: Vec::new(),
+                },
+            );
+        }
+        Request::Stop => {
+            this.managers.retain(|m| m.wl != ctx.proxy);
+            ctx.proxy.finished();
+        }
+    }
+    Ok(())
+}
+
+fn head_cb(ctx: RequestCtx<ZwlrOutputHeadV1>) -> io::Result<()> {
+    use zwlr_output_head_v1::Request;
+    match ctx.request {
+        Request::Release => {
+            for manager in &mut ctx.state.output_management.managers {
+                manager.heads.retain(|h| h.wl != ctx.proxy);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn mode_cb(ctx: RequestCtx<ZwlrOutputModeV1>) -> io::Result<()> {
+    use zwlr_output_mode_v1::Request;
+    match ctx.request {
+        Request::Release => {
+            for manager in &mut ctx.state.output_management.managers {
+                for head in &mut manager.heads {
+                    head.modes.retain(|(wl, _)| *wl != ctx.proxy);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn configuration_cb(ctx: RequestCtx<ZwlrOutputConfigurationV1>) -> io::Result<()> {
+    use zwlr_output_configuration_v1::Request;
+    let this = &mut ctx.state.output_management;
+    let config = this.configurations.get_mut(&ctx.proxy).unwrap();
+    match ctx.request {
+        Request::EnableHead(args) => {
+            config.add_head(args.head, Some(HeadConfig::new(args.id.clone())))?;
+            args.id.set_callback(configuration_head_cb);
+            this.config_heads.insert(args.id, ctx.proxy);
+        }
+        Request::DisableHead(head) => {
+            config.add_head(head, None)?;
+        }
+        request @ (Request::Apply | Request::Test) => {
+            if config.used {
+                return Err(io::Error::other("configuration already used"));
+            }
+            config.used = true;
+            if config.serial != this.serial {
+                ctx.proxy.cancelled();
+                return Ok(());
+            }
+            let Some(heads) = config.resolve_heads(&this.managers)? else {
+                ctx.proxy.cancelled();
+                return Ok(());
+            };
+            let test_only = matches!(request, Request::Test);
+            match apply(ctx.state, heads, test_only) {
+                Ok(()) => ctx.proxy.succeeded(),
+                Err(e) => {
+                    eprintln!("output management: {e}");
+                    ctx.proxy.failed();
+                }
+            }
+        }
+        Request::Destroy => {
+            this.configurations.remove(&ctx.proxy);
+            this.config_heads.retain(|_, config| *config != ctx.proxy);
+        }
+    }
+    Ok(())
+}
+
+fn configuration_head_cb(ctx: RequestCtx<ZwlrOutputConfigurationHeadV1>) -> io::Result<()> {
+    use zwlr_output_configuration_head_v1::Request;
+    let this = &mut ctx.state.output_management;
+    let config_wl = &this.config_heads[&ctx.proxy];
+    let config = this.configurations.get_mut(config_wl).unwrap();
+    if config.used {
+        return Err(io::Error::other("configuration already used"));
+    }
+    let (head, head_config) = config
+        .heads
+        .iter_mut()
+        .find_map(|(head, c)| c.as_mut().filter(|c| c.wl == ctx.proxy).map(|c| (head, c)))
+        .unwrap();
+    match ctx.request {
+        Request::SetMode(mode) => {
+            if head_config.mode.is_some() {
+                return Err(io::Error::other("mode already set"));
+            }
+            let mode = this
+                .managers
+                .iter()
+                .flat_map(|m| &m.heads)
+                .filter(|h| h.wl == *head)
+                .flat_map(|h| &h.modes)
+                .find(|(wl, _)| *wl == mode)
+                .map(|(_, mode)| *mode)
+                .ok_or_else(|| io::Error::other("mode does not belong to head"))?;
+            head_config.mode = Some(mode);
+        }
+        Request::SetCustomMode(args) => {
+            if head_config.mode.is_some() {
+                return Err(io::Error::other("mode already set"));
+            }
+            if args.width <= 0 || args.height <= 0 || args.refresh < 0 {
+                return Err(io::Error::other("invalid custom mode"));
+            }
+            head_config.mode = Some(OutputMode {
+                width: args.width as u32,
+                height: args.height as u32,
+                refresh: args.refresh as u32,
+                preferred: false,
+            });
+        }
+        Request::SetPosition(args) => {
+            if head_config.position.is_some() {
+                return Err(io::Error::other("position already set"));
+            }
+            head_config.position = Some((args.x, args.y));
+        }
+        Request::SetTransform(transform) => {
+            if head_config.transform.is_some() {
+                return Err(io::Error::other("transform already set"));
+            }
+            head_config.transform = Some(transform);
+        }
+        Request::SetScale(scale) => {
+            if head_config.scale.is_some() {
+                return Err(io::Error::other("scale already set"));
+            }
+            if scale.as_f64() <= 0.0 {
+                return Err(io::Error::other("invalid scale"));
+            }
+            head_config.scale = Some(scale);
+        }
+        Request::SetAdaptiveSync(_) => {
+            return Err(io::Error::other("adaptive sync is not supported"));
+        }
+    }
+    Ok(())
+}
+
+impl Configuration {
+    fn add_head(&mut self, head: ZwlrOutputHeadV1, config: Option<HeadConfig>) -> io::Result<()> {
+        if self.used {
+            return Err(io::Error::other("configuration already used"));
+        }
+        if self.heads.iter().any(|(h, _)| *h == head) {
+            return Err(io::Error::other("head configured twice"));
+        }
+        self.heads.push((head, config));
+        Ok(())
+    }
+
+    /// Map the configured heads to outputs.
+    ///
+    /// Returns `None` if the manager was stopped or some of the heads were released, i.e. the
+    /// configuration cannot be applied.
+    fn resolve_heads(
+        &mut self,
+        managers: &[Manager],
+    ) -> io::Result<Option<Vec<(OutputId, Option<HeadConfig>)>>> {
+        let Some(manager) = managers.iter().find(|m| m.wl == self.manager) else {
+            return Ok(None);
+        };
+        if manager
+            .heads
+            .iter()
+            .any(|h| !self.heads.iter().any(|(wl, _)| *wl == h.wl))
+        {
+            return Err(io::Error::other("not all heads are configured"));
+        }
+        let mut heads = Vec::new();
+        for (wl, config) in self.heads.drain(..) {
+            let Some(head) = manager.heads.iter().find(|h| h.wl == wl) else {
+                return Ok(None);
+            };
+            heads.push((head.output, config));
+        }
+        Ok(Some(heads))
+    }
+}
+
+/// Validate and, unless `test_only` is set, apply the configuration.
+fn apply(
+    state: &mut State,
+    heads: Vec<(OutputId, Option<HeadConfig>)>,
+    test_only: bool,
+) -> Result<(), String> {
+    let mut new_modes = Vec::new();
+    let mut new_scales = Vec::new();
+    for (output, config) in heads {
+        let config = config.ok_or("disabling outputs is not supported")?;
+        let rect = state.backend.output_geometry(output);
+        if config.position.is_some_and(|pos| pos != (rect.x, rect.y)) {
+            return Err("changing output position is not supported".into());
+        }
+        if config
+            .transform
+            .is_some_and(|t| t != wl_output::Transform::Normal)
+        {
+            return Err("output transforms are not supported".into());
+        }
+        if let Some(scale) = config.scale {
+            if !scale.is_int() {
+                return Err("fractional output scales are not supported".into());
+            }
+            new_scales.push((output, scale.as_int()));
+        }
+        if let Some(mode) = config.mode {
+            let mode = state
+                .backend
+                .output_modes(output)
+                .into_iter()
+                .find(|m| {
+                    m.width == mode.width
+                        && m.height == mode.height
+                        && (mode.refresh == 0 || m.refresh == mode.refresh)
+                })
+                .ok_or_else(|| format!("unsupported mode {mode:?}"))?;
+            new_modes.push((output, mode));
+        }
+    }
+
+    if test_only {
+        return Ok(());
+    }
+    for (output, mode) in new_modes {
+        state
+            .backend
+            .set_output_mode(output, mode)
+            .map_err(|e| e.to_string())?;
+    }
+    for (output, scale) in new_scales {
+        state.output_layout.set_scale(output, scale);
+    }
+    super::output_changed(state);
+    Ok(())
+}
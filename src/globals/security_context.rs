@@ -0,0 +1,171 @@
+//! `wp_security_context_v1`: lets a trusted client (usually a sandbox engine like Flatpak) create
+//! a listening socket for sandboxed clients. Clients which connect through such a socket are
+//! tagged with the security context and don't get access to privileged globals.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::Rc;
+
+use super::{GlobalsManager, IsGlobal};
+use crate::client::{Client, ClientId, RequestCtx};
+use crate::protocol::*;
+use crate::{Proxy, State};
+
+pub fn register_global(globals: &mut GlobalsManager) {
+    globals.add_privileged_global::<WpSecurityContextManagerV1>(1);
+}
+
+/// Metadata of a security context, attached to every client connected through its socket.
+#[derive(Debug, Default)]
+pub struct SecurityContext {
+    pub sandbox_engine: Option<CString>,
+    pub app_id: Option<CString>,
+    pub instance_id: Option<CString>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ListenerId(u64);
+
+#[derive(Default)]
+pub struct SecurityContexts {
+    pending: HashMap<WpSecurityContextV1, PendingContext>,
+    listeners: HashMap<ListenerId, Listener>,
+    /// Committed listeners which are not yet registered in the event loop.
+    new_listeners: Vec<ListenerId>,
+    next_id: u64,
+}
+
+struct PendingContext {
+    socket: UnixListener,
+    close_fd: OwnedFd,
+    context: SecurityContext,
+}
+
+struct Listener {
+    socket: UnixListener,
+    close_fd: OwnedFd,
+    context: Rc<SecurityContext>,
+}
+
+impl SecurityContexts {
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.pending.retain(|wl, _| wl.client_id() != client_id);
+    }
+
+    /// Returns the listeners committed since the last call, to be registered in the event loop.
+    pub fn take_new_listeners(&mut self) -> Vec<ListenerId> {
+        std::mem::take(&mut self.new_listeners)
+    }
+
+    /// The listening socket and the close fd of the listener, if it still exists.
+    pub fn fds(&self, id: ListenerId) -> Option<(RawFd, RawFd)> {
+        let listener = self.listeners.get(&id)?;
+        Some((listener.socket.as_raw_fd(), listener.close_fd.as_raw_fd()))
+    }
+
+    /// Accept a new connection on the listener.
+    ///
+    /// The listener may be already removed if its socket and close fd were reported in the same
+    /// event loop iteration, in which case `Ok(None)` is returned.
+    pub fn accept(
+        &mut self,
+        id: ListenerId,
+    ) -> io::Result<Option<(UnixStream, Rc<SecurityContext>)>> {
+        let Some(listener) = self.listeners.get(&id) else { return Ok(None) };
+        match listener.socket.accept() {
+            Ok((stream, _)) => Ok(Some((stream, listener.context.clone()))),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stop listening and close the fds.
+    ///
+    /// Should be called once the close fd becomes readable, which happens when the creator of
+    /// the security context closes the other end of it.
+    pub fn remove(&mut self, id: ListenerId) {
+        self.listeners.remove(&id);
+    }
+}
+
+impl IsGlobal for WpSecurityContextManagerV1 {
+    fn on_bind(&self, _client: &mut Client, _state: &mut State) {
+        self.set_callback(|ctx| {
+            use wp_security_context_manager_v1::Request;
+            match ctx.request {
+                Request::Destroy => (),
+                Request::CreateListener(args) => {
+                    args.id.set_callback(wp_security_context_cb);
+                    if ctx.client.security_context.is_some() {
+                        return Err(io::Error::other(
+                            "sandboxed clients cannot create security contexts",
+                        ));
+                    }
+                    let socket = UnixListener::from(args.listen_fd);
+                    socket
+                        .set_nonblocking(true)
+                        .map_err(|_| io::Error::other("invalid listen fd"))?;
+                    ctx.state.security_contexts.pending.insert(
+                        args.id,
+                        PendingContext {
+                            socket,
+                            close_fd: args.close_fd,
+                            context: SecurityContext::default(),
+                        },
+                    );
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+fn wp_security_context_cb(ctx: RequestCtx<WpSecurityContextV1>) -> io::Result<()> {
+    use wp_security_context_v1::Request;
+    let contexts = &mut ctx.state.security_contexts;
+    match ctx.request {
+        Request::Destroy => {
+            contexts.pending.remove(&ctx.proxy);
+            return Ok(());
+        }
+        Request::Commit => {
+            let pending = contexts
+                .pending
+                .remove(&ctx.proxy)
+                .ok_or_else(|| io::Error::other("security context already committed"))?;
+            let id = ListenerId(contexts.next_id);
+            contexts.next_id += 1;
+            contexts.listeners.insert(
+                id,
+                Listener {
+                    socket: pending.socket,
+                    close_fd: pending.close_fd,
+                    context: Rc::new(pending.context),
+                },
+            );
+            contexts.new_listeners.push(id);
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let context = &mut contexts
+        .pending
+        .get_mut(&ctx.proxy)
+        .ok_or_else(|| io::Error::other("security context already committed"))?
+        .context;
+    let (field, value) = match ctx.request {
+        Request::SetSandboxEngine(name) => (&mut context.sandbox_engine, name),
+        Request::SetAppId(app_id) => (&mut context.app_id, app_id),
+        Request::SetInstanceId(instance_id) => (&mut context.instance_id, instance_id),
+        Request::Destroy | Request::Commit => unreachable!(),
+    };
+    if field.is_some() {
+        return Err(io::Error::other("security context metadata already set"));
+    }
+    *field = Some(value);
+    Ok(())
+}
@@ -1,6 +1,9 @@
 use std::collections::hash_map;
+use std::ffi::c_void;
 use std::io;
 use std::os::fd::OwnedFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
 
 use super::IsGlobal;
 use crate::client::RequestCtx;
@@ -31,12 +34,83 @@ pub struct ShmBufferSpec {
 
 impl ShmPool {
     fn new(fd: OwnedFd, size: usize) -> io::Result<Self> {
+        static INSTALL_SIGBUS_HANDLER: Once = Once::new();
+        INSTALL_SIGBUS_HANDLER.call_once(install_sigbus_handler);
         Ok(Self {
             memmap: unsafe { memmap2::MmapOptions::new().len(size).map(&fd)? },
             size,
             refcnt: 0,
         })
     }
+
+    /// Mark the pool as being read until the returned guard is dropped.
+    ///
+    /// The client may truncate the file backing the pool at any time, after which reading the
+    /// mapping raises SIGBUS. While the guard is alive, such a fault replaces the whole mapping
+    /// with zeroed memory instead of crashing the compositor.
+    pub fn access(&self) -> ShmAccess {
+        ACCESS_ADDR.store(self.memmap.as_ptr() as usize, Ordering::Relaxed);
+        ACCESS_LEN.store(self.memmap.len(), Ordering::Relaxed);
+        ShmAccess { _priv: () }
+    }
+}
+
+/// See [`ShmPool::access`].
+pub struct ShmAccess {
+    _priv: (),
+}
+
+impl Drop for ShmAccess {
+    fn drop(&mut self) {
+        ACCESS_LEN.store(0, Ordering::Relaxed);
+        if ACCESS_FAULTED.swap(false, Ordering::Relaxed) {
+            eprintln!("shm pool was truncated by the client while being read");
+        }
+    }
+}
+
+static ACCESS_ADDR: AtomicUsize = AtomicUsize::new(0);
+static ACCESS_LEN: AtomicUsize = AtomicUsize::new(0);
+static ACCESS_FAULTED: AtomicBool = AtomicBool::new(false);
+
+fn install_sigbus_handler() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = sigbus_handler as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGBUS, &action, std::ptr::null_mut()) == -1 {
+            eprintln!(
+                "could not install SIGBUS handler: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+extern "C" fn sigbus_handler(_sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut c_void) {
+    let fault_addr = unsafe { (*info).si_addr() } as usize;
+    let addr = ACCESS_ADDR.load(Ordering::Relaxed);
+    let len = ACCESS_LEN.load(Ordering::Relaxed);
+    if len != 0 && fault_addr >= addr && fault_addr < addr + len {
+        // Returning from the handler retries the read, which now sees zeros.
+        let res = unsafe {
+            libc::mmap(
+                addr as *mut c_void,
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if res != libc::MAP_FAILED {
+            ACCESS_FAULTED.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+    // Not caused by reading a pool, let the fault happen again and crash as usual.
+    unsafe { libc::signal(libc::SIGBUS, libc::SIG_DFL) };
 }
 
 impl Shm {
@@ -94,6 +168,22 @@ fn wl_shm_pool_cb(ctx: RequestCtx<WlShmPool>) -> io::Result<()> {
             {
                 return Err(io::Error::other("provided unsupported shm format"));
             }
+            let pool_size = ctx
+                .state
+                .backend
+                .renderer_state()
+                .get_shm_state()
+                .get(&ctx.proxy)
+                .unwrap()
+                .size;
+            if args.offset < 0
+                || args.width <= 0
+                || args.height <= 0
+                || args.stride / 4 < args.width
+                || args.offset as usize + args.stride as usize * args.height as usize > pool_size
+            {
+                return Err(io::Error::other("shm buffer does not fit into the pool"));
+            }
             args.id.set_callback(wl_buffer_cb);
             ctx.client.shm.wl_buffers.push(args.id.clone());
             ctx.state.backend.renderer_state().create_shm_buffer(
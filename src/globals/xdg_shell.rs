@@ -21,6 +21,8 @@ use toplevel::XdgToplevelRole;
 
 pub struct XdgSurfaceRole {
     pub wl: XdgSurface,
+    /// The `xdg_wm_base` the surface was created with, which some errors are posted on.
+    pub wm_base: XdgWmBase,
     pub wl_surface: Weak<Surface>,
     pub specific: RefCell<SpecificRole>,
     pub popup: RefCell<Option<Rc<XdgPopupRole>>>,
@@ -33,6 +35,14 @@ pub struct XdgSurfaceRole {
 }
 
 impl XdgSurfaceRole {
+    /// The error for `get_toplevel` or `get_popup` after the role object was already created.
+    fn role_conflict(&self, object: &impl Proxy) -> io::Error {
+        self.wl_surface
+            .upgrade()
+            .unwrap()
+            .role_conflict(object, xdg_surface::Error::AlreadyConstructed as u32)
+    }
+
     pub fn get_window_geometry(&self) -> Option<WindowGeometry> {
         // TODO: check if surface is mapped
         self.effective_window_geometry.get()
@@ -48,25 +58,30 @@ impl XdgSurfaceRole {
     pub fn committed(&self, state: &mut State) -> io::Result<()> {
         let surface = self.wl_surface.upgrade().unwrap();
         if let Some(geom) = self.pending.window_geometry.take() {
-            let mut geom = pixman::Box32::from(geom);
-            if let Some(bbox) = surface.get_bounding_box() {
-                geom.x1 = geom.x1.max(bbox.x1);
-                geom.x2 = geom.x2.min(bbox.x2);
-                geom.y1 = geom.y1.max(bbox.y1);
-                geom.y2 = geom.y2.min(bbox.y2);
-                let geom = WindowGeometry::try_from(geom).unwrap();
-                self.effective_window_geometry.set(Some(geom));
-                self.cur.window_geometry.set(Some(geom));
-            } else {
-                self.effective_window_geometry.set(None);
-                self.cur.window_geometry.set(None);
-            }
+            self.cur.window_geometry.set(Some(geom));
         }
 
-        if self.cur.window_geometry.get().is_none() {
-            self.effective_window_geometry
-                .set(surface.get_bounding_box().and_then(|x| x.try_into().ok()))
-        }
+        // The requested geometry is kept as is and clamped to the bounding box on every commit,
+        // since the surface or its subsurfaces may grow after the geometry was set. A geometry
+        // completely outside of the bounding box is ignored.
+        let bbox = surface.get_bounding_box();
+        let effective = bbox.and_then(|bbox| {
+            self.cur
+                .window_geometry
+                .get()
+                .and_then(|geom| {
+                    let geom = pixman::Box32::from(geom);
+                    WindowGeometry::try_from(pixman::Box32 {
+                        x1: geom.x1.max(bbox.x1),
+                        y1: geom.y1.max(bbox.y1),
+                        x2: geom.x2.min(bbox.x2),
+                        y2: geom.y2.min(bbox.y2),
+                    })
+                    .ok()
+                })
+                .or_else(|| bbox.try_into().ok())
+        });
+        self.effective_window_geometry.set(effective);
 
         match &*self.specific.borrow() {
             SpecificRole::None => Ok(()),
@@ -170,7 +185,9 @@ impl IsGlobal for XdgWmBase {
                 Request::GetXdgSurface(args) => {
                     let surface = ctx.client.compositor.surfaces.get(&args.surface).unwrap();
                     if surface.has_role() {
-                        return Err(io::Error::other("surface already has a role"));
+                        return Err(
+                            surface.role_conflict(&ctx.proxy, xdg_wm_base::Error::Role as u32)
+                        );
                     }
                     if surface.buf_transform().is_some() || surface.pending_buffer.take().is_some()
                     {
@@ -181,6 +198,7 @@ impl IsGlobal for XdgWmBase {
                     args.id.set_callback(xdg_surface_cb);
                     let xdg_surface = Rc::new(XdgSurfaceRole {
                         wl: args.id.clone(),
+                        wm_base: ctx.proxy.clone(),
                         wl_surface: Rc::downgrade(surface),
                         specific: RefCell::new(SpecificRole::None),
                         popup: RefCell::new(None),
@@ -218,10 +236,17 @@ fn xdg_surface_cb(ctx: RequestCtx<XdgSurface>) -> io::Result<()> {
         }
         Request::GetToplevel(toplevel) => {
             if !matches!(&*xdg_surface.specific.borrow(), SpecificRole::None) {
-                return Err(io::Error::other("xdg surface already has a role"));
+                return Err(xdg_surface.role_conflict(&ctx.proxy));
             }
             if toplevel.version() >= 5 {
-                toplevel.wm_capabilities(Vec::new());
+                let mut capabilities = Vec::new();
+                for capability in [
+                    xdg_toplevel::WmCapabilities::Maximize,
+                    xdg_toplevel::WmCapabilities::Minimize,
+                ] {
+                    capabilities.extend_from_slice(&(capability as u32).to_ne_bytes());
+                }
+                toplevel.wm_capabilities(capabilities);
             }
             let toplevel = Rc::new(XdgToplevelRole::new(toplevel, xdg_surface));
             ctx.client
@@ -231,6 +256,9 @@ fn xdg_surface_cb(ctx: RequestCtx<XdgSurface>) -> io::Result<()> {
             *xdg_surface.specific.borrow_mut() = SpecificRole::Toplevel(toplevel);
         }
         Request::GetPopup(args) => {
+            if !matches!(&*xdg_surface.specific.borrow(), SpecificRole::None) {
+                return Err(xdg_surface.role_conflict(&ctx.proxy));
+            }
             let parent = args
                 .parent
                 .ok_or_else(|| io::Error::other("get_popup with null parent"))?;
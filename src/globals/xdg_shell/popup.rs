@@ -2,12 +2,13 @@ use std::cell::Cell;
 use std::io;
 use std::rc::{Rc, Weak};
 
-use crate::client::RequestCtx;
+use crate::client::{ProtocolError, RequestCtx};
 use crate::globals::compositor::Surface;
 use crate::State;
 use crate::{protocol::*, Proxy};
 
 use super::positioner::Positioner;
+use super::toplevel::XdgToplevelRole;
 use super::{SpecificRole, XdgSurfaceRole};
 
 pub struct XdgPopupRole {
@@ -17,6 +18,8 @@ pub struct XdgPopupRole {
     pub parent: Weak<XdgSurfaceRole>,
     pub positioner: Cell<Positioner>,
     pub grab: Cell<bool>,
+    /// Whether `popup_done` was sent. A dismissed popup is never mapped.
+    dismissed: Cell<bool>,
     next_configure_serial: Cell<u32>,
     last_serial: Cell<u32>,
 
@@ -39,6 +42,7 @@ impl XdgPopupRole {
             parent: Rc::downgrade(parent),
             positioner: Cell::new(positioner),
             grab: Cell::new(false),
+            dismissed: Cell::new(false),
             next_configure_serial: Cell::new(0),
             last_serial: Cell::new(0),
 
@@ -135,14 +139,56 @@ impl XdgPopupRole {
     //         }
     //     }
 
-    fn configure(&self) {
+    /// The position of the popup's surface relative to its parent's surface. The parent may be
+    /// another popup.
+    pub fn offset(&self) -> Option<(i32, i32)> {
+        let parent_geom = self.parent.upgrade().unwrap().get_window_geometry()?;
+        let geom = self.xdg_surface.upgrade().unwrap().get_window_geometry()?;
+        Some((
+            parent_geom.x + self.x.get() - geom.x,
+            parent_geom.y + self.y.get() - geom.y,
+        ))
+    }
+
+    /// The toplevel at the root of the popup's parent chain.
+    pub fn toplevel(&self) -> Option<Rc<XdgToplevelRole>> {
+        match &*self.parent.upgrade().unwrap().specific.borrow() {
+            SpecificRole::None => None,
+            SpecificRole::Toplevel(toplevel) => Some(toplevel.clone()),
+            SpecificRole::Popup(popup) => popup.toplevel(),
+        }
+    }
+
+    /// The work area of the output the popup's toplevel is on, relative to the parent's window
+    /// geometry like the popup position.
+    fn constraint_bounds(&self, state: &State) -> Option<pixman::Rectangle32> {
+        let parent = self.parent.upgrade().unwrap();
+        let (x, y) = parent.wl_surface.upgrade().unwrap().get_pos()?;
+        let geom = parent.get_window_geometry()?;
+        let output = self.toplevel()?.output(state)?;
+        let area = state
+            .output_layout
+            .work_area(state.backend.as_ref(), output);
+        Some(pixman::Rectangle32 {
+            x: area.x - x - geom.x,
+            y: area.y - y - geom.y,
+            ..area
+        })
+    }
+
+    fn dismiss(&self) {
+        self.dismissed.set(true);
+        self.wl.popup_done();
+    }
+
+    fn configure(&self, state: &State) {
         let serial = self.next_configure_serial.get();
         self.last_serial.set(serial);
         self.next_configure_serial.set(serial.wrapping_add(1));
         let positioner = self.positioner.get();
         let width = positioner.size.0.get();
         let height = positioner.size.1.get();
-        let (x, y) = positioner.get_position();
+        let (x, y) = positioner.get_position(self.constraint_bounds(state));
         self.x.set(x);
         self.y.set(y);
         self.wl.configure(x, y, width as i32, height as i32);
@@ -157,16 +203,35 @@ impl XdgPopupRole {
             if surface.cur.borrow().buffer.is_some() {
                 return Err(io::Error::other("unmapped surface commited a buffer"));
             }
-            self.configure();
+            self.configure(state);
             surface.configured.set(true);
         } else if !surface.mapped.get() {
+            if self.dismissed.get() {
+                return Ok(());
+            }
             if surface.cur.borrow().buffer.is_none() {
                 return Err(io::Error::other("did not submit initial buffer"));
             }
             if Some(self.last_serial.get()) != xdg_surface.last_acked_configure.get() {
                 return Err(io::Error::other("did not ack the initial config"));
             }
-            *self.parent.upgrade().unwrap().popup.borrow_mut() = Some(self.clone());
+            let parent = self.parent.upgrade().unwrap();
+            if let SpecificRole::Popup(parent_popup) = &*parent.specific.borrow() {
+                // Popups are destroyed in the reverse order of mapping, so a submenu can only be
+                // opened from the topmost popup.
+                if !state
+                    .popup_stack
+                    .last()
+                    .is_some_and(|p| p.wl == parent_popup.wl)
+                {
+                    return Err(ProtocolError::new(
+                        &xdg_surface.wm_base,
+                        xdg_wm_base::Error::NotTheTopmostPopup as u32,
+                        "popup parent is not the topmost popup",
+                    ));
+                }
+            }
+            *parent.popup.borrow_mut() = Some(self.clone());
             state.popup_stack.push(self.clone());
             surface.mapped.set(true);
         } else {
@@ -191,21 +256,52 @@ fn xdg_popup_cb(ctx: RequestCtx<XdgPopup>) -> io::Result<()> {
     match ctx.request {
         Request::Destroy => {
             let parent = popup.parent.upgrade().unwrap();
-            *parent.popup.borrow_mut() = None;
-            *popup.xdg_surface.upgrade().unwrap().specific.borrow_mut() = SpecificRole::None;
+            let mut parent_popup = parent.popup.borrow_mut();
+            if parent_popup.as_ref().is_some_and(|p| p.wl == popup.wl) {
+                *parent_popup = None;
+            }
+            drop(parent_popup);
+            let xdg_surface = popup.xdg_surface.upgrade().unwrap();
+            *xdg_surface.specific.borrow_mut() = SpecificRole::None;
             ctx.client.compositor.xdg_popups.remove(&ctx.proxy);
             surface.unmap(ctx.state);
-            if !ctx
-                .state
-                .popup_stack
-                .last()
-                .is_some_and(|p| p.wl == popup.wl)
-            {
-                return Err(io::Error::other("destroyed popup must be the top one"));
+            // Popups which were never mapped, like dismissed ones, are not in the stack.
+            let stack = &mut ctx.state.popup_stack;
+            if let Some(i) = stack.iter().position(|p| p.wl == popup.wl) {
+                if i + 1 != stack.len() {
+                    return Err(ProtocolError::new(
+                        &xdg_surface.wm_base,
+                        xdg_wm_base::Error::NotTheTopmostPopup as u32,
+                        "destroyed popup must be the top one",
+                    ));
+                }
+                stack.pop();
+            }
+            if popup.grab.get() {
+                // The popup had keyboard focus, give it to the next grabbing popup or the toplevel.
+                ctx.state.focus_keyboard();
             }
-            ctx.state.popup_stack.pop();
         }
-        Request::Grab(_args) => {
+        Request::Grab(args) => {
+            let parent = popup.parent.upgrade().unwrap();
+            if let SpecificRole::Popup(parent) = &*parent.specific.borrow() {
+                if parent.dismissed.get() {
+                    popup.dismiss();
+                    return Ok(());
+                }
+                if !parent.grab.get() {
+                    return Err(ProtocolError::new(
+                        &ctx.proxy,
+                        xdg_popup::Error::InvalidGrab as u32,
+                        "parent popup did not take a grab",
+                    ));
+                }
+            }
+            if !ctx.client.conn.seat.is_press_serial(args.serial) {
+                // A grab which is not a response to user input is denied.
+                popup.dismiss();
+                return Ok(());
+            }
             popup.grab.set(true);
             ctx.state
                 .seat
@@ -222,7 +318,7 @@ fn xdg_popup_cb(ctx: RequestCtx<XdgPopup>) -> io::Result<()> {
                     .unwrap(),
             )?;
             popup.positioner.set(positioner);
-            popup.configure();
+            popup.configure(ctx.state);
         }
     }
 
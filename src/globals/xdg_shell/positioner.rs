@@ -46,32 +46,100 @@ impl Positioner {
         })
     }
 
-    pub fn get_position(&self) -> (i32, i32) {
-        let (ax, ay, aw, ah) = self.anchor_rect;
-        let (x, y) = match self.anchor.unwrap_or(xdg_positioner::Anchor::None) {
-            xdg_positioner::Anchor::None => (ax + aw / 2, ay + ah / 2),
-            xdg_positioner::Anchor::Top => (ax + aw / 2, ay),
-            xdg_positioner::Anchor::Bottom => (ax + aw / 2, ay + ah),
-            xdg_positioner::Anchor::Left => (ax, ay + ah / 2),
-            xdg_positioner::Anchor::Right => (ax + aw, ay + ah / 2),
-            xdg_positioner::Anchor::TopLeft => (ax, ay),
-            xdg_positioner::Anchor::BottomLeft => (ax, ay + ah),
-            xdg_positioner::Anchor::TopRight => (ax + aw, ay),
-            xdg_positioner::Anchor::BottomRight => (ax + aw, ay + ah),
+    /// The position of the popup relative to the parent's window geometry. If the popup does not
+    /// fit into `bounds`, given in the same coordinates, it is flipped and then slid as the
+    /// constraint adjustment allows. Resizing is not implemented.
+    pub fn get_position(&self, bounds: Option<pixman::Rectangle32>) -> (i32, i32) {
+        let anchor = anchor_direction(self.anchor.unwrap_or(xdg_positioner::Anchor::None));
+        let gravity = gravity_direction(self.gravity.unwrap_or(xdg_positioner::Gravity::None));
+        let (mut x, mut y) = self.unconstrained_position(anchor, gravity);
+        let Some(bounds) = bounds else {
+            return (x, y);
         };
         let w = self.size.0.get() as i32;
         let h = self.size.1.get() as i32;
-        match self.gravity.unwrap_or(xdg_positioner::Gravity::None) {
-            xdg_positioner::Gravity::None => (x - w / 2, y - h / 2),
-            xdg_positioner::Gravity::Top => (x - w / 2, y - h),
-            xdg_positioner::Gravity::Bottom => (x - w / 2, y),
-            xdg_positioner::Gravity::Left => (x - w, y - h / 2),
-            xdg_positioner::Gravity::Right => (x, y - h / 2),
-            xdg_positioner::Gravity::TopLeft => (x - w, y - h),
-            xdg_positioner::Gravity::BottomLeft => (x - w, y),
-            xdg_positioner::Gravity::TopRight => (x, y - h),
-            xdg_positioner::Gravity::BottomRight => (x, y),
+        let fits_x = |x: i32| x >= bounds.x && x + w <= bounds.x + bounds.width as i32;
+        let fits_y = |y: i32| y >= bounds.y && y + h <= bounds.y + bounds.height as i32;
+        let adjustment = self.contraint_adjustment;
+
+        if !fits_x(x) && adjustment.contains(ConstraintAdjustment::FlipX) {
+            let (flipped, _) =
+                self.unconstrained_position((-anchor.0, anchor.1), (-gravity.0, gravity.1));
+            if fits_x(flipped) {
+                x = flipped;
+            }
+        }
+        if !fits_x(x) && adjustment.contains(ConstraintAdjustment::SlideX) {
+            // Prefer keeping the left edge visible if the popup is wider than the bounds.
+            x = x.min(bounds.x + bounds.width as i32 - w).max(bounds.x);
+        }
+
+        if !fits_y(y) && adjustment.contains(ConstraintAdjustment::FlipY) {
+            let (_, flipped) =
+                self.unconstrained_position((anchor.0, -anchor.1), (gravity.0, -gravity.1));
+            if fits_y(flipped) {
+                y = flipped;
+            }
         }
+        if !fits_y(y) && adjustment.contains(ConstraintAdjustment::SlideY) {
+            y = y.min(bounds.y + bounds.height as i32 - h).max(bounds.y);
+        }
+
+        (x, y)
+    }
+
+    /// The position for the anchor and gravity given as directions, see `anchor_direction`.
+    fn unconstrained_position(&self, anchor: (i32, i32), gravity: (i32, i32)) -> (i32, i32) {
+        let (ax, ay, aw, ah) = self.anchor_rect;
+        let w = self.size.0.get() as i32;
+        let h = self.size.1.get() as i32;
+        let along = |start: i32, len: i32, dir: i32| match dir {
+            -1 => start,
+            0 => start + len / 2,
+            _ => start + len,
+        };
+        let extend = |len: i32, dir: i32| match dir {
+            -1 => -len,
+            0 => -len / 2,
+            _ => 0,
+        };
+        (
+            along(ax, aw, anchor.0) + extend(w, gravity.0) + self.offset.0,
+            along(ay, ah, anchor.1) + extend(h, gravity.1) + self.offset.1,
+        )
+    }
+}
+
+/// The anchor as a horizontal and a vertical direction: -1 for left/top, 0 for the center and 1
+/// for right/bottom. Flipping an anchor negates the direction.
+fn anchor_direction(anchor: xdg_positioner::Anchor) -> (i32, i32) {
+    use xdg_positioner::Anchor;
+    match anchor {
+        Anchor::None => (0, 0),
+        Anchor::Top => (0, -1),
+        Anchor::Bottom => (0, 1),
+        Anchor::Left => (-1, 0),
+        Anchor::Right => (1, 0),
+        Anchor::TopLeft => (-1, -1),
+        Anchor::BottomLeft => (-1, 1),
+        Anchor::TopRight => (1, -1),
+        Anchor::BottomRight => (1, 1),
+    }
+}
+
+/// The gravity as directions, like `anchor_direction`.
+fn gravity_direction(gravity: xdg_positioner::Gravity) -> (i32, i32) {
+    use xdg_positioner::Gravity;
+    match gravity {
+        Gravity::None => (0, 0),
+        Gravity::Top => (0, -1),
+        Gravity::Bottom => (0, 1),
+        Gravity::Left => (-1, 0),
+        Gravity::Right => (1, 0),
+        Gravity::TopLeft => (-1, -1),
+        Gravity::BottomLeft => (-1, 1),
+        Gravity::TopRight => (1, -1),
+        Gravity::BottomRight => (1, 1),
     }
 }
 
@@ -129,3 +197,75 @@ pub(super) fn xdg_positioner_cb(ctx: RequestCtx<XdgPositioner>) -> io::Result<()
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::xdg_positioner::{Anchor, Gravity};
+
+    fn positioner(
+        size: (u32, u32),
+        anchor_rect: (i32, i32, i32, i32),
+        anchor: Anchor,
+        gravity: Gravity,
+        adjustment: ConstraintAdjustment,
+    ) -> Positioner {
+        Positioner {
+            size: (
+                NonZeroU32::new(size.0).unwrap(),
+                NonZeroU32::new(size.1).unwrap(),
+            ),
+            anchor_rect,
+            offset: (0, 0),
+            anchor: Some(anchor),
+            gravity: Some(gravity),
+            contraint_adjustment: adjustment,
+            reactive: false,
+        }
+    }
+
+    const BOUNDS: pixman::Rectangle32 = pixman::Rectangle32 {
+        x: 0,
+        y: 0,
+        width: 100,
+        height: 100,
+    };
+
+    #[test]
+    fn flip_x() {
+        let p = positioner(
+            (30, 10),
+            (80, 10, 10, 10),
+            Anchor::Right,
+            Gravity::Right,
+            ConstraintAdjustment::FlipX,
+        );
+        assert_eq!(p.get_position(None), (90, 10));
+        assert_eq!(p.get_position(Some(BOUNDS)), (50, 10));
+    }
+
+    #[test]
+    fn slide_y() {
+        let p = positioner(
+            (20, 30),
+            (10, 90, 10, 10),
+            Anchor::Bottom,
+            Gravity::Bottom,
+            ConstraintAdjustment::SlideY,
+        );
+        assert_eq!(p.get_position(None), (5, 100));
+        assert_eq!(p.get_position(Some(BOUNDS)), (5, 70));
+    }
+
+    #[test]
+    fn flip_which_does_not_fit_is_not_applied() {
+        let p = positioner(
+            (95, 10),
+            (10, 40, 10, 10),
+            Anchor::Right,
+            Gravity::Right,
+            ConstraintAdjustment::FlipX,
+        );
+        assert_eq!(p.get_position(Some(BOUNDS)), (20, 40));
+    }
+}
@@ -3,10 +3,15 @@ use std::ffi::CString;
 use std::io;
 use std::num::NonZeroU32;
 use std::rc::{Rc, Weak};
+use std::time::Instant;
 
+use crate::backend::OutputId;
 use crate::client::RequestCtx;
+use crate::focus_stack::Layer;
 use crate::globals::compositor::Surface;
+use crate::protocol::wp_cursor_shape_device_v1::Shape;
 use crate::protocol::xdg_toplevel::ResizeEdge;
+use crate::seat::Seat;
 use crate::State;
 use crate::{protocol::*, Proxy};
 
@@ -19,6 +24,15 @@ pub struct XdgToplevelRole {
 
     pub x: Cell<i32>,
     pub y: Cell<i32>,
+    /// Bitmask of workspaces this toplevel belongs to.
+    pub tags: Cell<u32>,
+    /// Minimized toplevels stay mapped but are neither rendered nor focusable.
+    pub minimized: Cell<bool>,
+    pub layer: Cell<Layer>,
+    /// When the toplevel was last mapped, for the open animation.
+    pub mapped_at: Cell<Instant>,
+    /// The area the toplevel is tiled to and its geometry from before, see `tile`.
+    tiled: Cell<Option<Tiled>>,
     resizing: Cell<Option<(ResizeEdge, i32, i32, u32)>>,
 
     cur_configure: Cell<ToplevelConfigure>,
@@ -32,12 +46,26 @@ pub struct XdgToplevelRole {
     dirty_max_size: Cell<bool>,
 }
 
+/// A change of toplevel metadata, queued in `State::toplevel_events` for the rest of the
+/// compositor to react to.
+pub enum ToplevelEvent {
+    TitleChanged(Weak<XdgToplevelRole>),
+    AppIdChanged(Weak<XdgToplevelRole>),
+}
+
 #[derive(Clone, Copy, Default)]
 struct ToplevelConfigure {
     serial: u32,
     width: u32,
     heinght: u32,
     activated: bool,
+    maximized: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Tiled {
+    area: pixman::Rectangle32,
+    untiled: pixman::Rectangle32,
 }
 
 impl XdgToplevelRole {
@@ -50,6 +78,11 @@ impl XdgToplevelRole {
 
             x: Cell::new(0),
             y: Cell::new(0),
+            tags: Cell::new(0),
+            minimized: Cell::new(false),
+            layer: Cell::new(Layer::Normal),
+            mapped_at: Cell::new(Instant::now()),
+            tiled: Cell::new(None),
             resizing: Cell::new(None),
 
             cur_configure: Cell::new(ToplevelConfigure::default()),
@@ -64,6 +97,33 @@ impl XdgToplevelRole {
         }
     }
 
+    pub fn title(&self) -> Option<CString> {
+        self.cur.borrow().title.clone()
+    }
+
+    pub fn app_id(&self) -> Option<CString> {
+        self.cur.borrow().app_id.clone()
+    }
+
+    /// The output this toplevel is on, see `OutputLayout::output_for_rect`.
+    pub fn output(&self, state: &State) -> Option<OutputId> {
+        let (width, height) = self
+            .xdg_surface
+            .upgrade()
+            .unwrap()
+            .get_window_geometry()
+            .map_or((0, 0), |geom| (geom.width.get(), geom.height.get()));
+        state.output_layout.output_for_rect(
+            state.backend.as_ref(),
+            &pixman::Rectangle32 {
+                x: self.x.get(),
+                y: self.y.get(),
+                width,
+                height,
+            },
+        )
+    }
+
     pub fn apply_pending_configure(&self) {
         if let Some(configure) = self.pending_configure.take() {
             self.cur_configure.set(configure);
@@ -71,6 +131,9 @@ impl XdgToplevelRole {
             if configure.activated {
                 states.extend_from_slice(&(xdg_toplevel::State::Activated as u32).to_ne_bytes());
             }
+            if configure.maximized {
+                states.extend_from_slice(&(xdg_toplevel::State::Maximized as u32).to_ne_bytes());
+            }
             self.wl
                 .configure(configure.width as i32, configure.heinght as i32, states);
             self.xdg_surface
@@ -81,18 +144,29 @@ impl XdgToplevelRole {
         }
     }
 
+    /// The pending configure, or a new one with the current state if there is none.
+    fn next_configure(&self) -> ToplevelConfigure {
+        self.pending_configure.get().unwrap_or_else(|| {
+            let mut conf = self.cur_configure.get();
+            conf.serial += 1;
+            conf
+        })
+    }
+
     pub fn set_activated(&self, value: bool) {
         if self.cur_configure.get().activated != value {
-            let mut configure = self.pending_configure.get().unwrap_or_else(|| {
-                let mut conf = self.cur_configure.get();
-                conf.serial += 1;
-                conf
-            });
+            let mut configure = self.next_configure();
             configure.activated = value;
             self.pending_configure.set(Some(configure));
         }
     }
 
+    fn set_maximized(&self, value: bool) {
+        let mut configure = self.next_configure();
+        configure.maximized = value;
+        self.pending_configure.set(Some(configure));
+    }
+
     pub fn request_size(&self, edge: ResizeEdge, mut width: NonZeroU32, mut height: NonZeroU32) {
         if !self.wl_surface.upgrade().unwrap().mapped.get() {
             return;
@@ -116,12 +190,7 @@ impl XdgToplevelRole {
             }
         }
 
-        let mut configure = self.pending_configure.take().unwrap_or_else(|| {
-            let mut c = self.cur_configure.get();
-            c.serial += 1;
-            c
-        });
-
+        let mut configure = self.next_configure();
         configure.width = width.get();
         configure.heinght = height.get();
         let serial = configure.serial;
@@ -146,14 +215,89 @@ impl XdgToplevelRole {
         }
     }
 
+    /// Move the toplevel to `area` and ask it to cover it, remembering its geometry for `untile`.
+    /// Does nothing if the toplevel is already tiled to `area`.
+    pub fn tile(&self, area: pixman::Rectangle32, maximized: bool) {
+        if !self.wl_surface.upgrade().unwrap().mapped.get() {
+            return;
+        }
+        let Some(geom) = self.xdg_surface.upgrade().unwrap().get_window_geometry() else {
+            return;
+        };
+        let (Some(width), Some(height)) =
+            (NonZeroU32::new(area.width), NonZeroU32::new(area.height))
+        else {
+            return;
+        };
+        let untiled = match self.tiled.get() {
+            Some(tiled) if same_rect(&tiled.area, &area) => return,
+            Some(tiled) => tiled.untiled,
+            None => pixman::Rectangle32 {
+                x: self.x.get(),
+                y: self.y.get(),
+                width: geom.width.get(),
+                height: geom.height.get(),
+            },
+        };
+        self.tiled.set(Some(Tiled { area, untiled }));
+        self.x.set(area.x);
+        self.y.set(area.y);
+        // Anchor the new size at the new position rather than where an earlier resize started.
+        self.resizing.set(None);
+        self.request_size(ResizeEdge::BottomRight, width, height);
+        self.set_maximized(maximized);
+    }
+
+    /// The geometry the toplevel had before `tile`, or `None` if it is not tiled.
+    pub fn untiled_geometry(&self) -> Option<pixman::Rectangle32> {
+        self.tiled.get().map(|tiled| tiled.untiled)
+    }
+
+    /// Move a tiled toplevel to `x, y` and ask it to return to its size from before `tile`.
+    pub fn untile(&self, x: i32, y: i32) {
+        let Some(Tiled { untiled, .. }) = self.tiled.take() else {
+            return;
+        };
+        self.x.set(x);
+        self.y.set(y);
+        self.resizing.set(None);
+        if let (Some(width), Some(height)) = (
+            NonZeroU32::new(untiled.width),
+            NonZeroU32::new(untiled.height),
+        ) {
+            self.request_size(ResizeEdge::BottomRight, width, height);
+        }
+        self.set_maximized(false);
+    }
+
+    /// Whether the toplevel is (about to be told that it is) maximized.
+    pub fn is_maximized(&self) -> bool {
+        self.pending_configure
+            .get()
+            .unwrap_or(self.cur_configure.get())
+            .maximized
+    }
+
     pub fn committed(self: &Rc<Self>, state: &mut State) -> io::Result<()> {
         if self.dirty_app_id.get() {
             self.dirty_app_id.set(false);
-            self.cur.borrow_mut().app_id = std::mem::take(&mut self.pending.borrow_mut().app_id);
+            let app_id = std::mem::take(&mut self.pending.borrow_mut().app_id);
+            if self.cur.borrow().app_id != app_id {
+                state
+                    .toplevel_events
+                    .push(ToplevelEvent::AppIdChanged(Rc::downgrade(self)));
+            }
+            self.cur.borrow_mut().app_id = app_id;
         }
         if self.dirty_title.get() {
-            self.dirty_app_id.set(false);
-            self.cur.borrow_mut().title = std::mem::take(&mut self.pending.borrow_mut().title);
+            self.dirty_title.set(false);
+            let title = std::mem::take(&mut self.pending.borrow_mut().title);
+            if self.cur.borrow().title != title {
+                state
+                    .toplevel_events
+                    .push(ToplevelEvent::TitleChanged(Rc::downgrade(self)));
+            }
+            self.cur.borrow_mut().title = title;
         }
         if self.dirty_min_size.get() {
             self.dirty_min_size.set(false);
@@ -171,6 +315,10 @@ impl XdgToplevelRole {
             if surface.cur.borrow().buffer.is_some() {
                 return Err(io::Error::other("unmapped surface commited a buffer"));
             }
+            // Let the client pick the right buffer scale before the first buffer.
+            if let Some((output, _, _)) = cascade_position(state) {
+                surface.send_preferred_buffer_scale(state.output_layout.scale(output));
+            }
             let serial = self.cur_configure.get().serial + 1;
             self.wl.configure(0, 0, Vec::new());
             xdg_surface.wl.configure(serial);
@@ -180,6 +328,7 @@ impl XdgToplevelRole {
                 width: 0,
                 heinght: 0,
                 activated: false,
+                maximized: false,
             });
             surface.configured.set(true);
         } else if !surface.mapped.get() {
@@ -187,13 +336,10 @@ impl XdgToplevelRole {
                 return Err(io::Error::other("did not ack the initial config"));
             }
             if surface.cur.borrow().buffer.is_some() {
-                let (x, y) = state
-                    .focus_stack
-                    .top()
-                    .map(|t| (t.x.get() + 50, t.y.get() + 50))
-                    .unwrap_or((20, 20));
+                let (x, y) = cascade_position(state).map_or((0, 0), |(_, x, y)| (x, y));
                 self.x.set(x);
                 self.y.set(y);
+                self.mapped_at.set(Instant::now());
                 state.focus_stack.push(self);
                 surface.mapped.set(true);
             }
@@ -217,6 +363,37 @@ impl XdgToplevelRole {
     }
 }
 
+/// Where a new window is placed, and the output that is on. Windows cascade from the top window,
+/// starting over once that would leave the work area of its output. `None` if there are no
+/// outputs.
+fn cascade_position(state: &State) -> Option<(OutputId, i32, i32)> {
+    Some(match state.focus_stack.top() {
+        Some(top) => {
+            let output = top.output(state)?;
+            let area = state
+                .output_layout
+                .work_area(state.backend.as_ref(), output);
+            let (x, y) = (top.x.get() + 50, top.y.get() + 50);
+            if x < area.x + area.width as i32 && y < area.y + area.height as i32 {
+                (output, x, y)
+            } else {
+                (output, area.x + 20, area.y + 20)
+            }
+        }
+        None => {
+            let pointer = &state.seat.pointer;
+            let output = state
+                .output_layout
+                .output_at(state.backend.as_ref(), pointer.x, pointer.y)
+                .or_else(|| state.backend.outputs().first().copied())?;
+            let area = state
+                .output_layout
+                .work_area(state.backend.as_ref(), output);
+            (output, area.x + 20, area.y + 20)
+        }
+    })
+}
+
 #[derive(Default)]
 pub struct XdgToplevelState {
     pub app_id: Option<CString>,
@@ -255,14 +432,22 @@ fn xdg_toplevel_cb(ctx: RequestCtx<XdgToplevel>) -> io::Result<()> {
             toplevel.pending.borrow_mut().app_id = Some(app_id);
         }
         Request::ShowWindowMenu(_) => (),
-        Request::Move(_args) => {
+        Request::Move(args) => {
+            if !is_grab_serial(&ctx.proxy, &ctx.state.seat, args.serial) {
+                return Ok(());
+            }
             ctx.state.seat.pointer.start_move(toplevel.clone());
+            ctx.state.cursor.set_shape(Shape::Move);
         }
         Request::Resize(args) => {
+            if !is_grab_serial(&ctx.proxy, &ctx.state.seat, args.serial) {
+                return Ok(());
+            }
             ctx.state
                 .seat
                 .pointer
                 .start_resize(args.edges, toplevel.clone());
+            ctx.state.cursor.set_resize_shape(args.edges);
         }
         Request::SetMaxSize(args) => {
             if args.width < 0 || args.height < 0 {
@@ -278,11 +463,41 @@ fn xdg_toplevel_cb(ctx: RequestCtx<XdgToplevel>) -> io::Result<()> {
             toplevel.dirty_min_size.set(true);
             toplevel.pending.borrow_mut().min_size = Some((args.width as u32, args.height as u32));
         }
-        Request::SetMaximized => (),
-        Request::UnsetMaximized => (),
+        Request::SetMaximized => {
+            if surface.mapped.get() {
+                crate::maximize(ctx.state, toplevel);
+                ctx.state.repaint_all();
+            }
+        }
+        Request::UnsetMaximized => {
+            if surface.mapped.get() {
+                crate::unmaximize(toplevel);
+                ctx.state.repaint_all();
+            }
+        }
         Request::SetFullscreen(_) => (), // Note: update the wm_capabilities event when implemented
         Request::UnsetFullscreen => (),
-        Request::SetMinimized => (),
+        Request::SetMinimized => {
+            if surface.mapped.get() {
+                surface.repaint(ctx.state);
+                ctx.state
+                    .focus_stack
+                    .minimize(toplevel, &mut ctx.state.seat);
+            }
+        }
     }
     Ok(())
 }
+
+fn same_rect(a: &pixman::Rectangle32, b: &pixman::Rectangle32) -> bool {
+    (a.x, a.y, a.width, a.height) == (b.x, b.y, b.width, b.height)
+}
+
+/// Whether an interactive move or resize may start with the serial: it must be of the last button
+/// press or touch down sent to the client, and the button or touch point must still be down on
+/// one of its surfaces.
+fn is_grab_serial(wl: &XdgToplevel, seat: &Seat, serial: u32) -> bool {
+    let client_id = wl.client_id();
+    wl.conn().seat.is_pointer_press_serial(serial)
+        && (seat.pointer.is_pressed_on_client(client_id) || seat.touch.is_down_on_client(client_id))
+}
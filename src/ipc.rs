@@ -0,0 +1,473 @@
+//! A line-based control socket for external tools.
+//!
+//! Each request is a single line. Each reply consists of zero or more lines of output followed by
+//! either an `ok` line or a single `error: <message>` line.
+//!
+//! Clients which sent `subscribe` additionally receive `event\t<name>\t<args>...` lines whenever
+//! the window list, the focused window, a window's title or app id, or the active workspace
+//! changes.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::config::Config;
+use crate::focus_stack::WORKSPACES;
+use crate::globals::xdg_shell::toplevel::{ToplevelEvent, XdgToplevelRole};
+use crate::wallpaper::Wallpapers;
+use crate::{Proxy, State};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IpcClientId(u64);
+
+pub struct Ipc {
+    listener: UnixListener,
+    path: PathBuf,
+    next_client_id: IpcClientId,
+    clients: HashMap<IpcClientId, IpcClient>,
+    last_toplevels: Vec<String>,
+    last_focused: Option<(String, String)>,
+    last_workspace: u32,
+}
+
+struct IpcClient {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    /// Output which did not fit into the socket buffer yet.
+    out: Vec<u8>,
+    /// Whether the event loop reports the socket as writable, see `Ipc::write_interest_changes`.
+    wants_write: bool,
+    subscribed: bool,
+}
+
+/// How much output may be queued for a client which does not read it before it is disconnected.
+const MAX_QUEUED: usize = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Outputs,
+    Toplevels,
+    Focus(String),
+    Spawn(String),
+    Dpms(bool),
+    Message(String),
+    Reload,
+    Subscribe,
+}
+
+impl Ipc {
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        // A compositor which did not exit cleanly leaves its socket behind. Remove it, unless
+        // another compositor is still listening on it.
+        if UnixStream::connect(&path).is_err() {
+            let _ = std::fs::remove_file(&path);
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path,
+            next_client_id: IpcClientId(0),
+            clients: HashMap::new(),
+            last_toplevels: Vec::new(),
+            last_focused: None,
+            last_workspace: 0,
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Accept a new connection, returning its id and fd to be registered in the event loop.
+    pub fn accept(&mut self) -> io::Result<Option<(IpcClientId, RawFd)>> {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // Output which the client does not read right away is queued, so that a stuck client
+        // cannot block the compositor.
+        stream.set_nonblocking(true)?;
+        let id = self.next_client_id;
+        self.next_client_id = IpcClientId(id.0 + 1);
+        let fd = stream.as_raw_fd();
+        self.clients.insert(
+            id,
+            IpcClient {
+                stream,
+                buf: Vec::new(),
+                out: Vec::new(),
+                wants_write: false,
+                subscribed: false,
+            },
+        );
+        Ok(Some((id, fd)))
+    }
+
+    /// Write the queued output, then read from the client and return all complete lines received
+    /// so far.
+    ///
+    /// Returns `Ok(None)` if the client has disconnected.
+    pub fn read_lines(&mut self, id: IpcClientId) -> io::Result<Option<Vec<String>>> {
+        let client = self.clients.get_mut(&id).unwrap();
+        client.flush()?;
+        let mut chunk = [0u8; 1024];
+        let n = match client.stream.read(&mut chunk) {
+            Ok(n) => n,
+            // Woken up because the socket became writable.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Some(Vec::new())),
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            return Ok(None);
+        }
+        client.buf.extend_from_slice(&chunk[..n]);
+        let mut lines = Vec::new();
+        while let Some(eol) = client.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = client.buf.drain(..=eol).collect();
+            lines.push(String::from_utf8_lossy(&line[..eol]).into_owned());
+        }
+        if client.buf.len() > 4096 {
+            return Err(io::Error::other("request too long"));
+        }
+        Ok(Some(lines))
+    }
+
+    pub fn handle_request(
+        &mut self,
+        id: IpcClientId,
+        line: &str,
+        state: &mut State,
+    ) -> io::Result<()> {
+        let reply = match Command::parse(line) {
+            Ok(Command::Subscribe) => {
+                self.clients.get_mut(&id).unwrap().subscribed = true;
+                Ok(String::new())
+            }
+            Ok(command) => command.execute(state),
+            Err(e) => Err(e),
+        };
+        self.reply(id, reply)
+    }
+
+    fn reply(&mut self, id: IpcClientId, reply: Result<String, String>) -> io::Result<()> {
+        let client = self.clients.get_mut(&id).unwrap();
+        let msg = match reply {
+            Ok(output) => format!("{output}ok\n"),
+            Err(e) => format!("error: {e}\n"),
+        };
+        client.write(msg.as_bytes())
+    }
+
+    /// The clients which got output queued or fully written since the last call, with whether
+    /// the event loop should report their socket as writable now.
+    pub fn write_interest_changes(&mut self) -> Vec<(RawFd, bool)> {
+        let mut changes = Vec::new();
+        for client in self.clients.values_mut() {
+            let wants_write = !client.out.is_empty();
+            if wants_write != client.wants_write {
+                client.wants_write = wants_write;
+                changes.push((client.stream.as_raw_fd(), wants_write));
+            }
+        }
+        changes
+    }
+
+    /// Compare the window list with the one seen last time and notify subscribers about the
+    /// differences.
+    ///
+    /// Returns the subscribers which could not be written to.
+    pub fn send_events(&mut self, state: &State) -> Vec<IpcClientId> {
+        let ids: Vec<String> = state
+            .focus_stack
+            .inner()
+            .iter()
+            .map(|tl| toplevel_id(&tl.upgrade().unwrap()))
+            .collect();
+        let focused = state
+            .focus_stack
+            .top()
+            .map(|tl| (toplevel_id(&tl), field(tl.title())));
+        let workspace = state.focus_stack.workspace();
+
+        let mut events = String::new();
+        for id in &self.last_toplevels {
+            if !ids.contains(id) {
+                events.push_str(&format!("event\twindow_closed\t{id}\n"));
+            }
+        }
+        for id in &ids {
+            if !self.last_toplevels.contains(id) {
+                events.push_str(&format!("event\twindow_opened\t{id}\n"));
+            }
+        }
+        for event in &state.toplevel_events {
+            let (toplevel, name, value): (_, _, fn(&XdgToplevelRole) -> _) = match event {
+                ToplevelEvent::TitleChanged(tl) => (tl, "window_title", XdgToplevelRole::title),
+                ToplevelEvent::AppIdChanged(tl) => (tl, "window_app_id", XdgToplevelRole::app_id),
+            };
+            let Some(toplevel) = toplevel.upgrade() else { continue };
+            let id = toplevel_id(&toplevel);
+            // Unmapped windows are not announced yet.
+            if ids.contains(&id) {
+                events.push_str(&format!(
+                    "event\t{name}\t{id}\t{}\n",
+                    field(value(&toplevel))
+                ));
+            }
+        }
+        if workspace != self.last_workspace {
+            events.push_str(&format!("event\tworkspace\t{}\n", workspace + 1));
+        }
+        if focused != self.last_focused {
+            match &focused {
+                Some((id, title)) => {
+                    events.push_str(&format!("event\twindow_focused\t{id}\t{title}\n"))
+                }
+                None => events.push_str("event\twindow_focused\n"),
+            }
+        }
+        self.last_toplevels = ids;
+        self.last_focused = focused;
+        self.last_workspace = workspace;
+
+        if events.is_empty() {
+            return Vec::new();
+        }
+        let mut failed = Vec::new();
+        for (&id, client) in &mut self.clients {
+            if client.subscribed {
+                if let Err(e) = client.write(events.as_bytes()) {
+                    eprintln!("IPC client error: {e}");
+                    failed.push(id);
+                }
+            }
+        }
+        failed
+    }
+
+    /// Forget the client, returning its stream so that it can be removed from the event loop.
+    pub fn remove(&mut self, id: IpcClientId) -> UnixStream {
+        self.clients.remove(&id).unwrap().stream
+    }
+}
+
+impl IpcClient {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.out.len() + bytes.len() > MAX_QUEUED {
+            return Err(io::Error::other("the client does not read its output"));
+        }
+        self.out.extend_from_slice(bytes);
+        self.flush()
+    }
+
+    /// Write as much of the queued output as the socket takes.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Ipc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for Ipc {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match (cmd, args) {
+            ("outputs", "") => Ok(Self::Outputs),
+            ("toplevels", "") => Ok(Self::Toplevels),
+            ("reload", "") => Ok(Self::Reload),
+            ("subscribe", "") => Ok(Self::Subscribe),
+            ("focus", id) if !id.is_empty() => Ok(Self::Focus(id.to_owned())),
+            ("spawn", cmd) if !cmd.is_empty() => Ok(Self::Spawn(cmd.to_owned())),
+            ("dpms", "on") => Ok(Self::Dpms(true)),
+            ("dpms", "off") => Ok(Self::Dpms(false)),
+            ("dpms", _) => Err("'dpms' takes 'on' or 'off'".into()),
+            ("message", text) if !text.is_empty() => Ok(Self::Message(text.to_owned())),
+            ("outputs" | "toplevels" | "reload" | "subscribe", _) => {
+                Err(format!("'{cmd}' takes no arguments"))
+            }
+            ("focus" | "spawn" | "message", _) => Err(format!("'{cmd}' requires an argument")),
+            _ => Err(format!("unknown command '{cmd}'")),
+        }
+    }
+
+    pub fn execute(self, state: &mut State) -> Result<String, String> {
+        match self {
+            Self::Outputs => {
+                let mut output = String::new();
+                for (id, rect) in state.backend.output_geometries() {
+                    output.push_str(&format!(
+                        "{}\t{}\t{}\t{}\t{}\n",
+                        state.backend.output_name(id),
+                        rect.x,
+                        rect.y,
+                        rect.width,
+                        rect.height,
+                    ));
+                }
+                Ok(output)
+            }
+            Self::Toplevels => {
+                let mut output = String::new();
+                let top = state.focus_stack.top();
+                for toplevel in state.focus_stack.inner() {
+                    let toplevel = toplevel.upgrade().unwrap();
+                    let xdg_surface = toplevel.xdg_surface.upgrade().unwrap();
+                    let (width, height) = xdg_surface
+                        .get_window_geometry()
+                        .map_or((0, 0), |geom| (geom.width.get(), geom.height.get()));
+                    output.push_str(&format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                        toplevel_id(&toplevel),
+                        toplevel.x.get(),
+                        toplevel.y.get(),
+                        width,
+                        height,
+                        top.as_ref().is_some_and(|t| Rc::ptr_eq(t, &toplevel)) as u8,
+                        workspaces(toplevel.tags.get()),
+                        field(toplevel.app_id()),
+                        field(toplevel.title()),
+                    ));
+                }
+                Ok(output)
+            }
+            Self::Focus(id) => {
+                let i = state
+                    .focus_stack
+                    .inner()
+                    .iter()
+                    .position(|tl| toplevel_id(&tl.upgrade().unwrap()) == id)
+                    .ok_or_else(|| format!("no toplevel with id '{id}'"))?;
+                let toplevel = state.focus_stack.get_i(i).unwrap();
+                toplevel.minimized.set(false);
+                if !state.focus_stack.is_visible(&toplevel) {
+                    let workspace = toplevel.tags.get().trailing_zeros();
+                    state
+                        .focus_stack
+                        .switch_workspace(workspace, &mut state.seat);
+                }
+                state.focus_stack.focus_i(i, &mut state.seat);
+                Ok(String::new())
+            }
+            Self::Spawn(cmd) => {
+                crate::spawn_detached(&cmd).map_err(|e| format!("could not spawn '{cmd}': {e}"))?;
+                Ok(String::new())
+            }
+            Self::Dpms(on) => {
+                state.set_dpms(on);
+                Ok(String::new())
+            }
+            Self::Message(text) => {
+                state.overlay.show(state.backend.as_mut(), &text);
+                Ok(String::new())
+            }
+            Self::Reload => {
+                let config = Config::load().map_err(|e| e.to_string())?;
+                if config.xkb_layout != state.config.xkb_layout
+                    || config.xkb_options != state.config.xkb_options
+                {
+                    state
+                        .seat
+                        .keyboard
+                        .set_keymap(&config.xkb_layout, config.xkb_options.clone())
+                        .map_err(|e| e.to_string())?;
+                }
+                let old = std::mem::take(&mut state.wallpapers);
+                old.destroy(state.backend.as_mut());
+                state.wallpapers = Wallpapers::new(&config, state.backend.as_mut());
+                state.config = config;
+                Ok(String::new())
+            }
+            Self::Subscribe => unreachable!("handled by Ipc::handle_request"),
+        }
+    }
+}
+
+/// An identifier of a toplevel, unique among the currently existing toplevels.
+fn toplevel_id(toplevel: &XdgToplevelRole) -> String {
+    format!("{}:{}", toplevel.wl.client_id(), toplevel.wl.id().as_u32())
+}
+
+/// Format a workspace bitmask as a comma-separated list of 1-based workspace numbers.
+fn workspaces(tags: u32) -> String {
+    (0..WORKSPACES)
+        .filter(|i| tags & (1 << i) != 0)
+        .map(|i| (i + 1).to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Format a client-provided string so that it can't break the line-based protocol.
+fn field(s: Option<CString>) -> String {
+    s.map_or_else(String::new, |s| {
+        s.to_string_lossy().replace(['\t', '\n'], " ")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("outputs"), Ok(Command::Outputs));
+        assert_eq!(Command::parse(" toplevels \r"), Ok(Command::Toplevels));
+        assert_eq!(
+            Command::parse("focus 1:23"),
+            Ok(Command::Focus("1:23".into()))
+        );
+        assert_eq!(
+            Command::parse("spawn foot -e htop"),
+            Ok(Command::Spawn("foot -e htop".into()))
+        );
+        assert!(Command::parse("focus").is_err());
+        assert_eq!(Command::parse("subscribe"), Ok(Command::Subscribe));
+        assert!(Command::parse("reload now").is_err());
+        assert_eq!(Command::parse("dpms off"), Ok(Command::Dpms(false)));
+        assert!(Command::parse("dpms").is_err());
+        assert_eq!(
+            Command::parse("message volume 50%"),
+            Ok(Command::Message("volume 50%".into()))
+        );
+        assert!(Command::parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn bind_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("ewc-test-{}-stale.sock", std::process::id()));
+        // Dropping the listener leaves the socket file behind, like a crashed compositor.
+        drop(UnixListener::bind(&path).unwrap());
+        let ipc = Ipc::bind(path.clone()).unwrap();
+        // A live socket is not taken over.
+        assert!(Ipc::bind(path).is_err());
+        drop(ipc);
+    }
+}
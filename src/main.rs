@@ -4,13 +4,16 @@ use std::env;
 use std::io;
 use std::num::NonZeroU32;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use backend::InputTimestamp;
 use globals::single_pixel_buffer::SinglePixelBufferManager;
 use globals::xdg_shell::popup::XdgPopupRole;
+use globals::xdg_shell::toplevel::ToplevelEvent;
 use xkbcommon::xkb;
 
 mod backend;
@@ -21,27 +24,45 @@ mod cursor;
 mod event_loop;
 mod focus_stack;
 mod globals;
+mod ipc;
+mod output_layout;
+mod overlay;
 mod protocol;
 mod seat;
+#[cfg(test)]
+mod test_harness;
+mod wallpaper;
 mod wayland_core;
 
-use crate::backend::{Backend, BackendEvent, Color, RenderNode};
+use crate::backend::{Backend, BackendEvent, Color, CursorNode, OutputId, RenderNode};
 use crate::client::{Client, ClientId};
-use crate::config::Config;
+use crate::config::{Config, Renderer};
 use crate::cursor::Cursor;
-use crate::event_loop::EventLoop;
-use crate::focus_stack::FocusStack;
+use crate::event_loop::{EventLoop, Timer};
+use crate::focus_stack::{FocusStack, Layer, BORDER_WIDTH};
 use crate::globals::compositor::{Compositor, Surface};
 use crate::globals::ewc_debug::Debugger;
+use crate::globals::gamma_control::GammaControls;
+#[cfg(feature = "gl")]
 use crate::globals::linux_dmabuf::LinuxDmabuf;
+use crate::globals::output_management::OutputManagement;
+use crate::globals::security_context::{ListenerId, SecurityContext, SecurityContexts};
 use crate::globals::GlobalsManager;
+use crate::ipc::{Ipc, IpcClientId};
+use crate::output_layout::OutputLayout;
+use crate::overlay::Overlay;
 use crate::protocol::wp_cursor_shape_device_v1::Shape;
 use crate::protocol::xdg_toplevel::ResizeEdge;
 use crate::protocol::*;
+use crate::seat::keyboard::{KEY_1, KEY_9};
 use crate::seat::pointer::{PtrState, BTN_LEFT, BTN_RIGHT};
 use crate::seat::Seat;
+use crate::wallpaper::Wallpapers;
 use crate::wayland_core::*;
 
+/// How long it takes a window to fade in with `Config::window_fade_in`.
+const FADE_IN: Duration = Duration::from_millis(150);
+
 #[macro_export]
 macro_rules! debug {
     ($debugger:expr, $($fmt:tt)*) => {
@@ -58,6 +79,19 @@ pub struct Server {
     clients: HashMap<ClientId, Client>,
     next_client_id: ClientId,
     event_loop: EventLoop,
+    ipc: Option<Ipc>,
+    frame_timer: Timer,
+    /// Expires when the screensaver should be started or the outputs turned off, unless there was
+    /// input since it was armed.
+    idle_timer: Timer,
+    idle_timer_armed: bool,
+    screensaver: Option<std::process::Child>,
+    last_frame: HashMap<OutputId, Instant>,
+    /// Outputs whose frames were postponed by `max_fps` until `frame_timer` expires.
+    delayed_frames: Vec<OutputId>,
+    /// Outputs which skipped a frame because nothing changed. They do not produce frames until
+    /// `may_go_idle` repaints them.
+    idle_outputs: Vec<OutputId>,
     state: State,
 }
 
@@ -67,9 +101,124 @@ pub struct State {
     pub backend: Box<dyn Backend>,
     pub seat: Seat,
     pub cursor: Cursor,
+    pub wallpapers: Wallpapers,
     pub focus_stack: FocusStack,
     pub popup_stack: Vec<Rc<XdgPopupRole>>,
     pub debugger: Debugger,
+    pub output_management: OutputManagement,
+    pub gamma_controls: GammaControls,
+    pub output_layout: OutputLayout,
+    pub overlay: Overlay,
+    pub security_contexts: SecurityContexts,
+    /// Toplevel metadata changes since the last `Server::may_go_idle`.
+    pub toplevel_events: Vec<ToplevelEvent>,
+    /// Whether the outputs are turned on, see `State::set_dpms`.
+    pub dpms_on: bool,
+    /// When the user was last active, which is what the idle timeouts count from.
+    pub last_input: Instant,
+    /// Outputs on which anything visible changed since they were last repainted: a surface was
+    /// committed or unmapped, there was input, or an animation is running.
+    pub dirty_outputs: HashSet<OutputId>,
+}
+
+impl State {
+    /// Repaint every output, for changes which are not tied to a place, like focus changes.
+    pub fn repaint_all(&mut self) {
+        self.dirty_outputs
+            .extend(self.backend.outputs().iter().copied());
+    }
+
+    /// Repaint the outputs which intersect the rectangle, given in the global compositor space.
+    pub fn repaint_rect(&mut self, rect: &pixman::Rectangle32) {
+        for (output, geom) in self.backend.output_geometries() {
+            if rects_intersect(rect, &geom) {
+                self.dirty_outputs.insert(output);
+            }
+        }
+    }
+
+    /// Repaint the outputs the cursor is on.
+    pub fn repaint_cursor(&mut self) {
+        let x = self.seat.pointer.x.round() as i32;
+        let y = self.seat.pointer.y.round() as i32;
+        let rect = match self.cursor.get_buffer() {
+            Some((buf_transform, hx, hy)) => pixman::Rectangle32 {
+                x: x - hx,
+                y: y - hy,
+                width: buf_transform.dst_width(),
+                height: buf_transform.dst_height(),
+            },
+            None => pixman::Rectangle32 {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            },
+        };
+        self.repaint_rect(&rect);
+    }
+
+    /// Give keyboard focus to the topmost grabbing popup, or to the focused toplevel if there is
+    /// none.
+    pub fn focus_keyboard(&mut self) {
+        if let Some(popup) = self.popup_stack.iter().rev().find(|p| p.grab.get()) {
+            self.seat
+                .keyboard
+                .focus_surface(Some(popup.wl_surface.upgrade().unwrap().wl.clone()));
+        } else if let Some(toplevel) = self.focus_stack.top() {
+            self.seat
+                .keyboard
+                .focus_surface(Some(toplevel.wl_surface.upgrade().unwrap().wl.clone()));
+        }
+    }
+
+    /// Send the scale of the output each window is on to the window and its popups, for windows
+    /// which were mapped, moved to another output or whose output changed scale.
+    pub fn send_preferred_buffer_scales(&self) {
+        for toplevel in self.focus_stack.inner() {
+            let toplevel = toplevel.upgrade().unwrap();
+            let Some(output) = toplevel.output(self) else {
+                continue;
+            };
+            let scale = self.output_layout.scale(output);
+            toplevel
+                .wl_surface
+                .upgrade()
+                .unwrap()
+                .send_preferred_buffer_scale(scale);
+        }
+        for popup in &self.popup_stack {
+            if let Some(output) = popup.toplevel().and_then(|t| t.output(self)) {
+                let scale = self.output_layout.scale(output);
+                popup
+                    .wl_surface
+                    .upgrade()
+                    .unwrap()
+                    .send_preferred_buffer_scale(scale);
+            }
+        }
+    }
+
+    /// Turn all outputs on or off. Turning them on counts as activity, so that they are not
+    /// blanked again right away.
+    pub fn set_dpms(&mut self, on: bool) {
+        if on {
+            self.last_input = Instant::now();
+        }
+        if self.dpms_on == on {
+            return;
+        }
+        self.dpms_on = on;
+        for output in self.backend.outputs().to_vec() {
+            if let Err(e) = self.backend.set_dpms(output, on) {
+                let name = self.backend.output_name(output);
+                eprintln!(
+                    "could not turn {name} {}: {e}",
+                    if on { "on" } else { "off" }
+                );
+            }
+        }
+    }
 }
 
 #[derive(Default, Clone)]
@@ -81,23 +230,26 @@ impl ToFlushSet {
     }
 }
 
-fn choose_backend() -> Box<dyn Backend> {
-    if let Some(b) = backend::wayland::new() {
+fn choose_backend(renderer: Renderer) -> Box<dyn Backend> {
+    if let Some(b) = backend::wayland::new(renderer) {
         eprintln!("using wayland backend");
         return b;
     }
 
-    if let Some(b) = backend::drmkms::new() {
+    if let Some(b) = backend::drmkms::new(renderer) {
         eprintln!("using drmkms backend");
         return b;
     }
 
-    panic!("No backend available")
+    eprintln!("no backend available");
+    std::process::exit(1);
 }
 
 impl Server {
-    pub fn destroy_client(&mut self, client_id: ClientId) {
-        eprintln!("destroying client");
+    pub fn destroy_client(&mut self, client_id: ClientId, error: io::Error) {
+        eprintln!("{} disconnected: {error}", self.clients[&client_id]);
+        self.clients[&client_id].conn.post_error(&error);
+        self.state.repaint_all();
         self.state.cursor.remove_client(client_id);
         self.state.globals.remove_client(client_id);
         self.state.seat.remove_client(client_id);
@@ -106,36 +258,79 @@ impl Server {
             .popup_stack
             .retain(|x| x.wl.client_id() != client_id);
         self.state.debugger.remove_client(client_id);
+        self.state.output_management.remove_client(client_id);
+        self.state
+            .gamma_controls
+            .remove_client(client_id, self.state.backend.as_mut());
+        self.state.security_contexts.remove_client(client_id);
         let client = self.clients.remove(&client_id).unwrap();
         client.compositor.destroy(&mut self.state);
         client.shm.destroy(&mut self.state);
+        #[cfg(feature = "gl")]
         client.linux_dambuf.destroy(&mut self.state);
         self.event_loop.remove(client.conn.as_raw_fd()).unwrap();
     }
 
-    pub fn new(socket_path: PathBuf) -> Self {
+    pub fn new(socket_path: PathBuf, ipc_path: PathBuf) -> Self {
         let config = Config::new();
-        let mut backend = choose_backend();
+        let backend = choose_backend(config.renderer);
+        Self::with_backend(backend, config, socket_path, ipc_path)
+    }
+
+    pub fn with_backend(
+        mut backend: Box<dyn Backend>,
+        config: Config,
+        socket_path: PathBuf,
+        ipc_path: PathBuf,
+    ) -> Self {
         let socket = UnixListener::bind(&socket_path).unwrap();
         socket.set_nonblocking(true).unwrap();
         let mut event_loop = EventLoop::new().unwrap();
+        let dirty_outputs = backend.outputs().iter().copied().collect();
         event_loop
             .add_fd(socket.as_raw_fd(), event_loop::Event::Socket)
             .unwrap();
+        let frame_timer = Timer::new().unwrap();
+        event_loop
+            .add_fd(frame_timer.as_raw_fd(), event_loop::Event::FrameTimer)
+            .unwrap();
+        let idle_timer = Timer::new().unwrap();
+        event_loop
+            .add_fd(idle_timer.as_raw_fd(), event_loop::Event::IdleTimer)
+            .unwrap();
+        let ipc = match Ipc::bind(ipc_path) {
+            Ok(ipc) => {
+                event_loop
+                    .add_fd(ipc.as_raw_fd(), event_loop::Event::IpcSocket)
+                    .unwrap();
+                Some(ipc)
+            }
+            Err(e) => {
+                eprintln!("could not create IPC socket: {e}");
+                None
+            }
+        };
         backend
             .register_fds_with(&mut |fd, data| {
                 event_loop.add_fd(fd, event_loop::Event::Backend(data))
             })
             .unwrap();
         let cursor = Cursor::new(backend.as_mut());
+        let wallpapers = Wallpapers::new(&config, backend.as_mut());
         let mut globals = GlobalsManager::default();
         Compositor::register_globals(&mut globals);
         Seat::register_globals(&mut globals);
         SinglePixelBufferManager::register_global(&mut globals);
         globals::cursor_shape::register_global(&mut globals);
+        globals::output_management::register_global(&mut globals);
+        globals::gamma_control::register_global(&mut globals);
+        globals::security_context::register_global(&mut globals);
         globals.add_global::<WlShm>(1);
-        globals.add_global::<WlOutput>(2);
-        globals.add_global::<EwcDebugV1>(1);
+        for &output in backend.outputs() {
+            globals.add_output_global(output, 4);
+        }
+        globals.add_privileged_global::<EwcDebugV1>(4);
+        #[cfg(feature = "gl")]
         if backend
             .renderer_state()
             .supported_dma_buf_formats()
@@ -150,14 +345,32 @@ impl Server {
             clients: HashMap::new(),
             next_client_id: ClientId::first(),
             event_loop,
+            ipc,
+            frame_timer,
+            idle_timer,
+            idle_timer_armed: false,
+            screensaver: None,
+            last_frame: HashMap::new(),
+            delayed_frames: Vec::new(),
+            idle_outputs: Vec::new(),
             state: State {
                 globals,
                 backend,
                 cursor,
+                wallpapers,
                 seat: Seat::new(&config),
                 focus_stack: FocusStack::default(),
                 popup_stack: Vec::new(),
                 debugger: Debugger::default(),
+                output_management: OutputManagement::default(),
+                gamma_controls: GammaControls::default(),
+                output_layout: OutputLayout::default(),
+                overlay: Overlay::default(),
+                security_contexts: SecurityContexts::default(),
+                toplevel_events: Vec::new(),
+                dpms_on: true,
+                last_input: Instant::now(),
+                dirty_outputs,
                 config,
             },
         }
@@ -166,6 +379,20 @@ impl Server {
 
 fn render_surface(render_list: &mut Vec<RenderNode>, surf: &Surface, alpha: f32, x: i32, y: i32) {
     let Some(buf_transform) = surf.buf_transform() else { return };
+    let (below, above) = {
+        let cur = surf.cur.borrow();
+        let (below, above) = cur.subsurfaces_split();
+        (below.to_vec(), above.to_vec())
+    };
+    for sub in &below {
+        render_surface(
+            render_list,
+            &sub.surface,
+            alpha,
+            x + sub.position.0,
+            y + sub.position.1,
+        );
+    }
     let mut cur = surf.cur.borrow_mut();
     render_list.push(RenderNode::Buffer {
         x,
@@ -175,39 +402,442 @@ fn render_surface(render_list: &mut Vec<RenderNode>, surf: &Surface, alpha: f32,
         buf_transform,
         frame_callbacks: std::mem::take(&mut cur.frame_cbs),
     });
-    for sub in &cur.subsurfaces.clone() {
-        let position = sub.position;
+    for sub in &above {
         render_surface(
             render_list,
             &sub.surface,
             alpha,
-            x + position.0,
-            y + position.1,
+            x + sub.position.0,
+            y + sub.position.1,
         );
     }
     if let Some(xdg) = surf.get_xdg_surface() {
         if let Some(popup) = &*xdg.popup.borrow() {
-            let parent = popup.parent.upgrade().unwrap();
-            let parent_geom = parent.get_window_geometry().unwrap();
-            let geom = popup
-                .xdg_surface
-                .upgrade()
-                .unwrap()
-                .get_window_geometry()
-                .unwrap();
+            let (dx, dy) = popup.offset().unwrap();
             render_surface(
                 render_list,
                 &popup.wl_surface.upgrade().unwrap(),
                 alpha,
-                x + parent_geom.x + popup.x.get() - geom.x,
-                y + parent_geom.y + popup.y.get() - geom.y,
+                x + dx,
+                y + dy,
             );
         }
     }
 }
 
+fn rects_intersect(a: &pixman::Rectangle32, b: &pixman::Rectangle32) -> bool {
+    a.x < b.x + b.width as i32
+        && b.x < a.x + a.width as i32
+        && a.y < b.y + b.height as i32
+        && b.y < a.y + a.height as i32
+}
+
+/// Where to put a window being moved to `x, y`, so that its edges snap to the edges of the work
+/// areas and of other windows within `Config::snap_distance` pixels. Windows snap next to each
+/// other with their borders touching.
+fn snap_position(state: &State, toplevel: &Rc<XdgToplevelRole>, x: i32, y: i32) -> (i32, i32) {
+    let distance = state.config.snap_distance as i32;
+    let Some(geom) = toplevel
+        .xdg_surface
+        .upgrade()
+        .unwrap()
+        .get_window_geometry()
+    else {
+        return (x, y);
+    };
+    let (w, h) = (geom.width.get() as i32, geom.height.get() as i32);
+    let b = BORDER_WIDTH as i32;
+
+    // Candidate positions of the window along each axis.
+    let mut xs = Vec::new();
+    let mut ys = Vec::new();
+    for (output, _) in state.backend.output_geometries() {
+        let area = state
+            .output_layout
+            .work_area(state.backend.as_ref(), output);
+        xs.extend([area.x + b, area.x + area.width as i32 - w - b]);
+        ys.extend([area.y + b, area.y + area.height as i32 - h - b]);
+    }
+    for other in state.focus_stack.visible() {
+        if Rc::ptr_eq(&other, toplevel) {
+            continue;
+        }
+        let Some(other_geom) = other.xdg_surface.upgrade().unwrap().get_window_geometry() else {
+            continue;
+        };
+        let (ox, oy) = (other.x.get(), other.y.get());
+        let (ow, oh) = (
+            other_geom.width.get() as i32,
+            other_geom.height.get() as i32,
+        );
+        // Only snap to windows which are (almost) next to each other.
+        if y < oy + oh + distance && oy < y + h + distance {
+            xs.extend([ox, ox + ow - w, ox + ow + 2 * b, ox - w - 2 * b]);
+        }
+        if x < ox + ow + distance && ox < x + w + distance {
+            ys.extend([oy, oy + oh - h, oy + oh + 2 * b, oy - h - 2 * b]);
+        }
+    }
+
+    let snap = |pos: i32, candidates: Vec<i32>| {
+        candidates
+            .into_iter()
+            .filter(|c| (c - pos).abs() <= distance)
+            .min_by_key(|c| (c - pos).abs())
+            .unwrap_or(pos)
+    };
+    (snap(x, xs), snap(y, ys))
+}
+
+/// The direction of an arrow key.
+fn arrow_direction(keysym: xkb::Keysym) -> Option<(i32, i32)> {
+    match keysym {
+        xkb::Keysym::Left => Some((-1, 0)),
+        xkb::Keysym::Right => Some((1, 0)),
+        xkb::Keysym::Up => Some((0, -1)),
+        xkb::Keysym::Down => Some((0, 1)),
+        _ => None,
+    }
+}
+
+/// The area a window dragged with the pointer at `x, y` is tiled to, if the pointer is at the top
+/// (the whole work area), left or right (a half of it) edge of an output. Edges shared with another
+/// output do not count. The returned area does not include the window borders, and is paired with
+/// whether it maximizes the window.
+fn tile_area(state: &State, x: f32, y: f32) -> Option<(pixman::Rectangle32, bool)> {
+    let backend = state.backend.as_ref();
+    let at_edge = |dx: f32, dy: f32| {
+        state
+            .output_layout
+            .output_at(backend, x + dx, y + dy)
+            .is_none()
+    };
+    let output = state.output_layout.output_at(backend, x, y)?;
+    let area = state.output_layout.work_area(backend, output);
+    let half = area.width / 2;
+    let (rect, maximized) = if at_edge(-1.0, 0.0) {
+        let rect = pixman::Rectangle32 {
+            width: half,
+            ..area
+        };
+        (rect, false)
+    } else if at_edge(1.0, 0.0) {
+        let rect = pixman::Rectangle32 {
+            x: area.x + half as i32,
+            width: area.width - half,
+            ..area
+        };
+        (rect, false)
+    } else if at_edge(0.0, -1.0) {
+        (area, true)
+    } else {
+        return None;
+    };
+    Some((inside_borders(rect), maximized))
+}
+
+/// The window geometry which makes a window together with its borders cover `rect`.
+fn inside_borders(rect: pixman::Rectangle32) -> pixman::Rectangle32 {
+    pixman::Rectangle32 {
+        x: rect.x + BORDER_WIDTH as i32,
+        y: rect.y + BORDER_WIDTH as i32,
+        width: rect.width.saturating_sub(2 * BORDER_WIDTH),
+        height: rect.height.saturating_sub(2 * BORDER_WIDTH),
+    }
+}
+
+/// Make the toplevel cover the work area of its output.
+fn maximize(state: &State, toplevel: &XdgToplevelRole) {
+    let Some(output) = toplevel.output(state) else {
+        return;
+    };
+    let area = state
+        .output_layout
+        .work_area(state.backend.as_ref(), output);
+    toplevel.tile(inside_borders(area), true);
+}
+
+/// Put a maximized toplevel back where it was, with the size it had.
+fn unmaximize(toplevel: &XdgToplevelRole) {
+    if toplevel.is_maximized() {
+        if let Some(untiled) = toplevel.untiled_geometry() {
+            toplevel.untile(untiled.x, untiled.y);
+        }
+    }
+}
+
+/// Maximize the toplevel, or restore it if it is tiled.
+fn toggle_maximized(state: &State, toplevel: &XdgToplevelRole) {
+    match toplevel.untiled_geometry() {
+        Some(untiled) => toplevel.untile(untiled.x, untiled.y),
+        None => maximize(state, toplevel),
+    }
+}
+
 impl Server {
+    /// Render a frame on the output, or postpone it if that would exceed `max_fps`.
+    fn frame(&mut self, output: OutputId) {
+        if !self.state.dirty_outputs.contains(&output) || !self.state.debugger.may_render(output) {
+            // Not rendering also means not asking for the next frame.
+            if !self.idle_outputs.contains(&output) {
+                self.idle_outputs.push(output);
+            }
+            return;
+        }
+        self.state.dirty_outputs.remove(&output);
+        if let (Some(max_fps), Some(last)) =
+            (self.state.config.max_fps, self.last_frame.get(&output))
+        {
+            let remaining = (Duration::from_secs(1) / max_fps).saturating_sub(last.elapsed());
+            if !remaining.is_zero() {
+                if self.delayed_frames.is_empty() {
+                    self.frame_timer.set(remaining).unwrap();
+                }
+                self.delayed_frames.push(output);
+                return;
+            }
+        }
+        self.last_frame.insert(output, Instant::now());
+        self.render_output(output);
+    }
+
+    /// Start the screensaver and turn the outputs off if there was no input for long enough.
+    fn idle_timer_expired(&mut self) {
+        self.idle_timer.clear().unwrap();
+        self.idle_timer_armed = false;
+        let idle = self.state.last_input.elapsed();
+        // A screensaver which exited by itself is reaped, so that it can be started again.
+        if let Some(child) = &mut self.screensaver {
+            if !matches!(child.try_wait(), Ok(None)) {
+                self.screensaver = None;
+            }
+        }
+        if let Some(screensaver) = &self.state.config.screensaver {
+            if self.screensaver.is_none() && idle >= Duration::from_secs(screensaver.timeout.into())
+            {
+                // In its own process group, so that the processes it starts can be killed too.
+                match std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&screensaver.command)
+                    .process_group(0)
+                    .spawn()
+                {
+                    Ok(child) => self.screensaver = Some(child),
+                    Err(e) => eprintln!("could not spawn '{}': {e}", screensaver.command),
+                }
+            }
+        }
+        if let Some(timeout) = self.state.config.blank_timeout {
+            if idle >= Duration::from_secs(timeout.into()) {
+                self.state.set_dpms(false);
+            }
+        }
+        self.arm_idle_timer();
+    }
+
+    /// Arm the idle timer for the earliest idle action which is still ahead. Actions whose timeout
+    /// has passed already happened, or wait for the next input.
+    fn arm_idle_timer(&mut self) {
+        if self.idle_timer_armed {
+            return;
+        }
+        let config = &self.state.config;
+        let idle = self.state.last_input.elapsed();
+        let next = [
+            config.screensaver.as_ref().map(|s| s.timeout),
+            config.blank_timeout,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|timeout| Duration::from_secs(timeout.into()))
+        .filter(|&timeout| timeout > idle)
+        .min();
+        if let Some(timeout) = next {
+            self.idle_timer.set(timeout - idle).unwrap();
+            self.idle_timer_armed = true;
+        }
+    }
+
+    /// Kill the screensaver and everything it started, if it is running.
+    fn dismiss_screensaver(&mut self) {
+        if let Some(mut child) = self.screensaver.take() {
+            // It may have exited already, which is fine.
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+        }
+    }
+
+    fn frame_timer_expired(&mut self) {
+        self.frame_timer.clear().unwrap();
+        for output in std::mem::take(&mut self.delayed_frames) {
+            self.last_frame.insert(output, Instant::now());
+            self.render_output(output);
+        }
+    }
+
+    fn render_output(&mut self, output: OutputId) {
+        let t = std::time::Instant::now();
+        let output_rect = self.state.backend.output_geometry(output);
+        let (ox, oy) = (output_rect.x, output_rect.y);
+        let mut render_list = Vec::new();
+        render_list.extend(
+            self.state
+                .wallpapers
+                .render_node(self.state.backend.as_mut(), output),
+        );
+        let mut animating = false;
+        let top = self.state.focus_stack.top();
+        for toplevel in self.state.focus_stack.visible() {
+            let xdg_surface = toplevel.xdg_surface.upgrade().unwrap();
+            let is_top = top.as_ref().is_some_and(|top| Rc::ptr_eq(top, &toplevel));
+            let mut alpha = if is_top { 1.0 } else { 0.8 };
+            let mut fading = false;
+            if self.state.config.window_fade_in {
+                let t = toplevel.mapped_at.get().elapsed().as_secs_f32() / FADE_IN.as_secs_f32();
+                alpha *= t.min(1.0);
+                fading = t < 1.0;
+            }
+            if let Some(geom) = xdg_surface.get_window_geometry() {
+                let wl_surface = xdg_surface.wl_surface.upgrade().unwrap();
+                let surf_x = toplevel.x.get() - geom.x;
+                let surf_y = toplevel.y.get() - geom.y;
+                let b = BORDER_WIDTH as i32;
+                let window_rect = pixman::Rectangle32 {
+                    x: toplevel.x.get() - b,
+                    y: toplevel.y.get() - b,
+                    width: geom.width.get() + 2 * BORDER_WIDTH,
+                    height: geom.height.get() + 2 * BORDER_WIDTH,
+                };
+                let on_output = rects_intersect(&window_rect, &output_rect)
+                    || wl_surface.get_bounding_box().is_some_and(|bbox| {
+                        rects_intersect(
+                            &pixman::Rectangle32 {
+                                x: surf_x + bbox.x1,
+                                y: surf_y + bbox.y1,
+                                width: (bbox.x2 - bbox.x1) as u32,
+                                height: (bbox.y2 - bbox.y1) as u32,
+                            },
+                            &output_rect,
+                        )
+                    });
+                if !on_output {
+                    continue;
+                }
+                animating |= fading;
+                let border_color = if is_top {
+                    Color::from_rgba(1.0, 0.0, 0.0, 1.0) * alpha
+                } else {
+                    Color::from_rgba(0.2, 0.2, 0.2, 1.0) * alpha
+                };
+                let x = toplevel.x.get() - ox;
+                let y = toplevel.y.get() - oy;
+                render_list.push(RenderNode::Rect(
+                    pixman::Rectangle32 {
+                        x: x - b,
+                        y: y - b,
+                        width: BORDER_WIDTH,
+                        height: geom.height.get() + 2 * BORDER_WIDTH,
+                    },
+                    border_color,
+                ));
+                render_list.push(RenderNode::Rect(
+                    pixman::Rectangle32 {
+                        x: x + geom.width.get() as i32,
+                        y: y - b,
+                        width: BORDER_WIDTH,
+                        height: geom.height.get() + 2 * BORDER_WIDTH,
+                    },
+                    border_color,
+                ));
+                render_list.push(RenderNode::Rect(
+                    pixman::Rectangle32 {
+                        x,
+                        y: y - b,
+                        width: geom.width.get(),
+                        height: BORDER_WIDTH,
+                    },
+                    border_color,
+                ));
+                render_list.push(RenderNode::Rect(
+                    pixman::Rectangle32 {
+                        x,
+                        y: y + geom.height.get() as i32,
+                        width: geom.width.get(),
+                        height: BORDER_WIDTH,
+                    },
+                    border_color,
+                ));
+                render_surface(
+                    &mut render_list,
+                    &wl_surface,
+                    alpha,
+                    surf_x - ox,
+                    surf_y - oy,
+                );
+            }
+        }
+        render_list.extend(
+            self.state
+                .overlay
+                .render_node(self.state.backend.as_mut(), output),
+        );
+        animating |= self.state.overlay.is_visible();
+        let cursor = self
+            .state
+            .cursor
+            .get_buffer()
+            .map(|(buf_transform, hx, hy)| CursorNode {
+                x: self.state.seat.pointer.x.round() as i32 - hx - ox,
+                y: self.state.seat.pointer.y.round() as i32 - hy - oy,
+                buf_transform,
+            });
+        debug!(
+            self.state.debugger,
+            "prepared render list of {} nodes in {:?}",
+            render_list.len(),
+            t.elapsed()
+        );
+        let bg_color = self
+            .state
+            .config
+            .output
+            .get(self.state.backend.output_name(output))
+            .and_then(|o| o.bg_color)
+            .unwrap_or(self.state.config.bg_color);
+        let t = std::time::Instant::now();
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u32;
+        self.state.backend.render_frame(
+            output,
+            Color::from_rgba(bg_color.0, bg_color.1, bg_color.2, 1.0),
+            &render_list,
+            cursor.as_ref(),
+            time,
+        );
+        if cursor.is_some() {
+            for cb in self.state.cursor.take_frame_callbacks() {
+                cb.done(time);
+            }
+        }
+        let gpu_time = self.state.backend.renderer_state().last_frame_gpu_time();
+        self.state.debugger.frame(t.elapsed(), gpu_time);
+        if animating {
+            self.state.dirty_outputs.insert(output);
+        }
+    }
+
     fn pointer_moved(&mut self, timestamp: InputTimestamp) {
+        self.state.repaint_cursor();
+        if matches!(self.state.seat.pointer.state, PtrState::Moving { .. }) {
+            self.state.repaint_all();
+        }
+        if self.state.seat.touch.is_down() {
+            let (x, y) = (self.state.seat.pointer.x, self.state.seat.pointer.y);
+            self.state.seat.touch.motion(timestamp, x, y);
+            return;
+        }
         match &self.state.seat.pointer.state {
             PtrState::Moving {
                 toplevel,
@@ -217,12 +847,28 @@ impl Server {
                 toplevel_start_y: ty,
             } => {
                 let toplevel = toplevel.upgrade().unwrap();
-                toplevel
-                    .x
-                    .set(tx + (self.state.seat.pointer.x - px).round() as i32);
-                toplevel
-                    .y
-                    .set(ty + (self.state.seat.pointer.y - py).round() as i32);
+                let (ptr_x, ptr_y) = (self.state.seat.pointer.x, self.state.seat.pointer.y);
+                let tile = if self.state.config.edge_tiling {
+                    tile_area(&self.state, ptr_x, ptr_y)
+                } else {
+                    None
+                };
+                if let Some((area, maximized)) = tile {
+                    toplevel.tile(area, maximized);
+                } else if let Some(untiled) = toplevel.untiled_geometry() {
+                    // Keep the restored window under the pointer and continue the move from here.
+                    let x = (ptr_x - untiled.width as f32 / 2.0).round() as i32;
+                    toplevel.untile(x, toplevel.y.get());
+                    self.state.seat.pointer.start_move(toplevel);
+                } else {
+                    let mut x = tx + (ptr_x - px).round() as i32;
+                    let mut y = ty + (ptr_y - py).round() as i32;
+                    if self.state.config.snap_distance > 0 {
+                        (x, y) = snap_position(&self.state, &toplevel, x, y);
+                    }
+                    toplevel.x.set(x);
+                    toplevel.y.set(y);
+                }
             }
             PtrState::Resizing {
                 toplevel,
@@ -263,8 +909,10 @@ impl Server {
                 {
                     let surf = self.state.seat.pointer.get_focused_surface().unwrap();
                     let (x, y) = surf.get_pos().unwrap();
-                    let sx = self.state.seat.pointer.x.round() - x as f32;
-                    let sy = self.state.seat.pointer.y.round() - y as f32;
+                    // Not rounded: clients which scale their buffers with a viewport need
+                    // the sub-pixel part to hit the right widget.
+                    let sx = self.state.seat.pointer.x - x as f32;
+                    let sy = self.state.seat.pointer.y - y as f32;
                     self.state
                         .seat
                         .pointer
@@ -274,6 +922,29 @@ impl Server {
                     .focus_stack
                     .surface_at(self.state.seat.pointer.x, self.state.seat.pointer.y)
                 {
+                    if self.state.config.focus_follows_mouse {
+                        let old_top = self.state.focus_stack.top();
+                        self.state
+                            .focus_stack
+                            .set_keyboard_focus_i(surf_under.toplevel_idx, &mut self.state.seat);
+                        if !Option::zip(old_top, self.state.focus_stack.top())
+                            .is_some_and(|(old, new)| Rc::ptr_eq(&old, &new))
+                        {
+                            // The borders of both windows change.
+                            self.state.repaint_all();
+                        }
+                    }
+                    let entering = !self
+                        .state
+                        .seat
+                        .pointer
+                        .get_focused_surface()
+                        .is_some_and(|focused| Rc::ptr_eq(&focused, &surf_under.surf));
+                    if entering {
+                        // Clients set their cursor in response to `enter`, which a client that
+                        // hid its cursor must not decide for them.
+                        self.state.cursor.set_shape(Shape::Default);
+                    }
                     self.state.seat.pointer.forward_pointer(
                         surf_under.surf,
                         timestamp,
@@ -288,107 +959,166 @@ impl Server {
         }
     }
 
+    /// Send the configure events and flush the clients once all pending events are processed.
+    fn may_go_idle(&mut self) {
+        let top = self.state.focus_stack.top();
+        for toplevel in self.state.focus_stack.inner() {
+            let toplevel = toplevel.upgrade().unwrap();
+            toplevel.set_activated(top.as_ref().is_some_and(|t| Rc::ptr_eq(t, &toplevel)));
+            toplevel.apply_pending_configure();
+        }
+        self.state.send_preferred_buffer_scales();
+
+        self.ipc_send_events();
+        self.state.toplevel_events.clear();
+        self.state.debugger.report_counters(self.clients.len());
+
+        self.arm_idle_timer();
+
+        // Outputs which are still not dirty go back to the list.
+        for output in std::mem::take(&mut self.idle_outputs) {
+            self.frame(output);
+        }
+
+        for client_id in self.to_flush_set.clone().0.borrow_mut().drain() {
+            if let Some(client) = self.clients.get(&client_id) {
+                if let Err(e) = client.conn.flush() {
+                    self.destroy_client(client_id, e);
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, stream: UnixStream, security_context: Option<Rc<SecurityContext>>) {
+        let id = self.next_client_id;
+        self.next_client_id = id.next();
+        let mut client = Client::new(stream, id, self.to_flush_set.clone());
+        eprintln!("new {client}");
+        if let Some(context) = &security_context {
+            eprintln!(
+                "client is sandboxed (engine {:?}, app id {:?}, instance id {:?})",
+                context.sandbox_engine, context.app_id, context.instance_id
+            );
+        }
+        client.security_context = security_context;
+        self.event_loop
+            .add_fd(client.conn.as_raw_fd(), event_loop::Event::Client(id))
+            .unwrap();
+        self.clients.insert(id, client);
+    }
+
+    fn register_security_context_listeners(&mut self) {
+        for id in self.state.security_contexts.take_new_listeners() {
+            let (socket, close_fd) = self.state.security_contexts.fds(id).unwrap();
+            self.event_loop
+                .add_fd(socket, event_loop::Event::SecurityContextSocket(id))
+                .unwrap();
+            self.event_loop
+                .add_fd(close_fd, event_loop::Event::SecurityContextClosed(id))
+                .unwrap();
+        }
+    }
+
+    fn security_context_accept(&mut self, id: ListenerId) {
+        match self.state.security_contexts.accept(id) {
+            Ok(None) => (),
+            Ok(Some((stream, context))) => self.add_client(stream, Some(context)),
+            Err(e) => {
+                eprintln!("security context socket error: {e}");
+                self.security_context_remove(id);
+            }
+        }
+    }
+
+    fn security_context_remove(&mut self, id: ListenerId) {
+        let Some((socket, close_fd)) = self.state.security_contexts.fds(id) else { return };
+        self.event_loop.remove(socket).unwrap();
+        self.event_loop.remove(close_fd).unwrap();
+        self.state.security_contexts.remove(id);
+    }
+
+    fn ipc_accept(&mut self) {
+        let Some(ipc) = &mut self.ipc else { return };
+        match ipc.accept() {
+            Ok(None) => (),
+            Ok(Some((id, fd))) => self
+                .event_loop
+                .add_fd(fd, event_loop::Event::IpcClient(id))
+                .unwrap(),
+            Err(e) => eprintln!("IPC socket error: {e}"),
+        }
+    }
+
+    fn ipc_poll(&mut self, id: IpcClientId) {
+        let ipc = self.ipc.as_mut().unwrap();
+        let result = ipc.read_lines(id).and_then(|lines| {
+            let Some(lines) = lines else { return Ok(false) };
+            self.state.repaint_all();
+            for line in lines {
+                ipc.handle_request(id, &line, &mut self.state)?;
+            }
+            Ok(true)
+        });
+        match result {
+            Ok(true) => return self.ipc_update_write_interest(),
+            Ok(false) => (),
+            Err(e) => eprintln!("IPC client error: {e}"),
+        }
+        self.ipc_remove(id);
+    }
+
+    /// Wait for the sockets of IPC clients with queued output to become writable.
+    fn ipc_update_write_interest(&mut self) {
+        let Some(ipc) = &mut self.ipc else { return };
+        for (fd, writable) in ipc.write_interest_changes() {
+            self.event_loop.set_writable(fd, writable).unwrap();
+        }
+    }
+
+    fn ipc_remove(&mut self, id: IpcClientId) {
+        let stream = self.ipc.as_mut().unwrap().remove(id);
+        self.event_loop.remove(stream.as_raw_fd()).unwrap();
+    }
+
+    fn ipc_send_events(&mut self) {
+        let Some(ipc) = &mut self.ipc else { return };
+        for id in ipc.send_events(&self.state) {
+            self.ipc_remove(id);
+        }
+        self.ipc_update_write_interest();
+    }
+
     fn poll_backend(&mut self, backend_data: u32) -> io::Result<()> {
         self.state.backend.poll(backend_data)?;
         while let Some(event) = self.state.backend.next_event() {
+            if matches!(
+                event,
+                BackendEvent::KeyPressed(..)
+                    | BackendEvent::KeyReleased(..)
+                    | BackendEvent::PointerMotionAbsolute(..)
+                    | BackendEvent::PointerMotionRelative(..)
+                    | BackendEvent::PointerBtnPress(..)
+                    | BackendEvent::PointerBtnRelease(..)
+                    | BackendEvent::PointerAxis(..)
+            ) {
+                self.state.last_input = Instant::now();
+                // Pointer motion only affects the outputs the cursor is on, see `pointer_moved`.
+                if !matches!(
+                    event,
+                    BackendEvent::PointerMotionAbsolute(..)
+                        | BackendEvent::PointerMotionRelative(..)
+                ) {
+                    self.state.repaint_all();
+                }
+                self.state.set_dpms(true);
+                self.dismiss_screensaver();
+            }
             match event {
                 BackendEvent::ShutDown => return Err(io::Error::other("backend shutdown")),
-                BackendEvent::Frame => {
-                    let t = std::time::Instant::now();
-                    let mut render_list = Vec::new();
-                    for (toplevel_i, toplevel) in self.state.focus_stack.inner().iter().enumerate()
-                    {
-                        let toplevel = toplevel.upgrade().unwrap();
-                        let xdg_surface = toplevel.xdg_surface.upgrade().unwrap();
-                        let alpha = if toplevel_i == self.state.focus_stack.inner().len() - 1 {
-                            1.0
-                        } else {
-                            0.8
-                        };
-                        if let Some(geom) = xdg_surface.get_window_geometry() {
-                            let border_color =
-                                if toplevel_i == self.state.focus_stack.inner().len() - 1 {
-                                    Color::from_rgba(1.0, 0.0, 0.0, 1.0)
-                                } else {
-                                    Color::from_rgba(0.2, 0.2, 0.2, 1.0) * alpha
-                                };
-                            render_list.push(RenderNode::Rect(
-                                pixman::Rectangle32 {
-                                    x: toplevel.x.get() - 2,
-                                    y: toplevel.y.get() - 2,
-                                    width: 2,
-                                    height: geom.height.get() + 4,
-                                },
-                                border_color,
-                            ));
-                            render_list.push(RenderNode::Rect(
-                                pixman::Rectangle32 {
-                                    x: toplevel.x.get() + geom.width.get() as i32,
-                                    y: toplevel.y.get() - 2,
-                                    width: 2,
-                                    height: geom.height.get() + 4,
-                                },
-                                border_color,
-                            ));
-                            render_list.push(RenderNode::Rect(
-                                pixman::Rectangle32 {
-                                    x: toplevel.x.get(),
-                                    y: toplevel.y.get() - 2,
-                                    width: geom.width.get(),
-                                    height: 2,
-                                },
-                                border_color,
-                            ));
-                            render_list.push(RenderNode::Rect(
-                                pixman::Rectangle32 {
-                                    x: toplevel.x.get(),
-                                    y: toplevel.y.get() + geom.height.get() as i32,
-                                    width: geom.width.get(),
-                                    height: 2,
-                                },
-                                border_color,
-                            ));
-                            render_surface(
-                                &mut render_list,
-                                &xdg_surface.wl_surface.upgrade().unwrap(),
-                                alpha,
-                                toplevel.x.get() - geom.x,
-                                toplevel.y.get() - geom.y,
-                            );
-                        }
-                    }
-                    if let Some((buf_transform, hx, hy)) = self.state.cursor.get_buffer() {
-                        render_list.push(RenderNode::Buffer {
-                            x: self.state.seat.pointer.x.round() as i32 - hx,
-                            y: self.state.seat.pointer.y.round() as i32 - hy,
-                            opaque_region: None,
-                            alpha: 1.0,
-                            buf_transform,
-                            frame_callbacks: Vec::new(),
-                        });
-                    }
-                    debug!(
-                        self.state.debugger,
-                        "prepared render list of {} nodes in {:?}",
-                        render_list.len(),
-                        t.elapsed()
-                    );
-                    let t = std::time::Instant::now();
-                    let time = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u32;
-                    self.state.backend.render_frame(
-                        Color::from_rgba(
-                            self.state.config.bg_color.0,
-                            self.state.config.bg_color.1,
-                            self.state.config.bg_color.2,
-                            1.0,
-                        ),
-                        &render_list,
-                        time,
-                    );
-                    self.state.debugger.frame(t.elapsed());
+                BackendEvent::Frame(output) => self.frame(output),
+                BackendEvent::OutputChanged(_output) => {
+                    self.state.repaint_all();
+                    globals::output_changed(&mut self.state);
                 }
                 BackendEvent::NewKeyboard(_id) => (),
                 BackendEvent::KeyboardRemoved(_id) => (),
@@ -399,12 +1129,60 @@ impl Server {
                         .keyboard
                         .xkb_state
                         .key_get_one_sym(xkb::Keycode::new(key + 8));
-                    if self.state.seat.keyboard.get_mods().logo && keysym == xkb::Keysym::Escape {
+                    let mods = self.state.seat.keyboard.get_mods();
+                    if mods.logo && keysym == xkb::Keysym::Escape {
                         return Err(io::Error::other("quit"));
-                    } else if self.state.seat.keyboard.get_mods().logo
-                        && keysym == xkb::Keysym::Return
+                    } else if mods.logo && keysym == xkb::Keysym::Return {
+                        if let Err(e) = spawn_detached("foot") {
+                            eprintln!("could not spawn foot: {e}");
+                        }
+                    } else if mods.logo && keysym == xkb::Keysym::space {
+                        self.state.seat.keyboard.next_layout();
+                    } else if mods.logo && keysym == xkb::Keysym::a {
+                        self.state.focus_stack.toggle_top_layer(Layer::Above);
+                    } else if mods.logo && keysym == xkb::Keysym::b {
+                        self.state.focus_stack.toggle_top_layer(Layer::Below);
+                    } else if mods.alt && keysym == xkb::Keysym::Tab {
+                        self.state.focus_stack.cycle(&mut self.state.seat);
+                    } else if let Some((dx, dy)) =
+                        arrow_direction(keysym).filter(|_| mods.logo && (mods.shift || mods.ctrl))
                     {
-                        std::process::Command::new("foot").spawn().unwrap();
+                        if let Some(toplevel) = self.state.focus_stack.top() {
+                            let step = self.state.config.keyboard_step as i32;
+                            if mods.shift {
+                                toplevel.x.set(toplevel.x.get() + dx * step);
+                                toplevel.y.set(toplevel.y.get() + dy * step);
+                            } else if let Some(geom) = toplevel
+                                .xdg_surface
+                                .upgrade()
+                                .unwrap()
+                                .get_window_geometry()
+                            {
+                                let resize = |size: NonZeroU32, d: i32| {
+                                    NonZeroU32::new((size.get() as i32 + d * step).max(1) as u32)
+                                        .unwrap()
+                                };
+                                toplevel.request_size(
+                                    ResizeEdge::BottomRight,
+                                    resize(geom.width, dx),
+                                    resize(geom.height, dy),
+                                );
+                            }
+                        }
+                    } else if mods.logo && (KEY_1..=KEY_9).contains(&key) {
+                        let workspace = key - KEY_1;
+                        let focus_stack = &mut self.state.focus_stack;
+                        if mods.ctrl {
+                            focus_stack.toggle_top_workspace(workspace, &mut self.state.seat);
+                        } else if mods.shift {
+                            focus_stack.move_top_to_workspace(workspace, &mut self.state.seat);
+                        } else {
+                            focus_stack.switch_workspace(workspace, &mut self.state.seat);
+                            self.state.overlay.show(
+                                self.state.backend.as_mut(),
+                                &format!("workspace {}", workspace + 1),
+                            );
+                        }
                     } else if keysym >= xkb::Keysym::XF86_Switch_VT_1
                         && keysym <= xkb::Keysym::XF86_Switch_VT_12
                     {
@@ -412,33 +1190,12 @@ impl Server {
                             .backend
                             .switch_vt(keysym.raw() - xkb::Keysym::XF86_Switch_VT_1.raw() + 1);
                     } else {
-                        if let Some(popup) =
-                            self.state.popup_stack.iter().rev().find(|p| p.grab.get())
-                        {
-                            self.state.seat.keyboard.focus_surface(Some(
-                                popup.wl_surface.upgrade().unwrap().wl.clone(),
-                            ));
-                        } else if let Some(toplevel) = self.state.focus_stack.top() {
-                            self.state.seat.keyboard.focus_surface(Some(
-                                toplevel.wl_surface.upgrade().unwrap().wl.clone(),
-                            ));
-                        }
+                        self.state.focus_keyboard();
                         self.state.seat.keyboard.update_key(key, timestamp, true);
                     }
                 }
                 BackendEvent::KeyReleased(_id, timestamp, key) => {
-                    if let Some(popup) = self.state.popup_stack.iter().rev().find(|p| p.grab.get())
-                    {
-                        self.state
-                            .seat
-                            .keyboard
-                            .focus_surface(Some(popup.wl_surface.upgrade().unwrap().wl.clone()));
-                    } else if let Some(toplevel) = self.state.focus_stack.top() {
-                        self.state
-                            .seat
-                            .keyboard
-                            .focus_surface(Some(toplevel.wl_surface.upgrade().unwrap().wl.clone()));
-                    }
+                    self.state.focus_keyboard();
                     self.state.seat.keyboard.update_key(key, timestamp, false);
                 }
                 BackendEvent::NewPointer(id) => {
@@ -450,17 +1207,24 @@ impl Server {
                     }
                 }
                 BackendEvent::PointerMotionAbsolute(_id, timestamp, x, y) => {
+                    self.state.repaint_cursor();
                     self.state.seat.pointer.x = x;
                     self.state.seat.pointer.y = y;
                     self.pointer_moved(timestamp);
                 }
                 BackendEvent::PointerMotionRelative(_id, timestamp, dx, dy) => {
+                    self.state.repaint_cursor();
                     self.state.seat.pointer.x += dx;
                     self.state.seat.pointer.y += dy;
                     self.pointer_moved(timestamp);
                 }
                 BackendEvent::PointerBtnPress(_id, timestmap, btn) => {
                     let mut handeled = false;
+                    let double_click = self.state.seat.pointer.register_press(
+                        btn,
+                        timestmap,
+                        self.state.config.double_click_interval,
+                    );
 
                     if self.state.seat.pointer.number_of_pressed_buttons() == 0 {
                         if let Some(surf_under) = self
@@ -481,13 +1245,43 @@ impl Server {
                                 if btn == BTN_LEFT {
                                     handeled = true;
                                     self.state.seat.pointer.start_move(toplevel);
+                                    self.state.cursor.set_shape(Shape::Move);
                                 } else if btn == BTN_RIGHT {
                                     handeled = true;
                                     self.state.seat.pointer.start_resize(
                                         xdg_toplevel::ResizeEdge::BottomRight,
                                         toplevel,
-                                    )
+                                    );
+                                    self.state
+                                        .cursor
+                                        .set_resize_shape(xdg_toplevel::ResizeEdge::BottomRight);
                                 }
+                            } else if self.state.config.touch_emulation
+                                && self.state.seat.keyboard.get_mods().logo
+                                && btn == BTN_LEFT
+                            {
+                                handeled = true;
+                                self.state.seat.touch.down(
+                                    surf_under.surf,
+                                    timestmap,
+                                    self.state.seat.pointer.x,
+                                    self.state.seat.pointer.y,
+                                    surf_under.sx,
+                                    surf_under.sy,
+                                );
+                            }
+                        } else if let Some(toplevel_idx) = self
+                            .state
+                            .focus_stack
+                            .top_border_at(self.state.seat.pointer.x, self.state.seat.pointer.y)
+                        {
+                            let toplevel = self.state.focus_stack.get_i(toplevel_idx).unwrap();
+                            self.state
+                                .focus_stack
+                                .focus_i(toplevel_idx, &mut self.state.seat);
+                            if btn == BTN_LEFT && double_click {
+                                toggle_maximized(&self.state, &toplevel);
+                                self.state.repaint_all();
                             }
                         }
                     }
@@ -498,20 +1292,18 @@ impl Server {
                         .update_button(btn, timestmap, true, !handeled);
                 }
                 BackendEvent::PointerBtnRelease(_id, timestamp, btn) => {
+                    if btn == BTN_LEFT {
+                        self.state.seat.touch.up(timestamp);
+                    }
                     match &self.state.seat.pointer.state {
-                        PtrState::Moving { .. } => {
-                            self.state.seat.pointer.state = PtrState::None;
-                            self.state
-                                .seat
-                                .pointer
-                                .update_button(btn, timestamp, false, false);
-                        }
-                        PtrState::Resizing { .. } => {
+                        PtrState::Moving { .. } | PtrState::Resizing { .. } => {
                             self.state.seat.pointer.state = PtrState::None;
                             self.state
                                 .seat
                                 .pointer
                                 .update_button(btn, timestamp, false, false);
+                            // The client under the pointer will set its own cursor on enter.
+                            self.state.cursor.set_shape(Shape::Default);
                         }
                         _ => {
                             self.state
@@ -521,8 +1313,8 @@ impl Server {
                         }
                     }
                 }
-                BackendEvent::PointerAxisVertial(_id, timestamp, value) => {
-                    self.state.seat.pointer.axis_vertical(value, timestamp);
+                BackendEvent::PointerAxis(_id, timestamp, axis) => {
+                    self.state.seat.pointer.axis(axis, timestamp);
                 }
                 BackendEvent::PointerRemoved(id) => {
                     eprintln!("pointer removed: id={id:?}");
@@ -539,6 +1331,21 @@ impl Drop for Server {
     }
 }
 
+/// Run a shell command in the background. The shell which starts it exits right away, so the
+/// command is reparented to init, which reaps it when it exits.
+pub fn spawn_detached(cmd: &str) -> io::Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg("sh -c \"$1\" &")
+        .arg("sh")
+        .arg(cmd)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("sh failed: {status}")));
+    }
+    Ok(())
+}
+
 pub fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
     let mut fds = [0, 0];
     if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
@@ -573,7 +1380,11 @@ fn main() {
     signal_hook::low_level::pipe::register(signal_hook::consts::SIGINT, quit_write.as_raw_fd())
         .unwrap();
 
-    let mut server = Server::new(socket_path);
+    let ipc_path = env::var_os("EWC_IPC_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| xdg_runtime.join(format!("ewc-ipc.{socket_name}.sock")));
+
+    let mut server = Server::new(socket_path, ipc_path);
     server
         .event_loop
         .add_fd(quit_read.as_raw_fd(), event_loop::Event::Quit)
@@ -581,53 +1392,46 @@ fn main() {
 
     println!("Running on {socket_name}");
     std::env::set_var("WAYLAND_DISPLAY", socket_name);
-    std::process::Command::new("foot").spawn().unwrap();
+    if let Some(ipc) = &server.ipc {
+        std::env::set_var("EWC_IPC_SOCKET", ipc.path());
+    }
+    if let Err(e) = spawn_detached("foot") {
+        eprintln!("could not spawn foot: {e}");
+    }
 
     loop {
         match server.event_loop.poll().unwrap() {
             event_loop::Event::Socket => match server.socket.accept() {
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
                 Err(e) => panic!("socket error: {e}"),
-                Ok((stream, _)) => {
-                    eprintln!("new client");
-                    let id = server.next_client_id;
-                    server.next_client_id = id.next();
-                    let client = Client::new(stream, id, server.to_flush_set.clone());
-                    server
-                        .event_loop
-                        .add_fd(client.conn.as_raw_fd(), event_loop::Event::Client(id))
-                        .unwrap();
-                    server.clients.insert(id, client);
-                }
+                Ok((stream, _)) => server.add_client(stream, None),
             },
-            event_loop::Event::Backend(id) => server.poll_backend(id).unwrap(),
+            event_loop::Event::Backend(id) => {
+                if let Err(e) = server.poll_backend(id) {
+                    eprintln!("exiting: {e}");
+                    break;
+                }
+            }
+            event_loop::Event::IpcSocket => server.ipc_accept(),
+            event_loop::Event::IpcClient(id) => server.ipc_poll(id),
+            event_loop::Event::SecurityContextSocket(id) => server.security_context_accept(id),
+            event_loop::Event::SecurityContextClosed(id) => server.security_context_remove(id),
+            event_loop::Event::FrameTimer => server.frame_timer_expired(),
+            event_loop::Event::IdleTimer => server.idle_timer_expired(),
             event_loop::Event::Quit => break,
             event_loop::Event::Client(client_id) => {
                 let client = server.clients.get_mut(&client_id).unwrap();
                 // print_client_surface_tree(client);
                 if let Err(e) = client.poll(&mut server.state) {
-                    eprintln!("client error: {e}");
-                    server.destroy_client(client_id);
-                }
-            }
-            event_loop::Event::MayGoIdle => {
-                for (i, toplevel) in server.state.focus_stack.inner().iter().enumerate() {
-                    let toplevel = toplevel.upgrade().unwrap();
-                    toplevel.set_activated(i == server.state.focus_stack.inner().len() - 1);
-                    toplevel.apply_pending_configure();
-                }
-
-                for client_id in server.to_flush_set.clone().0.borrow_mut().drain() {
-                    if let Some(client) = server.clients.get(&client_id) {
-                        if let Err(e) = client.conn.flush() {
-                            eprintln!("client error: {e}");
-                            server.destroy_client(client_id);
-                        }
-                    }
+                    server.destroy_client(client_id, e);
                 }
+                server.register_security_context_listeners();
             }
+            event_loop::Event::MayGoIdle => server.may_go_idle(),
         }
     }
+
+    server.state.backend.shutdown();
 }
 
 #[allow(dead_code)]
@@ -683,3 +1487,654 @@ fn print_client_surface_tree(client: &Client) {
     subtree(client, 2, None);
     eprintln!("    client surface tree -->");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::io::Write;
+
+    use super::*;
+    use crate::backend::recording::RecordedNode;
+    use crate::test_harness::{read_u32, Arg, Harness};
+
+    #[test]
+    fn focused_window_render_nodes() {
+        let mut h = Harness::new();
+        h.map_toplevel(100, 50);
+
+        let output = h.server.state.backend.outputs()[0];
+        h.server.render_output(output);
+
+        let frames = h.recording.borrow();
+        let frame = frames.last().unwrap();
+        assert_eq!(frame.output, output);
+        assert_eq!(frame.clear, Color::from_rgba(0.2, 0.1, 0.2, 1.0));
+        assert_eq!(frame.cursor, None);
+        let red = Color::from_rgba(1.0, 0.0, 0.0, 1.0);
+        let border = |x, y, width, height| RecordedNode::Rect {
+            x,
+            y,
+            width,
+            height,
+            color: red,
+        };
+        assert_eq!(
+            frame.nodes,
+            [
+                border(18, 18, 2, 54),
+                border(120, 18, 2, 54),
+                border(20, 18, 100, 2),
+                border(20, 70, 100, 2),
+                RecordedNode::Buffer {
+                    x: 20,
+                    y: 20,
+                    width: 100,
+                    height: 50,
+                    alpha: 1.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn commit_destroyed_buffer_unmaps() {
+        let mut h = Harness::new();
+        let t = h.map_toplevel(100, 50);
+        assert!(h.server.state.focus_stack.top().is_some());
+
+        // Attach another buffer, but destroy it before committing.
+        let buffer = h.client.new_id();
+        h.client.request(
+            h.single_pixel,
+            1,
+            &[
+                Arg::Uint(buffer),
+                Arg::Uint(0),
+                Arg::Uint(u32::MAX),
+                Arg::Uint(0),
+                Arg::Uint(u32::MAX),
+            ],
+        );
+        h.client
+            .request(t.surface, 1, &[Arg::Uint(buffer), Arg::Int(0), Arg::Int(0)]);
+        h.client.request(buffer, 0, &[]);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+
+        assert!(h.server.clients.contains_key(&h.client_id));
+        assert!(h.server.state.focus_stack.top().is_none());
+        let output = h.server.state.backend.outputs()[0];
+        h.server.render_output(output);
+        let frames = h.recording.borrow();
+        assert!(frames.last().unwrap().nodes.is_empty());
+    }
+
+    #[test]
+    fn destroying_toplevel_releases_buffer() {
+        let mut h = Harness::new();
+        let t = h.map_toplevel(100, 50);
+
+        // xdg_toplevel.destroy
+        h.client.request(t.toplevel, 0, &[]);
+        h.roundtrip();
+
+        let client = &h.server.clients[&h.client_id];
+        let surface = client.compositor.surfaces.values().next().unwrap();
+        assert!(!surface.mapped.get());
+        assert!(surface.cur.borrow().buffer.is_none());
+        assert!(surface.get_bounding_box().is_none());
+    }
+
+    #[test]
+    fn subsurface_hit_testing_follows_rendering() {
+        let mut h = Harness::new();
+        let subcompositor = h.bind("wl_subcompositor", 1);
+        let t = h.map_toplevel(100, 50);
+
+        // An overlay at (10, 10), and a subsurface without a buffer at (50, 10), which has a
+        // subsurface of its own. They are added on the next commit of the window.
+        let overlay = h.create_subsurface(subcompositor, t.surface, 10, 10);
+        h.attach_red_buffer(overlay, 20, 20);
+        h.client.request(overlay, 6, &[]);
+        let empty = h.create_subsurface(subcompositor, t.surface, 50, 10);
+        let hidden = h.create_subsurface(subcompositor, empty, 0, 0);
+        h.attach_red_buffer(hidden, 20, 20);
+        h.client.request(hidden, 6, &[]);
+        h.client.request(empty, 6, &[]);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+
+        // The toplevel is at (20, 20).
+        let focus_stack = &h.server.state.focus_stack;
+        let under = focus_stack.surface_at(35.5, 35.5).unwrap();
+        assert!(under.surf.get_xdg_surface().is_none());
+        assert_eq!((under.sx, under.sy), (5.5, 5.5));
+
+        // Not rendered, so the toplevel gets the input.
+        let under = focus_stack.surface_at(75.5, 35.5).unwrap();
+        assert!(under.surf.get_xdg_surface().is_some());
+        assert_eq!((under.sx, under.sy), (55.5, 15.5));
+    }
+
+    #[test]
+    fn nested_popup_position_and_hit_testing() {
+        let mut h = Harness::new();
+        let t = h.map_toplevel(100, 50);
+
+        let menu = h.create_popup(t.xdg_surface, (10, 10), (30, 20));
+        h.map_popup(&menu, 30, 20);
+        let submenu = h.create_popup(menu.xdg_surface, (20, 5), (20, 10));
+        h.map_popup(&submenu, 20, 10);
+
+        // The toplevel is at (20, 20), the menu at (30, 30) and the submenu at (50, 35).
+        let focus_stack = &h.server.state.focus_stack;
+        let under = focus_stack.surface_at(55.5, 36.5).unwrap();
+        assert_eq!(under.surf.wl.id().as_u32(), submenu.surface);
+        assert_eq!((under.sx, under.sy), (5.5, 1.5));
+        assert_eq!(under.surf.get_pos(), Some((50, 35)));
+
+        // The part of the menu which is not covered by the submenu.
+        let under = focus_stack.surface_at(45.5, 48.5).unwrap();
+        assert_eq!(under.surf.wl.id().as_u32(), menu.surface);
+        assert_eq!((under.sx, under.sy), (15.5, 18.5));
+        assert_eq!(under.surf.get_pos(), Some((30, 30)));
+
+        // Outside of both, on the toplevel.
+        let under = focus_stack.surface_at(75.5, 55.5).unwrap();
+        assert_eq!(under.surf.wl.id().as_u32(), t.surface);
+        assert_eq!(under.surf.get_pos(), Some((20, 20)));
+    }
+
+    #[test]
+    fn destroying_grabbing_popup_returns_keyboard_focus() {
+        let mut h = Harness::new();
+        let seat = h.bind("wl_seat", 5);
+        let pointer = h.client.new_id();
+        h.client.request(seat, 0, &[Arg::Uint(pointer)]);
+        let keyboard = h.client.new_id();
+        h.client.request(seat, 1, &[Arg::Uint(keyboard)]);
+        let t = h.map_toplevel(100, 50);
+        // The surface of the last wl_keyboard.enter
+        let keyboard_enter = |events: Vec<(u32, u16, Vec<u8>)>| {
+            events
+                .into_iter()
+                .filter(|(o, opcode, _)| *o == keyboard && *opcode == 1)
+                .map(|(_, _, args)| read_u32(&args, 4))
+                .last()
+        };
+
+        // Open a menu with a click into the window, which is at (20, 20).
+        h.input.pointer_motion(0, 50.5, 40.5);
+        h.input.pointer_button(1, BTN_LEFT, true);
+        let (_, _, args) = h
+            .dispatch_input()
+            .into_iter()
+            .find(|(o, opcode, _)| *o == pointer && *opcode == 3)
+            .expect("no button event");
+        let menu = h.create_popup(t.xdg_surface, (10, 10), (30, 20));
+        h.client.request(
+            menu.popup,
+            1,
+            &[Arg::Uint(seat), Arg::Uint(read_u32(&args, 0))],
+        );
+        h.client.request(menu.surface, 6, &[]);
+        assert_eq!(keyboard_enter(h.roundtrip()), Some(menu.surface));
+
+        h.client.request(menu.popup, 0, &[]);
+        assert_eq!(keyboard_enter(h.roundtrip()), Some(t.surface));
+    }
+
+    #[test]
+    fn borders_hide_windows_below() {
+        let mut h = Harness::new();
+        let seat = h.bind("wl_seat", 5);
+        let pointer = h.client.new_id();
+        h.client.request(seat, 0, &[Arg::Uint(pointer)]);
+        for _ in 0..2 {
+            h.map_toplevel(100, 50);
+        }
+
+        let focus_stack = &h.server.state.focus_stack;
+        let bottom = focus_stack.get_i(0).unwrap();
+        let top = focus_stack.get_i(1).unwrap();
+        bottom.x.set(0);
+        bottom.y.set(0);
+        top.x.set(50);
+        top.y.set(20);
+
+        // On the left border of the top window.
+        assert!(focus_stack.surface_at(48.5, 30.5).is_none());
+        // Just outside of it.
+        let under = focus_stack.surface_at(47.5, 30.5).unwrap();
+        assert_eq!(under.toplevel_idx, 0);
+        assert_eq!((under.sx, under.sy), (47.5, 30.5));
+
+        // The pointer leaves the top window for its border, without entering the one below.
+        let pointer_events = |events: Vec<(u32, u16, Vec<u8>)>| {
+            events
+                .into_iter()
+                .filter(|(o, _, _)| *o == pointer)
+                .map(|(_, opcode, _)| opcode)
+                .collect::<Vec<_>>()
+        };
+        h.input.pointer_motion(0, 60.5, 30.5);
+        // wl_pointer.enter
+        assert!(pointer_events(h.dispatch_input()).contains(&0));
+        h.input.pointer_motion(1, 48.5, 30.5);
+        // wl_pointer.leave and wl_pointer.frame
+        assert_eq!(pointer_events(h.dispatch_input()), [1, 5]);
+    }
+
+    #[test]
+    fn move_requires_button_press_serial() {
+        let mut h = Harness::new();
+        let seat = h.bind("wl_seat", 5);
+        let pointer = h.client.new_id();
+        h.client.request(seat, 0, &[Arg::Uint(pointer)]);
+        let keyboard = h.client.new_id();
+        h.client.request(seat, 1, &[Arg::Uint(keyboard)]);
+        let t = h.map_toplevel(100, 50);
+        let xdg_move = |h: &mut Harness, serial: u32| {
+            h.client
+                .request(t.toplevel, 5, &[Arg::Uint(seat), Arg::Uint(serial)]);
+            h.roundtrip();
+            h.server.state.seat.pointer.is_grabbed()
+        };
+        // wl_pointer.button and wl_keyboard.key
+        let press_serial = |events: Vec<(u32, u16, Vec<u8>)>, object: u32| {
+            events
+                .into_iter()
+                .find(|(o, opcode, _)| *o == object && *opcode == 3)
+                .map(|(_, _, args)| read_u32(&args, 0))
+                .expect("no press event")
+        };
+
+        // Press the button inside the window, which is at (20, 20).
+        h.input.pointer_motion(0, 50.5, 40.5);
+        h.input.pointer_button(1, BTN_LEFT, true);
+        let button_serial = press_serial(h.dispatch_input(), pointer);
+        assert!(!xdg_move(&mut h, button_serial.wrapping_add(100)));
+
+        // A key press in the middle of the drag does not make the button serial stale, and its
+        // own serial does not start a move.
+        h.input.key(2, KEY_1, true);
+        let key_serial = press_serial(h.dispatch_input(), keyboard);
+        assert!(!xdg_move(&mut h, key_serial));
+        assert!(xdg_move(&mut h, button_serial));
+    }
+
+    #[test]
+    fn border_double_click_maximizes() {
+        let mut h = Harness::new();
+        let t = h.map_toplevel(100, 50);
+        // The size and whether it is maximized from xdg_toplevel.configure, and the position.
+        let configured = |h: &Harness, events: Vec<(u32, u16, Vec<u8>)>| {
+            let (_, _, args) = events
+                .into_iter()
+                .find(|(o, opcode, _)| *o == t.toplevel && *opcode == 0)
+                .expect("no configure");
+            let maximized = (0..read_u32(&args, 8) as usize / 4)
+                .any(|i| read_u32(&args, 12 + 4 * i) == xdg_toplevel::State::Maximized as u32);
+            let toplevel = h.server.state.focus_stack.top().unwrap();
+            (
+                (read_u32(&args, 0), read_u32(&args, 4), maximized),
+                (toplevel.x.get(), toplevel.y.get()),
+            )
+        };
+        let double_click = |h: &mut Harness, time: u32, x: f32, y: f32| {
+            h.input.pointer_motion(time, x, y);
+            for i in 0..2 {
+                h.input.pointer_button(time + 2 * i, BTN_LEFT, true);
+                h.input.pointer_button(time + 2 * i + 1, BTN_LEFT, false);
+            }
+            let events = h.dispatch_input();
+            configured(&*h, events)
+        };
+        let maximized = ((1280 - 4, 720 - 4, true), (2, 2));
+        let restored = ((100, 50, false), (20, 20));
+
+        // On the top border of the window at (20, 20).
+        assert_eq!(double_click(&mut h, 0, 50.5, 19.5), maximized);
+        // The client has not resized yet, so the top border is at the top of the output now.
+        assert_eq!(double_click(&mut h, 1000, 50.5, 1.5), restored);
+
+        // xdg_toplevel.set_maximized and unset_maximized do the same.
+        h.client.request(t.toplevel, 9, &[]);
+        let events = h.roundtrip();
+        assert_eq!(configured(&h, events), maximized);
+        h.client.request(t.toplevel, 10, &[]);
+        let events = h.roundtrip();
+        assert_eq!(configured(&h, events), restored);
+    }
+
+    #[test]
+    fn edge_tiling_configures_once() {
+        let mut h = Harness::new();
+        h.server.state.config.edge_tiling = true;
+        let seat = h.bind("wl_seat", 5);
+        let pointer = h.client.new_id();
+        h.client.request(seat, 0, &[Arg::Uint(pointer)]);
+        let t = h.map_toplevel(100, 50);
+        let configures = |events: Vec<(u32, u16, Vec<u8>)>| {
+            events
+                .into_iter()
+                .filter(|(o, opcode, _)| *o == t.toplevel && *opcode == 0)
+                .map(|(_, _, args)| (read_u32(&args, 0), read_u32(&args, 4)))
+                .collect::<Vec<_>>()
+        };
+
+        h.input.pointer_motion(0, 50.5, 40.5);
+        h.input.pointer_button(1, BTN_LEFT, true);
+        let (_, _, args) = h
+            .dispatch_input()
+            .into_iter()
+            .find(|(o, opcode, _)| *o == pointer && *opcode == 3)
+            .unwrap();
+        h.client.request(
+            t.toplevel,
+            5,
+            &[Arg::Uint(seat), Arg::Uint(read_u32(&args, 0))],
+        );
+        h.roundtrip();
+
+        // Drag the window along the left edge of the output.
+        h.input.pointer_motion(2, 0.5, 300.5);
+        assert_eq!(configures(h.dispatch_input()), [(640 - 4, 720 - 4)]);
+        h.input.pointer_motion(3, 0.5, 310.5);
+        assert!(configures(h.dispatch_input()).is_empty());
+
+        // And away from it.
+        h.input.pointer_motion(4, 300.5, 310.5);
+        assert_eq!(configures(h.dispatch_input()), [(100, 50)]);
+    }
+
+    #[test]
+    fn idle_outputs_stop_rendering() {
+        let mut h = Harness::new();
+        let output = h.server.state.backend.outputs()[0];
+
+        h.server.frame(output);
+        assert_eq!(h.recording.borrow().len(), 1);
+        // Nothing changed, the frame is skipped.
+        h.server.frame(output);
+        assert_eq!(h.recording.borrow().len(), 1);
+
+        // A commit repaints the idle output without waiting for a frame event.
+        let surface = h.client.new_id();
+        h.client.request(h.compositor, 0, &[Arg::Uint(surface)]);
+        h.client.request(surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(h.recording.borrow().len(), 2);
+    }
+
+    #[test]
+    fn turning_outputs_on_resets_idle_time() {
+        let mut h = Harness::new();
+        h.server.state.config.blank_timeout = Some(60);
+        h.server.state.last_input = Instant::now() - Duration::from_secs(61);
+        h.server.idle_timer_expired();
+        assert!(!h.server.state.dpms_on);
+
+        // Turned on without input, e.g. over IPC. The old idle time must not blank them again.
+        h.server.state.set_dpms(true);
+        h.server.idle_timer_expired();
+        assert!(h.server.state.dpms_on);
+    }
+
+    #[test]
+    fn minimize_repaints_without_commit() {
+        let mut h = Harness::new();
+        let t = h.map_toplevel(100, 50);
+        let output = h.server.state.backend.outputs()[0];
+        h.server.frame(output);
+        h.server.frame(output);
+        let frames = h.recording.borrow().len();
+
+        // xdg_toplevel.set_minimized
+        h.client.request(t.toplevel, 13, &[]);
+        h.roundtrip();
+        let recording = h.recording.borrow();
+        assert_eq!(recording.len(), frames + 1);
+        assert!(recording.last().unwrap().nodes.is_empty());
+    }
+
+    #[test]
+    fn toplevel_title_and_app_id_commits() {
+        let mut h = Harness::new();
+        let t = h.create_toplevel();
+        let role = h.server.clients[&h.client_id]
+            .compositor
+            .xdg_toplevels
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        let cstring = |s: &str| Some(CString::new(s).unwrap());
+        h.client.request(t.xdg_surface, 4, &[Arg::Uint(t.serial)]);
+
+        h.client.request(t.toplevel, 2, &[Arg::Str("title")]);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(role.title(), cstring("title"));
+        assert_eq!(role.app_id(), None);
+
+        h.client.request(t.toplevel, 3, &[Arg::Str("app")]);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(role.title(), cstring("title"));
+        assert_eq!(role.app_id(), cstring("app"));
+
+        // A commit without changes must keep both.
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(role.title(), cstring("title"));
+        assert_eq!(role.app_id(), cstring("app"));
+    }
+
+    #[test]
+    fn window_geometry_with_shadow_subsurface() {
+        let mut h = Harness::new();
+        let subcompositor = h.bind("wl_subcompositor", 1);
+        let t = h.create_toplevel();
+        let xdg_surface = h.server.clients[&h.client_id]
+            .compositor
+            .xdg_toplevels
+            .values()
+            .next()
+            .unwrap()
+            .xdg_surface
+            .upgrade()
+            .unwrap();
+        let geometry = || {
+            let geom = xdg_surface.get_window_geometry().unwrap();
+            (geom.x, geom.y, geom.width.get(), geom.height.get())
+        };
+        let set_window_geometry = |h: &mut Harness, x, y, width, height| {
+            h.client.request(
+                t.xdg_surface,
+                3,
+                &[Arg::Int(x), Arg::Int(y), Arg::Int(width), Arg::Int(height)],
+            );
+        };
+
+        // The geometry includes a 4px wide part of the shadow, which is not there yet.
+        h.client.request(t.xdg_surface, 4, &[Arg::Uint(t.serial)]);
+        set_window_geometry(&mut h, -4, -4, 108, 58);
+        h.attach_red_buffer(t.surface, 100, 50);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(geometry(), (0, 0, 100, 50));
+
+        // A 10px shadow, below the main surface and extending above and left of it.
+        let shadow = h.client.new_id();
+        h.client.request(h.compositor, 0, &[Arg::Uint(shadow)]);
+        let subsurface = h.client.new_id();
+        h.client.request(
+            subcompositor,
+            1,
+            &[
+                Arg::Uint(subsurface),
+                Arg::Uint(shadow),
+                Arg::Uint(t.surface),
+            ],
+        );
+        h.client
+            .request(subsurface, 1, &[Arg::Int(-10), Arg::Int(-10)]);
+        h.client.request(subsurface, 3, &[Arg::Uint(t.surface)]);
+        h.attach_red_buffer(shadow, 120, 70);
+        h.client.request(shadow, 6, &[]);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(geometry(), (-4, -4, 108, 58));
+
+        // The usual CSD setup, where the shadow is excluded.
+        set_window_geometry(&mut h, 0, 0, 100, 50);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(geometry(), (0, 0, 100, 50));
+
+        // A geometry outside of all surfaces falls back to the bounding box.
+        set_window_geometry(&mut h, 200, 200, 10, 10);
+        h.client.request(t.surface, 6, &[]);
+        h.roundtrip();
+        assert_eq!(geometry(), (-10, -10, 120, 70));
+    }
+
+    #[test]
+    fn shm_formats_come_from_renderer() {
+        let mut h = Harness::new();
+        let shm = h.bind("wl_shm", 1);
+        let advertised: Vec<u32> = h
+            .roundtrip()
+            .into_iter()
+            .filter(|&(object, opcode, _)| object == shm && opcode == 0)
+            .map(|(_, _, args)| read_u32(&args, 0))
+            .collect();
+        let supported: Vec<u32> = h
+            .server
+            .state
+            .backend
+            .renderer_state()
+            .supported_shm_formats()
+            .iter()
+            .map(|&format| u32::from(format))
+            .collect();
+        assert_eq!(advertised, supported);
+    }
+
+    #[test]
+    fn request_split_across_reads() {
+        let mut h = Harness::new();
+        h.roundtrip();
+
+        // wl_compositor.create_surface, delivered in parts which end inside the header and
+        // inside the payload.
+        let surface = h.client.new_id();
+        let mut msg = h.compositor.to_ne_bytes().to_vec();
+        msg.extend_from_slice(&(12u32 << 16).to_ne_bytes());
+        msg.extend_from_slice(&surface.to_ne_bytes());
+        for part in [&msg[..6], &msg[6..10], &msg[10..]] {
+            h.client.stream.write_all(part).unwrap();
+            h.roundtrip();
+        }
+        assert_eq!(h.server.clients[&h.client_id].compositor.surfaces.len(), 1);
+    }
+
+    #[test]
+    fn fd_flood_disconnects() {
+        let mut h = Harness::new();
+        let fds = [h.client.stream.as_raw_fd(); 16];
+        let mut result = Ok(());
+        // wl_display.sync with fds which no request takes.
+        for _ in 0..20 {
+            let callback = h.client.new_id();
+            let mut msg = 1u32.to_ne_bytes().to_vec();
+            msg.extend_from_slice(&(12u32 << 16).to_ne_bytes());
+            msg.extend_from_slice(&callback.to_ne_bytes());
+            send_with_fds(&h.client.stream, &msg, &fds);
+            let client = h.server.clients.get_mut(&h.client_id).unwrap();
+            result = client.poll(&mut h.server.state);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(result.is_err());
+    }
+
+    fn send_with_fds(stream: &UnixStream, bytes: &[u8], fds: &[libc::c_int]) {
+        let fds_len = std::mem::size_of_val(fds) as u32;
+        let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr().cast_mut().cast(),
+            iov_len: bytes.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = cmsg_buf.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+            assert_eq!(
+                libc::sendmsg(stream.as_raw_fd(), &msg, 0),
+                bytes.len() as isize
+            );
+        }
+    }
+
+    #[test]
+    fn output_name_and_release() {
+        let mut h = Harness::new();
+        let output = h.bind("wl_output", 4);
+        let names: Vec<Vec<u8>> = h
+            .roundtrip()
+            .into_iter()
+            .filter(|&(object, opcode, _)| object == output && opcode == 4)
+            .map(|(_, _, args)| args[4..4 + read_u32(&args, 0) as usize - 1].to_vec())
+            .collect();
+        assert_eq!(names, [b"TEST-1"]);
+
+        // Released outputs are not updated anymore.
+        h.client.request(output, 0, &[]);
+        h.roundtrip();
+        globals::output_changed(&mut h.server.state);
+        assert!(h.roundtrip().iter().all(|&(object, _, _)| object != output));
+    }
+
+    #[test]
+    fn preferred_buffer_scale_follows_output() {
+        let mut h = Harness::new();
+        let output = h.server.state.backend.outputs()[0];
+        h.server.state.output_layout.set_scale(output, 2);
+        globals::output_changed(&mut h.server.state);
+        let scales = |events: Vec<(u32, u16, Vec<u8>)>, surface: u32| -> Vec<u32> {
+            events
+                .into_iter()
+                .filter(|&(object, opcode, _)| object == surface && opcode == 2)
+                .map(|(_, _, args)| read_u32(&args, 0))
+                .collect()
+        };
+
+        // Sent with the initial configure, before the client attaches a buffer.
+        let surface = h.client.new_id();
+        h.client.request(h.compositor, 0, &[Arg::Uint(surface)]);
+        let xdg_surface = h.client.new_id();
+        h.client
+            .request(h.wm_base, 2, &[Arg::Uint(xdg_surface), Arg::Uint(surface)]);
+        let toplevel = h.client.new_id();
+        h.client.request(xdg_surface, 1, &[Arg::Uint(toplevel)]);
+        h.client.request(surface, 6, &[]);
+        assert_eq!(scales(h.roundtrip(), surface), [2]);
+
+        // Only sent again once the scale changes.
+        let t = h.map_toplevel(100, 50);
+        h.server.state.output_layout.set_scale(output, 3);
+        globals::output_changed(&mut h.server.state);
+        assert_eq!(scales(h.roundtrip(), t.surface), [3]);
+        assert!(scales(h.roundtrip(), t.surface).is_empty());
+    }
+}
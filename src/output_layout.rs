@@ -0,0 +1,70 @@
+//! Where outputs are in the global compositor space, and which part of them windows may occupy.
+
+use std::collections::HashMap;
+
+use crate::backend::{Backend, OutputId};
+
+#[derive(Default)]
+pub struct OutputLayout {
+    /// Output scales set through output management. Outputs which are not here have a scale of 1.
+    scales: HashMap<OutputId, i32>,
+}
+
+impl OutputLayout {
+    /// The integer scale advertised to clients for the output. Rendering is not scaled, so this
+    /// only tells clients which buffer scale to use.
+    pub fn scale(&self, output: OutputId) -> i32 {
+        self.scales.get(&output).copied().unwrap_or(1)
+    }
+
+    pub fn set_scale(&mut self, output: OutputId, scale: i32) {
+        if scale == 1 {
+            self.scales.remove(&output);
+        } else {
+            self.scales.insert(output, scale);
+        }
+    }
+
+    /// The part of the output windows are placed in, which is what a maximized window covers.
+    /// This is the whole output until something that reserves space along its edges (like a layer
+    /// shell) is implemented.
+    pub fn work_area(&self, backend: &dyn Backend, output: OutputId) -> pixman::Rectangle32 {
+        backend.output_geometry(output)
+    }
+
+    /// The output containing the point, if any.
+    pub fn output_at(&self, backend: &dyn Backend, x: f32, y: f32) -> Option<OutputId> {
+        backend
+            .output_geometries()
+            .into_iter()
+            .find(|(_, r)| {
+                x >= r.x as f32
+                    && y >= r.y as f32
+                    && x < r.x as f32 + r.width as f32
+                    && y < r.y as f32 + r.height as f32
+            })
+            .map(|(output, _)| output)
+    }
+
+    /// The output a window with the given geometry is on: the one it overlaps the most, or the
+    /// first output if it is not visible on any of them. `None` if there are no outputs.
+    pub fn output_for_rect(
+        &self,
+        backend: &dyn Backend,
+        rect: &pixman::Rectangle32,
+    ) -> Option<OutputId> {
+        let overlap = |r: &pixman::Rectangle32| {
+            let w = (rect.x + rect.width as i32).min(r.x + r.width as i32) - rect.x.max(r.x);
+            let h = (rect.y + rect.height as i32).min(r.y + r.height as i32) - rect.y.max(r.y);
+            w.max(0) as u64 * h.max(0) as u64
+        };
+        backend
+            .output_geometries()
+            .into_iter()
+            .map(|(output, r)| (output, overlap(&r)))
+            .filter(|&(_, area)| area > 0)
+            .max_by_key(|&(_, area)| area)
+            .map(|(output, _)| output)
+            .or_else(|| backend.outputs().first().copied())
+    }
+}
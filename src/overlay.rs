@@ -0,0 +1,250 @@
+//! Short on-screen messages, like the current workspace, drawn by the compositor on top of the
+//! windows.
+
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, BufferId, OutputId, RenderNode};
+use crate::buffer_transform::BufferTransform;
+use crate::protocol::wl_output;
+
+/// How long a message is fully visible.
+const SHOW: Duration = Duration::from_secs(1);
+/// How long it then takes to fade out.
+const FADE: Duration = Duration::from_millis(300);
+/// Size of a font dot in pixels.
+const SCALE: u32 = 3;
+const PADDING: u32 = 4 * SCALE;
+/// Longer messages are cut off with "...", so that they fit on common outputs.
+const MAX_CHARS: usize = 60;
+
+#[derive(Default)]
+pub struct Overlay {
+    message: Option<Message>,
+}
+
+struct Message {
+    buf_id: BufferId,
+    shown_at: Instant,
+}
+
+impl Overlay {
+    /// Show a single line of text, replacing the current message. Characters outside of printable
+    /// ASCII are drawn as `?`, and text longer than `MAX_CHARS` is truncated.
+    pub fn show(&mut self, backend: &mut dyn Backend, text: &str) {
+        self.hide(backend);
+        let (width, height, bytes) = render_text(text);
+        let text = text.to_owned();
+        let buf_id = backend.renderer_state().create_argb8_texture(
+            width,
+            height,
+            &bytes,
+            Box::new(move || Some(render_text(&text).2)),
+        );
+        self.message = Some(Message {
+            buf_id,
+            shown_at: Instant::now(),
+        });
+    }
+
+    /// Whether a message is shown or fading out, so that the outputs have to keep being repainted.
+    pub fn is_visible(&self) -> bool {
+        self.message.is_some()
+    }
+
+    fn hide(&mut self, backend: &mut dyn Backend) {
+        if let Some(message) = self.message.take() {
+            backend.renderer_state().buffer_unlock(message.buf_id);
+        }
+    }
+
+    /// Returns a render node which shows the message in the middle of the output, or `None` if
+    /// there is no message or it has faded out.
+    pub fn render_node(
+        &mut self,
+        backend: &mut dyn Backend,
+        output: OutputId,
+    ) -> Option<RenderNode> {
+        let message = self.message.as_ref()?;
+        let elapsed = message.shown_at.elapsed();
+        if elapsed >= SHOW + FADE {
+            self.hide(backend);
+            return None;
+        }
+        let alpha = 1.0 - elapsed.saturating_sub(SHOW).as_secs_f32() / FADE.as_secs_f32();
+        let buf_id = message.buf_id;
+        let output_rect = backend.output_geometry(output);
+        let (width, height) = backend.renderer_state().get_buffer_size(buf_id);
+        let buf_transform = BufferTransform::new(
+            buf_id,
+            backend,
+            wl_output::Transform::Normal,
+            NonZeroU32::MIN,
+            None,
+            None,
+        )
+        .ok()?;
+        Some(RenderNode::Buffer {
+            x: (output_rect.width as i32 - width as i32) / 2,
+            y: (output_rect.height as i32 - height as i32) / 2,
+            opaque_region: None,
+            alpha,
+            buf_transform,
+            frame_callbacks: Vec::new(),
+        })
+    }
+}
+
+/// Draw white text on a translucent black background, returning the size and premultiplied
+/// ARGB8888 pixels.
+fn render_text(text: &str) -> (u32, u32, Vec<u8>) {
+    let glyph = |c: char| {
+        let i = if c.is_ascii() && !c.is_ascii_control() {
+            c as usize - 0x20
+        } else {
+            b'?' as usize - 0x20
+        };
+        &FONT[i]
+    };
+    let mut glyphs: Vec<&[u8; 5]> = text.chars().take(MAX_CHARS + 1).map(glyph).collect();
+    if glyphs.len() > MAX_CHARS {
+        glyphs.truncate(MAX_CHARS - 3);
+        glyphs.extend([glyph('.'); 3]);
+    }
+    // Glyphs are 5x7 dots with a one dot gap between them.
+    let dots_w = (glyphs.len() as u32 * 6).saturating_sub(1);
+    let width = dots_w * SCALE + PADDING * 2;
+    let height = 7 * SCALE + PADDING * 2;
+    let mut bytes = [0, 0, 0, 0xB0].repeat((width * height) as usize);
+    for (i, glyph) in glyphs.iter().enumerate() {
+        for (col, bits) in glyph.iter().enumerate() {
+            for row in 0..7 {
+                if bits & (1 << row) == 0 {
+                    continue;
+                }
+                let x0 = PADDING + (i as u32 * 6 + col as u32) * SCALE;
+                let y0 = PADDING + row * SCALE;
+                for y in y0..y0 + SCALE {
+                    for x in x0..x0 + SCALE {
+                        let offset = ((y * width + x) * 4) as usize;
+                        bytes[offset..offset + 4].copy_from_slice(&[0xFF; 4]);
+                    }
+                }
+            }
+        }
+    }
+    (width, height, bytes)
+}
+
+/// A 5x7 font for printable ASCII, starting at space. Each byte is a column, with the least
+/// significant bit at the top.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x10, 0x08, 0x08, 0x10, 0x08], // ~
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_text_is_truncated() {
+        let (short_width, height, _) = render_text(&"a".repeat(MAX_CHARS));
+        let (width, _, bytes) = render_text(&"a".repeat(1000));
+        assert_eq!(width, short_width);
+        assert_eq!(bytes.len(), (width * height * 4) as usize);
+    }
+}
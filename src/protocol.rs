@@ -9,4 +9,8 @@ g!("wayland-protocols/stable/viewporter/viewporter.xml");
 g!("wayland-protocols/stable/linux-dmabuf/linux-dmabuf-v1.xml");
 g!("wayland-protocols/staging/cursor-shape/cursor-shape-v1.xml");
 g!("wayland-protocols/staging/single-pixel-buffer/single-pixel-buffer-v1.xml");
+g!("wayland-protocols/staging/security-context/security-context-v1.xml");
 g!("wayland-protocols/unstable/tablet/tablet-unstable-v2.xml");
+
+g!("protocol/wlr-output-management-unstable-v1.xml");
+g!("protocol/wlr-gamma-control-unstable-v1.xml");
@@ -6,16 +6,21 @@ use std::os::fd::AsFd;
 use xkbcommon::xkb;
 
 use crate::backend::InputTimestamp;
-use crate::client::RequestCtx;
+use crate::client::{ClientId, RequestCtx};
 use crate::config::Config;
 use crate::protocol::*;
 use crate::wayland_core::Proxy;
 
 use super::DataSource;
 
+pub const KEY_1: u32 = 2;
+pub const KEY_9: u32 = 10;
+
 pub struct Keyboard {
     keymap_file: File,
     keymap_file_size: u32,
+    /// All `wl_keyboard`s of all clients, to re-send the keymap when it changes.
+    wl_keyboards: Vec<WlKeyboard>,
     pub xkb_state: xkb::State,
     mods: ModsState,
     focused_surface: Option<WlSurface>,
@@ -55,35 +60,18 @@ impl ModsState {
 pub struct ModsMask {
     pub logo: bool,
     pub alt: bool,
+    pub shift: bool,
+    pub ctrl: bool,
 }
 
 impl Keyboard {
     pub fn new(config: &Config) -> Self {
-        let xkb_context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
-        let xkb_keymap = xkb::Keymap::new_from_names(
-            &xkb_context,
-            "",
-            "",
-            &config.xkb_layout,
-            "",
-            config.xkb_options.clone(),
-            0,
-        )
-        .unwrap();
-        let keymap_string_ptr = unsafe {
-            xkb::ffi::xkb_keymap_get_as_string(xkb_keymap.get_raw_ptr(), xkb::KEYMAP_FORMAT_TEXT_V1)
-        };
-        assert!(!keymap_string_ptr.is_null());
-        let keymap_string = unsafe { CStr::from_ptr(keymap_string_ptr) };
-        let keymap_bytes = keymap_string.to_bytes_with_nul();
-        let mut keymap_file = shmemfdrs2::create_shmem(c"/ewc-keymap-file").unwrap();
-        keymap_file.write_all(keymap_bytes).unwrap();
-        let keymap_file_size = keymap_bytes.len() as u32;
-        unsafe { libc::free(keymap_string_ptr.cast()) };
-        let xkb_state = xkb::State::new(&xkb_keymap);
+        let (keymap_file, keymap_file_size, xkb_state) =
+            compile_keymap(&config.xkb_layout, config.xkb_options.clone()).unwrap();
         Self {
             keymap_file,
             keymap_file_size,
+            wl_keyboards: Vec::new(),
             mods: ModsState::get(&xkb_state),
             xkb_state,
             focused_surface: None,
@@ -91,13 +79,34 @@ impl Keyboard {
         }
     }
 
-    pub fn init_keyboard(&self, wl_keyboard: &WlKeyboard) -> io::Result<()> {
+    /// Switch to a new keymap and send it to all clients.
+    pub fn set_keymap(&mut self, layout: &str, options: Option<String>) -> io::Result<()> {
+        let (keymap_file, keymap_file_size, xkb_state) = compile_keymap(layout, options)?;
+        self.keymap_file = keymap_file;
+        self.keymap_file_size = keymap_file_size;
+        self.xkb_state = xkb_state;
+        self.mods = ModsState::get(&self.xkb_state);
+        for wl_keyboard in &self.wl_keyboards {
+            self.send_keymap(wl_keyboard)?;
+        }
+        if let Some(focused_surf) = &self.focused_surface {
+            let seat = &focused_surf.conn().seat;
+            let serial = seat.next_serial();
+            for kbd in seat.keyboards.borrow().iter() {
+                self.mods.send(serial, kbd);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.wl_keyboards.retain(|k| k.client_id() != client_id);
+    }
+
+    pub fn init_keyboard(&mut self, wl_keyboard: &WlKeyboard) -> io::Result<()> {
         wl_keyboard.set_callback(wl_keyboard_cb);
-        wl_keyboard.keymap(
-            wl_keyboard::KeymapFormat::XkbV1,
-            self.keymap_file.as_fd().try_clone_to_owned()?,
-            self.keymap_file_size,
-        );
+        self.send_keymap(wl_keyboard)?;
+        self.wl_keyboards.push(wl_keyboard.clone());
         if wl_keyboard.version() >= 4 {
             wl_keyboard.repeat_info(40, 300);
         }
@@ -109,14 +118,25 @@ impl Keyboard {
         Ok(())
     }
 
+    fn send_keymap(&self, wl_keyboard: &WlKeyboard) -> io::Result<()> {
+        wl_keyboard.keymap(
+            wl_keyboard::KeymapFormat::XkbV1,
+            self.keymap_file.as_fd().try_clone_to_owned()?,
+            self.keymap_file_size,
+        );
+        Ok(())
+    }
+
     pub fn focus_surface(&mut self, surface: Option<WlSurface>) {
         if self.focused_surface == surface {
             return;
         }
 
         if let Some(old_surf) = &self.focused_surface {
-            for kbd in old_surf.conn().seat.keyboards.borrow().iter() {
-                kbd.leave(1, old_surf);
+            let seat = &old_surf.conn().seat;
+            let serial = seat.next_serial();
+            for kbd in seat.keyboards.borrow().iter() {
+                kbd.leave(serial, old_surf);
             }
         }
 
@@ -140,8 +160,9 @@ impl Keyboard {
 
     fn enter(&self, wl_keyboard: &WlKeyboard) {
         if let Some(surf) = &self.focused_surface {
-            wl_keyboard.enter(1, surf, Vec::new());
-            self.mods.send(1, wl_keyboard);
+            let seat = &wl_keyboard.conn().seat;
+            wl_keyboard.enter(seat.next_serial(), surf, Vec::new());
+            self.mods.send(seat.next_serial(), wl_keyboard);
         }
     }
 
@@ -161,15 +182,7 @@ impl Keyboard {
             },
         );
 
-        let mods = ModsState::get(&self.xkb_state);
-        if self.mods != mods {
-            self.mods = mods;
-            if let Some(focused_surf) = &self.focused_surface {
-                for kbd in focused_surf.conn().seat.keyboards.borrow().iter() {
-                    mods.send(1, kbd);
-                }
-            }
-        }
+        self.update_mods();
 
         let state = if pressed {
             wl_keyboard::KeyState::Pressed
@@ -178,22 +191,60 @@ impl Keyboard {
         };
 
         if let Some(focused_surf) = &self.focused_surface {
-            for kbd in focused_surf.conn().seat.keyboards.borrow().iter() {
-                kbd.key(1, timestamp.get(), key, state);
+            let seat = &focused_surf.conn().seat;
+            let serial = if pressed {
+                seat.next_key_press_serial()
+            } else {
+                seat.next_serial()
+            };
+            for kbd in seat.keyboards.borrow().iter() {
+                kbd.key(serial, timestamp.get(), key, state);
+            }
+        }
+    }
+
+    /// Lock the next layout of the keymap, wrapping around after the last one.
+    pub fn next_layout(&mut self) {
+        let num_layouts = self.xkb_state.get_keymap().num_layouts();
+        if num_layouts < 2 {
+            return;
+        }
+        let layout = self.xkb_state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE);
+        self.xkb_state.update_mask(
+            self.mods.depressed,
+            self.mods.latched,
+            self.mods.locked,
+            0,
+            0,
+            (layout + 1) % num_layouts,
+        );
+        self.update_mods();
+    }
+
+    /// Send the modifiers to the focused client if they have changed.
+    fn update_mods(&mut self) {
+        let mods = ModsState::get(&self.xkb_state);
+        if self.mods != mods {
+            self.mods = mods;
+            if let Some(focused_surf) = &self.focused_surface {
+                let seat = &focused_surf.conn().seat;
+                let serial = seat.next_serial();
+                for kbd in seat.keyboards.borrow().iter() {
+                    mods.send(serial, kbd);
+                }
             }
         }
     }
 
     pub fn get_mods(&self) -> ModsMask {
         let mask = self.mods.depressed | self.mods.latched;
+        let keymap = self.xkb_state.get_keymap();
+        let is_active = |name: &str| mask & (1 << keymap.mod_get_index(name)) != 0;
         ModsMask {
-            logo: mask
-                & (1 << self
-                    .xkb_state
-                    .get_keymap()
-                    .mod_get_index(xkb::MOD_NAME_LOGO))
-                != 0,
-            alt: mask & (1 << self.xkb_state.get_keymap().mod_get_index(xkb::MOD_NAME_ALT)) != 0,
+            logo: is_active(xkb::MOD_NAME_LOGO),
+            alt: is_active(xkb::MOD_NAME_ALT),
+            shift: is_active(xkb::MOD_NAME_SHIFT),
+            ctrl: is_active(xkb::MOD_NAME_CTRL),
         }
     }
 
@@ -227,8 +278,35 @@ impl Keyboard {
     }
 }
 
+/// Compile a keymap and write it to a shared memory file, which can be sent to clients.
+fn compile_keymap(layout: &str, options: Option<String>) -> io::Result<(File, u32, xkb::State)> {
+    let xkb_context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let xkb_keymap = xkb::Keymap::new_from_names(&xkb_context, "", "", layout, "", options, 0)
+        .ok_or_else(|| {
+            io::Error::other(format!("could not compile keymap for layout '{layout}'"))
+        })?;
+    let keymap_string_ptr = unsafe {
+        xkb::ffi::xkb_keymap_get_as_string(xkb_keymap.get_raw_ptr(), xkb::KEYMAP_FORMAT_TEXT_V1)
+    };
+    assert!(!keymap_string_ptr.is_null());
+    let keymap_string = unsafe { CStr::from_ptr(keymap_string_ptr) };
+    let keymap_bytes = keymap_string.to_bytes_with_nul();
+    let keymap_file = shmemfdrs2::create_shmem(c"/ewc-keymap-file").and_then(|mut file| {
+        file.write_all(keymap_bytes)?;
+        Ok(file)
+    });
+    let keymap_file_size = keymap_bytes.len() as u32;
+    unsafe { libc::free(keymap_string_ptr.cast()) };
+    Ok((keymap_file?, keymap_file_size, xkb::State::new(&xkb_keymap)))
+}
+
 fn wl_keyboard_cb(ctx: RequestCtx<WlKeyboard>) -> io::Result<()> {
     let wl_keyboard::Request::Release = ctx.request;
+    ctx.state
+        .seat
+        .keyboard
+        .wl_keyboards
+        .retain(|k| *k != ctx.proxy);
     ctx.client
         .conn
         .seat
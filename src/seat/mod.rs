@@ -1,29 +1,74 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::io;
 
-use crate::client::{ClientId, RequestCtx};
+use crate::client::{ClientId, ProtocolError, RequestCtx};
 use crate::config::Config;
 use crate::globals::{GlobalsManager, IsGlobal};
 use crate::protocol::*;
 use crate::wayland_core::Proxy;
 use crate::{Client, State};
 
-mod keyboard;
+pub mod keyboard;
 pub mod pointer;
+pub mod touch;
 
 pub struct Seat {
     pub keyboard: keyboard::Keyboard,
     pub pointer: pointer::Pointer,
+    pub touch: touch::Touch,
 }
 
 #[derive(Default)]
 pub struct ClientSeat {
     pub keyboards: RefCell<Vec<WlKeyboard>>,
     pub pointers: RefCell<Vec<WlPointer>>,
+    pub touches: RefCell<Vec<WlTouch>>,
     pub data_devices: RefCell<Vec<WlDataDevice>>,
     pub data_offers: RefCell<HashMap<WlDataOffer, WlDataSource>>,
+    /// The last serial sent to the client. Serials are counted per client, they are only
+    /// compared with serials of the same client.
+    serial: Cell<u32>,
+    /// The serial of the last button press or touch down sent to the client.
+    pointer_press_serial: Cell<Option<u32>>,
+    /// The serial of the last key press sent to the client.
+    key_press_serial: Cell<Option<u32>>,
+}
+
+impl ClientSeat {
+    /// A new serial for an event sent to the client.
+    pub fn next_serial(&self) -> u32 {
+        let serial = self.serial.get().wrapping_add(1);
+        self.serial.set(serial);
+        serial
+    }
+
+    /// A new serial for a button press or touch down event.
+    pub fn next_pointer_press_serial(&self) -> u32 {
+        let serial = self.next_serial();
+        self.pointer_press_serial.set(Some(serial));
+        serial
+    }
+
+    /// A new serial for a key press event.
+    pub fn next_key_press_serial(&self) -> u32 {
+        let serial = self.next_serial();
+        self.key_press_serial.set(Some(serial));
+        serial
+    }
+
+    /// Whether the serial is of the last button press or touch down sent to the client.
+    /// Interactive moves and resizes must be started in response to it.
+    pub fn is_pointer_press_serial(&self, serial: u32) -> bool {
+        self.pointer_press_serial.get() == Some(serial)
+    }
+
+    /// Whether the serial is of the last press of any kind sent to the client. Popup grabs must
+    /// be taken in response to it, requests with stale or forged serials are denied.
+    pub fn is_press_serial(&self, serial: u32) -> bool {
+        self.is_pointer_press_serial(serial) || self.key_press_serial.get() == Some(serial)
+    }
 }
 
 #[derive(Debug)]
@@ -34,7 +79,7 @@ pub struct DataSource {
 
 impl Seat {
     pub fn register_globals(globals: &mut GlobalsManager) {
-        globals.add_global::<WlSeat>(5);
+        globals.add_global::<WlSeat>(9);
         globals.add_global::<WlDataDeviceManager>(3);
     }
 
@@ -42,10 +87,12 @@ impl Seat {
         Self {
             keyboard: keyboard::Keyboard::new(config),
             pointer: pointer::Pointer::new(),
+            touch: touch::Touch::new(),
         }
     }
 
     pub fn remove_client(&mut self, client_id: ClientId) {
+        self.keyboard.remove_client(client_id);
         if self
             .keyboard
             .get_selection()
@@ -58,6 +105,7 @@ impl Seat {
     pub fn surface_unmapped(&mut self, wl_surface: &WlSurface) {
         self.keyboard.surface_unmapped(wl_surface);
         self.pointer.surface_unmapped(wl_surface);
+        self.touch.surface_unmapped(wl_surface);
     }
 }
 
@@ -82,8 +130,17 @@ impl DataSource {
 }
 
 impl IsGlobal for WlSeat {
-    fn on_bind(&self, _client: &mut Client, _state: &mut State) {
-        self.capabilities(wl_seat::Capability::Keyboard | wl_seat::Capability::Pointer);
+    fn on_bind(&self, _client: &mut Client, state: &mut State) {
+        use wl_seat::Capability;
+        self.capabilities(if state.config.touch_emulation {
+            Capability::Keyboard | Capability::Pointer | Capability::Touch
+        } else {
+            Capability::Keyboard | Capability::Pointer
+        });
+        if self.version() >= 2 {
+            // There is only one seat.
+            self.name(c"seat0".into());
+        }
         self.set_callback(|ctx| {
             use wl_seat::Request;
             match ctx.request {
@@ -100,8 +157,16 @@ impl IsGlobal for WlSeat {
                         .borrow_mut()
                         .push(wl_keyboard);
                 }
-                Request::GetTouch(_) => {
-                    return Err(io::Error::other("touch input not supporetd"));
+                Request::GetTouch(wl_touch) => {
+                    if !ctx.state.config.touch_emulation {
+                        return Err(ProtocolError::new(
+                            &ctx.proxy,
+                            wl_seat::Error::MissingCapability as u32,
+                            "the seat has no touch capability",
+                        ));
+                    }
+                    ctx.state.seat.touch.init_new_resource(&wl_touch);
+                    ctx.client.conn.seat.touches.borrow_mut().push(wl_touch);
                 }
                 Request::Release => (),
             }
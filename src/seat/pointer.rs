@@ -1,8 +1,8 @@
 use std::io;
 use std::rc::{Rc, Weak};
 
-use crate::backend::InputTimestamp;
-use crate::client::RequestCtx;
+use crate::backend::{InputTimestamp, PointerAxis};
+use crate::client::{ClientId, RequestCtx};
 use crate::globals::compositor::{Surface, SurfaceRole};
 use crate::globals::xdg_shell::toplevel::XdgToplevelRole;
 use crate::protocol::*;
@@ -24,8 +24,13 @@ pub struct Pointer {
     pub x: f32,
     pub y: f32,
     pressed_buttons: Vec<u32>,
+    /// The button, time and position of the last press which did not complete a double click.
+    last_press: Option<(u32, InputTimestamp, f32, f32)>,
 }
 
+/// How far, in pixels, the pointer may move between the two presses of a double click.
+const DOUBLE_CLICK_DISTANCE: f32 = 4.0;
+
 pub struct SurfacePointer {
     surface: Rc<Surface>,
     pressed_buttons: Vec<u32>,
@@ -64,15 +69,18 @@ impl Pointer {
         wl_pointer.set_callback(wl_pointer_cb);
         if let PtrState::Entered(sp) = &self.state {
             if sp.surface.wl.client_id() == wl_pointer.client_id() {
-                wl_pointer.enter(1, &sp.surface.wl, sp.x, sp.y);
+                let serial = wl_pointer.conn().seat.next_serial();
+                wl_pointer.enter(serial, &sp.surface.wl, sp.x, sp.y);
             }
         }
     }
 
     pub fn leave_any_surface(&mut self) {
         if let PtrState::Entered(sp) = &self.state {
-            for ptr in sp.surface.wl.conn().seat.pointers.borrow().iter() {
-                ptr.leave(1, &sp.surface.wl);
+            let seat = &sp.surface.wl.conn().seat;
+            let serial = seat.next_serial();
+            for ptr in seat.pointers.borrow().iter() {
+                ptr.leave(serial, &sp.surface.wl);
                 if ptr.version() >= 5 {
                     ptr.frame();
                 }
@@ -102,8 +110,10 @@ impl Pointer {
                 return;
             }
 
-            for ptr in sp.surface.wl.conn().seat.pointers.borrow().iter() {
-                ptr.leave(1, &sp.surface.wl);
+            let seat = &sp.surface.wl.conn().seat;
+            let serial = seat.next_serial();
+            for ptr in seat.pointers.borrow().iter() {
+                ptr.leave(serial, &sp.surface.wl);
                 if ptr.version() >= 5 {
                     ptr.frame();
                 }
@@ -117,8 +127,10 @@ impl Pointer {
             y,
         });
 
-        for ptr in surface.wl.conn().seat.pointers.borrow().iter() {
-            ptr.enter(1, &surface.wl, x, y);
+        let seat = &surface.wl.conn().seat;
+        let serial = seat.next_serial();
+        for ptr in seat.pointers.borrow().iter() {
+            ptr.enter(serial, &surface.wl, x, y);
             if ptr.version() >= 5 {
                 ptr.frame();
             }
@@ -142,16 +154,22 @@ impl Pointer {
             if let PtrState::Entered(sp) = &mut self.state {
                 if pressed && !sp.pressed_buttons.contains(&btn) {
                     sp.pressed_buttons.push(btn);
-                    for ptr in sp.surface.wl.conn().seat.pointers.borrow().iter() {
-                        ptr.button(1, timestamp.get(), btn, wl_pointer::ButtonState::Pressed);
+                    let seat = &sp.surface.wl.conn().seat;
+                    let serial = seat.next_pointer_press_serial();
+                    for ptr in seat.pointers.borrow().iter() {
+                        let state = wl_pointer::ButtonState::Pressed;
+                        ptr.button(serial, timestamp.get(), btn, state);
                         if ptr.version() >= 5 {
                             ptr.frame()
                         }
                     }
                 } else if !pressed && sp.pressed_buttons.contains(&btn) {
                     sp.pressed_buttons.retain(|x| *x != btn);
-                    for ptr in sp.surface.wl.conn().seat.pointers.borrow().iter() {
-                        ptr.button(1, timestamp.get(), btn, wl_pointer::ButtonState::Released);
+                    let seat = &sp.surface.wl.conn().seat;
+                    let serial = seat.next_serial();
+                    for ptr in seat.pointers.borrow().iter() {
+                        let state = wl_pointer::ButtonState::Released;
+                        ptr.button(serial, timestamp.get(), btn, state);
                         if ptr.version() >= 5 {
                             ptr.frame()
                         }
@@ -161,23 +179,85 @@ impl Pointer {
         }
     }
 
+    /// Record a press of the button at the current position. Returns whether it completes a
+    /// double click: the second press of the same button within `interval` milliseconds, close to
+    /// the first one. An interval of zero disables double clicks.
+    pub fn register_press(&mut self, btn: u32, timestamp: InputTimestamp, interval: u32) -> bool {
+        let double_click = interval > 0
+            && self.last_press.is_some_and(|(last_btn, last_time, x, y)| {
+                last_btn == btn
+                    && timestamp.get().wrapping_sub(last_time.get()) <= interval
+                    && (self.x - x).abs() <= DOUBLE_CLICK_DISTANCE
+                    && (self.y - y).abs() <= DOUBLE_CLICK_DISTANCE
+            });
+        // The press after a double click starts a new one.
+        self.last_press = (!double_click).then_some((btn, timestamp, self.x, self.y));
+        double_click
+    }
+
     pub fn number_of_pressed_buttons(&self) -> usize {
         self.pressed_buttons.len()
     }
 
-    pub fn axis_vertical(&mut self, value: f32, timestamp: InputTimestamp) {
-        if let Some(surface) = self.get_focused_surface() {
-            for ptr in surface.wl.conn().seat.pointers.borrow().iter() {
-                if value != 0.0 {
-                    ptr.axis(
-                        timestamp.get(),
-                        wl_pointer::Axis::VerticalScroll,
-                        Fixed::from(value),
+    /// Whether a button which was pressed on a surface of the client is still held.
+    pub fn is_pressed_on_client(&self, client_id: ClientId) -> bool {
+        match &self.state {
+            PtrState::Entered(sp) => {
+                !sp.pressed_buttons.is_empty() && sp.surface.wl.client_id() == client_id
+            }
+            _ => false,
+        }
+    }
+
+    pub fn axis(&mut self, axis: PointerAxis, timestamp: InputTimestamp) {
+        if axis.is_empty() {
+            return;
+        }
+        let Some(surface) = self.get_focused_surface() else { return };
+        for ptr in surface.wl.conn().seat.pointers.borrow().iter() {
+            if ptr.version() >= 5 {
+                if let Some(source) = axis.source {
+                    ptr.axis_source(match source {
+                        wl_pointer::AxisSource::WheelTilt if ptr.version() < 6 => {
+                            wl_pointer::AxisSource::Wheel
+                        }
+                        source => source,
+                    });
+                }
+            }
+            for (wl_axis, value) in [
+                (wl_pointer::Axis::VerticalScroll, axis.vertical),
+                (wl_pointer::Axis::HorizontalScroll, axis.horizontal),
+            ] {
+                if value.is_empty() {
+                    continue;
+                }
+                if ptr.version() >= 9 {
+                    ptr.axis_relative_direction(
+                        wl_axis,
+                        if value.inverted {
+                            wl_pointer::AxisRelativeDirection::Inverted
+                        } else {
+                            wl_pointer::AxisRelativeDirection::Identical
+                        },
                     );
-                    if ptr.version() >= 5 {
-                        ptr.frame()
+                }
+                if value.value120 != 0 {
+                    if ptr.version() >= 8 {
+                        ptr.axis_value120(wl_axis, value.value120);
+                    } else if ptr.version() >= 5 && value.value120 / 120 != 0 {
+                        ptr.axis_discrete(wl_axis, value.value120 / 120);
                     }
                 }
+                if value.value != 0.0 {
+                    ptr.axis(timestamp.get(), wl_axis, Fixed::from(value.value));
+                }
+                if value.stop && ptr.version() >= 5 {
+                    ptr.axis_stop(timestamp.get(), wl_axis);
+                }
+            }
+            if ptr.version() >= 5 {
+                ptr.frame();
             }
         }
     }
@@ -211,6 +291,15 @@ impl Pointer {
         };
     }
 
+    /// Whether an interactive move or resize is in progress. The cursor is controlled by the
+    /// compositor in this case.
+    pub fn is_grabbed(&self) -> bool {
+        matches!(
+            self.state,
+            PtrState::Moving { .. } | PtrState::Resizing { .. }
+        )
+    }
+
     pub fn get_focused_surface(&self) -> Option<Rc<Surface>> {
         match &self.state {
             PtrState::Entered(sp) => Some(sp.surface.clone()),
@@ -237,20 +326,42 @@ impl Pointer {
 fn wl_pointer_cb(ctx: RequestCtx<WlPointer>) -> io::Result<()> {
     use wl_pointer::Request;
     match ctx.request {
-        Request::SetCursor(args) => match args.surface {
-            None => ctx.state.cursor.hide(),
-            Some(surf) => {
-                let surface = ctx.client.compositor.surfaces.get(&surf).unwrap();
-                match &mut *surface.role.borrow_mut() {
-                    x @ SurfaceRole::None => *x = SurfaceRole::Cursor,
-                    SurfaceRole::Cursor => (),
-                    _ => return Err(io::Error::other("surface already has a role")),
+        Request::SetCursor(args) => {
+            // Only the client with pointer focus may change the cursor. There is no focus while
+            // the pointer is grabbed.
+            let has_focus = ctx
+                .state
+                .seat
+                .pointer
+                .get_focused_surface()
+                .is_some_and(|surface| surface.wl.client_id() == ctx.proxy.client_id());
+            match args.surface {
+                None if !has_focus => (),
+                None => {
+                    ctx.state.repaint_cursor();
+                    ctx.state.cursor.hide();
+                }
+                Some(surf) => {
+                    let surface = ctx.client.compositor.surfaces.get(&surf).unwrap();
+                    if surface.has_role() && !matches!(&*surface.role.borrow(), SurfaceRole::Cursor)
+                    {
+                        return Err(
+                            surface.role_conflict(&ctx.proxy, wl_pointer::Error::Role as u32)
+                        );
+                    }
+                    *surface.role.borrow_mut() = SurfaceRole::Cursor;
+                    if has_focus {
+                        ctx.state.repaint_cursor();
+                        ctx.state.cursor.set_surface(
+                            surface.clone(),
+                            args.hotspot_x,
+                            args.hotspot_y,
+                        );
+                        ctx.state.repaint_cursor();
+                    }
                 }
-                ctx.state
-                    .cursor
-                    .set_surface(surface.clone(), args.hotspot_x, args.hotspot_y);
             }
-        },
+        }
         Request::Release => {
             ctx.client
                 .conn
@@ -262,3 +373,39 @@ fn wl_pointer_cb(ctx: RequestCtx<WlPointer>) -> io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_press() {
+        let mut pointer = Pointer::new();
+        let t = InputTimestamp::new;
+
+        assert!(!pointer.register_press(BTN_LEFT, t(1000), 400));
+        assert!(pointer.register_press(BTN_LEFT, t(1400), 400));
+        // The third press starts a new double click.
+        assert!(!pointer.register_press(BTN_LEFT, t(1500), 400));
+
+        // Too late.
+        assert!(!pointer.register_press(BTN_LEFT, t(2000), 400));
+        // Another button.
+        assert!(!pointer.register_press(BTN_RIGHT, t(2100), 400));
+
+        // Too far away.
+        pointer.x = DOUBLE_CLICK_DISTANCE + 1.0;
+        assert!(!pointer.register_press(BTN_RIGHT, t(2200), 400));
+        pointer.x += DOUBLE_CLICK_DISTANCE;
+        pointer.y -= DOUBLE_CLICK_DISTANCE;
+        assert!(pointer.register_press(BTN_RIGHT, t(2300), 400));
+
+        // The timestamps wrap around.
+        assert!(!pointer.register_press(BTN_LEFT, t(u32::MAX - 100), 400));
+        assert!(pointer.register_press(BTN_LEFT, t(100), 400));
+
+        // Double clicks are disabled.
+        assert!(!pointer.register_press(BTN_LEFT, t(200), 0));
+        assert!(!pointer.register_press(BTN_LEFT, t(300), 0));
+    }
+}
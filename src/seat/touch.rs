@@ -0,0 +1,127 @@
+//! `wl_touch`. There is no touch input yet, a single touch point can only be emulated with the
+//! pointer (see `Config::touch_emulation`).
+
+use std::io;
+use std::rc::Rc;
+
+use crate::backend::InputTimestamp;
+use crate::client::{ClientId, RequestCtx};
+use crate::globals::compositor::Surface;
+use crate::protocol::*;
+use crate::wayland_core::{Fixed, Proxy};
+
+/// The id of the only touch point.
+const TOUCH_ID: i32 = 0;
+
+#[derive(Default)]
+pub struct Touch {
+    point: Option<TouchPoint>,
+}
+
+struct TouchPoint {
+    surface: Rc<Surface>,
+    /// The global position of the surface when the touch point went down. Motion is relative to
+    /// it, even if the touch point leaves the surface.
+    origin_x: f32,
+    origin_y: f32,
+}
+
+impl Touch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn init_new_resource(&self, wl_touch: &WlTouch) {
+        wl_touch.set_callback(wl_touch_cb);
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.point.is_some()
+    }
+
+    /// Whether the touch point went down on a surface of the client.
+    pub fn is_down_on_client(&self, client_id: ClientId) -> bool {
+        self.point
+            .as_ref()
+            .is_some_and(|point| point.surface.wl.client_id() == client_id)
+    }
+
+    /// Put the touch point down at global position `x, y`, which is `sx, sy` in surface-local
+    /// coordinates.
+    pub fn down(
+        &mut self,
+        surface: Rc<Surface>,
+        timestamp: InputTimestamp,
+        x: f32,
+        y: f32,
+        sx: f32,
+        sy: f32,
+    ) {
+        let seat = &surface.wl.conn().seat;
+        let serial = seat.next_pointer_press_serial();
+        for touch in seat.touches.borrow().iter() {
+            touch.down(
+                serial,
+                timestamp.get(),
+                &surface.wl,
+                TOUCH_ID,
+                Fixed::from(sx),
+                Fixed::from(sy),
+            );
+            touch.frame();
+        }
+        self.point = Some(TouchPoint {
+            surface,
+            origin_x: x - sx,
+            origin_y: y - sy,
+        });
+    }
+
+    /// Move the touch point to global position `x, y`.
+    pub fn motion(&mut self, timestamp: InputTimestamp, x: f32, y: f32) {
+        let Some(point) = &self.point else { return };
+        for touch in point.surface.wl.conn().seat.touches.borrow().iter() {
+            touch.motion(
+                timestamp.get(),
+                TOUCH_ID,
+                Fixed::from(x - point.origin_x),
+                Fixed::from(y - point.origin_y),
+            );
+            touch.frame();
+        }
+    }
+
+    pub fn up(&mut self, timestamp: InputTimestamp) {
+        let Some(point) = self.point.take() else { return };
+        let seat = &point.surface.wl.conn().seat;
+        let serial = seat.next_serial();
+        for touch in seat.touches.borrow().iter() {
+            touch.up(serial, timestamp.get(), TOUCH_ID);
+            touch.frame();
+        }
+    }
+
+    pub fn surface_unmapped(&mut self, wl_surface: &WlSurface) {
+        if self
+            .point
+            .as_ref()
+            .is_some_and(|point| point.surface.wl == *wl_surface)
+        {
+            for touch in wl_surface.conn().seat.touches.borrow().iter() {
+                touch.cancel();
+            }
+            self.point = None;
+        }
+    }
+}
+
+fn wl_touch_cb(ctx: RequestCtx<WlTouch>) -> io::Result<()> {
+    let wl_touch::Request::Release = ctx.request;
+    ctx.client
+        .conn
+        .seat
+        .touches
+        .borrow_mut()
+        .retain(|t| *t != ctx.proxy);
+    Ok(())
+}
@@ -0,0 +1,340 @@
+//! A server with a [`RecordingBackend`] and a client which talks the wire protocol directly, for
+//! tests which need real surfaces and windows.
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::backend::recording::{Input, Recording, RecordingBackend};
+use crate::client::ClientId;
+use crate::config::Config;
+use crate::Server;
+
+/// A bare-bones client which talks the wire protocol directly.
+pub struct TestClient {
+    pub stream: UnixStream,
+    next_id: u32,
+}
+
+pub enum Arg<'a> {
+    Uint(u32),
+    Int(i32),
+    Str(&'a str),
+}
+
+impl TestClient {
+    pub fn new_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub fn request(&mut self, object: u32, opcode: u16, args: &[Arg]) {
+        let mut payload = Vec::new();
+        for arg in args {
+            match arg {
+                Arg::Uint(x) => payload.extend_from_slice(&x.to_ne_bytes()),
+                Arg::Int(x) => payload.extend_from_slice(&x.to_ne_bytes()),
+                Arg::Str(s) => {
+                    payload.extend_from_slice(&(s.len() as u32 + 1).to_ne_bytes());
+                    payload.extend_from_slice(s.as_bytes());
+                    payload.push(0);
+                    payload.resize(payload.len().next_multiple_of(4), 0);
+                }
+            }
+        }
+        let size = (payload.len() + 8) as u32;
+        let mut msg = object.to_ne_bytes().to_vec();
+        msg.extend_from_slice(&((size << 16) | opcode as u32).to_ne_bytes());
+        msg.extend_from_slice(&payload);
+        self.stream.write_all(&msg).unwrap();
+    }
+
+    /// Read all events sent so far, as (object, opcode, payload).
+    pub fn events(&mut self) -> Vec<(u32, u16, Vec<u8>)> {
+        let mut bytes = Vec::new();
+        self.stream.set_nonblocking(true).unwrap();
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => bytes.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{e}"),
+            }
+        }
+        self.stream.set_nonblocking(false).unwrap();
+        let mut events = Vec::new();
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let (object, size_opcode) = (read_u32(rest, 0), read_u32(rest, 4));
+            let size = (size_opcode >> 16) as usize;
+            events.push((object, size_opcode as u16, rest[8..size].to_vec()));
+            rest = &rest[size..];
+        }
+        events
+    }
+}
+
+pub fn read_u32(bytes: &[u8], i: usize) -> u32 {
+    u32::from_ne_bytes(bytes[i..i + 4].try_into().unwrap())
+}
+
+/// A server with a [`RecordingBackend`] and a single connected [`TestClient`], which has already
+/// received the globals and bound the ones most tests need.
+pub struct Harness {
+    pub server: Server,
+    pub recording: Recording,
+    pub input: Input,
+    pub client_id: ClientId,
+    pub client: TestClient,
+    pub registry: u32,
+    pub globals: HashMap<String, u32>,
+    /// `wl_compositor` v6
+    pub compositor: u32,
+    /// `xdg_wm_base` v5
+    pub wm_base: u32,
+    /// `wp_single_pixel_buffer_manager_v1` v1
+    pub single_pixel: u32,
+    /// `wp_viewporter` v1
+    pub viewporter: u32,
+}
+
+pub struct TestToplevel {
+    pub surface: u32,
+    pub xdg_surface: u32,
+    pub toplevel: u32,
+    /// The serial of the initial configure.
+    pub serial: u32,
+}
+
+pub struct TestPopup {
+    pub surface: u32,
+    pub xdg_surface: u32,
+    pub popup: u32,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("ewc-test-{}-{n}", std::process::id());
+        let dir = env::temp_dir();
+        let (backend, recording, input) = RecordingBackend::new(1280, 720);
+        let mut server = Server::with_backend(
+            backend,
+            Config::default(),
+            dir.join(format!("{name}.sock")),
+            dir.join(format!("{name}-ipc.sock")),
+        );
+
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let client_id = server.next_client_id;
+        server.add_client(server_end, None);
+        let mut harness = Self {
+            server,
+            recording,
+            input,
+            client_id,
+            client: TestClient {
+                stream: client_end,
+                next_id: 1,
+            },
+            registry: 0,
+            globals: HashMap::new(),
+            compositor: 0,
+            wm_base: 0,
+            single_pixel: 0,
+            viewporter: 0,
+        };
+
+        // wl_display.get_registry
+        harness.registry = harness.client.new_id();
+        harness.client.request(1, 1, &[Arg::Uint(harness.registry)]);
+        for (object, opcode, args) in harness.roundtrip() {
+            if object == harness.registry && opcode == 0 {
+                let len = read_u32(&args, 4) as usize;
+                let interface = String::from_utf8(args[8..8 + len - 1].to_vec()).unwrap();
+                harness.globals.insert(interface, read_u32(&args, 0));
+            }
+        }
+        harness.compositor = harness.bind("wl_compositor", 6);
+        harness.wm_base = harness.bind("xdg_wm_base", 5);
+        harness.single_pixel = harness.bind("wp_single_pixel_buffer_manager_v1", 1);
+        harness.viewporter = harness.bind("wp_viewporter", 1);
+        harness
+    }
+
+    /// Let the server process the requests sent so far and return the events it sent back.
+    pub fn roundtrip(&mut self) -> Vec<(u32, u16, Vec<u8>)> {
+        let client = self.server.clients.get_mut(&self.client_id).unwrap();
+        client.poll(&mut self.server.state).unwrap();
+        self.server.may_go_idle();
+        self.client.events()
+    }
+
+    /// Let the server process the queued input and return the events it sent back.
+    pub fn dispatch_input(&mut self) -> Vec<(u32, u16, Vec<u8>)> {
+        self.server.poll_backend(0).unwrap();
+        self.roundtrip()
+    }
+
+    pub fn bind(&mut self, interface: &str, version: u32) -> u32 {
+        let id = self.client.new_id();
+        self.client.request(
+            self.registry,
+            0,
+            &[
+                Arg::Uint(self.globals[interface]),
+                Arg::Str(interface),
+                Arg::Uint(version),
+                Arg::Uint(id),
+            ],
+        );
+        id
+    }
+
+    /// Create a toplevel and do the initial commit, without mapping it.
+    pub fn create_toplevel(&mut self) -> TestToplevel {
+        let surface = self.client.new_id();
+        self.client
+            .request(self.compositor, 0, &[Arg::Uint(surface)]);
+        let xdg_surface = self.client.new_id();
+        self.client.request(
+            self.wm_base,
+            2,
+            &[Arg::Uint(xdg_surface), Arg::Uint(surface)],
+        );
+        let toplevel = self.client.new_id();
+        self.client.request(xdg_surface, 1, &[Arg::Uint(toplevel)]);
+        self.client.request(surface, 6, &[]);
+        let serial = self
+            .roundtrip()
+            .into_iter()
+            .find(|(object, opcode, _)| *object == xdg_surface && *opcode == 0)
+            .map(|(_, _, args)| read_u32(&args, 0))
+            .expect("no configure event");
+        TestToplevel {
+            surface,
+            xdg_surface,
+            toplevel,
+            serial,
+        }
+    }
+
+    /// Create a toplevel, ack the initial configure and map it with a red buffer of the given
+    /// size.
+    pub fn map_toplevel(&mut self, width: i32, height: i32) -> TestToplevel {
+        let t = self.create_toplevel();
+        self.client
+            .request(t.xdg_surface, 4, &[Arg::Uint(t.serial)]);
+        self.attach_red_buffer(t.surface, width, height);
+        self.client.request(t.surface, 6, &[]);
+        self.roundtrip();
+        t
+    }
+
+    /// Create a popup of `parent` (an xdg_surface) with the given position relative to the
+    /// parent's window geometry and size. The popup is not committed yet, so it can still take a
+    /// grab.
+    pub fn create_popup(
+        &mut self,
+        parent: u32,
+        (x, y): (i32, i32),
+        (width, height): (i32, i32),
+    ) -> TestPopup {
+        let positioner = self.client.new_id();
+        self.client
+            .request(self.wm_base, 1, &[Arg::Uint(positioner)]);
+        self.client
+            .request(positioner, 1, &[Arg::Int(width), Arg::Int(height)]);
+        self.client.request(
+            positioner,
+            2,
+            &[Arg::Int(x), Arg::Int(y), Arg::Int(1), Arg::Int(1)],
+        );
+        // Anchor top_left, gravity bottom_right
+        self.client.request(positioner, 3, &[Arg::Uint(5)]);
+        self.client.request(positioner, 4, &[Arg::Uint(8)]);
+        let surface = self.client.new_id();
+        self.client
+            .request(self.compositor, 0, &[Arg::Uint(surface)]);
+        let xdg_surface = self.client.new_id();
+        self.client.request(
+            self.wm_base,
+            2,
+            &[Arg::Uint(xdg_surface), Arg::Uint(surface)],
+        );
+        let popup = self.client.new_id();
+        self.client.request(
+            xdg_surface,
+            2,
+            &[Arg::Uint(popup), Arg::Uint(parent), Arg::Uint(positioner)],
+        );
+        TestPopup {
+            surface,
+            xdg_surface,
+            popup,
+        }
+    }
+
+    /// Send the initial commit of the popup, ack the configure and map it with a buffer.
+    pub fn map_popup(&mut self, popup: &TestPopup, width: i32, height: i32) {
+        self.client.request(popup.surface, 6, &[]);
+        let serial = self
+            .roundtrip()
+            .into_iter()
+            .find(|(object, opcode, _)| *object == popup.xdg_surface && *opcode == 0)
+            .map(|(_, _, args)| read_u32(&args, 0))
+            .expect("no configure event");
+        self.client
+            .request(popup.xdg_surface, 4, &[Arg::Uint(serial)]);
+        self.attach_red_buffer(popup.surface, width, height);
+        self.client.request(popup.surface, 6, &[]);
+        self.roundtrip();
+    }
+
+    /// Create a surface and make it a subsurface of `parent` at the given position. The position
+    /// takes effect on the next commit of the parent.
+    pub fn create_subsurface(&mut self, subcompositor: u32, parent: u32, x: i32, y: i32) -> u32 {
+        let surface = self.client.new_id();
+        self.client
+            .request(self.compositor, 0, &[Arg::Uint(surface)]);
+        let subsurface = self.client.new_id();
+        self.client.request(
+            subcompositor,
+            1,
+            &[Arg::Uint(subsurface), Arg::Uint(surface), Arg::Uint(parent)],
+        );
+        self.client
+            .request(subsurface, 1, &[Arg::Int(x), Arg::Int(y)]);
+        surface
+    }
+
+    /// Attach an opaque red buffer of the given size to the surface, without committing.
+    pub fn attach_red_buffer(&mut self, surface: u32, width: i32, height: i32) {
+        let buffer = self.client.new_id();
+        self.client.request(
+            self.single_pixel,
+            1,
+            &[
+                Arg::Uint(buffer),
+                Arg::Uint(u32::MAX),
+                Arg::Uint(0),
+                Arg::Uint(0),
+                Arg::Uint(u32::MAX),
+            ],
+        );
+        let viewport = self.client.new_id();
+        self.client.request(
+            self.viewporter,
+            1,
+            &[Arg::Uint(viewport), Arg::Uint(surface)],
+        );
+        self.client
+            .request(viewport, 2, &[Arg::Int(width), Arg::Int(height)]);
+        self.client
+            .request(surface, 1, &[Arg::Uint(buffer), Arg::Int(0), Arg::Int(0)]);
+    }
+}
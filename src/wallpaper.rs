@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use crate::backend::{Backend, BufferId, OutputId, RenderNode};
+use crate::buffer_transform::BufferTransform;
+use crate::config::Config;
+use crate::protocol::wl_output;
+use crate::Fixed;
+
+/// Wallpaper textures, keyed by output name.
+#[derive(Default)]
+pub struct Wallpapers {
+    textures: HashMap<String, BufferId>,
+}
+
+impl Wallpapers {
+    pub fn new(config: &Config, backend: &mut dyn Backend) -> Self {
+        let mut textures = HashMap::new();
+        for (name, output) in &config.output {
+            let Some(path) = &output.wallpaper else { continue };
+            match load_ppm(path) {
+                Ok((width, height, bytes)) => {
+                    let path = path.clone();
+                    let buf_id = backend.renderer_state().create_argb8_texture(
+                        width,
+                        height,
+                        &bytes,
+                        Box::new(move || load_ppm(&path).ok().map(|(_, _, bytes)| bytes)),
+                    );
+                    textures.insert(name.clone(), buf_id);
+                }
+                Err(e) => eprintln!("could not load wallpaper {path:?}: {e}"),
+            }
+        }
+        Self { textures }
+    }
+
+    pub fn destroy(self, backend: &mut dyn Backend) {
+        for buf_id in self.textures.into_values() {
+            backend.renderer_state().buffer_unlock(buf_id);
+        }
+    }
+
+    /// Returns a render node which stretches the wallpaper over the whole output.
+    pub fn render_node(&self, backend: &mut dyn Backend, output: OutputId) -> Option<RenderNode> {
+        let buf_id = *self.textures.get(backend.output_name(output))?;
+        let output_rect = backend.output_geometry(output);
+        let (width, height) = backend.renderer_state().get_buffer_size(buf_id);
+        let buf_transform = BufferTransform::new(
+            buf_id,
+            backend,
+            wl_output::Transform::Normal,
+            NonZeroU32::MIN,
+            Some((
+                0.0,
+                0.0,
+                Fixed::from(width as i32),
+                Fixed::from(height as i32),
+            )),
+            Some((output_rect.width, output_rect.height)),
+        )
+        .ok()?;
+        Some(RenderNode::Buffer {
+            x: 0,
+            y: 0,
+            opaque_region: None,
+            alpha: 1.0,
+            buf_transform,
+            frame_callbacks: Vec::new(),
+        })
+    }
+}
+
+/// Load a binary PPM (P6) image, returning its size and ARGB8888 pixels.
+fn load_ppm(path: &Path) -> io::Result<(u32, u32, Vec<u8>)> {
+    let contents = std::fs::read(path)?;
+    parse_ppm(&contents).ok_or_else(|| io::Error::other("not a valid binary PPM image"))
+}
+
+fn parse_ppm(mut data: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    data = data.strip_prefix(b"P6")?;
+
+    let mut header = [0u32; 3];
+    for val in &mut header {
+        loop {
+            let ws = data.iter().take_while(|c| c.is_ascii_whitespace()).count();
+            data = &data[ws..];
+            if data.first() == Some(&b'#') {
+                let eol = data.iter().position(|&c| c == b'\n')?;
+                data = &data[eol..];
+            } else {
+                break;
+            }
+        }
+        let len = data.iter().position(|c| !c.is_ascii_digit())?;
+        *val = std::str::from_utf8(&data[..len]).ok()?.parse().ok()?;
+        data = &data[len..];
+    }
+    let [width, height, maxval] = header;
+    if width == 0 || height == 0 || maxval != 255 {
+        return None;
+    }
+
+    // Exactly one whitespace character separates the header from the pixels.
+    let (sep, data) = data.split_first()?;
+    if !sep.is_ascii_whitespace() {
+        return None;
+    }
+    let pixels = data.get(..(width as usize * height as usize * 3))?;
+
+    let mut bytes = Vec::with_capacity(width as usize * height as usize * 4);
+    for rgb in pixels.chunks_exact(3) {
+        bytes.extend_from_slice(&[rgb[2], rgb[1], rgb[0], 255]);
+    }
+    Some((width, height, bytes))
+}